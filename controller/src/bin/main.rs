@@ -11,13 +11,17 @@ extern crate alloc;
 use core::cell::RefCell;
 use core::mem::ManuallyDrop;
 
+use controller::mavlink::TelemetrySignals;
 use controller::signal::{
-    battery_signal, controller_connected_signal, drone_altitude_signal, drone_battery_level_signal, input_signal,
-    new_battery_signal_emitter, new_controller_connected_signal_emitter, new_drone_altitude_signal_emitter,
-    new_drone_battery_level_signal_emitter, new_input_signal_emitter, new_radio_link_quality_signal_emitter,
-    new_radio_signal_emitter, radio_link_quality_signal, radio_signal,
+    battery_signal, command_results_signal, controller_connected_signal, downlink_statistics_signal,
+    drone_altitude_signals, drone_attitude_signals, drone_battery_signals, gcs_command_signal, input_signal,
+    new_battery_signal_emitter, new_command_results_signal_emitter, new_controller_connected_signal_emitter,
+    new_downlink_statistics_signal_emitter, new_drone_altitude_signal_emitter, new_drone_attitude_signal_emitter,
+    new_drone_battery_signal_emitter, new_gcs_command_signal_emitter, new_input_signal_emitter,
+    new_radio_link_quality_signal_emitter, new_radio_signal_emitter, new_survey_signal_emitter,
+    radio_link_quality_signals, radio_signals, start_telemetry_tap, survey_signal,
 };
-use controller::{gui, input, radio};
+use controller::{gui, input, mavlink, radio};
 use embassy_embedded_hal::shared_bus::{asynch, blocking};
 use embassy_executor::Spawner;
 use embassy_sync::blocking_mutex::raw::NoopRawMutex;
@@ -25,13 +29,18 @@ use embassy_sync::blocking_mutex::NoopMutex;
 use embassy_sync::mutex::Mutex;
 use esp_hal::clock::CpuClock;
 use esp_hal::gpio::{Input, InputConfig, Level, Output, OutputConfig, Pull};
+use esp_hal::rng::Rng;
 use esp_hal::spi::master::{Config, Spi};
 use esp_hal::spi::Mode;
 use esp_hal::time::Rate;
 use esp_hal::timer::systimer::SystemTimer;
 use esp_hal::timer::timg::TimerGroup;
+use esp_hal::usb_serial_jtag::UsbSerialJtag;
 use esp_hal::{Async, Blocking};
+use esp_storage::FlashStorage;
 use esp_wifi::EspWifiController;
+use fc_common::registry;
+use fc_common::storage::Store;
 use static_cell::StaticCell;
 
 #[panic_handler]
@@ -48,10 +57,15 @@ static SPI2_BUS: StaticCell<Mutex<NoopRawMutex, Spi<Async>>> = StaticCell::new()
 //static SPI3_BUS: StaticCell<BlockingMutex<NoopRawMutex, Spi<Blocking>>> = StaticCell::new();
 static SPI3_BUS: StaticCell<NoopMutex<RefCell<Spi<Blocking>>>> = StaticCell::new();
 
+/// The NVS partition of the default partition table, which nothing else on the controller uses.
+const STORAGE_OFFSET: u32 = 0x9000;
+const STORAGE_SIZE: u32 = 0x6000;
+
 #[esp_hal_embassy::main]
 async fn main(spawner: Spawner) {
     // generator version: 0.5.0
     esp_println::logger::init_logger_from_env();
+    start_telemetry_tap();
     let config = esp_hal::Config::default().with_cpu_clock(CpuClock::max());
     let peripherals = esp_hal::init(config);
 
@@ -60,8 +74,8 @@ async fn main(spawner: Spawner) {
     let timg0 = TimerGroup::new(peripherals.TIMG0);
 
     esp_println::println!("Init WIFI!");
-    let wifi_init = esp_wifi::init(timg0.timer0, esp_hal::rng::Rng::new(peripherals.RNG))
-        .expect("Failed to initialize WIFI/BLE controller");
+    let rng = Rng::new(peripherals.RNG);
+    let wifi_init = esp_wifi::init(timg0.timer0, rng).expect("Failed to initialize WIFI/BLE controller");
 
     let systimer = SystemTimer::new(peripherals.SYSTIMER);
     esp_hal_embassy::init(systimer.alarm0);
@@ -106,14 +120,32 @@ async fn main(spawner: Spawner) {
     let radio_device = asynch::spi::SpiDevice::new(spi2_bus, radio_cs);
     let radio_irq = Input::new(peripherals.GPIO12, InputConfig::default().with_pull(Pull::Up));
 
+    let usb_serial = UsbSerialJtag::new(peripherals.USB_DEVICE).into_async();
+
+    let store =
+        Store::new(FlashStorage::new(), STORAGE_OFFSET, STORAGE_SIZE).expect("Failed to read settings from flash");
+
     /* Create signal emitters */
     let battery_emitter = new_battery_signal_emitter();
     let input_emitter = new_input_signal_emitter();
     let controller_emitter = new_controller_connected_signal_emitter();
     let radio_status_emitter = new_radio_signal_emitter();
-    let drone_battery_emitter = new_drone_battery_level_signal_emitter();
+    let drone_attitude_emitter = new_drone_attitude_signal_emitter();
+    let drone_battery_emitter = new_drone_battery_signal_emitter();
     let drone_altitude_emitter = new_drone_altitude_signal_emitter();
     let radio_link_quality_emitter = new_radio_link_quality_signal_emitter();
+    let downlink_statistics_emitter = new_downlink_statistics_signal_emitter();
+    let gcs_command_emitter = new_gcs_command_signal_emitter();
+    let command_results_emitter = new_command_results_signal_emitter();
+    let survey_emitter = new_survey_signal_emitter();
+
+    /* Claim signal subscribers. The ones shared by the GUI and MAVLink are claimed all at once, which fails to
+    compile if their declared subscriber counts are off */
+    let [gui_radio, mavlink_radio] = radio_signals().unwrap();
+    let [gui_drone_attitude, mavlink_drone_attitude] = drone_attitude_signals().unwrap();
+    let [gui_drone_battery, mavlink_drone_battery] = drone_battery_signals().unwrap();
+    let [gui_drone_altitude, mavlink_drone_altitude] = drone_altitude_signals().unwrap();
+    let [gui_link_quality, mavlink_link_quality] = radio_link_quality_signals().unwrap();
 
     /* Start up sub-systems */
     spawner
@@ -130,12 +162,14 @@ async fn main(spawner: Spawner) {
             display_device,
             display_rst,
             display_dc,
-            battery_signal(),
-            radio_signal(),
-            controller_connected_signal(),
-            drone_battery_level_signal(),
-            drone_altitude_signal(),
-            radio_link_quality_signal(),
+            battery_signal().unwrap(),
+            gui_radio,
+            controller_connected_signal().unwrap(),
+            gui_drone_attitude,
+            gui_drone_battery,
+            gui_drone_altitude,
+            gui_link_quality,
+            survey_signal().unwrap(),
         ))
         .unwrap();
     spawner
@@ -143,13 +177,44 @@ async fn main(spawner: Spawner) {
             radio_device,
             radio_ce,
             radio_irq,
-            input_signal(),
+            input_signal().unwrap(),
             radio_status_emitter,
-            drone_altitude_emitter,
+            drone_attitude_emitter,
             drone_battery_emitter,
+            drone_altitude_emitter,
             radio_link_quality_emitter,
+            downlink_statistics_emitter,
+            gcs_command_signal().unwrap(),
+            command_results_emitter,
+            survey_emitter,
+            store,
+            rng,
         ))
         .unwrap();
+    spawner
+        .spawn(mavlink::run(
+            usb_serial,
+            TelemetrySignals {
+                radio: mavlink_radio,
+                attitude: mavlink_drone_attitude,
+                battery: mavlink_drone_battery,
+                altitude: mavlink_drone_altitude,
+                link_quality: mavlink_link_quality,
+                downlink_statistics: downlink_statistics_signal().unwrap(),
+            },
+            gcs_command_emitter,
+            command_results_signal().unwrap(),
+        ))
+        .unwrap();
+
+    for info in registry::signals() {
+        esp_println::println!(
+            "Signal {}: {}/{} subscribers claimed",
+            info.name,
+            info.claimed(),
+            info.subscribers
+        );
+    }
 
     core::future::pending::<()>().await;
 }
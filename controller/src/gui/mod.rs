@@ -3,14 +3,16 @@ use uom::num_traits::Float;
 mod assets;
 mod battery_indicator;
 mod label;
+mod survey_graph;
 
 use alloc::format;
 use core::fmt::Debug;
 use core::str::FromStr;
 
 use embassy_embedded_hal::shared_bus::blocking::spi::SpiDevice;
-use embassy_futures::select::{select6, Either6};
+use embassy_futures::select::{select, select3, select5, Either, Either3, Either5};
 use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+use embassy_time::Duration;
 use embedded_graphics::image::Image;
 use embedded_graphics::mono_font::ascii::FONT_8X13_BOLD;
 use embedded_graphics::mono_font::MonoTextStyle;
@@ -21,21 +23,23 @@ use esp_hal::delay::Delay;
 use esp_hal::gpio::Output;
 use esp_hal::spi::master::Spi;
 use esp_hal::Blocking;
-use fc_common::SignalBase;
+use fc_common::survey::ChannelSurvey;
+use fc_common::telemetry::{AltitudeTelemetry, AttitudeTelemetry, BatteryTelemetry, FlightMode};
+use fc_common::{SignalBase, SignalExt};
 use ssd1351::mode::GraphicsMode;
 use ssd1351::prelude::SPIInterface;
 use ssd1351::properties::DisplayRotation;
 use ssd1351::properties::DisplaySize::Display128x128;
 use tinyui::component::Component;
-use uom::num_traits::Euclid;
 
 use crate::gui::assets::{
     DRONE_DISCONNECTED_ICON_RAW, DRONE_ICON_RAW, GAMEPAD_CONNECTED_ICON_RAW, GAMEPAD_DISCONNECTED_ICON_RAW,
 };
 use crate::gui::label::Label;
+use crate::gui::survey_graph::SurveyGraph;
 use crate::signal::{
-    BatterySignal, ControllerConnectedSignal, DroneAltitudeSignal, DroneBatteryLevelSignal, RadioLinkQualitySignal,
-    RadioSignal,
+    BatterySignal, BindStatus, ControllerBattery, ControllerConnectedSignal, DroneAltitudeSignal, DroneAttitudeSignal,
+    DroneBatterySignal, RadioLinkQualitySignal, RadioSignal, RadioStatus, SurveySignal,
 };
 
 #[embassy_executor::task]
@@ -43,12 +47,14 @@ pub async fn run(
     spi_device: SpiDevice<'static, NoopRawMutex, Spi<'static, Blocking>, Output<'static>>,
     mut rst: Output<'static>,
     dc: Output<'static>,
-    mut battery_signal: BatterySignal,
-    mut radio_signal: RadioSignal,
-    mut controller_signal: ControllerConnectedSignal,
-    mut drone_battery_signal: DroneBatteryLevelSignal,
-    mut drone_altitude_signal: DroneAltitudeSignal,
-    mut radio_link_quality_signal: RadioLinkQualitySignal,
+    battery_signal: BatterySignal,
+    radio_signal: RadioSignal,
+    controller_signal: ControllerConnectedSignal,
+    drone_attitude_signal: DroneAttitudeSignal,
+    drone_battery_signal: DroneBatterySignal,
+    drone_altitude_signal: DroneAltitudeSignal,
+    radio_link_quality_signal: RadioLinkQualitySignal,
+    survey_signal: SurveySignal,
 ) {
     let interface = SPIInterface::new(spi_device, dc);
 
@@ -63,75 +69,165 @@ pub async fn run(
     display.reset(&mut rst, &mut delay).unwrap();
     display.init().unwrap();
 
+    show(
+        &mut display,
+        battery_signal,
+        radio_signal,
+        controller_signal,
+        drone_attitude_signal,
+        drone_battery_signal,
+        drone_altitude_signal,
+        radio_link_quality_signal,
+        survey_signal,
+    )
+    .await
+}
+
+/// Draws whatever the signals bring. Takes any display and any signals, so it runs against a mocked display on
+/// the host as well.
+#[allow(clippy::too_many_arguments)]
+pub async fn show<D>(
+    display: &mut D,
+    mut battery_signal: impl SignalBase<ControllerBattery>,
+    mut radio_signal: impl SignalBase<RadioStatus>,
+    mut controller_signal: impl SignalBase<bool>,
+    mut drone_attitude_signal: impl SignalBase<AttitudeTelemetry>,
+    mut drone_battery_signal: impl SignalBase<BatteryTelemetry>,
+    mut drone_altitude_signal: impl SignalBase<AltitudeTelemetry>,
+    radio_link_quality_signal: impl SignalBase<f32>,
+    mut survey_signal: impl SignalBase<ChannelSurvey>,
+) -> !
+where
+    D: DrawTarget<Color = Rgb565>,
+    D::Error: Debug,
+{
     let style = MonoTextStyle::new(&FONT_8X13_BOLD, Rgb565::WHITE);
     let mut gamepad_battery_label: Label<'_, _, 15> =
         Label::new("-%", style, Point::new(22, -3), Rgb565::BLACK).unwrap();
     let mut drone_battery_label: Label<'_, _, 15> = Label::new("-%", style, Point::new(92, -3), Rgb565::BLACK).unwrap();
     let mut altitude_label: Label<'_, _, 15> = Label::new("-%", style, Point::new(0, 40), Rgb565::BLACK).unwrap();
+    let mut status_label: Label<'_, _, 15> = Label::new("-", style, Point::new(0, 55), Rgb565::BLACK).unwrap();
     let mut quality_label: Label<'_, _, 15> = Label::new("-%", style, Point::new(0, 70), Rgb565::BLACK).unwrap();
+    let mut attitude_label: Label<'_, _, 15> = Label::new("-", style, Point::new(0, 85), Rgb565::BLACK).unwrap();
+    let mut voltage_label: Label<'_, _, 15> = Label::new("-", style, Point::new(0, 100), Rgb565::BLACK).unwrap();
 
     let gamepad_connected_icon = Image::new(&GAMEPAD_CONNECTED_ICON_RAW, Point::new(0, 0));
     let gamepad_disconnected_icon = Image::new(&GAMEPAD_DISCONNECTED_ICON_RAW, Point::new(0, 0));
     let drone_icon = Image::new(&DRONE_ICON_RAW, Point::new(70, 0));
     let drone_disconnected_icon = Image::new(&DRONE_DISCONNECTED_ICON_RAW, Point::new(70, 0));
+    let mut survey_graph = SurveyGraph::new();
+    // The radio updates the link quality with every frame, far more often than it's worth redrawing.
+    let mut radio_link_quality_signal = radio_link_quality_signal.throttle(Duration::from_millis(250));
     loop {
-        match select6(
+        match select5(
             battery_signal.next_value(),
             controller_signal.next_value(),
             radio_signal.next_value(),
-            core::future::pending::<()>(),
-            //drone_battery_signal.next_value(),
-            drone_altitude_signal.next_value(),
-            radio_link_quality_signal.next_value(),
+            select3(
+                drone_attitude_signal.next_value(),
+                drone_battery_signal.next_value(),
+                drone_altitude_signal.next_value(),
+            ),
+            select(radio_link_quality_signal.next_value(), survey_signal.next_value()),
         )
         .await
         {
-            Either6::First(battery) => {
+            Either5::First(battery) => {
                 esp_println::println!("DRAWING battery text");
                 gamepad_battery_label.set_text(&format!("{}%", battery.level)).unwrap();
-                gamepad_battery_label.draw(&mut display).unwrap();
+                gamepad_battery_label.draw(display).unwrap();
             }
-            Either6::Second(connected) => {
+            Either5::Second(connected) => {
                 if connected {
-                    gamepad_connected_icon.draw(&mut display).unwrap();
+                    gamepad_connected_icon.draw(display).unwrap();
                     gamepad_battery_label.set_visible(true);
                 } else {
-                    gamepad_disconnected_icon.draw(&mut display).unwrap();
+                    gamepad_disconnected_icon.draw(display).unwrap();
                     gamepad_battery_label.set_visible(false);
                 }
-                gamepad_battery_label.draw(&mut display).unwrap();
+                gamepad_battery_label.draw(display).unwrap();
             }
-            Either6::Third(radio) => {
+            Either5::Third(radio) => {
                 if radio.connected {
-                    drone_icon.draw(&mut display).unwrap();
+                    drone_icon.draw(display).unwrap();
                     drone_battery_label.set_visible(true);
                 } else {
-                    drone_disconnected_icon.draw(&mut display).unwrap();
+                    drone_disconnected_icon.draw(display).unwrap();
                     drone_battery_label.set_visible(false);
                 }
-                drone_battery_label.draw(&mut display).unwrap();
+                drone_battery_label.draw(display).unwrap();
+
+                // Once bound, the label shows the drone's status as soon as it answers.
+                let bind_text = match radio.bind {
+                    BindStatus::Unbound => Some("UNBOUND"),
+                    BindStatus::Binding => Some("BINDING"),
+                    BindStatus::Bound => None,
+                };
+                if let Some(text) = bind_text {
+                    status_label.set_text(text).unwrap();
+                    status_label.draw(display).unwrap();
+                }
             }
-            Either6::Fourth(level) => {
-                drone_battery_label.set_text(&format!("{}%", 0)).unwrap();
-                drone_battery_label.draw(&mut display).unwrap();
+            Either5::Fourth(Either3::First(attitude)) => {
+                status_label.set_text(&status_text(&attitude)).unwrap();
+                status_label.draw(display).unwrap();
+
+                let (roll, pitch, yaw) = attitude.attitude_degrees();
+                attitude_label
+                    .set_text(&format!("R{:.0} P{:.0} Y{:.0}", roll, pitch, yaw))
+                    .unwrap();
+                attitude_label.draw(display).unwrap();
             }
-            Either6::Fifth(altitude) => {
-                let (meters, quarters) = altitude.div_rem_euclid(&4);
+            Either5::Fourth(Either3::Second(battery)) => {
+                drone_battery_label
+                    .set_text(&format!("{}%", battery.battery_level))
+                    .unwrap();
+                drone_battery_label.draw(display).unwrap();
+
+                voltage_label
+                    .set_text(&format!(
+                        "{:.2}V {:?}",
+                        { battery.voltage_mv } as f32 / 1000.0,
+                        battery.battery_status()
+                    ))
+                    .unwrap();
+                voltage_label.draw(display).unwrap();
+            }
+            Either5::Fourth(Either3::Third(altitude)) => {
                 altitude_label
-                    .set_text(&format!("Alt: {}.{}m", meters, quarters * 25))
+                    .set_text(&format!("Alt: {:.2}m", { altitude.altitude_cm } as f32 / 100.0))
                     .unwrap();
-                altitude_label.draw(&mut display).unwrap();
+                altitude_label.draw(display).unwrap();
             }
-            Either6::Sixth(quality) => {
+            Either5::Fifth(Either::First(quality)) => {
                 quality_label
                     .set_text(&format!("Link: {}%", (quality * 100.0).round()))
                     .unwrap();
-                quality_label.draw(&mut display).unwrap();
+                quality_label.draw(display).unwrap();
+            }
+            Either5::Fifth(Either::Second(survey)) => {
+                survey_graph.set_survey(survey);
+                if survey_graph.needs_redraw() {
+                    survey_graph.draw(display).unwrap();
+                }
             }
         }
     }
 }
 
+/// Armed state and flight mode, followed by a `!` if the drone reports any faults.
+fn status_text(status: &AttitudeTelemetry) -> alloc::string::String {
+    let armed = if status.is_armed() { "ARMED" } else { "SAFE" };
+    let mode = match status.flight_mode() {
+        FlightMode::Rate => "Rate",
+        FlightMode::Angle => "Angle",
+        FlightMode::Horizon => "Horizon",
+    };
+    let fault = if status.faults().is_empty() { "" } else { " !" };
+
+    format!("{} {}{}", armed, mode, fault)
+}
+
 struct ThrottleIndicator<C> {
     needs_redraw: bool,
     throttle: u8,
@@ -0,0 +1,82 @@
+use embedded_graphics::pixelcolor::Rgb565;
+use embedded_graphics::prelude::*;
+use embedded_graphics::primitives::{Line, PrimitiveStyle, Rectangle};
+use fc_common::fhss::{FIRST_CHANNEL, LAST_CHANNEL};
+use fc_common::survey::{ChannelSurvey, CHANNEL_COUNT};
+use tinyui::component::Component;
+
+/// The occupancy of every channel found by the site survey, one pixel column per channel. Channels outside the
+/// hopping range are dimmed, and the cleanest channel inside it is marked.
+pub struct SurveyGraph {
+    needs_redraw: bool,
+    survey: ChannelSurvey,
+}
+
+impl SurveyGraph {
+    const HEIGHT: u32 = 14;
+
+    pub fn new() -> Self {
+        Self {
+            needs_redraw: false,
+            survey: ChannelSurvey::new(),
+        }
+    }
+
+    pub fn set_survey(&mut self, survey: ChannelSurvey) {
+        if self.survey != survey {
+            self.survey = survey;
+            self.needs_redraw = true;
+        }
+    }
+
+    fn color(&self, channel: u8, cleanest: u8) -> Rgb565 {
+        if channel == cleanest {
+            Rgb565::CYAN
+        } else if !(FIRST_CHANNEL..=LAST_CHANNEL).contains(&channel) {
+            Rgb565::CSS_DIM_GRAY
+        } else {
+            match self.survey.occupancy(channel) {
+                0..10 => Rgb565::GREEN,
+                10..40 => Rgb565::YELLOW,
+                _ => Rgb565::RED,
+            }
+        }
+    }
+}
+
+impl Component<Rgb565> for SurveyGraph {
+    fn size(&self) -> Size {
+        Size::new(CHANNEL_COUNT as u32, Self::HEIGHT)
+    }
+
+    fn position(&self) -> Point {
+        Point::new(1, 128 - Self::HEIGHT as i32)
+    }
+
+    fn needs_redraw(&self) -> bool {
+        self.needs_redraw
+    }
+
+    fn draw<D>(&mut self, target: &mut D) -> Result<(), <D as DrawTarget>::Error>
+    where
+        D: DrawTarget<Color = Rgb565>,
+    {
+        Rectangle::new(self.position(), self.size())
+            .into_styled(PrimitiveStyle::with_fill(Rgb565::BLACK))
+            .draw(target)?;
+
+        let cleanest = self.survey.cleanest(FIRST_CHANNEL..=LAST_CHANNEL);
+        let bottom = self.position().y + Self::HEIGHT as i32 - 1;
+        for channel in 0..CHANNEL_COUNT as u8 {
+            // Every channel gets at least a pixel, so the quiet ones show up as a baseline.
+            let height = (self.survey.occupancy(channel) as u32 * Self::HEIGHT / 100).max(1) as i32;
+            let x = self.position().x + channel as i32;
+            Line::new(Point::new(x, bottom), Point::new(x, bottom - height + 1))
+                .into_styled(PrimitiveStyle::with_stroke(self.color(channel, cleanest), 1))
+                .draw(target)?;
+        }
+        self.needs_redraw = false;
+
+        Ok(())
+    }
+}
@@ -45,6 +45,7 @@ impl Into<ControllerInput> for HIDReport {
     }
 }
 
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum GamepadButton {
     A,
     B,
@@ -56,18 +57,30 @@ pub enum GamepadButton {
     R4,
 }
 
+impl GamepadButton {
+    /// The button's bit in the report's button byte.
+    pub const fn mask(self) -> u8 {
+        match self {
+            GamepadButton::A => 1,
+            GamepadButton::B => 2,
+            GamepadButton::X => 8,
+            GamepadButton::Y => 16,
+            GamepadButton::LB => 64,
+            GamepadButton::RB => 128,
+            GamepadButton::L4 => 4,
+            GamepadButton::R4 => 32,
+        }
+    }
+}
+
+/// Whether `button` is down in the button byte `buttons`.
+pub fn button_pressed(buttons: u8, button: GamepadButton) -> bool {
+    (buttons & button.mask()) != 0
+}
+
 impl HIDReport {
     pub fn button_pressed(&self, button: GamepadButton) -> bool {
-        match button {
-            GamepadButton::A => (self.buttons & 1) != 0,
-            GamepadButton::B => (self.buttons & 2) != 0,
-            GamepadButton::X => (self.buttons & 8) != 0,
-            GamepadButton::Y => (self.buttons & 16) != 0,
-            GamepadButton::LB => (self.buttons & 64) != 0,
-            GamepadButton::RB => (self.buttons & 128) != 0,
-            GamepadButton::L4 => (self.buttons & 4) != 0,
-            GamepadButton::R4 => (self.buttons & 32) != 0,
-        }
+        button_pressed(self.buttons, button)
     }
 }
 
@@ -3,6 +3,7 @@ use esp_wifi::EspWifiController;
 mod gamepad;
 mod pilot_controller;
 
+pub use gamepad::{button_pressed, GamepadButton};
 pub use pilot_controller::{get_controller_state, get_input_state, reset_buttons_latch};
 
 use crate::signal::{BatteryEmitter, ControllerConnectedEmitter, InputEmitter};
@@ -1,8 +1,9 @@
 use core::sync::atomic::{AtomicBool, AtomicU8, Ordering};
 
-use fc_common::FlightInput;
+use fc_common::rc::RcChannels;
 
 use crate::input::gamepad::HIDReport;
+use crate::signal::ControllerInput;
 
 pub struct ControllerState<B, U> {
     pub connected: B,
@@ -28,9 +29,9 @@ pub struct PilotInputState<T> {
     pub buttons_latch: T,
 }
 
-impl Into<FlightInput> for PilotInputState<u8> {
-    fn into(self) -> FlightInput {
-        FlightInput {
+impl Into<RcChannels> for PilotInputState<u8> {
+    fn into(self) -> RcChannels {
+        ControllerInput {
             left_stick_x: self.left_stick_x,
             left_stick_y: self.left_stick_y,
             right_stick_x: self.right_stick_x,
@@ -39,6 +40,7 @@ impl Into<FlightInput> for PilotInputState<u8> {
             right_trigger: self.right_trigger,
             buttons: self.buttons_latch,
         }
+        .into()
     }
 }
 
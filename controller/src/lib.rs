@@ -3,6 +3,7 @@
 extern crate alloc;
 pub mod gui;
 pub mod input;
+pub mod mavlink;
 pub mod moving_sum;
 pub mod radio;
 pub mod signal;
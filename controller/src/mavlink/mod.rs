@@ -0,0 +1,204 @@
+use embassy_futures::join::join;
+use embassy_futures::select::{select, Either};
+use embassy_time::{Duration, Instant, Ticker};
+use embedded_io_async::{Read, Write};
+use esp_hal::usb_serial_jtag::{UsbSerialJtag, UsbSerialJtagRx, UsbSerialJtagTx};
+use esp_hal::Async;
+use fc_common::mavlink::{
+    Attitude, CommandAck, CommandLong, Heartbeat, MavlinkEncoder, MavlinkMessage, MavlinkParser, RadioStatus,
+    SysStatus, VfrHud, MAV_AUTOPILOT_GENERIC, MAV_MODE_FLAG_CUSTOM_MODE_ENABLED, MAV_MODE_FLAG_SAFETY_ARMED,
+    MAV_STATE_ACTIVE, MAV_STATE_CRITICAL, MAV_STATE_STANDBY, MAV_SYS_STATUS_SENSOR_ABSOLUTE_PRESSURE,
+    MAV_SYS_STATUS_SENSOR_BATTERY, MAV_SYS_STATUS_SENSOR_RC_RECEIVER, MAV_TYPE_QUADROTOR, MAX_PACKET_SIZE,
+};
+use fc_common::telemetry::{AltitudeTelemetry, AttitudeTelemetry, BatteryStatus, BatteryTelemetry};
+use fc_common::SignalBase;
+
+use crate::signal::{
+    CommandResultsSignal, DownlinkStatisticsSignal, DroneAltitudeSignal, DroneAttitudeSignal, DroneBatterySignal,
+    GcsCommandEmitter, RadioLinkQualitySignal, RadioSignal,
+};
+
+/// The drone, as seen by the ground station.
+const SYSTEM_ID: u8 = 1;
+/// MAV_COMP_ID_AUTOPILOT1
+const COMPONENT_ID: u8 = 1;
+
+/// ATTITUDE and VFR_HUD are sent at this rate, everything else once a second.
+const FAST_RATE_HZ: u64 = 10;
+
+pub struct TelemetrySignals {
+    pub radio: RadioSignal,
+    pub attitude: DroneAttitudeSignal,
+    pub battery: DroneBatterySignal,
+    pub altitude: DroneAltitudeSignal,
+    pub link_quality: RadioLinkQualitySignal,
+    pub downlink_statistics: DownlinkStatisticsSignal,
+}
+
+/// Bridges the drone to ground control software over the USB serial port.
+///
+/// Telemetry is streamed as MAVLink v2 once the drone has answered for the first time, and arming, flight mode,
+/// calibration and reboot commands are accepted through COMMAND_LONG. esp_println writes its log to the same port, so
+/// the ground station has to skip over log lines between packets, which MAVLink parsers do.
+#[embassy_executor::task]
+pub async fn run(
+    usb: UsbSerialJtag<'static, Async>,
+    telemetry: TelemetrySignals,
+    gcs_command_emitter: GcsCommandEmitter,
+    command_results_signal: CommandResultsSignal,
+) {
+    let (rx, tx) = usb.split();
+    join(
+        send_telemetry(tx, telemetry, command_results_signal),
+        receive_commands(rx, gcs_command_emitter),
+    )
+    .await;
+}
+
+async fn send_telemetry(
+    mut tx: UsbSerialJtagTx<'static, Async>,
+    mut telemetry: TelemetrySignals,
+    mut command_results_signal: CommandResultsSignal,
+) {
+    let mut encoder = MavlinkEncoder::new(SYSTEM_ID, COMPONENT_ID);
+    let mut ticker = Ticker::every(Duration::from_hz(FAST_RATE_HZ));
+    let mut ticks = 0u64;
+    loop {
+        let report = match select(ticker.next(), command_results_signal.next_value()).await {
+            Either::First(_) => None,
+            Either::Second(report) => report,
+        };
+        if !telemetry.radio.get().connected {
+            continue;
+        }
+
+        if let Some(report) = report {
+            send(&mut tx, &mut encoder, &CommandAck::new(&report.command, report.result)).await;
+            continue;
+        }
+
+        let attitude = telemetry.attitude.get();
+        let altitude = telemetry.altitude.get();
+        send(&mut tx, &mut encoder, &attitude_message(&attitude)).await;
+        send(&mut tx, &mut encoder, &vfr_hud_message(&attitude, &altitude)).await;
+
+        if ticks % FAST_RATE_HZ == 0 {
+            let battery = telemetry.battery.get();
+            let link_quality = telemetry.link_quality.get();
+            let statistics = telemetry.downlink_statistics.get();
+            send(&mut tx, &mut encoder, &heartbeat_message(&attitude, &battery)).await;
+            let sys_status = SysStatus {
+                sensors_present: SENSORS,
+                sensors_enabled: SENSORS,
+                sensors_health: sensors_health(&battery),
+                voltage_battery_mv: battery.voltage_mv,
+                current_battery_ca: -1,
+                drop_rate_comm: (statistics.loss_percent * 100.0) as u16,
+                errors_comm: statistics.lost.min(u16::MAX as u32) as u16,
+                battery_remaining: battery.battery_level as i8,
+                ..Default::default()
+            };
+            send(&mut tx, &mut encoder, &sys_status).await;
+            let radio_status = RadioStatus {
+                rxerrors: statistics.lost.min(u16::MAX as u32) as u16,
+                fixed: 0,
+                rssi: (link_quality.clamp(0.0, 1.0) * 254.0) as u8,
+                // The drone doesn't measure the uplink, and neither side can measure noise.
+                remrssi: 255,
+                txbuf: 100,
+                noise: 255,
+                remnoise: 255,
+            };
+            send(&mut tx, &mut encoder, &radio_status).await;
+        }
+        ticks += 1;
+    }
+}
+
+async fn receive_commands(mut rx: UsbSerialJtagRx<'static, Async>, mut gcs_command_emitter: GcsCommandEmitter) {
+    let mut parser = MavlinkParser::new();
+    let mut buf = [0u8; 64];
+    loop {
+        let len = match rx.read(&mut buf).await {
+            Ok(len) => len,
+            Err(e) => {
+                esp_println::println!("USB serial read error: {:?}", e);
+                continue;
+            }
+        };
+
+        for frame in buf[..len].iter().filter_map(|byte| parser.push(*byte)) {
+            if frame.message_id != CommandLong::ID {
+                continue;
+            }
+            let command_long = CommandLong::deserialize(frame.payload());
+            if command_long.target_system != SYSTEM_ID {
+                continue;
+            }
+            match command_long.to_command() {
+                Some(command) => gcs_command_emitter.emit(Some(command)),
+                None => esp_println::println!("Unsupported MAVLink command {}", command_long.command),
+            }
+        }
+    }
+}
+
+async fn send<M: MavlinkMessage>(tx: &mut UsbSerialJtagTx<'static, Async>, encoder: &mut MavlinkEncoder, message: &M) {
+    let mut buf = [0u8; MAX_PACKET_SIZE];
+    let len = encoder.encode(message, &mut buf).expect("Messages fit in a packet");
+    if let Err(e) = tx.write_all(&buf[..len]).await {
+        esp_println::println!("USB serial write error: {:?}", e);
+    }
+}
+
+const SENSORS: u32 =
+    MAV_SYS_STATUS_SENSOR_ABSOLUTE_PRESSURE | MAV_SYS_STATUS_SENSOR_RC_RECEIVER | MAV_SYS_STATUS_SENSOR_BATTERY;
+
+fn sensors_health(battery: &BatteryTelemetry) -> u32 {
+    match battery.battery_status() {
+        BatteryStatus::Ok | BatteryStatus::Low => SENSORS,
+        BatteryStatus::Critical | BatteryStatus::Cutoff => SENSORS & !MAV_SYS_STATUS_SENSOR_BATTERY,
+    }
+}
+
+fn heartbeat_message(attitude: &AttitudeTelemetry, battery: &BatteryTelemetry) -> Heartbeat {
+    let mut base_mode = MAV_MODE_FLAG_CUSTOM_MODE_ENABLED;
+    if attitude.is_armed() {
+        base_mode |= MAV_MODE_FLAG_SAFETY_ARMED;
+    }
+    let system_status = match battery.battery_status() {
+        BatteryStatus::Critical | BatteryStatus::Cutoff => MAV_STATE_CRITICAL,
+        _ if attitude.is_armed() => MAV_STATE_ACTIVE,
+        _ => MAV_STATE_STANDBY,
+    };
+
+    Heartbeat {
+        custom_mode: attitude.flight_mode() as u32,
+        mav_type: MAV_TYPE_QUADROTOR,
+        autopilot: MAV_AUTOPILOT_GENERIC,
+        base_mode,
+        system_status,
+    }
+}
+
+fn attitude_message(attitude: &AttitudeTelemetry) -> Attitude {
+    let (roll, pitch, yaw) = attitude.attitude_degrees();
+    Attitude {
+        time_boot_ms: Instant::now().as_millis() as u32,
+        roll: roll.to_radians(),
+        pitch: pitch.to_radians(),
+        yaw: yaw.to_radians(),
+        // The drone doesn't report its rates.
+        ..Default::default()
+    }
+}
+
+fn vfr_hud_message(attitude: &AttitudeTelemetry, altitude: &AltitudeTelemetry) -> VfrHud {
+    let (_, _, yaw) = attitude.attitude_degrees();
+    VfrHud {
+        alt: altitude.altitude_cm as f32 / 100.0,
+        climb: altitude.vertical_speed_cms as f32 / 100.0,
+        heading: (yaw as i16).rem_euclid(360),
+        ..Default::default()
+    }
+}
@@ -0,0 +1,58 @@
+use embassy_time::{Duration, Instant};
+
+use crate::input::GamepadButton;
+
+const BUTTON_A: u8 = GamepadButton::A.mask();
+const BUTTON_B: u8 = GamepadButton::B.mask();
+const BUTTON_X: u8 = GamepadButton::X.mask();
+
+const HOLD_TIME: Duration = Duration::from_secs(3);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BindRequest {
+    /// Bind to a drone in bind mode, replacing the current binding.
+    Bind,
+    /// Forget the current binding.
+    Unbind,
+}
+
+/// Detects the button combinations that bind and unbind: A + X and B + X, held for three seconds.
+///
+/// Each request fires once per hold. Holding for that long keeps them from being set off by accident mid-flight.
+pub struct BindButtons {
+    held: Option<(BindRequest, Instant)>,
+    fired: bool,
+}
+
+impl BindButtons {
+    pub fn new() -> Self {
+        Self {
+            held: None,
+            fired: false,
+        }
+    }
+
+    pub fn update(&mut self, buttons: u8, now: Instant) -> Option<BindRequest> {
+        let combination = match buttons & (BUTTON_A | BUTTON_B | BUTTON_X) {
+            combination if combination == BUTTON_A | BUTTON_X => Some(BindRequest::Bind),
+            combination if combination == BUTTON_B | BUTTON_X => Some(BindRequest::Unbind),
+            _ => None,
+        };
+
+        match (combination, self.held) {
+            (Some(request), Some((held, since))) if request == held => {
+                if !self.fired && now - since >= HOLD_TIME {
+                    self.fired = true;
+                    return Some(request);
+                }
+            }
+            (Some(request), _) => {
+                self.held = Some((request, now));
+                self.fired = false;
+            }
+            (None, _) => self.held = None,
+        }
+
+        None
+    }
+}
@@ -0,0 +1,36 @@
+//! Mirrors the frames the radio task sends and receives onto the USB serial port, between the log lines, for the
+//! `capture-decode` tool to pick out. Only built with the `capture` feature, see [`fc_common::capture`].
+
+use embassy_time::Instant;
+use fc_common::auth::Direction;
+use fc_common::capture::{CaptureRecord, Transmission, MAX_RECORD_SIZE};
+use fc_common::link::Received;
+use fc_common::protocol::{Message, ProtocolError};
+
+/// An uplink frame sent as `sealed` on `channel`, after `retries` retries and whether it was acked in the end.
+pub fn sent(channel: u8, sealed: &[u8], message: &Message, retries: u8, acked: bool) {
+    let record = CaptureRecord::sent(now_ms(), Direction::Uplink, channel, sealed, message);
+    write(record.with_transmission(Transmission { retries, acked }));
+}
+
+/// An ack payload that arrived as `bytes` on `channel`.
+pub fn arrived(channel: u8, bytes: &[u8], received: &Result<Received, ProtocolError>) {
+    let frame = received.as_ref().ok().map(|received| &received.frame);
+    write(CaptureRecord::arrived(
+        now_ms(),
+        Direction::Downlink,
+        channel,
+        bytes,
+        frame,
+    ));
+}
+
+fn write(record: CaptureRecord) {
+    let mut buf = [0u8; MAX_RECORD_SIZE];
+    let len = record.encode(&mut buf);
+    esp_println::Printer::write_bytes(&buf[..len]);
+}
+
+fn now_ms() -> u32 {
+    Instant::now().as_millis() as u32
+}
@@ -0,0 +1,71 @@
+use fc_common::command::Command;
+use fc_common::telemetry::FlightMode;
+
+use crate::input::{button_pressed, GamepadButton};
+
+/// The buttons that switch flight modes, and the modes they switch to.
+const MODE_BUTTONS: [(GamepadButton, FlightMode); 3] = [
+    (GamepadButton::A, FlightMode::Rate),
+    (GamepadButton::B, FlightMode::Angle),
+    (GamepadButton::X, FlightMode::Horizon),
+];
+
+/// Turns gamepad button presses into drone commands.
+///
+/// Commands fire once when a button goes down, holding it does not repeat the command. A, B and X
+/// switch to rate, angle and horizon mode, but only once released, and not at all if another of
+/// them was held with it: A + X and B + X are the bind and unbind combinations.
+pub struct ButtonCommands {
+    previous_buttons: u8,
+    /// Whether more than one flight mode button has been down since they were last all up.
+    chord: bool,
+}
+
+impl ButtonCommands {
+    pub fn new() -> Self {
+        Self {
+            previous_buttons: 0,
+            chord: false,
+        }
+    }
+
+    pub fn update(&mut self, buttons: u8) -> Option<Command> {
+        let pressed = buttons & !self.previous_buttons;
+        let released = self.previous_buttons & !buttons;
+        self.previous_buttons = buttons;
+        let pressed = |button| button_pressed(pressed, button);
+
+        let held = MODE_BUTTONS
+            .iter()
+            .filter(|(button, _)| button_pressed(buttons, *button))
+            .count();
+        if held > 1 {
+            self.chord = true;
+        }
+        let mode = if held == 0 && !core::mem::take(&mut self.chord) {
+            MODE_BUTTONS
+                .iter()
+                .find(|(button, _)| button_pressed(released, *button))
+                .map(|(_, mode)| *mode)
+        } else {
+            None
+        };
+
+        if pressed(GamepadButton::LB) {
+            // Disarm wins if both shoulder buttons go down at once.
+            Some(Command::Disarm)
+        } else if pressed(GamepadButton::RB) {
+            Some(Command::Arm)
+        } else if let Some(mode) = mode {
+            Some(Command::SetFlightMode(mode))
+        } else if pressed(GamepadButton::Y) {
+            Some(Command::ZeroBarometer)
+        } else if pressed(GamepadButton::L4) {
+            Some(Command::CalibrateGyro)
+        } else if pressed(GamepadButton::R4) {
+            Some(Command::Reboot)
+        } else {
+            None
+        }
+    }
+}
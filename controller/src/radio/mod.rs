@@ -1,22 +1,64 @@
+mod bind;
+#[cfg(feature = "capture")]
+mod capture;
+mod command;
 mod state;
+mod survey;
 
 use embassy_embedded_hal::shared_bus::asynch::spi::SpiDevice;
+use embassy_futures::select::{select, Either};
 use embassy_sync::blocking_mutex::raw::NoopRawMutex;
-use embassy_time::{Delay, Duration, Ticker};
+use embassy_time::{Delay, Duration, Instant, Ticker};
 use embedded_hal::digital::OutputPin;
 use esp_hal::gpio::{Event, Input, Output};
+use esp_hal::rng::Rng;
 use esp_hal::spi::master::Spi;
 use esp_hal::Async;
-use fc_common::{DroneStatus, FlightInput, SignalBase, DRONE_STATUS_SIZE, FLIGHT_INPUT_SIZE};
-use nrf24_rs::config::{NrfConfig, PALevel, PayloadSize};
+use esp_storage::FlashStorage;
+use fc_common::adapt::{self, LinkAdapter, LinkProfile, LinkSwitch};
+use fc_common::auth::Direction;
+use fc_common::bind::{BindInfo, BindOffer, BIND_ADDRESS, BIND_CHANNEL, BIND_INFO_SIZE, MAX_BIND_PACKET_SIZE};
+use fc_common::command::{Command, CommandError, CommandPoll, CommandResult, CommandSender};
+use fc_common::fhss::{HopSequence, HopTransmitter, FIRST_CHANNEL, LAST_CHANNEL, UPLINK_PERIOD};
+use fc_common::link::{Link, Received};
+use fc_common::param::{ParamClient, ParamPoll};
+use fc_common::protocol::{Frame, Message, ProtocolError, MAX_FRAME_SIZE};
+use fc_common::storage::Store;
+use fc_common::SignalBase;
+use nrf24_rs::config::{DataRate, NrfConfig, PALevel, PayloadSize};
 use nrf24_rs::{Nrf24l01, MAX_PAYLOAD_SIZE};
-use zerocopy::{FromBytes, IntoBytes};
 
 use crate::moving_sum::MovingSum;
+use crate::radio::bind::{BindButtons, BindRequest};
+use crate::radio::command::ButtonCommands;
 use crate::signal::{
-    DroneAltitudeEmitter, DroneBatteryLevelEmitter, InputSignal, RadioEmitter, RadioLinkQualityEmitter, RadioStatus,
+    BindStatus, CommandReport, CommandResultsEmitter, DownlinkStatisticsEmitter, DroneAltitudeEmitter,
+    DroneAttitudeEmitter, DroneBatteryEmitter, GcsCommandSignal, InputSignal, RadioEmitter, RadioLinkQualityEmitter,
+    RadioStatus, SurveyEmitter,
 };
 
+type Radio =
+    Nrf24l01<SpiDevice<'static, NoopRawMutex, Spi<'static, Async>, Output<'static>>, Output<'static>, nrf24_rs::Async>;
+
+/// How long the last gamepad report is good for. Past that the gamepad is assumed gone, and the drone gets centered
+/// sticks and released buttons rather than whatever the pilot held when it went quiet.
+const INPUT_TIMEOUT: Duration = Duration::from_millis(500);
+/// How long the drone may go without an authentic answer before the controller offers a new session. A drone that
+/// restarted has lost the session, and can't be told apart from one out of range otherwise.
+const SESSION_TIMEOUT: Duration = Duration::from_secs(1);
+
+enum Mode {
+    Unbound,
+    Binding(BindOffer),
+    Bound {
+        link: Link,
+        hop: HopTransmitter,
+        adapter: LinkAdapter,
+        /// When the drone last answered.
+        heard_at: Instant,
+    },
+}
+
 #[embassy_executor::task]
 pub async fn run(
     spi_device: SpiDevice<'static, NoopRawMutex, Spi<'static, Async>, Output<'static>>,
@@ -24,23 +66,30 @@ pub async fn run(
     mut irq: Input<'static>,
     mut input_signal: InputSignal,
     mut radio_status_emitter: RadioEmitter,
+    mut drone_attitude_emitter: DroneAttitudeEmitter,
+    mut drone_battery_emitter: DroneBatteryEmitter,
     mut drone_altitude_emitter: DroneAltitudeEmitter,
-    mut drone_battery_emitter: DroneBatteryLevelEmitter,
     mut radio_link_quality_emitter: RadioLinkQualityEmitter,
+    mut downlink_statistics_emitter: DownlinkStatisticsEmitter,
+    mut gcs_command_signal: GcsCommandSignal,
+    mut command_results_emitter: CommandResultsEmitter,
+    mut survey_emitter: SurveyEmitter,
+    mut store: Store<FlashStorage>,
+    mut rng: Rng,
 ) {
     const {
         assert!(
-            FLIGHT_INPUT_SIZE < MAX_PAYLOAD_SIZE as usize,
-            "FlightInput size exceeds max payload size"
+            MAX_FRAME_SIZE <= MAX_PAYLOAD_SIZE as usize,
+            "Frame size exceeds max payload size"
         );
     }
-    radio_status_emitter.emit(RadioStatus { connected: false });
     irq.listen(Event::FallingEdge);
 
     esp_println::println!("TX Radio init");
     let config = NrfConfig::default()
         .channel(76)
-        .pa_level(PALevel::Min)
+        .pa_level(pa_level(LinkProfile::ROBUST))
+        .data_rate(data_rate(LinkProfile::ROBUST))
         .payload_size(PayloadSize::Dynamic)
         .ack_payloads_enabled(true);
 
@@ -58,80 +107,399 @@ pub async fn run(
         esp_println::println!("!!! Radio not connected!");
     }
     esp_println::println!("TX Radio connected");
-    radio.open_writing_pipe(b"Node1").await.unwrap();
+
+    // The survey steers the hop sequence clear of the busiest channels until the link finds its own way around them.
+    let survey = survey::run(&mut radio).await;
+    esp_println::println!(
+        "Channel survey done, cleanest channel {}",
+        survey.cleanest(FIRST_CHANNEL..=LAST_CHANNEL)
+    );
+    survey_emitter.emit(survey.clone());
+
+    let mut mode = match store.binding() {
+        Some(info) => {
+            radio.open_writing_pipe(&info.address).await.unwrap();
+            open_link(info, &mut rng)
+        }
+        None => Mode::Unbound,
+    };
+    radio_status_emitter.emit(RadioStatus {
+        connected: false,
+        bind: bind_status(&mode),
+    });
 
     esp_println::println!("Radio 1 started!");
 
-    let mut ticker = Ticker::every(Duration::from_millis(10));
-    let mut i = 0;
+    let mut ticker = Ticker::every(UPLINK_PERIOD);
     let mut moving_sum: MovingSum<u8, u16, 50> = MovingSum::new();
-    let mut quality_update_ticker = 0;
-    const QUALITY_UPDATE_FREQUENCY: usize = 10;
-    let mut total_failures = 0;
+    let mut commands = CommandSender::new();
+    let mut button_commands = ButtonCommands::new();
+    let mut bind_buttons = BindButtons::new();
+    let mut drone_parameters = ParamClient::new();
+    let mut drone_seen = false;
+    // The blacklist the drone should hop with, until it has been handed to the command sender.
+    let mut pending_blacklist = None;
+    let mut profile = LinkProfile::ROBUST;
     loop {
-        ticker.next().await;
-        esp_println::println!("tick! {}   fail: {}", i, total_failures);
-        i = i + 1;
+        if let Either::Second(command) = select(ticker.next(), gcs_command_signal.next_value()).await {
+            if let Some(command) = command {
+                submit(&mut commands, command, &mut command_results_emitter);
+            }
+            continue;
+        }
+
+        let input = input_signal.get_if_fresh(INPUT_TIMEOUT).unwrap_or_default();
+        match bind_buttons.update(input.buttons, Instant::now()) {
+            Some(BindRequest::Bind) => {
+                esp_println::println!("Offering to bind");
+                mode = Mode::Binding(new_offer(&mut rng));
+                radio.set_channel(BIND_CHANNEL).await.unwrap();
+                radio.open_writing_pipe(&BIND_ADDRESS).await.unwrap();
+            }
+            Some(BindRequest::Unbind) => {
+                esp_println::println!("Forgetting the drone");
+                if let Err(e) = store.clear_binding() {
+                    esp_println::println!("Unable to clear binding: {:?}", e);
+                }
+                mode = Mode::Unbound;
+            }
+            None => {}
+        }
+        if !matches!(mode, Mode::Bound { .. }) {
+            drone_seen = false;
+            radio_status_emitter.emit_if_changed(RadioStatus {
+                connected: false,
+                bind: bind_status(&mode),
+            });
+        }
+        // Binding happens with the most robust profile, as does a new link.
+        let wanted = match &mut mode {
+            Mode::Bound { link, adapter, .. } => {
+                adapter.update(link.next_counter());
+                adapter.profile()
+            }
+            _ => LinkProfile::ROBUST,
+        };
+        if wanted != profile {
+            profile = wanted;
+            esp_println::println!("Link profile {:?}", profile);
+            radio.set_data_rate(data_rate(profile)).await.unwrap();
+            radio.set_pa_level(pa_level(profile)).await.unwrap();
+        }
+
+        let (link, hop, adapter, heard_at) = match &mut mode {
+            Mode::Unbound => continue,
+            Mode::Binding(offer) => {
+                if offer_binding(&mut radio, &mut irq, offer).await {
+                    let info = offer.info().clone();
+                    esp_println::println!("Bound to {:?}", info);
+                    if let Err(e) = store.save_binding(&info) {
+                        esp_println::println!("Unable to store binding: {:?}", e);
+                    }
+                    radio.open_writing_pipe(&info.address).await.unwrap();
+                    mode = open_link(&info, &mut rng);
+                    // Command ids start over with the new link.
+                    commands = CommandSender::new();
+                }
+                continue;
+            }
+            Mode::Bound {
+                link,
+                hop,
+                adapter,
+                heard_at,
+            } => (link, hop, adapter, heard_at),
+        };
+        if link.session().is_some() && heard_at.elapsed() > SESSION_TIMEOUT {
+            esp_println::println!("Drone silent, offering a new session");
+            link.offer_session(rng.random());
+            // The drone forgets the commands it executed, its hop blacklist and the link switch it staged once the
+            // session starts. The command in flight may or may not have been carried out, and the drone gets the
+            // survey's blacklist again once it answers.
+            if let Some(abandoned) = commands.abandon() {
+                esp_println::println!("Command {:?} was given up on for the new session", abandoned);
+                report_unacknowledged(abandoned, &mut command_results_emitter);
+            }
+            hop.set_blacklist(0);
+            adapter.unschedule();
+            pending_blacklist = None;
+            drone_seen = false;
+        }
+
+        if let Some(command) = button_commands.update(input.buttons) {
+            submit(&mut commands, command, &mut command_results_emitter);
+        }
+        // A blacklist waits for the command slot to be free rather than being dropped, the drone has to get it for
+        // the two sides to hop together.
+        if let Some(blacklist) = pending_blacklist {
+            match commands.submit(Command::SetHopBlacklist(blacklist), Instant::now()) {
+                Ok(_) => pending_blacklist = None,
+                Err(CommandError::Busy) => {}
+            }
+        }
 
-        let input: FlightInput = input_signal.get().into();
+        // Nothing but the handshake goes out until the drone has accepted a session. Commands and parameter requests
+        // borrow a stick input slot at most once per retry interval each.
+        let message = match link.take_handshake() {
+            Some(handshake) => handshake,
+            None => match commands.poll(Instant::now()) {
+                CommandPoll::Transmit(request) => Message::CommandRequest(request),
+                CommandPoll::TimedOut(command) => {
+                    esp_println::println!("Command {:?} was never acknowledged", command);
+                    report_unacknowledged(command, &mut command_results_emitter);
+                    // Sent again unless a newer blacklist replaced it in the meantime.
+                    if let Command::SetHopBlacklist(blacklist) = command {
+                        pending_blacklist.get_or_insert(blacklist);
+                    }
+                    Message::RcChannels(input.into())
+                }
+                CommandPoll::Idle => match drone_parameters.poll(Instant::now()) {
+                    ParamPoll::Transmit(request) => Message::ParamRequest(request),
+                    ParamPoll::TimedOut(request) => {
+                        esp_println::println!("Parameter request {:?} was never answered", request);
+                        Message::RcChannels(input.into())
+                    }
+                    ParamPoll::Idle => Message::RcChannels(input.into()),
+                },
+            },
+        };
+        let mut frame = [0u8; MAX_FRAME_SIZE];
+        let frame_len = link
+            .encode(&message, now_ms(), &mut frame)
+            .expect("Uplink messages fit in a frame");
 
-        match radio.write(&mut delay, input.as_bytes()).await {
+        radio.set_channel(hop.channel()).await.unwrap();
+        let mut delivered = false;
+        let mut retries = 0;
+        match radio.write(&mut delay, &frame[..frame_len]).await {
             Ok(_) => {
                 irq.wait_for_low().await;
 
-                moving_sum.push(radio.retries_in_last_transmission().await.unwrap());
-                quality_update_ticker += 1;
-                if quality_update_ticker > QUALITY_UPDATE_FREQUENCY {
-                    quality_update_ticker = 0;
-                    let link_score = 1.0 - (moving_sum.average() / 15.0);
-                    radio_link_quality_emitter.emit(link_score);
-                }
+                retries = radio.retries_in_last_transmission().await.unwrap();
+                moving_sum.push(retries);
+                // Consumers throttle these to the rate they need.
+                radio_link_quality_emitter.emit(1.0 - (moving_sum.average() / 15.0));
+                downlink_statistics_emitter.emit_if_changed(link.statistics());
 
                 let status = radio.status().await.unwrap();
                 radio.reset_status().await.unwrap();
 
+                delivered = !status.reached_max_retries();
                 if status.reached_max_retries() {
                     esp_println::println!("MAX_RT");
-                    total_failures += 1;
                     radio.flush_tx().await.unwrap();
-                } else if let Some(ack) = read_ack(&mut radio).await {
-                    radio_status_emitter.emit_if_changed(RadioStatus { connected: true });
-                    drone_altitude_emitter.emit(ack.altitude);
-                    drone_battery_emitter.emit(ack.battery_level);
-                } else {
-                    total_failures += 1;
+                } else if let Some(ack) = read_ack(&mut radio, link, hop.channel()).await {
+                    *heard_at = Instant::now();
+                    radio_status_emitter.emit_if_changed(RadioStatus {
+                        connected: true,
+                        bind: BindStatus::Bound,
+                    });
+                    if !drone_seen {
+                        drone_seen = true;
+                        // Logs the drone's parameters once it first answers, and moves the hop sequence off the
+                        // channels the survey found busy.
+                        drone_parameters.list(Instant::now());
+                        let blacklist = survey.hop_blacklist(hop.sequence());
+                        if blacklist != 0 {
+                            esp_println::println!("Survey hop blacklist {:08x}", blacklist);
+                            pending_blacklist = Some(blacklist);
+                        }
+                    }
+                    match ack {
+                        Message::AttitudeTelemetry(attitude) => drone_attitude_emitter.emit(attitude),
+                        Message::BatteryTelemetry(battery) => drone_battery_emitter.emit(battery),
+                        Message::AltitudeTelemetry(altitude) => drone_altitude_emitter.emit(altitude),
+                        Message::DiagnosticsTelemetry(diagnostics) => {
+                            esp_println::println!("Drone diagnostics {:?}", diagnostics)
+                        }
+                        Message::ParamResponse(response) => {
+                            if let Some(response) = drone_parameters.receive(&response, Instant::now()) {
+                                esp_println::println!(
+                                    "Drone parameter #{} {:?}: {:?} ({:?})",
+                                    { response.id },
+                                    response.name(),
+                                    response.value(),
+                                    response.status()
+                                );
+                            }
+                        }
+                        Message::CommandAck(ack) => {
+                            if let Some(outcome) = commands.acknowledge(&ack) {
+                                esp_println::println!("Command {:?}: {:?}", outcome.command, outcome.result);
+                                match outcome.command {
+                                    // The drone switched over when it received the command, now the controller does.
+                                    Command::SetHopBlacklist(blacklist) => {
+                                        if outcome.result == CommandResult::Accepted {
+                                            hop.set_blacklist(blacklist);
+                                        }
+                                    }
+                                    // Both sides switch data rate with the frame the command names.
+                                    Command::SetLinkProfile(switch) => {
+                                        if outcome.result == CommandResult::Accepted {
+                                            adapter.schedule(switch);
+                                        }
+                                    }
+                                    command => command_results_emitter.emit(Some(CommandReport {
+                                        command,
+                                        result: Some(outcome.result),
+                                    })),
+                                }
+                            }
+                        }
+                        Message::SessionAccept(accept) => {
+                            esp_println::println!("Drone accepted session {:?}", accept.session())
+                        }
+                        message => {
+                            esp_println::println!("Unexpected ACK message {:?}", message.message_type())
+                        }
+                    }
                 }
             }
             Err(e) => {
                 radio.reset_status().await.unwrap();
-                total_failures += 1;
                 esp_println::println!("ERR: Radio write error: {:?}", e);
             }
         }
+
+        #[cfg(feature = "capture")]
+        capture::sent(hop.channel(), &frame[..frame_len], &message, retries, delivered);
+        if let Some(blacklist) = hop.record(delivered) {
+            esp_println::println!("New hop blacklist {:08x}", blacklist);
+            pending_blacklist = Some(blacklist);
+        }
+        if let Some(next) = adapter.record(delivered, retries, Instant::now()) {
+            let switch = LinkSwitch::new(next, link.next_counter());
+            esp_println::println!("Proposing link profile {:?}", switch);
+            if let Err(e) = commands.submit(Command::SetLinkProfile(switch), Instant::now()) {
+                // The adapter proposes it again after another window if the link still calls for it.
+                esp_println::println!("Link profile {:?} not proposed: {:?}", next, e);
+            }
+        }
+    }
+}
+
+/// Queues `command` for the drone. If another command is still in flight it is rejected right away, the same
+/// way the drone rejects commands it can't carry out at the moment. A disarm can't wait, and takes the place of
+/// the command in flight instead.
+fn submit(commands: &mut CommandSender, command: Command, results: &mut CommandResultsEmitter) {
+    if command == Command::Disarm {
+        if let (_, Some(abandoned)) = commands.preempt(command, Instant::now()) {
+            esp_println::println!("Command {:?} was given up on for {:?}", abandoned, command);
+            report_unacknowledged(abandoned, results);
+        }
+        return;
+    }
+    if let Err(e) = commands.submit(command, Instant::now()) {
+        esp_println::println!("Dropping command {:?}: {:?}", command, e);
+        results.emit(Some(CommandReport {
+            command,
+            result: Some(CommandResult::Rejected),
+        }));
     }
 }
 
-async fn read_ack(
-    radio: &mut Nrf24l01<
-        SpiDevice<'static, NoopRawMutex, Spi<'static, Async>, Output<'static>>,
-        Output<'static>,
-        nrf24_rs::Async,
-    >,
-) -> Option<DroneStatus> {
+/// Reports a command that may or may not have been carried out. The link's own commands aren't reported, they're
+/// proposed again if still needed.
+fn report_unacknowledged(command: Command, results: &mut CommandResultsEmitter) {
+    if !matches!(command, Command::SetHopBlacklist(_) | Command::SetLinkProfile(_)) {
+        results.emit(Some(CommandReport { command, result: None }));
+    }
+}
+
+fn bind_status(mode: &Mode) -> BindStatus {
+    match mode {
+        Mode::Unbound => BindStatus::Unbound,
+        Mode::Binding(_) => BindStatus::Binding,
+        Mode::Bound { .. } => BindStatus::Bound,
+    }
+}
+
+/// A new identity for the controller and the drone. The RNG is only truly random while the radio subsystem is
+/// running, which it is since Bluetooth is started before the radio task.
+fn new_offer(rng: &mut Rng) -> BindOffer {
+    let mut random = [0u8; BIND_INFO_SIZE];
+    rng.read(&mut random);
+    BindOffer::new(BindInfo::from_random(&random), rng.random())
+}
+
+/// Sends the next packet of `offer`, and returns whether the drone accepted it.
+async fn offer_binding(radio: &mut Radio, irq: &mut Input<'static>, offer: &mut BindOffer) -> bool {
+    let mut packet = [0u8; MAX_BIND_PACKET_SIZE];
+    let len = offer.next_packet(&mut packet);
+    if let Err(e) = radio.write(&mut Delay, &packet[..len]).await {
+        radio.reset_status().await.unwrap();
+        esp_println::println!("ERR: Radio write error: {:?}", e);
+        return false;
+    }
+    irq.wait_for_low().await;
+
+    let status = radio.status().await.unwrap();
+    radio.reset_status().await.unwrap();
+    if status.reached_max_retries() {
+        // No drone in bind mode around.
+        radio.flush_tx().await.unwrap();
+        return false;
+    }
+    if !status.data_ready() {
+        return false;
+    }
+
+    let mut ack = [0u8; 32];
+    match radio.read(&mut ack).await {
+        Ok(len) => offer.is_accepted(&ack[..len]),
+        Err(e) => {
+            esp_println::println!("Error reading ACK {:?}", e);
+            false
+        }
+    }
+}
+
+/// The link to the bound drone, offering it a new session. The radio has to be switched over to its address.
+fn open_link(info: &BindInfo, rng: &mut Rng) -> Mode {
+    let mut link = Link::new(info.key.clone(), Direction::Uplink);
+    link.set_encryption(true);
+    link.offer_session(rng.random());
+    Mode::Bound {
+        link,
+        hop: HopTransmitter::new(HopSequence::new(info.hop_seed)),
+        adapter: LinkAdapter::new(Instant::now()),
+        heard_at: Instant::now(),
+    }
+}
+
+async fn read_ack(radio: &mut Radio, link: &mut Link, channel: u8) -> Option<Message> {
     let mut ack_buffer = [0; 32];
     match radio.read(&mut ack_buffer).await {
         Ok(len) => {
-            if len != DRONE_STATUS_SIZE {
-                /* After connection has been re-established between controller and drone, it seems like
-                there may be ACKs of larger size than expected. I choose to just log these for now */
-                esp_println::println!("Received ACK of size {}", len);
-                None
-            } else {
-                if let Ok(drone_status) = DroneStatus::read_from_bytes(&ack_buffer[0..len]) {
-                    esp_println::println!("ACK received ({}) {:?}", len, drone_status);
-                    Some(drone_status)
-                } else {
-                    esp_println::println!("Unable to parse ACK");
+            let received = link.receive(&ack_buffer[..len], now_ms());
+            #[cfg(feature = "capture")]
+            capture::arrived(channel, &ack_buffer[..len], &received);
+            #[cfg(not(feature = "capture"))]
+            let _ = channel;
+            match received {
+                Ok(Received { status, .. }) if !status.is_fresh() => {
+                    esp_println::println!("Discarding duplicate ACK");
+                    None
+                }
+                Ok(Received {
+                    frame: Frame { header, message },
+                    ..
+                }) => {
+                    esp_println::println!("ACK received (#{}) {:?}", header.stamp.counter, header.message_type);
+                    Some(message)
+                }
+                Err(ProtocolError::StaleCounter(counter)) => {
+                    esp_println::println!("Discarding stale ACK #{}", counter);
+                    None
+                }
+                Err(ProtocolError::VersionMismatch(version)) => {
+                    esp_println::println!("Drone speaks protocol version {}. Update its firmware", version);
+                    None
+                }
+                Err(e) => {
+                    /* After connection has been re-established between controller and drone, it seems like
+                    there may be stale or truncated ACKs. I choose to just log these for now */
+                    esp_println::println!("Unable to parse ACK of size {}: {:?}", len, e);
                     None
                 }
             }
@@ -142,3 +510,25 @@ async fn read_ack(
         }
     }
 }
+
+fn data_rate(profile: LinkProfile) -> DataRate {
+    match profile.data_rate() {
+        adapt::DataRate::Kbps250 => DataRate::R250Kbps,
+        adapt::DataRate::Mbps1 => DataRate::R1Mbps,
+        adapt::DataRate::Mbps2 => DataRate::R2Mbps,
+    }
+}
+
+fn pa_level(profile: LinkProfile) -> PALevel {
+    match profile.power_level() {
+        adapt::PowerLevel::Min => PALevel::Min,
+        adapt::PowerLevel::Low => PALevel::Low,
+        adapt::PowerLevel::High => PALevel::High,
+        adapt::PowerLevel::Max => PALevel::Max,
+    }
+}
+
+/// The millisecond clock used to stamp frames. Only differences between stamps matter, so it's fine for it to wrap.
+fn now_ms() -> u16 {
+    Instant::now().as_millis() as u16
+}
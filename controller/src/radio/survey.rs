@@ -0,0 +1,28 @@
+use embassy_time::Timer;
+use fc_common::survey::{ChannelSurvey, CHANNEL_COUNT, SURVEY_SWEEPS};
+
+use crate::radio::Radio;
+
+/// How long the radio listens on a channel. The received power detector needs the receiver to have been on for
+/// 170 µs, 130 µs of which it takes to settle.
+const LISTEN_MICROS: u64 = 200;
+
+/// Sweeps every channel with the received power detector. It takes a couple of seconds, during which the radio
+/// neither sends nor answers anything.
+pub async fn run(radio: &mut Radio) -> ChannelSurvey {
+    let mut survey = ChannelSurvey::new();
+    for _ in 0..SURVEY_SWEEPS {
+        let mut busy = [false; CHANNEL_COUNT];
+        for channel in 0..CHANNEL_COUNT as u8 {
+            radio.set_channel(channel).await.unwrap();
+            radio.start_listening().await.unwrap();
+            Timer::after_micros(LISTEN_MICROS).await;
+            radio.stop_listening().await.unwrap();
+            busy[channel as usize] = radio.received_power_detected().await.unwrap();
+        }
+        survey.record_sweep(|channel| busy[channel as usize]);
+    }
+    radio.flush_rx().await.unwrap();
+
+    survey
+}
@@ -1,25 +1,72 @@
+use core::fmt::Debug;
+
 use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
-use fc_common::{define_signal, FlightInput, Signal, SignalBase, SignalEmitter};
+use fc_common::command::{Command, CommandResult};
+use fc_common::link::LinkStatistics;
+use fc_common::rc::{axis_from_u8, from_switch, trigger_from_u8, Channel, RcChannels};
+use fc_common::survey::ChannelSurvey;
+use fc_common::telemetry::{AltitudeTelemetry, AttitudeTelemetry, BatteryTelemetry};
+use fc_common::{define_signal, registry, Signal, SignalBase, SignalEmitter, SignalInfo};
+
+use crate::input::{button_pressed, GamepadButton};
 
-define_signal!(Radio, RadioStatus, 1);
+define_signal!(Radio, RadioStatus, 2);
 define_signal!(ControllerConnected, bool, 1);
 define_signal!(Battery, ControllerBattery, 2);
 define_signal!(Input, ControllerInput, 1);
-define_signal!(DroneBatteryLevel, u8, 1);
-define_signal!(DroneAltitude, u8, 1);
-define_signal!(RadioLinkQuality, f32, 1);
+define_signal!(DroneAttitude, AttitudeTelemetry, 2);
+define_signal!(DroneBattery, BatteryTelemetry, 2);
+define_signal!(DroneAltitude, AltitudeTelemetry, 2);
+define_signal!(RadioLinkQuality, f32, 2);
+define_signal!(DownlinkStatistics, LinkStatistics, 1);
+// Commands from the ground station, to be sent to the drone.
+define_signal!(GcsCommand, Option<Command>, 1);
+define_signal!(CommandResults, Option<CommandReport>, 1);
+// The site survey the radio ran at startup.
+define_signal!(Survey, ChannelSurvey, 1);
+
+/// Prints the values of the signals worth watching as they're emitted. Others can be tapped with
+/// [`SignalInfo::set_tapped`] while debugging.
+pub fn start_telemetry_tap() {
+    registry::set_tap(Some(print_value));
+    radio_link_quality_signal_info().set_tapped(true);
+}
+
+fn print_value(info: &'static SignalInfo, value: &dyn Debug) {
+    esp_println::println!("{} = {:?}", info.name, value);
+}
 
 #[derive(Clone, Copy, Debug, PartialEq)]
 pub struct RadioStatus {
     pub connected: bool,
+    pub bind: BindStatus,
 }
 
 impl Default for RadioStatus {
     fn default() -> Self {
-        RadioStatus { connected: false }
+        RadioStatus {
+            connected: false,
+            bind: BindStatus::Unbound,
+        }
     }
 }
 
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BindStatus {
+    Unbound,
+    /// Offering to bind to a drone in bind mode.
+    Binding,
+    Bound,
+}
+
+/// What became of a command sent to the drone, whether it came from the buttons or the ground station.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CommandReport {
+    pub command: Command,
+    /// `None` if the drone never acknowledged the command.
+    pub result: Option<CommandResult>,
+}
+
 #[derive(Clone, Copy, Debug, PartialEq)]
 pub struct ControllerBattery {
     pub level: u8,
@@ -56,16 +103,29 @@ impl Default for ControllerInput {
     }
 }
 
-impl Into<FlightInput> for ControllerInput {
-    fn into(self) -> FlightInput {
-        FlightInput {
-            left_stick_x: self.left_stick_x,
-            left_stick_y: self.left_stick_y,
-            right_stick_x: self.right_stick_x,
-            right_stick_y: self.right_stick_y,
-            left_trigger: self.left_trigger,
-            right_trigger: self.right_trigger,
-            buttons: self.buttons,
+/// The gamepad buttons on the aux channels, in order. The shoulder buttons arm and disarm with commands instead.
+const AUX_BUTTONS: [GamepadButton; 6] = [
+    GamepadButton::A,
+    GamepadButton::B,
+    GamepadButton::X,
+    GamepadButton::Y,
+    GamepadButton::L4,
+    GamepadButton::R4,
+];
+
+impl Into<RcChannels> for ControllerInput {
+    /// Mode 2 sticks: throttle and yaw on the left, pitch and roll on the right.
+    fn into(self) -> RcChannels {
+        let mut channels = RcChannels::default();
+        channels.set(Channel::Roll, axis_from_u8(self.right_stick_x));
+        channels.set(Channel::Pitch, axis_from_u8(self.right_stick_y));
+        channels.set(Channel::Throttle, axis_from_u8(self.left_stick_y));
+        channels.set(Channel::Yaw, axis_from_u8(self.left_stick_x));
+        channels.set(Channel::LeftTrigger, trigger_from_u8(self.left_trigger));
+        channels.set(Channel::RightTrigger, trigger_from_u8(self.right_trigger));
+        for (channel, button) in Channel::AUX.into_iter().zip(AUX_BUTTONS) {
+            channels.set(channel, from_switch(button_pressed(self.buttons, button)));
         }
+        channels
     }
 }
@@ -0,0 +1,573 @@
+//! Link adaptation: the transmit power and data rate of the radio link.
+//!
+//! Both sides step along the same ladder of [`LinkProfile`]s, from the most robust one, 250 kbps at
+//! full power, to the most economical one, 2 Mbps at minimum power. The controller's
+//! [`LinkAdapter`] judges the link by how many frames get through and how many retries they take,
+//! and proposes the next profile up or down the ladder. The proposal goes to the drone as a
+//! [`crate::command::Command::SetLinkProfile`].
+//!
+//! The transmit power is each side's own business, but both have to switch data rate together. So
+//! every proposal is a [`LinkSwitch`] that names the first uplink frame sent at the new rate, far
+//! enough ahead that by then the controller has either had the drone's ack or given up on the
+//! command. The controller switches with that frame if the command was acknowledged. The drone's
+//! [`LinkFollower`] goes by the counters of the frames it hears, and by the time when frames go
+//! missing, so frames lost on a bad hop don't make it switch early. If the two ever end up on
+//! different rates, neither hears the other, and both fall back to [`LinkProfile::ROBUST`] after
+//! [`LOST_TIMEOUT`].
+
+use embassy_time::{Duration, Instant};
+
+use crate::command::{COMMAND_MAX_ATTEMPTS, COMMAND_RETRY_INTERVAL};
+use crate::fhss::UPLINK_PERIOD;
+
+/// How long a side goes without hearing the other before falling back to [`LinkProfile::ROBUST`].
+pub const LOST_TIMEOUT: Duration = Duration::from_millis(500);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DataRate {
+    Kbps250,
+    Mbps1,
+    Mbps2,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PowerLevel {
+    /// -18 dBm.
+    Min,
+    /// -12 dBm.
+    Low,
+    /// -6 dBm.
+    High,
+    /// 0 dBm.
+    Max,
+}
+
+/// A rung of the ladder of data rate and transmit power pairs, both sides use the same one.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LinkProfile(u8);
+
+impl LinkProfile {
+    /// From the most robust to the most economical. Power goes up before the data rate comes down,
+    /// since a lower data rate takes longer on air and is more likely to be hit by interference.
+    const LADDER: [(DataRate, PowerLevel); 6] = [
+        (DataRate::Kbps250, PowerLevel::Max),
+        (DataRate::Mbps1, PowerLevel::Max),
+        (DataRate::Mbps2, PowerLevel::Max),
+        (DataRate::Mbps2, PowerLevel::High),
+        (DataRate::Mbps2, PowerLevel::Low),
+        (DataRate::Mbps2, PowerLevel::Min),
+    ];
+
+    /// What both sides bind and start out with, and fall back to when they lose each other.
+    pub const ROBUST: LinkProfile = LinkProfile(0);
+
+    pub fn data_rate(&self) -> DataRate {
+        Self::LADDER[self.0 as usize].0
+    }
+
+    pub fn power_level(&self) -> PowerLevel {
+        Self::LADDER[self.0 as usize].1
+    }
+
+    /// The next rung towards [`LinkProfile::ROBUST`], if there is one.
+    pub fn more_robust(&self) -> Option<LinkProfile> {
+        self.0.checked_sub(1).map(LinkProfile)
+    }
+
+    /// The next rung away from [`LinkProfile::ROBUST`], if there is one.
+    pub fn less_robust(&self) -> Option<LinkProfile> {
+        Some(LinkProfile(self.0 + 1)).filter(|profile| (profile.0 as usize) < Self::LADDER.len())
+    }
+}
+
+impl From<LinkProfile> for u8 {
+    fn from(profile: LinkProfile) -> Self {
+        profile.0
+    }
+}
+
+impl TryFrom<u8> for LinkProfile {
+    type Error = u8;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        if (value as usize) < Self::LADDER.len() {
+            Ok(LinkProfile(value))
+        } else {
+            Err(value)
+        }
+    }
+}
+
+impl defmt::Format for LinkProfile {
+    fn format(&self, fmt: defmt::Formatter) {
+        let rate = match self.data_rate() {
+            DataRate::Kbps250 => "250kbps",
+            DataRate::Mbps1 => "1Mbps",
+            DataRate::Mbps2 => "2Mbps",
+        };
+        let power = match self.power_level() {
+            PowerLevel::Min => "-18dBm",
+            PowerLevel::Low => "-12dBm",
+            PowerLevel::High => "-6dBm",
+            PowerLevel::Max => "0dBm",
+        };
+        defmt::write!(fmt, "{} {}", rate, power)
+    }
+}
+
+/// A move to another [`LinkProfile`], and the uplink frame it takes effect with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LinkSwitch {
+    pub profile: LinkProfile,
+    /// The low 16 bits of the counter of the first uplink frame sent with `profile`.
+    pub counter: u16,
+}
+
+impl LinkSwitch {
+    /// How many uplink frames after the proposal the switch happens. The controller gives up on the
+    /// command before then, so both sides know whether it happens.
+    const DELAY: u16 = (COMMAND_RETRY_INTERVAL.as_millis() * COMMAND_MAX_ATTEMPTS as u64
+        / UPLINK_PERIOD.as_millis()) as u16
+        + 1;
+
+    /// A switch to `profile`, proposed when the next uplink frame has the counter `next_counter`.
+    pub fn new(profile: LinkProfile, next_counter: u32) -> Self {
+        Self {
+            profile,
+            counter: (next_counter as u16).wrapping_add(Self::DELAY),
+        }
+    }
+
+    /// Whether the frame with the counter `counter` is sent with the new profile.
+    fn is_due(&self, counter: u32) -> bool {
+        (counter as u16).wrapping_sub(self.counter) as i16 >= 0
+    }
+}
+
+impl defmt::Format for LinkSwitch {
+    fn format(&self, fmt: defmt::Formatter) {
+        defmt::write!(fmt, "{} at #{}", self.profile, self.counter)
+    }
+}
+
+/// The controller's side of link adaptation. Judges the link a window of frames at a time.
+pub struct LinkAdapter {
+    profile: LinkProfile,
+    frames: u8,
+    losses: u8,
+    retries: u16,
+    excellent_windows: u8,
+    last_delivered: Instant,
+    /// No less robust profile is proposed before then.
+    hold_until: Instant,
+    /// A profile with another data rate the drone agreed to, until its frame is next.
+    switch: Option<LinkSwitch>,
+}
+
+impl LinkAdapter {
+    /// How many frames are judged together.
+    const WINDOW: u8 = 50;
+    /// A window losing at least this many frames...
+    const POOR_LOSSES: u8 = 5;
+    /// ...or needing this many retries calls for a more robust profile.
+    const POOR_RETRIES: u16 = 3 * Self::WINDOW as u16;
+    /// A window without losses and at most this many retries is excellent...
+    const EXCELLENT_RETRIES: u16 = Self::WINDOW as u16 / 4;
+    /// ...and this many excellent windows in a row call for a less robust profile.
+    const EXCELLENT_WINDOWS: u8 = 4;
+    /// How long the adapter sticks with a profile it had to step up to, so it doesn't keep
+    /// bouncing between two.
+    const HOLD: Duration = Duration::from_secs(10);
+
+    pub fn new(now: Instant) -> Self {
+        Self {
+            profile: LinkProfile::ROBUST,
+            frames: 0,
+            losses: 0,
+            retries: 0,
+            excellent_windows: 0,
+            last_delivered: now,
+            hold_until: now,
+            switch: None,
+        }
+    }
+
+    /// The profile to transmit with.
+    pub fn profile(&self) -> LinkProfile {
+        self.profile
+    }
+
+    /// Records whether a frame got through and how many retries it took. Returns a new profile
+    /// when the link calls for one, to be sent to the drone as a [`LinkSwitch`] and applied with
+    /// [`LinkAdapter::schedule`] once acknowledged.
+    ///
+    /// After [`LOST_TIMEOUT`] without a frame getting through, the adapter falls back to
+    /// [`LinkProfile::ROBUST`] on its own, as the drone does.
+    pub fn record(&mut self, delivered: bool, retries: u8, now: Instant) -> Option<LinkProfile> {
+        if delivered {
+            self.last_delivered = now;
+        } else if now - self.last_delivered >= LOST_TIMEOUT {
+            if self.profile != LinkProfile::ROBUST {
+                self.profile = LinkProfile::ROBUST;
+                self.hold_until = now + Self::HOLD;
+            }
+            self.switch = None;
+            self.start_over();
+            return None;
+        }
+        if self.switch.is_some() {
+            // The link is judged again once the switch is done.
+            return None;
+        }
+
+        self.frames += 1;
+        self.losses += !delivered as u8;
+        self.retries += retries as u16;
+        if self.frames < Self::WINDOW {
+            return None;
+        }
+
+        let (losses, retries) = (self.losses, self.retries);
+        let excellent_windows = self.excellent_windows;
+        self.start_over();
+        if losses >= Self::POOR_LOSSES || retries >= Self::POOR_RETRIES {
+            self.hold_until = now + Self::HOLD;
+            return self.profile.more_robust();
+        }
+        if losses > 0 || retries > Self::EXCELLENT_RETRIES {
+            return None;
+        }
+        self.excellent_windows = excellent_windows.saturating_add(1);
+        if self.excellent_windows < Self::EXCELLENT_WINDOWS || now < self.hold_until {
+            return None;
+        }
+        self.excellent_windows = 0;
+        self.profile.less_robust()
+    }
+
+    pub fn set_profile(&mut self, profile: LinkProfile) {
+        self.profile = profile;
+        self.start_over();
+    }
+
+    /// Applies a switch the drone acknowledged. Takes up its profile right away if it keeps the
+    /// data rate, otherwise with the frame it names, see [`LinkAdapter::update`].
+    pub fn schedule(&mut self, switch: LinkSwitch) {
+        if switch.profile.data_rate() == self.profile.data_rate() {
+            self.set_profile(switch.profile);
+        } else {
+            self.switch = Some(switch);
+        }
+    }
+
+    /// Forgets the scheduled switch. Call when a new session starts, its counter belongs to the old
+    /// one.
+    pub fn unschedule(&mut self) {
+        self.switch = None;
+    }
+
+    /// Call before sending every frame, with the counter it's sent with.
+    pub fn update(&mut self, counter: u32) {
+        if let Some(switch) = self.switch.filter(|switch| switch.is_due(counter)) {
+            self.switch = None;
+            self.set_profile(switch.profile);
+        }
+    }
+
+    fn start_over(&mut self) {
+        self.frames = 0;
+        self.losses = 0;
+        self.retries = 0;
+        self.excellent_windows = 0;
+    }
+}
+
+/// The drone's side of link adaptation. Knows which profile to listen with.
+pub struct LinkFollower {
+    profile: LinkProfile,
+    period: Duration,
+    staged: Option<LinkSwitch>,
+    last_heard: Instant,
+    /// The counter of the last frame heard.
+    last_counter: u32,
+}
+
+impl LinkFollower {
+    /// `period` is the time between uplink frames.
+    pub fn new(period: Duration, now: Instant) -> Self {
+        Self {
+            profile: LinkProfile::ROBUST,
+            period,
+            staged: None,
+            last_heard: now,
+            last_counter: 0,
+        }
+    }
+
+    /// The profile to listen and answer with.
+    pub fn profile(&self) -> LinkProfile {
+        self.profile
+    }
+
+    /// Takes up the profile of `switch` right away if it keeps the data rate, otherwise once the
+    /// frame it names is next.
+    pub fn stage(&mut self, switch: LinkSwitch) {
+        if switch.profile.data_rate() == self.profile.data_rate() {
+            self.profile = switch.profile;
+            self.staged = None;
+        } else {
+            self.staged = Some(switch);
+        }
+    }
+
+    /// Forgets the staged switch. Call when a new session starts, its counter belongs to the old
+    /// one.
+    pub fn unstage(&mut self) {
+        self.staged = None;
+    }
+
+    /// Records that the frame with the counter `counter` arrived from the controller.
+    pub fn received(&mut self, counter: u32, now: Instant) {
+        self.last_heard = now;
+        self.last_counter = counter;
+    }
+
+    /// Switches to the staged profile once the frame it goes with is next, and falls back to
+    /// [`LinkProfile::ROBUST`] once the controller has been gone for [`LOST_TIMEOUT`].
+    pub fn update(&mut self, now: Instant) {
+        if let Some(switch) = self.staged.filter(|switch| now >= self.switch_at(switch)) {
+            self.profile = switch.profile;
+            self.staged = None;
+            self.last_heard = now;
+        }
+        if now - self.last_heard >= LOST_TIMEOUT {
+            self.profile = LinkProfile::ROBUST;
+            self.staged = None;
+        }
+    }
+
+    /// When [`LinkFollower::update`] has to be called next, unless a frame arrives before.
+    pub fn deadline(&self) -> Instant {
+        match &self.staged {
+            Some(switch) => self.switch_at(switch),
+            None if self.profile != LinkProfile::ROBUST => self.last_heard + LOST_TIMEOUT,
+            // There is nothing left to fall back to.
+            None => Instant::MAX,
+        }
+    }
+
+    /// Right after the last frame at the old rate arrived, or half a period after it was due.
+    fn switch_at(&self, switch: &LinkSwitch) -> Instant {
+        if switch.is_due(self.last_counter.wrapping_add(1)) {
+            return self.last_heard;
+        }
+        let missing = switch.counter.wrapping_sub(self.last_counter as u16) - 1;
+        self.last_heard + self.period * missing as u32 + self.period / 2
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PERIOD: Duration = Duration::from_millis(10);
+
+    fn at(ms: u64) -> Instant {
+        Instant::from_millis(ms)
+    }
+
+    /// Feeds the adapter `frames` frames, one per period from `start` ms on, and returns the
+    /// profiles it proposed.
+    fn feed(
+        adapter: &mut LinkAdapter,
+        start: u64,
+        frames: u64,
+        frame: impl Fn(u64) -> (bool, u8),
+    ) -> Option<LinkProfile> {
+        let mut proposed = None;
+        for i in 0..frames {
+            let (delivered, retries) = frame(i);
+            let now = at(start + i * PERIOD.as_millis());
+            proposed = adapter.record(delivered, retries, now).or(proposed);
+        }
+        proposed
+    }
+
+    #[test]
+    fn ladder_round_trips() {
+        let mut profile = LinkProfile::ROBUST;
+        assert_eq!(profile.data_rate(), DataRate::Kbps250);
+        assert_eq!(profile.power_level(), PowerLevel::Max);
+        assert_eq!(profile.more_robust(), None);
+        while let Some(next) = profile.less_robust() {
+            assert_eq!(next.more_robust(), Some(profile));
+            assert_eq!(LinkProfile::try_from(u8::from(next)), Ok(next));
+            profile = next;
+        }
+        assert_eq!(profile.data_rate(), DataRate::Mbps2);
+        assert_eq!(profile.power_level(), PowerLevel::Min);
+        assert_eq!(LinkProfile::try_from(u8::from(profile) + 1), Err(6));
+    }
+
+    #[test]
+    fn adapter_steps_down_only_after_a_run_of_excellent_windows() {
+        let mut adapter = LinkAdapter::new(at(0));
+        let window = LinkAdapter::WINDOW as u64;
+
+        // Three excellent windows aren't enough, a window with a loss starts the count over.
+        assert_eq!(feed(&mut adapter, 0, 3 * window, |_| (true, 0)), None);
+        assert_eq!(feed(&mut adapter, 1_500, window, |i| (i != 7, 0)), None);
+        assert_eq!(feed(&mut adapter, 2_000, 3 * window, |_| (true, 0)), None);
+
+        let proposed = feed(&mut adapter, 3_500, window, |_| (true, 0));
+        assert_eq!(proposed, LinkProfile::ROBUST.less_robust());
+        // Nothing changes until the drone acknowledged.
+        assert_eq!(adapter.profile(), LinkProfile::ROBUST);
+        adapter.set_profile(proposed.unwrap());
+        assert_eq!(adapter.profile(), proposed.unwrap());
+    }
+
+    #[test]
+    fn adapter_steps_up_on_losses_and_retries_and_holds() {
+        let mut adapter = LinkAdapter::new(at(0));
+        let window = LinkAdapter::WINDOW as u64;
+        let economical = LinkProfile::try_from(4).unwrap();
+        adapter.set_profile(economical);
+
+        let proposed = feed(&mut adapter, 0, window, |i| (i % 10 != 0, 0));
+        assert_eq!(proposed, economical.more_robust());
+        adapter.set_profile(proposed.unwrap());
+
+        let proposed = feed(&mut adapter, 500, window, |_| (true, 4));
+        assert_eq!(proposed, economical.more_robust().unwrap().more_robust());
+        adapter.set_profile(proposed.unwrap());
+
+        // Excellent from now on, but the adapter holds on to the profile for a while.
+        assert_eq!(feed(&mut adapter, 1_000, 8 * window, |_| (true, 0)), None);
+        let proposed = feed(&mut adapter, 5_000, 20 * window, |_| (true, 0));
+        assert_eq!(proposed, economical.more_robust());
+    }
+
+    #[test]
+    fn adapter_falls_back_when_the_drone_is_gone() {
+        let mut adapter = LinkAdapter::new(at(0));
+        adapter.set_profile(LinkProfile::try_from(3).unwrap());
+        feed(&mut adapter, 0, 10, |_| (true, 0));
+
+        feed(&mut adapter, 100, 45, |_| (false, 15));
+        assert_ne!(adapter.profile(), LinkProfile::ROBUST);
+        feed(&mut adapter, 550, 10, |_| (false, 15));
+        assert_eq!(adapter.profile(), LinkProfile::ROBUST);
+    }
+
+    #[test]
+    fn adapter_switches_data_rate_with_the_named_frame() {
+        let mut adapter = LinkAdapter::new(at(0));
+        let faster = LinkProfile::ROBUST.less_robust().unwrap();
+
+        // Counters wrap around in the command.
+        let switch = LinkSwitch::new(faster, 0xFFF0);
+        assert_eq!(switch.counter, LinkSwitch::DELAY - 0x10);
+        adapter.schedule(switch);
+        adapter.update(0xFFF1);
+        adapter.update(0x1_0000 + switch.counter as u32 - 1);
+        assert_eq!(adapter.profile(), LinkProfile::ROBUST);
+        // Nothing else is proposed in the meantime.
+        assert_eq!(
+            feed(&mut adapter, 0, 5 * LinkAdapter::WINDOW as u64, |_| (
+                true, 0
+            )),
+            None
+        );
+
+        adapter.update(0x1_0000 + switch.counter as u32);
+        assert_eq!(adapter.profile(), faster);
+
+        // Power changes don't wait.
+        let fast = LinkProfile::try_from(2).unwrap();
+        adapter.set_profile(fast);
+        adapter.schedule(LinkSwitch::new(fast.less_robust().unwrap(), 0));
+        assert_eq!(adapter.profile(), fast.less_robust().unwrap());
+    }
+
+    #[test]
+    fn follower_switches_data_rate_with_the_named_frame() {
+        let mut follower = LinkFollower::new(PERIOD, at(0));
+        let faster = LinkProfile::ROBUST.less_robust().unwrap();
+
+        follower.received(10, at(0));
+        follower.stage(LinkSwitch {
+            profile: faster,
+            counter: 15,
+        });
+        assert_eq!(follower.profile(), LinkProfile::ROBUST);
+        assert_eq!(follower.deadline(), at(45));
+
+        for counter in 11..14 {
+            let now = at((counter - 10) * PERIOD.as_millis());
+            follower.received(counter as u32, now);
+            follower.update(now);
+            assert_eq!(follower.profile(), LinkProfile::ROBUST);
+        }
+        // The last frame at the old rate.
+        follower.received(14, at(40));
+        follower.update(at(40));
+        assert_eq!(follower.profile(), faster);
+        assert_eq!(follower.deadline(), at(40) + LOST_TIMEOUT);
+    }
+
+    #[test]
+    fn follower_keeps_time_while_frames_go_missing() {
+        let mut follower = LinkFollower::new(PERIOD, at(0));
+        let faster = LinkProfile::ROBUST.less_robust().unwrap();
+
+        follower.received(10, at(0));
+        follower.stage(LinkSwitch {
+            profile: faster,
+            counter: 15,
+        });
+        // A few channels in a row are jammed, that isn't the controller switching.
+        follower.received(11, at(10));
+        for ms in [20, 30, 40] {
+            follower.update(at(ms));
+            assert_eq!(follower.profile(), LinkProfile::ROBUST);
+        }
+        assert_eq!(follower.deadline(), at(45));
+        follower.update(at(45));
+        assert_eq!(follower.profile(), faster);
+    }
+
+    #[test]
+    fn follower_takes_power_changes_right_away() {
+        let mut follower = LinkFollower::new(PERIOD, at(0));
+        let fast = LinkProfile::try_from(2).unwrap();
+        follower.stage(LinkSwitch::new(fast, 0));
+        follower.update(at(1_010));
+        assert_eq!(follower.profile(), fast);
+
+        // Nothing to switch to when the data rate stays the same.
+        follower.received(110, at(1_020));
+        follower.stage(LinkSwitch::new(fast.less_robust().unwrap(), 110));
+        assert_eq!(follower.profile(), fast.less_robust().unwrap());
+    }
+
+    #[test]
+    fn follower_forgets_switches_of_old_sessions_and_falls_back() {
+        let mut follower = LinkFollower::new(PERIOD, at(0));
+        follower.stage(LinkSwitch::new(LinkProfile::try_from(2).unwrap(), 0));
+        follower.unstage();
+        for counter in 0..200 {
+            let now = at(counter * PERIOD.as_millis());
+            follower.received(counter as u32, now);
+            follower.update(now);
+        }
+        assert_eq!(follower.profile(), LinkProfile::ROBUST);
+
+        follower.stage(LinkSwitch::new(LinkProfile::try_from(1).unwrap(), 200));
+        follower.update(at(3_010));
+        assert_eq!(follower.profile(), LinkProfile::try_from(1).unwrap());
+        follower.update(at(3_010) + LOST_TIMEOUT);
+        assert_eq!(follower.profile(), LinkProfile::ROBUST);
+        assert_eq!(follower.deadline(), Instant::MAX);
+    }
+}
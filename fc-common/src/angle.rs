@@ -0,0 +1,205 @@
+//! Angle and horizon mode: an outer loop on top of the [rate controller](crate::rate) that levels
+//! the drone.
+//!
+//! In angle mode the roll and pitch sticks ask for angles up to a maximum instead of rates, and the
+//! drone levels itself when they're centered. How far the [attitude](crate::attitude) estimate is
+//! off the target, times a gain, is the rate asked of the rate controller. Horizon mode does the
+//! same around the center of the sticks and blends into rate mode towards full deflection, so the
+//! drone can still flip. Yaw is always a rate.
+//!
+//! The angles are taken as small enough that the roll and pitch rates are about the body's axes.
+
+use uom::si::angle::{degree, radian};
+use uom::si::angular_velocity::radian_per_second;
+use uom::si::f32::{Angle, AngularVelocity};
+
+use crate::attitude::Quaternion;
+use crate::imu::Vector3;
+use crate::param::{Param, ParamStore};
+use crate::rate;
+use crate::rc::{Channel, RcChannels};
+use crate::telemetry::FlightMode;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AngleGains {
+    /// The rate asked for per angle off the target, in 1/s.
+    pub kp: f32,
+    /// The angle asked for with a stick all the way out.
+    pub max_angle: Angle,
+}
+
+impl AngleGains {
+    pub fn from_parameters(parameters: &ParamStore) -> Self {
+        Self {
+            kp: parameters.get_f32(Param::AngleP),
+            max_angle: Angle::new::<degree>(parameters.get_u8(Param::MaxAngleDeg) as f32),
+        }
+    }
+}
+
+/// The rates to ask of the rate controller in `mode`, for the sticks at `channels` and the drone at
+/// `attitude`. The angle loop never asks for more than `max_rate`, rate mode's full deflection.
+pub fn setpoint(
+    mode: FlightMode,
+    channels: &RcChannels,
+    attitude: &Quaternion,
+    gains: &AngleGains,
+    max_rate: AngularVelocity,
+) -> Vector3<AngularVelocity> {
+    let rates = rate::setpoint(channels, max_rate);
+    if mode == FlightMode::Rate {
+        return rates;
+    }
+
+    let euler = attitude.euler();
+    let max_rate = max_rate.get::<radian_per_second>();
+    let level = |stick: f32, angle: Angle| {
+        let error = (gains.max_angle * stick - angle).get::<radian>();
+        AngularVelocity::new::<radian_per_second>((gains.kp * error).clamp(-max_rate, max_rate))
+    };
+    let (roll_stick, pitch_stick) = (
+        channels.normalized(Channel::Roll),
+        channels.normalized(Channel::Pitch),
+    );
+    let roll = level(roll_stick, euler.roll);
+    let pitch = level(pitch_stick, euler.pitch);
+    match mode {
+        FlightMode::Rate | FlightMode::Angle => Vector3::new(roll, pitch, rates.z),
+        FlightMode::Horizon => {
+            // Levelling fades out as either stick goes further out.
+            let deflection = roll_stick.abs().max(pitch_stick.abs());
+            let blend = |level: AngularVelocity, rate: AngularVelocity| {
+                level * (1.0 - deflection) + rate * deflection
+            };
+            Vector3::new(blend(roll, rates.x), blend(pitch, rates.y), rates.z)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rc::{CHANNEL_CENTER, CHANNEL_MAX};
+    use libm::{cosf, sinf};
+    use uom::si::angular_velocity::degree_per_second;
+
+    fn gains() -> AngleGains {
+        AngleGains {
+            kp: 5.0,
+            max_angle: Angle::new::<degree>(45.0),
+        }
+    }
+
+    fn max_rate() -> AngularVelocity {
+        AngularVelocity::new::<degree_per_second>(360.0)
+    }
+
+    /// Rolled by `degrees` about x.
+    fn rolled(degrees: f32) -> Quaternion {
+        let half = degrees.to_radians() / 2.0;
+        Quaternion {
+            w: cosf(half),
+            x: sinf(half),
+            y: 0.0,
+            z: 0.0,
+        }
+    }
+
+    fn sticks(roll: u16, yaw: u16) -> RcChannels {
+        let mut channels = RcChannels::default();
+        channels.set(Channel::Roll, roll);
+        channels.set(Channel::Yaw, yaw);
+        channels
+    }
+
+    fn dps(setpoint: Vector3<AngularVelocity>) -> Vector3<f32> {
+        setpoint.map(|rate| rate.get::<degree_per_second>())
+    }
+
+    fn assert_near(actual: f32, expected: f32) {
+        assert!((actual - expected).abs() < 0.01, "{actual} vs {expected}");
+    }
+
+    #[test]
+    fn rate_mode_passes_the_sticks_through() {
+        let channels = sticks(CHANNEL_MAX, CHANNEL_MAX);
+        let setpoint = setpoint(
+            FlightMode::Rate,
+            &channels,
+            &rolled(30.0),
+            &gains(),
+            max_rate(),
+        );
+        assert_eq!(setpoint, rate::setpoint(&channels, max_rate()));
+    }
+
+    #[test]
+    fn angle_mode_levels_and_holds_the_stick_angle() {
+        let centered = sticks(CHANNEL_CENTER, CHANNEL_CENTER);
+        let level = |attitude: &Quaternion, channels: &RcChannels| {
+            dps(setpoint(
+                FlightMode::Angle,
+                channels,
+                attitude,
+                &gains(),
+                max_rate(),
+            ))
+        };
+
+        // Rolled right with the sticks centered, it rolls back left.
+        assert_near(level(&rolled(20.0), &centered).x, -100.0);
+        assert_eq!(level(&Quaternion::IDENTITY, &centered), Vector3::default());
+
+        // Full stick asks for the maximum angle, and nothing more once it's there.
+        let right = sticks(CHANNEL_MAX, CHANNEL_CENTER);
+        assert_near(level(&Quaternion::IDENTITY, &right).x, 225.0);
+        assert_near(level(&rolled(45.0), &right).x, 0.0);
+
+        // Far off, it's no faster than rate mode.
+        assert_near(level(&rolled(-120.0), &right).x, 360.0);
+
+        // Yaw stays a rate.
+        let yaw = sticks(CHANNEL_CENTER, CHANNEL_MAX);
+        assert_near(level(&Quaternion::IDENTITY, &yaw).z, -360.0);
+    }
+
+    #[test]
+    fn horizon_mode_blends_into_rate_mode() {
+        let horizon = |roll: u16| {
+            dps(setpoint(
+                FlightMode::Horizon,
+                &sticks(roll, CHANNEL_CENTER),
+                &rolled(20.0),
+                &gains(),
+                max_rate(),
+            ))
+            .x
+        };
+        // Levelling around the center, pure rate at full stick.
+        assert_near(horizon(CHANNEL_CENTER), -100.0);
+        assert_near(horizon(CHANNEL_MAX), 360.0);
+
+        // Half way, half of each: half of 45° less 20° at 5/s, and half of 360°/s.
+        let half = CHANNEL_CENTER + (CHANNEL_MAX - CHANNEL_CENTER) / 2;
+        let stick = sticks(half, CHANNEL_CENTER).normalized(Channel::Roll);
+        let level = 5.0 * (45.0 * stick - 20.0);
+        assert_near(horizon(half), level * (1.0 - stick) + 360.0 * stick * stick);
+    }
+
+    #[test]
+    fn levels_a_drone_that_follows_the_rates() {
+        let centered = sticks(CHANNEL_CENTER, CHANNEL_CENTER);
+        let mut roll = 60.0;
+        for _ in 0..1_000 {
+            let setpoint = dps(setpoint(
+                FlightMode::Angle,
+                &centered,
+                &rolled(roll),
+                &gains(),
+                max_rate(),
+            ));
+            roll += setpoint.x * 0.002;
+        }
+        assert!(roll.abs() < 0.1, "{roll}");
+    }
+}
@@ -0,0 +1,396 @@
+//! Estimates the drone's attitude from the IMU, with a Mahony filter.
+//!
+//! The gyro is integrated into a quaternion, and the drift that builds up is pulled back towards
+//! where the accelerometer says up is, and optionally where the magnetometer says north is. The
+//! same error also feeds an integrator that learns the gyro's bias. Without a magnetometer, yaw
+//! is the integrated gyro alone and its bias stays unknown.
+//!
+//! Angles are about the sensor's axes, right-handed, with z pointing up when the drone is level:
+//! roll is about x, pitch about y and yaw about z.
+
+use embassy_time::{Duration, Instant};
+use libm::{asinf, atan2f, sqrtf};
+use uom::si::acceleration::standard_gravity;
+use uom::si::angle::radian;
+use uom::si::angular_velocity::radian_per_second;
+use uom::si::f32::{Acceleration, Angle, AngularVelocity, MagneticFluxDensity};
+use uom::si::magnetic_flux_density::tesla;
+
+use crate::imu::Vector3;
+use crate::param::{Param, ParamStore};
+use crate::{EmitterBase, SignalBase};
+
+/// Accelerometer readings further than this from 1 g are mostly the drone's own acceleration,
+/// not gravity, so they're left out.
+const ACCEL_TRUST_G: f32 = 0.5;
+
+/// A rotation from the drone's frame to the earth's, as a unit quaternion.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Quaternion {
+    pub w: f32,
+    pub x: f32,
+    pub y: f32,
+    pub z: f32,
+}
+
+impl Default for Quaternion {
+    fn default() -> Self {
+        Self::IDENTITY
+    }
+}
+
+impl Quaternion {
+    /// Level and pointing along the x axis.
+    pub const IDENTITY: Quaternion = Quaternion {
+        w: 1.0,
+        x: 0.0,
+        y: 0.0,
+        z: 0.0,
+    };
+
+    pub fn euler(&self) -> EulerAngles {
+        let Quaternion { w, x, y, z } = *self;
+        let roll = atan2f(2.0 * (w * x + y * z), 1.0 - 2.0 * (x * x + y * y));
+        // Rounding can push the sine of ±90° just past 1.
+        let pitch = asinf((2.0 * (w * y - z * x)).clamp(-1.0, 1.0));
+        let yaw = atan2f(2.0 * (w * z + x * y), 1.0 - 2.0 * (y * y + z * z));
+        EulerAngles {
+            roll: Angle::new::<radian>(roll),
+            pitch: Angle::new::<radian>(pitch),
+            yaw: Angle::new::<radian>(yaw),
+        }
+    }
+
+    fn normalized(self) -> Self {
+        let norm = sqrtf(self.w * self.w + self.x * self.x + self.y * self.y + self.z * self.z);
+        Self {
+            w: self.w / norm,
+            x: self.x / norm,
+            y: self.y / norm,
+            z: self.z / norm,
+        }
+    }
+
+    /// Rotates `v` from the earth's frame into the drone's.
+    fn rotate_to_body(self, v: [f32; 3]) -> [f32; 3] {
+        let Quaternion { w, x, y, z } = self;
+        let [vx, vy, vz] = v;
+        [
+            2.0 * (vx * (0.5 - y * y - z * z) + vy * (x * y + w * z) + vz * (x * z - w * y)),
+            2.0 * (vx * (x * y - w * z) + vy * (0.5 - x * x - z * z) + vz * (y * z + w * x)),
+            2.0 * (vx * (x * z + w * y) + vy * (y * z - w * x) + vz * (0.5 - x * x - y * y)),
+        ]
+    }
+
+    /// Rotates `v` from the drone's frame into the earth's.
+    fn rotate_to_earth(self, v: [f32; 3]) -> [f32; 3] {
+        self.conjugate().rotate_to_body(v)
+    }
+
+    fn conjugate(self) -> Self {
+        Self {
+            w: self.w,
+            x: -self.x,
+            y: -self.y,
+            z: -self.z,
+        }
+    }
+}
+
+/// Roll, pitch and yaw, each within ±180° and pitch within ±90°.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct EulerAngles {
+    pub roll: Angle,
+    pub pitch: Angle,
+    pub yaw: Angle,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MahonyGains {
+    /// How hard the estimate is pulled towards the accelerometer and magnetometer, in 1/s.
+    pub kp: f32,
+    /// How fast the gyro bias is learned. Zero stops learning and forgets the bias.
+    pub ki: f32,
+}
+
+impl MahonyGains {
+    pub fn from_parameters(parameters: &ParamStore) -> Self {
+        Self {
+            kp: parameters.get_f32(Param::AttitudeKp),
+            ki: parameters.get_f32(Param::AttitudeKi),
+        }
+    }
+}
+
+pub struct Mahony {
+    gains: MahonyGains,
+    attitude: Quaternion,
+    /// In rad/s.
+    gyro_bias: [f32; 3],
+}
+
+impl Mahony {
+    pub fn new(gains: MahonyGains) -> Self {
+        Self {
+            gains,
+            attitude: Quaternion::IDENTITY,
+            gyro_bias: [0.0; 3],
+        }
+    }
+
+    pub fn set_gains(&mut self, gains: MahonyGains) {
+        self.gains = gains;
+    }
+
+    pub fn attitude(&self) -> Quaternion {
+        self.attitude
+    }
+
+    pub fn gyro_bias(&self) -> Vector3<AngularVelocity> {
+        let [x, y, z] = self.gyro_bias;
+        Vector3::new(x, y, z).map(AngularVelocity::new::<radian_per_second>)
+    }
+
+    /// Advances the estimate by `dt`, over which the drone turned at `gyro`.
+    pub fn update(
+        &mut self,
+        gyro: &Vector3<AngularVelocity>,
+        accel: &Vector3<Acceleration>,
+        magnetometer: Option<&Vector3<MagneticFluxDensity>>,
+        dt: Duration,
+    ) {
+        let dt = dt.as_micros() as f32 / 1_000_000.0;
+        let mut error = [0.0; 3];
+
+        let accel = [accel.x, accel.y, accel.z].map(|a| a.get::<standard_gravity>());
+        if (norm(accel) - 1.0).abs() < ACCEL_TRUST_G {
+            let up = self.attitude.rotate_to_body([0.0, 0.0, 1.0]);
+            error = add(error, cross(normalized(accel), up));
+        }
+
+        if let Some(magnetometer) = magnetometer {
+            let field = [magnetometer.x, magnetometer.y, magnetometer.z].map(|b| b.get::<tesla>());
+            if norm(field) > 0.0 {
+                let field = normalized(field);
+                // Only the heading is wanted from the field, not its dip, so the field is expected
+                // along x with the dip it was measured with.
+                let [x, y, z] = self.attitude.rotate_to_earth(field);
+                let expected = [sqrtf(x * x + y * y), 0.0, z];
+                error = add(error, cross(field, self.attitude.rotate_to_body(expected)));
+            }
+        }
+
+        if self.gains.ki > 0.0 {
+            self.gyro_bias = add(self.gyro_bias, scale(error, -self.gains.ki * dt));
+        } else {
+            self.gyro_bias = [0.0; 3];
+        }
+
+        let gyro = [gyro.x, gyro.y, gyro.z].map(|g| g.get::<radian_per_second>());
+        let [gx, gy, gz] = add(
+            add(gyro, scale(self.gyro_bias, -1.0)),
+            scale(error, self.gains.kp),
+        );
+        let Quaternion { w, x, y, z } = self.attitude;
+        let half_dt = 0.5 * dt;
+        self.attitude = Quaternion {
+            w: w + (-x * gx - y * gy - z * gz) * half_dt,
+            x: x + (w * gx + y * gz - z * gy) * half_dt,
+            y: y + (w * gy - x * gz + z * gx) * half_dt,
+            z: z + (w * gz + x * gy - y * gx) * half_dt,
+        }
+        .normalized();
+    }
+}
+
+fn add(a: [f32; 3], b: [f32; 3]) -> [f32; 3] {
+    [a[0] + b[0], a[1] + b[1], a[2] + b[2]]
+}
+
+fn scale(a: [f32; 3], factor: f32) -> [f32; 3] {
+    a.map(|a| a * factor)
+}
+
+fn cross(a: [f32; 3], b: [f32; 3]) -> [f32; 3] {
+    [
+        a[1] * b[2] - a[2] * b[1],
+        a[2] * b[0] - a[0] * b[2],
+        a[0] * b[1] - a[1] * b[0],
+    ]
+}
+
+fn norm(a: [f32; 3]) -> f32 {
+    sqrtf(a[0] * a[0] + a[1] * a[1] + a[2] * a[2])
+}
+
+fn normalized(a: [f32; 3]) -> [f32; 3] {
+    scale(a, 1.0 / norm(a))
+}
+
+/// Estimates the attitude from the gyro and the latest accelerometer reading, updating it whenever a
+/// gyro sample arrives. The gains follow the parameters.
+///
+/// The gyro signal only holds its latest sample, so the ones emitted while this task is behind are
+/// skipped, and the next one stands in for the whole gap: each step runs from when the previous
+/// sample used was emitted to when this one was.
+pub async fn run(
+    mut gyro_signal: impl SignalBase<Vector3<AngularVelocity>>,
+    mut accel_signal: impl SignalBase<Vector3<Acceleration>>,
+    mut attitude_emitter: impl EmitterBase<Quaternion>,
+    mut parameters_signal: impl SignalBase<ParamStore>,
+) -> ! {
+    let mut estimator = Mahony::new(MahonyGains::from_parameters(&parameters_signal.get()));
+    let mut last_update = None;
+    loop {
+        let gyro = gyro_signal.next_value().await;
+        let emitted_at = Instant::now() - gyro_signal.age().unwrap_or_default();
+        if let Some(last_update) = last_update {
+            estimator.set_gains(MahonyGains::from_parameters(&parameters_signal.get()));
+            // The ICM-20948's magnetometer isn't read yet.
+            estimator.update(&gyro, &accel_signal.get(), None, emitted_at - last_update);
+            attitude_emitter.emit(estimator.attitude());
+        }
+        last_update = Some(emitted_at);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::harness::{self, run_for};
+    use crate::{Signal, SignalEmitter, define_signal};
+    use core::f32::consts::PI;
+    use core::pin::pin;
+    use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+    use libm::{cosf, sinf};
+    use uom::si::angle::degree;
+    use uom::si::angular_velocity::degree_per_second;
+
+    const DT: Duration = Duration::from_millis(2);
+    const GAINS: MahonyGains = MahonyGains { kp: 2.0, ki: 0.5 };
+
+    fn gyro_dps(x: f32, y: f32, z: f32) -> Vector3<AngularVelocity> {
+        Vector3::new(x, y, z).map(AngularVelocity::new::<degree_per_second>)
+    }
+
+    /// What the accelerometer reads at rest, rolled by `roll` degrees.
+    fn rolled(roll: f32) -> Vector3<Acceleration> {
+        let roll = roll * PI / 180.0;
+        Vector3::new(0.0, sinf(roll), cosf(roll)).map(Acceleration::new::<standard_gravity>)
+    }
+
+    /// A field pointing along x and down into the ground, seen from a drone yawed by `yaw` degrees
+    /// and then rolled by `roll`.
+    fn field(roll: f32, yaw: f32) -> Vector3<MagneticFluxDensity> {
+        let (roll, yaw) = (roll * PI / 180.0, yaw * PI / 180.0);
+        let (x, y, z) = (20.0 * cosf(yaw), -20.0 * sinf(yaw), -40.0);
+        Vector3::new(
+            x,
+            cosf(roll) * y + sinf(roll) * z,
+            cosf(roll) * z - sinf(roll) * y,
+        )
+        .map(|b| MagneticFluxDensity::new::<tesla>(b * 1e-6))
+    }
+
+    fn assert_angle(actual: Angle, expected_degrees: f32, tolerance: f32) {
+        let actual = actual.get::<degree>();
+        assert!(
+            (actual - expected_degrees).abs() < tolerance,
+            "{actual}° != {expected_degrees}°"
+        );
+    }
+
+    #[test]
+    fn follows_synthetic_motion() {
+        let mut mahony = Mahony::new(GAINS);
+
+        // A second at rest, then half a second rolling at 90 °/s, with the accelerometer agreeing.
+        for _ in 0..500 {
+            mahony.update(&gyro_dps(0.0, 0.0, 0.0), &rolled(0.0), None, DT);
+        }
+        assert_eq!(mahony.attitude().euler(), EulerAngles::default());
+        for i in 0..250 {
+            let roll = 90.0 * (i as f32 * 0.002);
+            mahony.update(&gyro_dps(90.0, 0.0, 0.0), &rolled(roll), None, DT);
+        }
+        let attitude = mahony.attitude().euler();
+        assert_angle(attitude.roll, 45.0, 0.1);
+        assert_angle(attitude.pitch, 0.0, 0.1);
+
+        // Then a yaw of 90 °/s for a second. Gravity stays put.
+        let accel = rolled(45.0);
+        let yaw_rate = gyro_dps(0.0, 90.0 * sinf(PI / 4.0), 90.0 * cosf(PI / 4.0));
+        for _ in 0..500 {
+            mahony.update(&yaw_rate, &accel, None, DT);
+        }
+        let attitude = mahony.attitude().euler();
+        assert_angle(attitude.roll, 45.0, 0.5);
+        assert_angle(attitude.yaw, 90.0, 0.5);
+
+        // Hard acceleration doesn't drag the estimate along.
+        let mut mahony = Mahony::new(GAINS);
+        let shove = Vector3::new(3.0, 0.0, 1.0).map(Acceleration::new::<standard_gravity>);
+        mahony.update(&gyro_dps(0.0, 0.0, 0.0), &shove, None, DT);
+        assert_eq!(mahony.attitude(), Quaternion::IDENTITY);
+    }
+
+    #[test]
+    fn converges_and_learns_the_gyro_bias() {
+        let mut mahony = Mahony::new(GAINS);
+        // Booted tilted, on a gyro that drifts on every axis.
+        let bias = gyro_dps(1.0, -2.0, 0.5);
+        for _ in 0..30_000 {
+            mahony.update(&bias, &rolled(30.0), Some(&field(30.0, 0.0)), DT);
+        }
+        let attitude = mahony.attitude().euler();
+        assert_angle(attitude.roll, 30.0, 0.5);
+        assert_angle(attitude.pitch, 0.0, 0.5);
+        assert_angle(attitude.yaw, 0.0, 0.5);
+        for (learned, actual) in [
+            (mahony.gyro_bias().x, bias.x),
+            (mahony.gyro_bias().y, bias.y),
+            (mahony.gyro_bias().z, bias.z),
+        ] {
+            let error = (learned - actual).get::<degree_per_second>();
+            assert!(error.abs() < 0.05, "bias off by {error} °/s");
+        }
+
+        // A level drone yawed 60° finds its heading again.
+        let mut mahony = Mahony::new(GAINS);
+        for _ in 0..30_000 {
+            mahony.update(
+                &gyro_dps(0.0, 0.0, 0.0),
+                &rolled(0.0),
+                Some(&field(0.0, 60.0)),
+                DT,
+            );
+        }
+        assert_angle(mahony.attitude().euler().yaw, 60.0, 0.5);
+    }
+
+    define_signal!(AttitudeGyro, Vector3<AngularVelocity>, 1);
+    define_signal!(AttitudeAccel, Vector3<Acceleration>, 1);
+    define_signal!(Attitude, Quaternion, 1);
+    define_signal!(AttitudeParameters, ParamStore, 1);
+    #[test]
+    fn estimates_from_the_imu_signals() {
+        let _time = harness::lock();
+        let mut gyro = new_attitude_gyro_signal_emitter();
+        let mut attitude = attitude_signal().unwrap();
+        new_attitude_parameters_signal_emitter().emit(ParamStore::default());
+        new_attitude_accel_signal_emitter().emit(rolled(-20.0));
+
+        let mut task = pin!(run(
+            attitude_gyro_signal().unwrap(),
+            attitude_accel_signal().unwrap(),
+            new_attitude_signal_emitter(),
+            attitude_parameters_signal().unwrap(),
+        ));
+        for _ in 0..3_000 {
+            gyro.emit(gyro_dps(0.0, 0.0, 0.0));
+            run_for(task.as_mut(), 1);
+        }
+        assert_angle(attitude.get().euler().roll, -20.0, 0.5);
+        assert!(attitude.age().unwrap() <= Duration::from_millis(1));
+    }
+}
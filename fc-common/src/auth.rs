@@ -0,0 +1,385 @@
+//! Authentication and optional encryption of radio frames with a key shared by the controller and
+//! the drone.
+//!
+//! Every frame is sealed with keys that are only ever used once. They are derived from the link
+//! key, the [`Session`] the frame belongs to, its [`Direction`] and its counter by running one
+//! ChaCha20 block (RFC 8439) starting at the controller's nonce, with the nonce
+//! `direction || drone sequence || 0 || counter || drone boot` (numbers little-endian):
+//!
+//! - bytes 0..16 key a SipHash-2-4 MAC over the header and the (possibly encrypted) message, of
+//!   which the first [`MAC_SIZE`] bytes are sent;
+//! - bytes 16..64 are XORed onto the message when the frame is encrypted.
+//!
+//! Since a session never repeats, and the counter never repeats for a direction within a session,
+//! neither do the keys. Including the direction keeps a frame sent one way from being valid the
+//! other way. The handshake that sets up a session is the exception, see [`Session::HANDSHAKE`].
+
+/// The size of a [`LinkKey`] in bytes.
+pub const KEY_SIZE: usize = 32;
+/// The size of the MAC sent with every frame. 32 bits are plenty at a few hundred frames per
+/// second, an attacker has to send billions of forgeries to get one accepted.
+pub const MAC_SIZE: usize = 4;
+/// The longest message that can be encrypted with the keystream of a single block.
+pub const MAX_ENCRYPTED_SIZE: usize = 48;
+
+/// The secret shared by a controller and a drone, generated when binding them.
+///
+/// Intentionally not `Debug` or `defmt::Format`, so it can't end up in a log.
+#[derive(Clone, PartialEq, Eq)]
+pub struct LinkKey([u8; KEY_SIZE]);
+
+impl LinkKey {
+    pub const fn new(bytes: [u8; KEY_SIZE]) -> Self {
+        Self(bytes)
+    }
+
+    /// Parses 64 hex digits. Meant for constants, it panics (at compile time) on anything else.
+    pub const fn from_hex(hex: &str) -> Self {
+        let hex = hex.as_bytes();
+        assert!(hex.len() == 2 * KEY_SIZE, "A link key is 64 hex digits");
+
+        let mut bytes = [0; KEY_SIZE];
+        let mut i = 0;
+        while i < KEY_SIZE {
+            bytes[i] = hex_digit(hex[2 * i]) << 4 | hex_digit(hex[2 * i + 1]);
+            i += 1;
+        }
+
+        Self(bytes)
+    }
+
+    pub fn as_bytes(&self) -> &[u8; KEY_SIZE] {
+        &self.0
+    }
+}
+
+const fn hex_digit(digit: u8) -> u8 {
+    match digit {
+        b'0'..=b'9' => digit - b'0',
+        b'a'..=b'f' => digit - b'a' + 10,
+        b'A'..=b'F' => digit - b'A' + 10,
+        _ => panic!("Invalid hex digit in link key"),
+    }
+}
+
+/// Which way a frame travels.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Direction {
+    /// From the controller to the drone.
+    Uplink = 0,
+    /// From the drone to the controller, in ack payloads.
+    Downlink = 1,
+}
+
+impl Direction {
+    pub fn reverse(self) -> Self {
+        match self {
+            Direction::Uplink => Direction::Downlink,
+            Direction::Downlink => Direction::Uplink,
+        }
+    }
+}
+
+impl defmt::Format for Direction {
+    fn format(&self, fmt: defmt::Formatter) {
+        match self {
+            Direction::Uplink => defmt::write!(fmt, "Uplink"),
+            Direction::Downlink => defmt::write!(fmt, "Downlink"),
+        }
+    }
+}
+
+/// The nonces that make the keys of one session of the link unique. See [`crate::link`] for how
+/// the controller and the drone agree on them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Session {
+    /// Picked at random by the controller for every session it offers.
+    pub controller_nonce: u32,
+    /// The drone's boot count, which is never 0 and never repeats.
+    pub drone_boot: u32,
+    /// How many sessions the drone accepted since it booted, before this one.
+    pub drone_sequence: u16,
+}
+
+impl Session {
+    /// Seals the offers and acceptances that set up the other sessions. Its keys repeat, so it
+    /// only ever seals messages that are harmless to replay, and encrypts nothing.
+    pub const HANDSHAKE: Session = Session {
+        controller_nonce: 0,
+        drone_boot: 0,
+        drone_sequence: 0,
+    };
+}
+
+impl defmt::Format for Session {
+    fn format(&self, fmt: defmt::Formatter) {
+        defmt::write!(
+            fmt,
+            "session(controller({:x}) boot({}) sequence({}))",
+            self.controller_nonce,
+            self.drone_boot,
+            self.drone_sequence
+        )
+    }
+}
+
+/// The one-time keys of a single frame.
+pub struct FrameKeys {
+    mac_key: [u8; 16],
+    keystream: [u8; MAX_ENCRYPTED_SIZE],
+}
+
+impl FrameKeys {
+    pub fn new(key: &LinkKey, session: &Session, direction: Direction, counter: u32) -> Self {
+        let mut nonce = [0; 12];
+        nonce[0] = direction as u8;
+        nonce[1..3].copy_from_slice(&session.drone_sequence.to_le_bytes());
+        nonce[4..8].copy_from_slice(&counter.to_le_bytes());
+        nonce[8..12].copy_from_slice(&session.drone_boot.to_le_bytes());
+        let block = chacha20_block(&key.0, session.controller_nonce, &nonce);
+
+        let mut mac_key = [0; 16];
+        mac_key.copy_from_slice(&block[..16]);
+        let mut keystream = [0; MAX_ENCRYPTED_SIZE];
+        keystream.copy_from_slice(&block[16..]);
+
+        Self { mac_key, keystream }
+    }
+
+    /// Encrypts or decrypts `data` in place. Panics if it's longer than [`MAX_ENCRYPTED_SIZE`].
+    pub fn apply_keystream(&self, data: &mut [u8]) {
+        let keystream = &self.keystream[..data.len()];
+        for (byte, key) in data.iter_mut().zip(keystream) {
+            *byte ^= key;
+        }
+    }
+
+    pub fn mac(&self, data: &[u8]) -> [u8; MAC_SIZE] {
+        let mut mac = [0; MAC_SIZE];
+        mac.copy_from_slice(&siphash24(&self.mac_key, data).to_le_bytes()[..MAC_SIZE]);
+        mac
+    }
+
+    /// Checks `mac` in constant time, so the time taken doesn't tell how many bytes were right.
+    pub fn verify(&self, data: &[u8], mac: &[u8]) -> bool {
+        let expected = self.mac(data);
+        mac.len() == MAC_SIZE
+            && expected
+                .iter()
+                .zip(mac)
+                .fold(0, |diff, (a, b)| diff | (a ^ b))
+                == 0
+    }
+}
+
+fn quarter_round(s: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize) {
+    s[a] = s[a].wrapping_add(s[b]);
+    s[d] = (s[d] ^ s[a]).rotate_left(16);
+    s[c] = s[c].wrapping_add(s[d]);
+    s[b] = (s[b] ^ s[c]).rotate_left(12);
+    s[a] = s[a].wrapping_add(s[b]);
+    s[d] = (s[d] ^ s[a]).rotate_left(8);
+    s[c] = s[c].wrapping_add(s[d]);
+    s[b] = (s[b] ^ s[c]).rotate_left(7);
+}
+
+/// The ChaCha20 block function of RFC 8439, section 2.3.
+fn chacha20_block(key: &[u8; KEY_SIZE], counter: u32, nonce: &[u8; 12]) -> [u8; 64] {
+    let word = |bytes: &[u8], i: usize| {
+        u32::from_le_bytes([
+            bytes[4 * i],
+            bytes[4 * i + 1],
+            bytes[4 * i + 2],
+            bytes[4 * i + 3],
+        ])
+    };
+
+    let mut state = [0u32; 16];
+    state[..4].copy_from_slice(&[0x61707865, 0x3320646e, 0x79622d32, 0x6b206574]);
+    for i in 0..8 {
+        state[4 + i] = word(key, i);
+    }
+    state[12] = counter;
+    for i in 0..3 {
+        state[13 + i] = word(nonce, i);
+    }
+
+    let mut working = state;
+    for _ in 0..10 {
+        quarter_round(&mut working, 0, 4, 8, 12);
+        quarter_round(&mut working, 1, 5, 9, 13);
+        quarter_round(&mut working, 2, 6, 10, 14);
+        quarter_round(&mut working, 3, 7, 11, 15);
+        quarter_round(&mut working, 0, 5, 10, 15);
+        quarter_round(&mut working, 1, 6, 11, 12);
+        quarter_round(&mut working, 2, 7, 8, 13);
+        quarter_round(&mut working, 3, 4, 9, 14);
+    }
+
+    let mut block = [0; 64];
+    for i in 0..16 {
+        block[4 * i..4 * i + 4].copy_from_slice(&working[i].wrapping_add(state[i]).to_le_bytes());
+    }
+
+    block
+}
+
+/// SipHash-2-4, as specified by Aumasson and Bernstein.
+fn siphash24(key: &[u8; 16], data: &[u8]) -> u64 {
+    let k0 = u64::from_le_bytes(key[..8].try_into().unwrap());
+    let k1 = u64::from_le_bytes(key[8..].try_into().unwrap());
+    let mut v = [
+        k0 ^ 0x736f6d6570736575,
+        k1 ^ 0x646f72616e646f6d,
+        k0 ^ 0x6c7967656e657261,
+        k1 ^ 0x7465646279746573,
+    ];
+
+    fn round(v: &mut [u64; 4]) {
+        v[0] = v[0].wrapping_add(v[1]);
+        v[1] = v[1].rotate_left(13) ^ v[0];
+        v[0] = v[0].rotate_left(32);
+        v[2] = v[2].wrapping_add(v[3]);
+        v[3] = v[3].rotate_left(16) ^ v[2];
+        v[0] = v[0].wrapping_add(v[3]);
+        v[3] = v[3].rotate_left(21) ^ v[0];
+        v[2] = v[2].wrapping_add(v[1]);
+        v[1] = v[1].rotate_left(17) ^ v[2];
+        v[2] = v[2].rotate_left(32);
+    }
+
+    let mut compress = |m: u64| {
+        v[3] ^= m;
+        round(&mut v);
+        round(&mut v);
+        v[0] ^= m;
+    };
+
+    let mut chunks = data.chunks_exact(8);
+    for chunk in &mut chunks {
+        compress(u64::from_le_bytes(chunk.try_into().unwrap()));
+    }
+    // The last word holds the remaining bytes and the length of the data in its top byte.
+    let mut last = [0; 8];
+    let remainder = chunks.remainder();
+    last[..remainder.len()].copy_from_slice(remainder);
+    last[7] = data.len() as u8;
+    compress(u64::from_le_bytes(last));
+
+    v[2] ^= 0xff;
+    for _ in 0..4 {
+        round(&mut v);
+    }
+
+    v[0] ^ v[1] ^ v[2] ^ v[3]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sequential<const N: usize>() -> [u8; N] {
+        core::array::from_fn(|i| i as u8)
+    }
+
+    #[test]
+    fn chacha20_block_rfc8439() {
+        // RFC 8439, section 2.3.2.
+        let nonce = [0, 0, 0, 0x09, 0, 0, 0, 0x4a, 0, 0, 0, 0];
+        let block = chacha20_block(&sequential(), 1, &nonce);
+        let expected = [
+            0x10, 0xf1, 0xe7, 0xe4, 0xd1, 0x3b, 0x59, 0x15, 0x50, 0x0f, 0xdd, 0x1f, 0xa3, 0x20,
+            0x71, 0xc4, 0xc7, 0xd1, 0xf4, 0xc7, 0x33, 0xc0, 0x68, 0x03, 0x04, 0x22, 0xaa, 0x9a,
+            0xc3, 0xd4, 0x6c, 0x4e, 0xd2, 0x82, 0x64, 0x46, 0x07, 0x9f, 0xaa, 0x09, 0x14, 0xc2,
+            0xd7, 0x05, 0xd9, 0x8b, 0x02, 0xa2, 0xb5, 0x12, 0x9c, 0xd1, 0xde, 0x16, 0x4e, 0xb9,
+            0xcb, 0xd0, 0x83, 0xe8, 0xa2, 0x50, 0x3c, 0x4e,
+        ];
+        assert_eq!(block, expected);
+    }
+
+    #[test]
+    fn siphash24_reference_vectors() {
+        // From appendix A of the SipHash paper and the reference implementation's vectors.
+        let key = sequential();
+        assert_eq!(siphash24(&key, &[]), 0x726fdb47dd0e0e31);
+        assert_eq!(siphash24(&key, &sequential::<15>()), 0xa129ca6149be45e5);
+    }
+
+    #[test]
+    fn key_from_hex() {
+        let key =
+            LinkKey::from_hex("000102030405060708090a0b0c0d0e0f101112131415161718191A1B1C1D1E1F");
+        assert_eq!(key.as_bytes(), &sequential());
+    }
+
+    const SESSION: Session = Session {
+        controller_nonce: 0x5eed,
+        drone_boot: 3,
+        drone_sequence: 1,
+    };
+
+    #[test]
+    fn frame_keys_depend_on_direction_and_counter() {
+        let key = LinkKey::new(sequential());
+        let data = b"same data";
+        let uplink = FrameKeys::new(&key, &SESSION, Direction::Uplink, 7).mac(data);
+        let downlink = FrameKeys::new(&key, &SESSION, Direction::Downlink, 7).mac(data);
+        let next = FrameKeys::new(&key, &SESSION, Direction::Uplink, 8).mac(data);
+
+        assert_ne!(uplink, downlink);
+        assert_ne!(uplink, next);
+    }
+
+    #[test]
+    fn frame_keys_depend_on_every_nonce_of_the_session() {
+        let key = LinkKey::new(sequential());
+        let data = b"same data";
+        let mac = |session: Session| FrameKeys::new(&key, &session, Direction::Uplink, 7).mac(data);
+        let reference = mac(SESSION);
+
+        for other in [
+            Session::HANDSHAKE,
+            Session {
+                controller_nonce: 0x5eee,
+                ..SESSION
+            },
+            Session {
+                drone_boot: 4,
+                ..SESSION
+            },
+            Session {
+                drone_sequence: 2,
+                ..SESSION
+            },
+        ] {
+            assert_ne!(mac(other), reference, "{other:?}");
+        }
+    }
+
+    #[test]
+    fn verify_rejects_wrong_mac() {
+        let keys = FrameKeys::new(&LinkKey::new(sequential()), &SESSION, Direction::Uplink, 1);
+        let mut mac = keys.mac(b"data");
+        assert!(keys.verify(b"data", &mac));
+        assert!(!keys.verify(b"date", &mac));
+        assert!(!keys.verify(b"data", &mac[..MAC_SIZE - 1]));
+        mac[MAC_SIZE - 1] ^= 1;
+        assert!(!keys.verify(b"data", &mac));
+    }
+
+    #[test]
+    fn keystream_round_trip() {
+        let keys = FrameKeys::new(
+            &LinkKey::new(sequential()),
+            &SESSION,
+            Direction::Downlink,
+            42,
+        );
+        let mut data = *b"attack at dawn";
+        keys.apply_keystream(&mut data);
+        assert_ne!(&data, b"attack at dawn");
+        keys.apply_keystream(&mut data);
+        assert_eq!(&data, b"attack at dawn");
+    }
+}
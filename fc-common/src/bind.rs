@@ -0,0 +1,336 @@
+//! Binding a controller to a drone.
+//!
+//! A drone in bind mode listens on [`BIND_ADDRESS`] and [`BIND_CHANNEL`]. The controller generates a
+//! [`BindInfo`] from random bytes and offers it in two packets, over and over, until the drone
+//! answers with an accept packet in an ack payload. Both store the info and from then on use its
+//! address and key.
+//!
+//! ```text
+//! offer:  | 0xB1 | part | offer id | half of the info | CRC-16 |
+//! accept: | 0xB2 | offer id | CRC-16 of the info | CRC-16 |
+//! ```
+//!
+//! The offer id is a random `u32` picked by the controller for each bind attempt, so parts of
+//! different attempts are never mixed up. Bind packets can't be authenticated since there is no
+//! shared key yet, anyone listening while binding learns the key. Bind close to the drone.
+
+use core::fmt;
+
+use crate::auth::{KEY_SIZE, LinkKey};
+use crate::crc::crc16;
+
+pub const ADDRESS_SIZE: usize = 5;
+/// The pipe address of drones in bind mode.
+pub const BIND_ADDRESS: [u8; ADDRESS_SIZE] = *b"MDBND";
+/// Above the Wi-Fi channels, to keep binding out of everyone's way.
+pub const BIND_CHANNEL: u8 = 110;
+pub const BIND_INFO_SIZE: usize = ADDRESS_SIZE + 4 + KEY_SIZE;
+
+const OFFER_MAGIC: u8 = 0xB1;
+const ACCEPT_MAGIC: u8 = 0xB2;
+const PART_SIZE: usize = BIND_INFO_SIZE.div_ceil(2);
+const CHECKSUM_SIZE: usize = 2;
+pub const MAX_BIND_PACKET_SIZE: usize = 2 + 4 + PART_SIZE + CHECKSUM_SIZE;
+const ACCEPT_SIZE: usize = 1 + 4 + 2 + CHECKSUM_SIZE;
+
+/// The identity of a bound controller and drone.
+#[derive(Clone, PartialEq, Eq)]
+pub struct BindInfo {
+    /// The pipe address the controller sends to.
+    pub address: [u8; ADDRESS_SIZE],
+    /// Seeds the channel hopping sequence.
+    pub hop_seed: u32,
+    pub key: LinkKey,
+}
+
+impl BindInfo {
+    /// A new identity made from `random`, which must come from a proper random number generator.
+    pub fn from_random(random: &[u8; BIND_INFO_SIZE]) -> Self {
+        let mut info = Self::from_bytes(random);
+        // The nRF24 mistakes addresses that start with alternating bits for the preamble, which
+        // causes false detections.
+        if matches!(info.address[0], 0x00 | 0x55 | 0xAA | 0xFF) {
+            info.address[0] ^= 0x0F;
+        }
+        if info.address == BIND_ADDRESS {
+            info.address[ADDRESS_SIZE - 1] ^= 0x01;
+        }
+
+        info
+    }
+
+    pub fn to_bytes(&self) -> [u8; BIND_INFO_SIZE] {
+        let mut bytes = [0; BIND_INFO_SIZE];
+        bytes[..ADDRESS_SIZE].copy_from_slice(&self.address);
+        bytes[ADDRESS_SIZE..ADDRESS_SIZE + 4].copy_from_slice(&self.hop_seed.to_le_bytes());
+        bytes[ADDRESS_SIZE + 4..].copy_from_slice(self.key.as_bytes());
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8; BIND_INFO_SIZE]) -> Self {
+        let mut address = [0; ADDRESS_SIZE];
+        address.copy_from_slice(&bytes[..ADDRESS_SIZE]);
+        let mut hop_seed = [0; 4];
+        hop_seed.copy_from_slice(&bytes[ADDRESS_SIZE..ADDRESS_SIZE + 4]);
+        let mut key = [0; KEY_SIZE];
+        key.copy_from_slice(&bytes[ADDRESS_SIZE + 4..]);
+
+        Self {
+            address,
+            hop_seed: u32::from_le_bytes(hop_seed),
+            key: LinkKey::new(key),
+        }
+    }
+
+    fn check(&self) -> u16 {
+        crc16(&self.to_bytes())
+    }
+}
+
+// The key is left out, so it can't end up in a log.
+impl fmt::Debug for BindInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BindInfo")
+            .field("address", &self.address)
+            .field("hop_seed", &self.hop_seed)
+            .finish_non_exhaustive()
+    }
+}
+
+impl defmt::Format for BindInfo {
+    fn format(&self, fmt: defmt::Formatter) {
+        defmt::write!(
+            fmt,
+            "bind_info(address({:x}) hop_seed({:x}))",
+            self.address,
+            self.hop_seed
+        )
+    }
+}
+
+/// Appends the checksum to the first `len` bytes of `buf` and returns the packet length.
+fn seal(buf: &mut [u8], len: usize) -> usize {
+    let crc = crc16(&buf[..len]);
+    buf[len..len + CHECKSUM_SIZE].copy_from_slice(&crc.to_le_bytes());
+    len + CHECKSUM_SIZE
+}
+
+/// The packet without its checksum, if the checksum matches.
+fn unseal(packet: &[u8]) -> Option<&[u8]> {
+    let content_len = packet.len().checked_sub(CHECKSUM_SIZE)?;
+    let (content, crc) = packet.split_at(content_len);
+    (crc16(content).to_le_bytes() == crc).then_some(content)
+}
+
+/// The controller's side of binding.
+pub struct BindOffer {
+    info: BindInfo,
+    offer_id: u32,
+    next_part: usize,
+}
+
+impl BindOffer {
+    pub fn new(info: BindInfo, offer_id: u32) -> Self {
+        Self {
+            info,
+            offer_id,
+            next_part: 0,
+        }
+    }
+
+    pub fn info(&self) -> &BindInfo {
+        &self.info
+    }
+
+    /// Writes the next packet to send into `buf` and returns its length. The two parts of the offer
+    /// take turns.
+    pub fn next_packet(&mut self, buf: &mut [u8; MAX_BIND_PACKET_SIZE]) -> usize {
+        let part = self.next_part;
+        self.next_part = 1 - part;
+
+        let info = self.info.to_bytes();
+        let data = &info[part * PART_SIZE..((part + 1) * PART_SIZE).min(BIND_INFO_SIZE)];
+        buf[0] = OFFER_MAGIC;
+        buf[1] = part as u8;
+        buf[2..6].copy_from_slice(&self.offer_id.to_le_bytes());
+        buf[6..6 + data.len()].copy_from_slice(data);
+        seal(buf, 6 + data.len())
+    }
+
+    /// Whether `ack` is the drone accepting this offer.
+    pub fn is_accepted(&self, ack: &[u8]) -> bool {
+        let mut expected = [0; ACCEPT_SIZE];
+        accept_packet(&mut expected, self.offer_id, &self.info);
+        ack == expected
+    }
+}
+
+fn accept_packet(buf: &mut [u8; ACCEPT_SIZE], offer_id: u32, info: &BindInfo) {
+    buf[0] = ACCEPT_MAGIC;
+    buf[1..5].copy_from_slice(&offer_id.to_le_bytes());
+    buf[5..7].copy_from_slice(&info.check().to_le_bytes());
+    seal(buf, ACCEPT_SIZE - CHECKSUM_SIZE);
+}
+
+/// A complete offer, received by [`BindListener`].
+pub struct Accepted {
+    pub info: BindInfo,
+    packet: [u8; ACCEPT_SIZE],
+}
+
+impl Accepted {
+    /// The packet to send back as an ack payload, so the controller knows the drone is bound.
+    pub fn packet(&self) -> &[u8] {
+        &self.packet
+    }
+}
+
+/// The drone's side of binding.
+pub struct BindListener {
+    offer_id: Option<u32>,
+    data: [u8; BIND_INFO_SIZE],
+    received: [bool; 2],
+}
+
+impl BindListener {
+    pub fn new() -> Self {
+        Self {
+            offer_id: None,
+            data: [0; BIND_INFO_SIZE],
+            received: [false; 2],
+        }
+    }
+
+    /// Handles a bind packet. Once both parts of an offer have arrived, every further packet of it
+    /// returns the offer, since the controller keeps sending until it gets the accept packet.
+    pub fn receive(&mut self, packet: &[u8]) -> Option<Accepted> {
+        let content = unseal(packet)?;
+        if content.len() < 6 || content[0] != OFFER_MAGIC {
+            return None;
+        }
+        let part = content[1] as usize;
+        let offer_id = u32::from_le_bytes([content[2], content[3], content[4], content[5]]);
+        let data = &content[6..];
+        let start = part.checked_mul(PART_SIZE).filter(|_| part < 2)?;
+        if data.len() != PART_SIZE.min(BIND_INFO_SIZE - start) {
+            return None;
+        }
+
+        if self.offer_id != Some(offer_id) {
+            self.offer_id = Some(offer_id);
+            self.received = [false; 2];
+        }
+        self.data[start..start + data.len()].copy_from_slice(data);
+        self.received[part] = true;
+        if self.received != [true; 2] {
+            return None;
+        }
+
+        let info = BindInfo::from_bytes(&self.data);
+        let mut accepted = Accepted {
+            info,
+            packet: [0; ACCEPT_SIZE],
+        };
+        accept_packet(&mut accepted.packet, offer_id, &accepted.info);
+        Some(accepted)
+    }
+}
+
+impl Default for BindListener {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn info() -> BindInfo {
+        BindInfo::from_random(&core::array::from_fn(|i| (i as u8).wrapping_mul(37) ^ 0x5C))
+    }
+
+    #[test]
+    fn info_round_trip() {
+        let info = info();
+        assert_eq!(BindInfo::from_bytes(&info.to_bytes()), info);
+    }
+
+    #[test]
+    fn random_address_avoids_preamble_patterns() {
+        for first in [0x00, 0x55, 0xAA, 0xFF] {
+            let mut random = [0x12; BIND_INFO_SIZE];
+            random[0] = first;
+            let info = BindInfo::from_random(&random);
+            assert!(!matches!(info.address[0], 0x00 | 0x55 | 0xAA | 0xFF));
+        }
+
+        let mut random = [0; BIND_INFO_SIZE];
+        random[..ADDRESS_SIZE].copy_from_slice(&BIND_ADDRESS);
+        assert_ne!(BindInfo::from_random(&random).address, BIND_ADDRESS);
+    }
+
+    #[test]
+    fn offer_is_accepted() {
+        let mut offer = BindOffer::new(info(), 0xCAFE);
+        let mut listener = BindListener::new();
+        let mut buf = [0; MAX_BIND_PACKET_SIZE];
+
+        let len = offer.next_packet(&mut buf);
+        assert!(listener.receive(&buf[..len]).is_none());
+        let len = offer.next_packet(&mut buf);
+        let accepted = listener.receive(&buf[..len]).unwrap();
+
+        assert_eq!(accepted.info, info());
+        assert!(offer.is_accepted(accepted.packet()));
+        // The controller keeps offering until the accept packet gets through.
+        let len = offer.next_packet(&mut buf);
+        assert!(listener.receive(&buf[..len]).is_some());
+    }
+
+    #[test]
+    fn parts_of_different_offers_are_not_mixed() {
+        let mut old = BindOffer::new(BindInfo::from_random(&[1; BIND_INFO_SIZE]), 1);
+        let mut new = BindOffer::new(info(), 2);
+        let mut listener = BindListener::new();
+        let mut buf = [0; MAX_BIND_PACKET_SIZE];
+
+        let len = old.next_packet(&mut buf);
+        listener.receive(&buf[..len]);
+        new.next_packet(&mut buf);
+        let len = new.next_packet(&mut buf);
+        assert!(listener.receive(&buf[..len]).is_none());
+
+        let len = new.next_packet(&mut buf);
+        assert_eq!(listener.receive(&buf[..len]).unwrap().info, info());
+    }
+
+    #[test]
+    fn corrupted_packets_are_ignored() {
+        let mut offer = BindOffer::new(info(), 7);
+        let mut listener = BindListener::new();
+        let mut buf = [0; MAX_BIND_PACKET_SIZE];
+
+        let len = offer.next_packet(&mut buf);
+        listener.receive(&buf[..len]);
+        let len = offer.next_packet(&mut buf);
+        buf[10] ^= 0x01;
+        assert!(listener.receive(&buf[..len]).is_none());
+        assert!(listener.receive(&[]).is_none());
+    }
+
+    #[test]
+    fn accept_for_another_offer_is_rejected() {
+        let offer = BindOffer::new(info(), 7);
+        let mut other = BindOffer::new(info(), 8);
+        let mut listener = BindListener::new();
+        let mut buf = [0; MAX_BIND_PACKET_SIZE];
+        let mut accepted = None;
+        for _ in 0..2 {
+            let len = other.next_packet(&mut buf);
+            accepted = listener.receive(&buf[..len]);
+        }
+
+        assert!(!offer.is_accepted(accepted.unwrap().packet()));
+    }
+}
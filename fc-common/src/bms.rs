@@ -0,0 +1,156 @@
+//! The battery management task: measures the pack voltage and turns it into a charge level and a
+//! status, with the thresholds taken from the parameters.
+
+use embassy_time::{Duration, Ticker};
+
+use crate::param::{Param, ParamStore};
+use crate::telemetry::BatteryStatus;
+use crate::{EmitterBase, SignalBase};
+
+pub const SAMPLE_PERIOD: Duration = Duration::from_millis(500);
+/// A full 2S pack. The cutoff, critical and low thresholds are parameters.
+const BATTERY_MAX_MV: u32 = 8_400;
+
+/// Charge level in percent.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct BatteryLevel(pub u8);
+
+/// Battery pack voltage in millivolts.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct BatteryVoltage(pub u16);
+
+/// Measures the pack voltage.
+pub trait VoltageSensor {
+    fn read_mv(&mut self) -> u32;
+}
+
+// TODO: Re-write this to sample at 200-500 HZ. Sample vrefint and battery each time, average the samples and signal every 500ms.
+pub async fn run(
+    mut sensor: impl VoltageSensor,
+    mut battery_level_emitter: impl EmitterBase<BatteryLevel>,
+    mut battery_voltage_emitter: impl EmitterBase<BatteryVoltage>,
+    mut battery_status_emitter: impl EmitterBase<BatteryStatus>,
+    mut parameters_signal: impl SignalBase<ParamStore>,
+) -> ! {
+    let mut ticker = Ticker::every(SAMPLE_PERIOD);
+    loop {
+        let mut battery_mv = sensor.read_mv();
+        battery_voltage_emitter.emit(BatteryVoltage(battery_mv as u16));
+
+        let parameters = parameters_signal.get();
+        let cutoff_mv = parameters.get_u16(Param::BatteryCutoffMv) as u32;
+        let critical_mv = parameters.get_u16(Param::BatteryCriticalMv) as u32;
+        let low_mv = parameters.get_u16(Param::BatteryLowMv) as u32;
+        if battery_mv < cutoff_mv {
+            battery_mv = cutoff_mv;
+        }
+
+        let voltage_range = BATTERY_MAX_MV.saturating_sub(cutoff_mv).max(1);
+        let level = BatteryLevel(
+            ((battery_mv - cutoff_mv) * 100)
+                .div_ceil(voltage_range)
+                .min(100) as u8,
+        );
+
+        let status = if battery_mv <= cutoff_mv {
+            BatteryStatus::Cutoff
+        } else if battery_mv <= critical_mv {
+            BatteryStatus::Critical
+        } else if battery_mv <= low_mv {
+            BatteryStatus::Low
+        } else {
+            BatteryStatus::Ok
+        };
+
+        battery_level_emitter.emit(level);
+        battery_status_emitter.emit(status);
+
+        ticker.next().await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::harness::{self, Scripted, run_for};
+    use crate::param::ParamValue;
+    use crate::{Signal, SignalEmitter, define_signal};
+    use core::pin::pin;
+    use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+
+    /// Reads the last scripted voltage until the next one is pushed.
+    struct MockSensor {
+        readings: Scripted<u32>,
+        last_mv: u32,
+    }
+
+    impl VoltageSensor for MockSensor {
+        fn read_mv(&mut self) -> u32 {
+            self.last_mv = self.readings.next().unwrap_or(self.last_mv);
+            self.last_mv
+        }
+    }
+
+    define_signal!(BmsLevel, BatteryLevel, 1);
+    define_signal!(BmsVoltage, BatteryVoltage, 1);
+    define_signal!(BmsStatus, BatteryStatus, 1);
+    define_signal!(BmsParameters, ParamStore, 1);
+    #[test]
+    fn battery_management() {
+        let _time = harness::lock();
+        let readings = Scripted::new();
+        let mut level = bms_level_signal().unwrap();
+        let mut voltage = bms_voltage_signal().unwrap();
+        let mut status = bms_status_signal().unwrap();
+        let mut parameters_emitter = new_bms_parameters_signal_emitter();
+        let mut parameters = ParamStore::default();
+        parameters
+            .set(Param::BatteryCutoffMv, ParamValue::U16(6_400))
+            .unwrap();
+        parameters
+            .set(Param::BatteryCriticalMv, ParamValue::U16(6_800))
+            .unwrap();
+        parameters
+            .set(Param::BatteryLowMv, ParamValue::U16(7_200))
+            .unwrap();
+        parameters_emitter.emit(parameters.clone());
+
+        readings.push(8_000);
+        let mut task = pin!(run(
+            MockSensor {
+                readings: readings.clone(),
+                last_mv: 0,
+            },
+            new_bms_level_signal_emitter(),
+            new_bms_voltage_signal_emitter(),
+            new_bms_status_signal_emitter(),
+            bms_parameters_signal().unwrap(),
+        ));
+        run_for(task.as_mut(), 0);
+        assert_eq!(voltage.get(), BatteryVoltage(8_000));
+        assert_eq!(level.get(), BatteryLevel(80));
+        assert_eq!(status.get(), BatteryStatus::Ok);
+
+        // Nothing changes until the next sample.
+        readings.push(7_000);
+        run_for(task.as_mut(), SAMPLE_PERIOD.as_millis() - 1);
+        assert_eq!(voltage.get(), BatteryVoltage(8_000));
+        run_for(task.as_mut(), 1);
+        assert_eq!(voltage.get(), BatteryVoltage(7_000));
+        assert_eq!(status.get(), BatteryStatus::Low);
+
+        // The thresholds follow the parameters.
+        parameters
+            .set(Param::BatteryLowMv, ParamValue::U16(6_900))
+            .unwrap();
+        parameters_emitter.emit(parameters);
+        run_for(task.as_mut(), SAMPLE_PERIOD.as_millis());
+        assert_eq!(status.get(), BatteryStatus::Ok);
+
+        readings.push(6_000);
+        run_for(task.as_mut(), SAMPLE_PERIOD.as_millis());
+        assert_eq!(voltage.get(), BatteryVoltage(6_000));
+        assert_eq!(level.get(), BatteryLevel(0));
+        assert_eq!(status.get(), BatteryStatus::Cutoff);
+    }
+}
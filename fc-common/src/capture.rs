@@ -0,0 +1,379 @@
+//! Capturing radio traffic for offline analysis.
+//!
+//! Firmware built with capturing enabled mirrors every frame it sends or receives into a
+//! [`CaptureRecord`]: the frame with its message in the clear and without its MAC, when it went
+//! over the air and on which channel, and for the controller's uplink frames how many retries it
+//! took and whether it was acknowledged. Records are written out as
+//!
+//! ```text
+//! | 0xCA | length | event | direction | time (u32 ms) | channel | retries | flags | frame | CRC-16 |
+//! ```
+//!
+//! where the length counts the bytes from the event to the end of the frame, and the CRC covers
+//! the length and those bytes. [`CaptureParser`] picks records out of a byte stream that is shared
+//! with other output.
+
+use crate::auth::Direction;
+use crate::crc::crc16;
+use crate::protocol::{self, Frame, MAX_FRAME_SIZE, Message};
+
+/// Starts every record.
+pub const CAPTURE_STX: u8 = 0xCA;
+/// The fields between the length and the frame.
+const FIELDS_SIZE: usize = 9;
+const CRC_SIZE: usize = 2;
+pub const MAX_RECORD_SIZE: usize = 2 + FIELDS_SIZE + MAX_FRAME_SIZE + CRC_SIZE;
+
+const FLAG_TRANSMISSION: u8 = 0x01;
+const FLAG_ACKED: u8 = 0x02;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum CaptureEvent {
+    /// A frame the capturing side sent: an uplink frame from the controller, an ack payload from
+    /// the drone.
+    Sent = 0,
+    /// A frame that arrived and passed authentication.
+    Received = 1,
+    /// A frame that arrived but was rejected. The record holds it as received, still sealed.
+    Rejected = 2,
+}
+
+impl TryFrom<u8> for CaptureEvent {
+    type Error = u8;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(CaptureEvent::Sent),
+            1 => Ok(CaptureEvent::Received),
+            2 => Ok(CaptureEvent::Rejected),
+            _ => Err(value),
+        }
+    }
+}
+
+/// How an uplink transmission went, as the controller's radio reports it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Transmission {
+    pub retries: u8,
+    /// Whether an ack came back, otherwise the radio gave up after the last retry.
+    pub acked: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CaptureRecord {
+    /// The capturing side's millisecond clock.
+    pub time_ms: u32,
+    pub event: CaptureEvent,
+    pub direction: Direction,
+    pub channel: u8,
+    /// Only known for frames the controller sent.
+    pub transmission: Option<Transmission>,
+    len: u8,
+    data: [u8; MAX_FRAME_SIZE],
+}
+
+impl CaptureRecord {
+    /// A frame that was sent as `sealed`, the output of [`protocol::encode`] for `message`.
+    pub fn sent(
+        time_ms: u32,
+        direction: Direction,
+        channel: u8,
+        sealed: &[u8],
+        message: &Message,
+    ) -> Self {
+        let mut record = Self::new(time_ms, CaptureEvent::Sent, direction, channel);
+        if let Ok(header) = protocol::decode_header(sealed) {
+            let frame = Frame {
+                header,
+                message: message.clone(),
+            };
+            record.set_frame(&frame);
+        }
+        record
+    }
+
+    pub fn received(time_ms: u32, direction: Direction, channel: u8, frame: &Frame) -> Self {
+        let mut record = Self::new(time_ms, CaptureEvent::Received, direction, channel);
+        record.set_frame(frame);
+        record
+    }
+
+    /// A frame that arrived as `bytes`, and decoded into `frame` unless it was rejected.
+    pub fn arrived(
+        time_ms: u32,
+        direction: Direction,
+        channel: u8,
+        bytes: &[u8],
+        frame: Option<&Frame>,
+    ) -> Self {
+        match frame {
+            Some(frame) => Self::received(time_ms, direction, channel, frame),
+            None => Self::rejected(time_ms, direction, channel, bytes),
+        }
+    }
+
+    /// A frame that arrived as `bytes` but couldn't be decoded.
+    pub fn rejected(time_ms: u32, direction: Direction, channel: u8, bytes: &[u8]) -> Self {
+        let mut record = Self::new(time_ms, CaptureEvent::Rejected, direction, channel);
+        let len = bytes.len().min(MAX_FRAME_SIZE);
+        record.data[..len].copy_from_slice(&bytes[..len]);
+        record.len = len as u8;
+        record
+    }
+
+    pub fn with_transmission(mut self, transmission: Transmission) -> Self {
+        self.transmission = Some(transmission);
+        self
+    }
+
+    /// The frame as recorded, see [`protocol::encode_unsealed`].
+    pub fn data(&self) -> &[u8] {
+        &self.data[..self.len as usize]
+    }
+
+    /// The recorded frame, unless it was rejected.
+    pub fn frame(&self) -> Option<Frame> {
+        match self.event {
+            CaptureEvent::Rejected => None,
+            _ => protocol::decode_unsealed(self.data()).ok(),
+        }
+    }
+
+    /// Writes the record to `buf` and returns the number of bytes written.
+    pub fn encode(&self, buf: &mut [u8; MAX_RECORD_SIZE]) -> usize {
+        let end = 2 + FIELDS_SIZE + self.len as usize;
+        let flags = match self.transmission {
+            Some(Transmission { acked, .. }) => {
+                FLAG_TRANSMISSION | if acked { FLAG_ACKED } else { 0 }
+            }
+            None => 0,
+        };
+        buf[0] = CAPTURE_STX;
+        buf[1] = (FIELDS_SIZE + self.len as usize) as u8;
+        buf[2] = self.event as u8;
+        buf[3] = self.direction as u8;
+        buf[4..8].copy_from_slice(&self.time_ms.to_le_bytes());
+        buf[8] = self.channel;
+        buf[9] = self
+            .transmission
+            .map_or(0, |transmission| transmission.retries);
+        buf[10] = flags;
+        buf[11..end].copy_from_slice(self.data());
+        let crc = crc16(&buf[1..end]);
+        buf[end..end + CRC_SIZE].copy_from_slice(&crc.to_le_bytes());
+
+        end + CRC_SIZE
+    }
+
+    fn new(time_ms: u32, event: CaptureEvent, direction: Direction, channel: u8) -> Self {
+        Self {
+            time_ms,
+            event,
+            direction,
+            channel,
+            transmission: None,
+            len: 0,
+            data: [0; MAX_FRAME_SIZE],
+        }
+    }
+
+    fn set_frame(&mut self, frame: &Frame) {
+        let len = protocol::encode_unsealed(frame, &mut self.data).expect("Frames fit in a record");
+        self.len = len as u8;
+    }
+}
+
+/// Picks capture records out of a byte stream.
+///
+/// Anything that isn't a valid record is skipped, so the stream may be shared with other output.
+pub struct CaptureParser {
+    buf: [u8; MAX_RECORD_SIZE],
+    len: usize,
+}
+
+impl CaptureParser {
+    pub fn new() -> Self {
+        Self {
+            buf: [0; MAX_RECORD_SIZE],
+            len: 0,
+        }
+    }
+
+    /// Feeds one byte and returns a record if it completed one.
+    pub fn push(&mut self, byte: u8) -> Option<CaptureRecord> {
+        if self.len == 0 && byte != CAPTURE_STX {
+            return None;
+        }
+        self.buf[self.len] = byte;
+        self.len += 1;
+
+        loop {
+            if self.len < 2 {
+                return None;
+            }
+            let content_len = self.buf[1] as usize;
+            if !(FIELDS_SIZE..=FIELDS_SIZE + MAX_FRAME_SIZE).contains(&content_len) {
+                self.consume(1);
+                continue;
+            }
+            let record_len = 2 + content_len + CRC_SIZE;
+            if self.len < record_len {
+                return None;
+            }
+
+            if let Some(record) = self.check(content_len) {
+                self.consume(record_len);
+                return Some(record);
+            }
+            // A real record may have started inside the rejected one, so continue from the next
+            // STX. The bytes after it may already hold a complete record.
+            self.consume(1);
+        }
+    }
+
+    fn check(&self, content_len: usize) -> Option<CaptureRecord> {
+        let end = 2 + content_len;
+        if crc16(&self.buf[1..end]).to_le_bytes() != self.buf[end..end + CRC_SIZE] {
+            return None;
+        }
+
+        let direction = match self.buf[3] {
+            0 => Direction::Uplink,
+            1 => Direction::Downlink,
+            _ => return None,
+        };
+        let flags = self.buf[10];
+        let mut record = CaptureRecord::new(
+            u32::from_le_bytes([self.buf[4], self.buf[5], self.buf[6], self.buf[7]]),
+            CaptureEvent::try_from(self.buf[2]).ok()?,
+            direction,
+            self.buf[8],
+        );
+        if flags & FLAG_TRANSMISSION != 0 {
+            record.transmission = Some(Transmission {
+                retries: self.buf[9],
+                acked: flags & FLAG_ACKED != 0,
+            });
+        }
+        let data = &self.buf[2 + FIELDS_SIZE..end];
+        record.data[..data.len()].copy_from_slice(data);
+        record.len = data.len() as u8;
+        Some(record)
+    }
+
+    /// Drops the first `count` buffered bytes and anything up to the next STX after them.
+    fn consume(&mut self, count: usize) {
+        let next = self.buf[count..self.len]
+            .iter()
+            .position(|b| *b == CAPTURE_STX)
+            .map_or(self.len, |i| count + i);
+        self.buf.copy_within(next..self.len, 0);
+        self.len -= next;
+    }
+}
+
+impl Default for CaptureParser {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::LinkKey;
+    use crate::link::Link;
+    use crate::rc::{Channel, RcChannels};
+
+    fn parse(bytes: &[u8]) -> ([Option<CaptureRecord>; 4], usize) {
+        let mut parser = CaptureParser::new();
+        let mut records = [None, None, None, None];
+        let mut count = 0;
+        for record in bytes.iter().filter_map(|byte| parser.push(*byte)) {
+            records[count] = Some(record);
+            count += 1;
+        }
+        (records, count)
+    }
+
+    /// The controller's end of a link the drone has accepted a session on.
+    fn controller_link() -> Link {
+        let key = LinkKey::new([7; 32]);
+        let mut link = Link::new(key.clone(), Direction::Uplink);
+        let mut drone = Link::new(key, Direction::Downlink);
+        link.offer_session(1);
+        drone.accept_sessions(1);
+        let mut buf = [0u8; MAX_FRAME_SIZE];
+        let offer = link.take_handshake().unwrap();
+        let len = link.encode(&offer, 1, &mut buf).unwrap();
+        drone.receive(&buf[..len], 1).unwrap();
+        let accept = drone.take_handshake().unwrap();
+        let len = drone.encode(&accept, 1, &mut buf).unwrap();
+        link.receive(&buf[..len], 1).unwrap();
+        link
+    }
+
+    fn sent_record() -> (CaptureRecord, Message) {
+        let mut link = controller_link();
+        link.set_encryption(true);
+        let mut channels = RcChannels::default();
+        channels.set(Channel::Throttle, 1500);
+        let message = Message::RcChannels(channels);
+        let mut sealed = [0u8; MAX_FRAME_SIZE];
+        let len = link.encode(&message, 1234, &mut sealed).unwrap();
+
+        let record = CaptureRecord::sent(99_000, Direction::Uplink, 42, &sealed[..len], &message)
+            .with_transmission(Transmission {
+                retries: 3,
+                acked: true,
+            });
+        (record, message)
+    }
+
+    #[test]
+    fn records_frames_in_the_clear() {
+        let (record, message) = sent_record();
+        let frame = record.frame().unwrap();
+        assert_eq!(frame.message, message);
+        assert_eq!(frame.header.stamp.timestamp, 1234);
+
+        let rejected = CaptureRecord::rejected(5, Direction::Downlink, 9, &[1, 2, 3]);
+        assert_eq!(rejected.data(), &[1, 2, 3]);
+        assert_eq!(rejected.frame(), None);
+    }
+
+    #[test]
+    fn parser_round_trip_with_noise() {
+        let (sent, _) = sent_record();
+        let rejected = CaptureRecord::rejected(99_010, Direction::Downlink, 42, &[0xCA; 5]);
+
+        let mut stream = [0u8; 3 * MAX_RECORD_SIZE];
+        let mut len = 0;
+        for chunk in [&b"log line\n"[..], &[CAPTURE_STX, 200, 0xCA][..]] {
+            stream[len..len + chunk.len()].copy_from_slice(chunk);
+            len += chunk.len();
+        }
+        let mut buf = [0u8; MAX_RECORD_SIZE];
+        for record in [&sent, &rejected] {
+            let record_len = record.encode(&mut buf);
+            stream[len..len + record_len].copy_from_slice(&buf[..record_len]);
+            len += record_len;
+        }
+
+        let (records, count) = parse(&stream[..len]);
+        assert_eq!(count, 2);
+        assert_eq!(records[0].as_ref(), Some(&sent));
+        assert_eq!(records[1].as_ref(), Some(&rejected));
+    }
+
+    #[test]
+    fn parser_skips_corrupted_records() {
+        let (sent, _) = sent_record();
+        let mut buf = [0u8; MAX_RECORD_SIZE];
+        let len = sent.encode(&mut buf);
+        buf[6] ^= 0x40;
+
+        assert_eq!(parse(&buf[..len]).1, 0);
+    }
+}
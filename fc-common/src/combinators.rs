@@ -0,0 +1,553 @@
+//! Adapters on top of [`SignalBase`], so consumers don't each have to rate limit and filter by hand.
+//!
+//! Every adapter is a [`SignalBase`] itself, so they chain:
+//!
+//! ```rust,ignore
+//! let mut quality = radio_link_quality_signal()
+//!     .map(|score| (score * 100.0) as u8)
+//!     .throttle(Duration::from_millis(250));
+//! ```
+//!
+//! Like a plain [`Signal`](crate::Signal), an adapter only ever holds the newest value of its inputs, and
+//! `get` and `age` work on what the adapter has produced or would produce right now.
+
+use core::marker::PhantomData;
+
+use embassy_futures::join::join;
+use embassy_futures::select::{Either, select};
+use embassy_time::{Duration, Instant, Ticker, Timer, with_timeout};
+
+use crate::SignalBase;
+
+pub trait SignalExt<T>: SignalBase<T> + Sized {
+    /// Transforms every value.
+    fn map<U, F: FnMut(T) -> U>(self, f: F) -> Adapted<Map<Self, T, F>, U> {
+        Adapted::new(Map {
+            signal: self,
+            f,
+            _input: PhantomData,
+        })
+    }
+
+    /// Only passes the values `predicate` accepts. Until one is accepted, `get` returns the default value.
+    fn filter<F: FnMut(&T) -> bool>(self, predicate: F) -> Adapted<Filter<Self, T, F>, T> {
+        Adapted::new(Filter {
+            signal: self,
+            predicate,
+            accepted: None,
+        })
+    }
+
+    /// Produces at most one value per `period`, the newest one at the time.
+    fn throttle(self, period: Duration) -> Adapted<Throttle<Self>, T> {
+        Adapted::new(Throttle {
+            signal: self,
+            period,
+            ready_at: Instant::MIN,
+        })
+    }
+
+    /// Produces a value once no newer one was emitted for `quiet`.
+    fn debounce(self, quiet: Duration) -> Adapted<Debounce<Self>, T> {
+        Adapted::new(Debounce {
+            signal: self,
+            quiet,
+        })
+    }
+
+    /// Produces the current value every `period`, whether it changed or not.
+    fn sample_every(self, period: Duration) -> Adapted<SampleEvery<Self>, T> {
+        Adapted::new(SampleEvery {
+            signal: self,
+            ticker: Ticker::every(period),
+        })
+    }
+
+    /// Pairs the next value of both signals, waiting for both of them.
+    fn zip<U, S: SignalBase<U>>(self, other: S) -> Adapted<Zip<Self, S>, (T, U)> {
+        Adapted::new(Zip {
+            first: self,
+            second: other,
+        })
+    }
+
+    /// Pairs the latest values of both signals whenever either of them emits.
+    fn combine_latest<U, S: SignalBase<U>>(
+        self,
+        other: S,
+    ) -> Adapted<CombineLatest<Self, S>, (T, U)> {
+        Adapted::new(CombineLatest {
+            first: self,
+            second: other,
+        })
+    }
+
+    /// Produces the last `N` values on every new one.
+    fn windowed<const N: usize>(self) -> Adapted<Windowed<Self, T, N>, Window<T, N>>
+    where
+        T: Clone + Default,
+    {
+        Adapted::new(Windowed {
+            signal: self,
+            window: Window::new(),
+        })
+    }
+}
+
+impl<T, S: SignalBase<T>> SignalExt<T> for S {}
+
+mod sealed {
+    use embassy_time::Duration;
+
+    /// What an adapter does. [`Adapted`](super::Adapted) makes a [`SignalBase`](crate::SignalBase) of it.
+    #[allow(async_fn_in_trait)]
+    pub trait Adapter<T> {
+        async fn next(&mut self) -> T;
+        fn current(&mut self) -> T;
+        fn age(&mut self) -> Option<Duration>;
+    }
+}
+
+use sealed::Adapter;
+
+/// A signal adapter, see [`SignalExt`].
+pub struct Adapted<A, T> {
+    adapter: A,
+    /// The value produced last, for `next_distinct`.
+    last: Option<T>,
+}
+
+impl<A, T> Adapted<A, T> {
+    fn new(adapter: A) -> Self {
+        Self {
+            adapter,
+            last: None,
+        }
+    }
+}
+
+impl<T: Clone + PartialEq, A: Adapter<T>> SignalBase<T> for Adapted<A, T> {
+    async fn next_value(&mut self) -> T {
+        let value = self.adapter.next().await;
+        self.last = Some(value.clone());
+        value
+    }
+
+    async fn next_distinct(&mut self) -> T {
+        loop {
+            let value = self.adapter.next().await;
+            if self.last.as_ref() != Some(&value) {
+                self.last = Some(value.clone());
+                return value;
+            }
+        }
+    }
+
+    fn get(&mut self) -> T {
+        let value = self.adapter.current();
+        self.last = Some(value.clone());
+        value
+    }
+
+    fn age(&mut self) -> Option<Duration> {
+        self.adapter.age()
+    }
+}
+
+pub struct Map<S, T, F> {
+    signal: S,
+    f: F,
+    _input: PhantomData<fn(T)>,
+}
+
+impl<S: SignalBase<T>, T, U, F: FnMut(T) -> U> Adapter<U> for Map<S, T, F> {
+    async fn next(&mut self) -> U {
+        (self.f)(self.signal.next_value().await)
+    }
+
+    fn current(&mut self) -> U {
+        (self.f)(self.signal.get())
+    }
+
+    fn age(&mut self) -> Option<Duration> {
+        self.signal.age()
+    }
+}
+
+pub struct Filter<S, T, F> {
+    signal: S,
+    predicate: F,
+    /// The last accepted value, and when it was emitted.
+    accepted: Option<(T, Option<Instant>)>,
+}
+
+impl<S: SignalBase<T>, T: Clone + Default, F: FnMut(&T) -> bool> Filter<S, T, F> {
+    fn offer(&mut self, value: T) -> bool {
+        if !(self.predicate)(&value) {
+            return false;
+        }
+        let emitted_at = self.signal.age().map(|age| Instant::now() - age);
+        self.accepted = Some((value, emitted_at));
+        true
+    }
+}
+
+impl<S: SignalBase<T>, T: Clone + Default, F: FnMut(&T) -> bool> Adapter<T> for Filter<S, T, F> {
+    async fn next(&mut self) -> T {
+        loop {
+            let value = self.signal.next_value().await;
+            if self.offer(value.clone()) {
+                return value;
+            }
+        }
+    }
+
+    fn current(&mut self) -> T {
+        let value = self.signal.get();
+        self.offer(value);
+        self.accepted
+            .as_ref()
+            .map(|(value, _)| value.clone())
+            .unwrap_or_default()
+    }
+
+    fn age(&mut self) -> Option<Duration> {
+        let emitted_at = self.accepted.as_ref()?.1?;
+        Some(Instant::now().saturating_duration_since(emitted_at))
+    }
+}
+
+pub struct Throttle<S> {
+    signal: S,
+    period: Duration,
+    ready_at: Instant,
+}
+
+impl<S: SignalBase<T>, T> Adapter<T> for Throttle<S> {
+    async fn next(&mut self) -> T {
+        // Anything emitted in the meantime is still there once the wait is over, as the newest value.
+        Timer::at(self.ready_at).await;
+        let value = self.signal.next_value().await;
+        self.ready_at = Instant::now() + self.period;
+        value
+    }
+
+    fn current(&mut self) -> T {
+        self.signal.get()
+    }
+
+    fn age(&mut self) -> Option<Duration> {
+        self.signal.age()
+    }
+}
+
+pub struct Debounce<S> {
+    signal: S,
+    quiet: Duration,
+}
+
+impl<S: SignalBase<T>, T> Adapter<T> for Debounce<S> {
+    async fn next(&mut self) -> T {
+        let mut value = self.signal.next_value().await;
+        loop {
+            match with_timeout(self.quiet, self.signal.next_value()).await {
+                Ok(newer) => value = newer,
+                Err(_) => return value,
+            }
+        }
+    }
+
+    fn current(&mut self) -> T {
+        self.signal.get()
+    }
+
+    fn age(&mut self) -> Option<Duration> {
+        self.signal.age()
+    }
+}
+
+pub struct SampleEvery<S> {
+    signal: S,
+    ticker: Ticker,
+}
+
+impl<S: SignalBase<T>, T> Adapter<T> for SampleEvery<S> {
+    async fn next(&mut self) -> T {
+        self.ticker.next().await;
+        self.signal.get()
+    }
+
+    fn current(&mut self) -> T {
+        self.signal.get()
+    }
+
+    fn age(&mut self) -> Option<Duration> {
+        self.signal.age()
+    }
+}
+
+/// The age of a pair is that of its older half.
+fn pair_age(first: Option<Duration>, second: Option<Duration>) -> Option<Duration> {
+    Some(first?.max(second?))
+}
+
+pub struct Zip<A, B> {
+    first: A,
+    second: B,
+}
+
+impl<A: SignalBase<T>, B: SignalBase<U>, T, U> Adapter<(T, U)> for Zip<A, B> {
+    async fn next(&mut self) -> (T, U) {
+        join(self.first.next_value(), self.second.next_value()).await
+    }
+
+    fn current(&mut self) -> (T, U) {
+        (self.first.get(), self.second.get())
+    }
+
+    fn age(&mut self) -> Option<Duration> {
+        pair_age(self.first.age(), self.second.age())
+    }
+}
+
+pub struct CombineLatest<A, B> {
+    first: A,
+    second: B,
+}
+
+impl<A: SignalBase<T>, B: SignalBase<U>, T, U> Adapter<(T, U)> for CombineLatest<A, B> {
+    async fn next(&mut self) -> (T, U) {
+        match select(self.first.next_value(), self.second.next_value()).await {
+            Either::First(first) => (first, self.second.get()),
+            Either::Second(second) => (self.first.get(), second),
+        }
+    }
+
+    fn current(&mut self) -> (T, U) {
+        (self.first.get(), self.second.get())
+    }
+
+    fn age(&mut self) -> Option<Duration> {
+        pair_age(self.first.age(), self.second.age())
+    }
+}
+
+pub struct Windowed<S, T, const N: usize> {
+    signal: S,
+    window: Window<T, N>,
+}
+
+impl<S: SignalBase<T>, T: Clone + Default, const N: usize> Adapter<Window<T, N>>
+    for Windowed<S, T, N>
+{
+    async fn next(&mut self) -> Window<T, N> {
+        let value = self.signal.next_value().await;
+        self.window.push(value);
+        self.window.clone()
+    }
+
+    /// The window so far. Values that weren't waited for aren't in it.
+    fn current(&mut self) -> Window<T, N> {
+        self.window.clone()
+    }
+
+    fn age(&mut self) -> Option<Duration> {
+        self.signal.age()
+    }
+}
+
+/// The last `N` values of a signal, or fewer until `N` were emitted. Also the ring buffer behind
+/// a signal's [`History`](crate::history::History).
+#[derive(Clone, Debug, PartialEq)]
+pub struct Window<T, const N: usize> {
+    values: [Option<T>; N],
+    len: usize,
+    /// Where the next value goes, which is also where the oldest one is once the window is full.
+    next: usize,
+}
+
+impl<T, const N: usize> Default for Window<T, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T, const N: usize> Window<T, N> {
+    pub const fn new() -> Self {
+        const { assert!(N > 0, "A window holds at least one value") };
+        Self {
+            values: [const { None }; N],
+            len: 0,
+            next: 0,
+        }
+    }
+
+    /// Adds `value`, dropping the oldest value if the window is full.
+    pub fn push(&mut self, value: T) {
+        self.values[self.next] = Some(value);
+        self.next = (self.next + 1) % N;
+        self.len = (self.len + 1).min(N);
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.len == N
+    }
+
+    pub fn capacity(&self) -> usize {
+        N
+    }
+
+    /// From the oldest value to the newest.
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = &T> {
+        let start = (self.next + N - self.len) % N;
+        (0..self.len).filter_map(move |i| self.values[(start + i) % N].as_ref())
+    }
+
+    pub fn newest(&self) -> Option<&T> {
+        self.iter().next_back()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::harness::{self, advance};
+    use crate::{Signal, SignalEmitter, define_signal};
+    use core::pin::pin;
+    use embassy_futures::{block_on, poll_once};
+    use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+
+    define_signal!(Combinator1, u8, 3);
+    #[test]
+    fn map_and_filter() {
+        let mut emitter = new_combinator1_signal_emitter();
+        let mut doubled = combinator1_signal().unwrap().map(|value| value as u16 * 2);
+        let mut odd = combinator1_signal().unwrap().filter(|value| value % 2 == 1);
+
+        assert_eq!(doubled.get(), 0);
+        assert_eq!(odd.age(), None);
+        emitter.emit(3);
+        assert_eq!(block_on(doubled.next_value()), 6);
+        assert_eq!(block_on(odd.next_value()), 3);
+
+        emitter.emit(4);
+        assert_eq!(doubled.get(), 8);
+        assert_eq!(odd.get(), 3);
+        let mut next = pin!(odd.next_value());
+        assert!(poll_once(next.as_mut()).is_pending());
+        emitter.emit(5);
+        assert_eq!(block_on(next), 5);
+
+        // Adapters chain, and skip repeats like signals do.
+        emitter.emit(5);
+        emitter.emit(6);
+        let mut chained = combinator1_signal()
+            .unwrap()
+            .map(|value| value / 2)
+            .filter(|value| *value > 0);
+        assert_eq!(block_on(chained.next_distinct()), 3);
+        emitter.emit(7);
+        let mut next = pin!(chained.next_distinct());
+        assert!(poll_once(next.as_mut()).is_pending());
+        emitter.emit(8);
+        assert_eq!(block_on(next), 4);
+    }
+
+    define_signal!(Combinator2, u8, 3);
+    #[test]
+    fn throttle_debounce_and_sample() {
+        let _time = harness::lock();
+        let mut emitter = new_combinator2_signal_emitter();
+        let mut throttled = combinator2_signal()
+            .unwrap()
+            .throttle(Duration::from_millis(100));
+        let mut debounced = combinator2_signal()
+            .unwrap()
+            .debounce(Duration::from_millis(50));
+
+        emitter.emit(1);
+        assert_eq!(block_on(throttled.next_value()), 1);
+        emitter.emit(2);
+        emitter.emit(3);
+        let mut next = pin!(throttled.next_value());
+        assert!(poll_once(next.as_mut()).is_pending());
+        advance(100);
+        assert_eq!(block_on(next), 3);
+
+        let mut next = pin!(debounced.next_value());
+        assert!(poll_once(next.as_mut()).is_pending());
+        advance(30);
+        emitter.emit(4);
+        assert!(poll_once(next.as_mut()).is_pending());
+        advance(30);
+        assert!(poll_once(next.as_mut()).is_pending());
+        advance(20);
+        assert_eq!(block_on(next), 4);
+
+        // Sampling repeats the value when nothing new was emitted.
+        let mut sampled = combinator2_signal()
+            .unwrap()
+            .sample_every(Duration::from_millis(20));
+        for _ in 0..2 {
+            let mut next = pin!(sampled.next_value());
+            assert!(poll_once(next.as_mut()).is_pending());
+            advance(20);
+            assert_eq!(block_on(next), 4);
+        }
+    }
+
+    define_signal!(Combinator3, u8, 2);
+    define_signal!(Combinator4, bool, 2);
+    #[test]
+    fn zip_and_combine_latest() {
+        let _time = harness::lock();
+        let mut numbers = new_combinator3_signal_emitter();
+        let mut flags = new_combinator4_signal_emitter();
+        let mut zipped = combinator3_signal()
+            .unwrap()
+            .zip(combinator4_signal().unwrap());
+        let mut combined = combinator3_signal()
+            .unwrap()
+            .combine_latest(combinator4_signal().unwrap());
+
+        numbers.emit(1);
+        assert_eq!(block_on(combined.next_value()), (1, false));
+        {
+            let mut next = pin!(zipped.next_value());
+            assert!(poll_once(next.as_mut()).is_pending());
+            advance(10);
+            flags.emit(true);
+            assert_eq!(block_on(next), (1, true));
+        }
+        assert_eq!(block_on(combined.next_value()), (1, true));
+
+        // The pair is as old as its older half.
+        assert_eq!(zipped.age(), Some(Duration::from_millis(10)));
+        assert_eq!(combined.age(), Some(Duration::from_millis(10)));
+    }
+
+    define_signal!(Combinator5, u8, 1);
+    #[test]
+    fn windowed() {
+        let mut emitter = new_combinator5_signal_emitter();
+        let mut windowed = combinator5_signal().unwrap().windowed::<3>();
+
+        assert!(windowed.get().is_empty());
+        for value in 1..=4 {
+            emitter.emit(value);
+            let window = block_on(windowed.next_value());
+            assert_eq!(window.newest(), Some(&value));
+        }
+        let window = windowed.get();
+        assert!(window.is_full());
+        assert!(window.iter().eq([2, 3, 4].iter()));
+    }
+}
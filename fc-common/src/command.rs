@@ -0,0 +1,666 @@
+//! Commands sent from the controller to the drone.
+//!
+//! Commands are delivered with stop-and-wait: the [`CommandSender`] keeps retransmitting its one
+//! outstanding [`CommandRequest`] until the drone answers with a [`CommandAck`] carrying the same
+//! id. The [`CommandReceiver`] remembers the last id it executed and only re-acks duplicates, so
+//! every command runs exactly once no matter how many of its copies or acks are lost.
+
+use embassy_time::{Duration, Instant};
+use zerocopy::{FromBytes, Immutable, IntoBytes};
+
+use crate::adapt::{LinkProfile, LinkSwitch};
+use crate::telemetry::FlightMode;
+
+/// How long the sender waits for an ack before sending a command again.
+pub const COMMAND_RETRY_INTERVAL: Duration = Duration::from_millis(50);
+/// How many times a command is sent before the sender gives up on it.
+pub const COMMAND_MAX_ATTEMPTS: u8 = 20;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Command {
+    Arm,
+    Disarm,
+    SetFlightMode(FlightMode),
+    /// Makes the current barometer reading the new zero altitude.
+    ZeroBarometer,
+    CalibrateGyro,
+    Reboot,
+    /// Replaces the frequency hopping blacklist, see [`crate::fhss::HopSequence::blacklist`].
+    SetHopBlacklist(u32),
+    /// Moves the link to another data rate and transmit power, see [`crate::adapt`].
+    SetLinkProfile(LinkSwitch),
+}
+
+impl Command {
+    const ARM: u8 = 1;
+    const DISARM: u8 = 2;
+    const SET_FLIGHT_MODE: u8 = 3;
+    const ZERO_BAROMETER: u8 = 4;
+    const CALIBRATE_GYRO: u8 = 5;
+    const REBOOT: u8 = 6;
+    // 7 is kept for a motor test, once the drone drives its motors.
+    const SET_HOP_BLACKLIST: u8 = 8;
+    const SET_LINK_PROFILE: u8 = 9;
+
+    pub fn to_request(&self, id: u16) -> CommandRequest {
+        let (command, args) = match *self {
+            Command::Arm => (Self::ARM, [0; 4]),
+            Command::Disarm => (Self::DISARM, [0; 4]),
+            Command::SetFlightMode(mode) => (Self::SET_FLIGHT_MODE, [mode as u8, 0, 0, 0]),
+            Command::ZeroBarometer => (Self::ZERO_BAROMETER, [0; 4]),
+            Command::CalibrateGyro => (Self::CALIBRATE_GYRO, [0; 4]),
+            Command::Reboot => (Self::REBOOT, [0; 4]),
+            Command::SetHopBlacklist(blacklist) => {
+                (Self::SET_HOP_BLACKLIST, blacklist.to_le_bytes())
+            }
+            Command::SetLinkProfile(switch) => {
+                let [low, high] = switch.counter.to_le_bytes();
+                (
+                    Self::SET_LINK_PROFILE,
+                    [switch.profile.into(), low, high, 0],
+                )
+            }
+        };
+
+        CommandRequest { id, command, args }
+    }
+
+    /// Returns `None` if the request holds an unknown command or invalid arguments.
+    pub fn from_request(request: &CommandRequest) -> Option<Command> {
+        let args = request.args;
+        match request.command {
+            Self::ARM => Some(Command::Arm),
+            Self::DISARM => Some(Command::Disarm),
+            Self::SET_FLIGHT_MODE => FlightMode::try_from(args[0])
+                .ok()
+                .map(Command::SetFlightMode),
+            Self::ZERO_BAROMETER => Some(Command::ZeroBarometer),
+            Self::CALIBRATE_GYRO => Some(Command::CalibrateGyro),
+            Self::REBOOT => Some(Command::Reboot),
+            Self::SET_HOP_BLACKLIST => Some(Command::SetHopBlacklist(u32::from_le_bytes(args))),
+            Self::SET_LINK_PROFILE => LinkProfile::try_from(args[0]).ok().map(|profile| {
+                Command::SetLinkProfile(LinkSwitch {
+                    profile,
+                    counter: u16::from_le_bytes([args[1], args[2]]),
+                })
+            }),
+            _ => None,
+        }
+    }
+}
+
+impl defmt::Format for Command {
+    fn format(&self, fmt: defmt::Formatter) {
+        match self {
+            Command::Arm => defmt::write!(fmt, "Arm"),
+            Command::Disarm => defmt::write!(fmt, "Disarm"),
+            Command::SetFlightMode(mode) => defmt::write!(fmt, "SetFlightMode({})", mode),
+            Command::ZeroBarometer => defmt::write!(fmt, "ZeroBarometer"),
+            Command::CalibrateGyro => defmt::write!(fmt, "CalibrateGyro"),
+            Command::Reboot => defmt::write!(fmt, "Reboot"),
+            Command::SetHopBlacklist(blacklist) => {
+                defmt::write!(fmt, "SetHopBlacklist({:x})", blacklist)
+            }
+            Command::SetLinkProfile(switch) => defmt::write!(fmt, "SetLinkProfile({})", switch),
+        }
+    }
+}
+
+/// How the drone handled a command.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum CommandResult {
+    Accepted = 0,
+    /// The command is valid but can't be carried out right now, e.g. rebooting while armed.
+    Rejected = 1,
+    /// The drone doesn't implement this command.
+    Unsupported = 2,
+    /// The request couldn't be decoded into a [`Command`].
+    Invalid = 3,
+}
+
+impl TryFrom<u8> for CommandResult {
+    type Error = u8;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(CommandResult::Accepted),
+            1 => Ok(CommandResult::Rejected),
+            2 => Ok(CommandResult::Unsupported),
+            3 => Ok(CommandResult::Invalid),
+            _ => Err(value),
+        }
+    }
+}
+
+impl defmt::Format for CommandResult {
+    fn format(&self, fmt: defmt::Formatter) {
+        match self {
+            CommandResult::Accepted => defmt::write!(fmt, "Accepted"),
+            CommandResult::Rejected => defmt::write!(fmt, "Rejected"),
+            CommandResult::Unsupported => defmt::write!(fmt, "Unsupported"),
+            CommandResult::Invalid => defmt::write!(fmt, "Invalid"),
+        }
+    }
+}
+
+/// A command as sent over the uplink.
+#[derive(IntoBytes, FromBytes, Immutable, Debug, PartialEq, Default, Clone)]
+#[repr(C, packed)]
+pub struct CommandRequest {
+    /// Identifies the command for acks and duplicate detection. Retransmissions keep the id.
+    pub id: u16,
+    pub command: u8,
+    /// Command specific arguments, see [`Command::to_request`].
+    pub args: [u8; 4],
+}
+
+impl defmt::Format for CommandRequest {
+    fn format(&self, fmt: defmt::Formatter) {
+        defmt::write!(fmt, "command(#{}, {})", { self.id }, self.command)
+    }
+}
+
+/// The drone's answer to a [`CommandRequest`], sent in an ack payload.
+#[derive(IntoBytes, FromBytes, Immutable, Debug, PartialEq, Default, Clone)]
+#[repr(C, packed)]
+pub struct CommandAck {
+    pub id: u16,
+    /// A [`CommandResult`].
+    pub result: u8,
+}
+
+impl CommandAck {
+    /// Unknown values are reported as [`CommandResult::Invalid`].
+    pub fn result(&self) -> CommandResult {
+        CommandResult::try_from(self.result).unwrap_or(CommandResult::Invalid)
+    }
+}
+
+impl defmt::Format for CommandAck {
+    fn format(&self, fmt: defmt::Formatter) {
+        defmt::write!(fmt, "command_ack(#{}, {})", { self.id }, self.result())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CommandError {
+    /// Another command is still waiting for its ack.
+    Busy,
+}
+
+impl defmt::Format for CommandError {
+    fn format(&self, fmt: defmt::Formatter) {
+        match self {
+            CommandError::Busy => defmt::write!(fmt, "Busy"),
+        }
+    }
+}
+
+/// What the sender wants to do in the current uplink slot.
+#[derive(Debug, Clone, PartialEq)]
+pub enum CommandPoll {
+    /// Nothing to send, the slot can carry stick input.
+    Idle,
+    Transmit(CommandRequest),
+    /// The command was sent [`COMMAND_MAX_ATTEMPTS`] times without an ack. It may or may not have
+    /// been executed.
+    TimedOut(Command),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CommandOutcome {
+    pub command: Command,
+    pub result: CommandResult,
+}
+
+struct PendingCommand {
+    id: u16,
+    command: Command,
+    attempts: u8,
+    next_attempt: Instant,
+}
+
+/// The controller's half of the command channel.
+pub struct CommandSender {
+    next_id: u16,
+    pending: Option<PendingCommand>,
+}
+
+impl CommandSender {
+    pub fn new() -> Self {
+        Self {
+            next_id: 1,
+            pending: None,
+        }
+    }
+
+    /// Queues `command` to be sent on the next [`CommandSender::poll`] and returns its id.
+    pub fn submit(&mut self, command: Command, now: Instant) -> Result<u16, CommandError> {
+        if self.pending.is_some() {
+            return Err(CommandError::Busy);
+        }
+
+        Ok(self.start(command, now))
+    }
+
+    /// Queues `command` like [`CommandSender::submit`], giving up on the pending command if there
+    /// is one, for commands that can't wait such as disarming. Returns the new command's id and
+    /// the command given up on, which may or may not have been executed as if it had timed out.
+    pub fn preempt(&mut self, command: Command, now: Instant) -> (u16, Option<Command>) {
+        let abandoned = self.abandon();
+        (self.start(command, now), abandoned)
+    }
+
+    /// Gives up on the pending command, if there is one, and returns it. It may or may not have
+    /// been executed, as if it had timed out. Call this when a new session with the drone starts,
+    /// since the drone forgets which commands it executed.
+    pub fn abandon(&mut self) -> Option<Command> {
+        self.pending.take().map(|pending| pending.command)
+    }
+
+    fn start(&mut self, command: Command, now: Instant) -> u16 {
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+        self.pending = Some(PendingCommand {
+            id,
+            command,
+            attempts: 0,
+            next_attempt: now,
+        });
+
+        id
+    }
+
+    pub fn is_busy(&self) -> bool {
+        self.pending.is_some()
+    }
+
+    /// Call once per uplink slot. Commands are sent at most once per [`COMMAND_RETRY_INTERVAL`],
+    /// so the remaining slots keep carrying stick input.
+    pub fn poll(&mut self, now: Instant) -> CommandPoll {
+        let Some(pending) = &mut self.pending else {
+            return CommandPoll::Idle;
+        };
+        if now < pending.next_attempt {
+            return CommandPoll::Idle;
+        }
+        if pending.attempts >= COMMAND_MAX_ATTEMPTS {
+            let command = pending.command;
+            self.pending = None;
+            return CommandPoll::TimedOut(command);
+        }
+
+        pending.attempts += 1;
+        pending.next_attempt = now + COMMAND_RETRY_INTERVAL;
+        CommandPoll::Transmit(pending.command.to_request(pending.id))
+    }
+
+    /// Completes the pending command if `ack` belongs to it. Acks for anything else, such as
+    /// repeated acks for an already completed command, are ignored.
+    pub fn acknowledge(&mut self, ack: &CommandAck) -> Option<CommandOutcome> {
+        let pending = self.pending.as_ref()?;
+        if pending.id != ack.id {
+            return None;
+        }
+
+        let command = pending.command;
+        self.pending = None;
+        Some(CommandOutcome {
+            command,
+            result: ack.result(),
+        })
+    }
+}
+
+impl Default for CommandSender {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The drone's half of the command channel.
+pub struct CommandReceiver {
+    last: Option<CommandAck>,
+}
+
+impl CommandReceiver {
+    pub fn new() -> Self {
+        Self { last: None }
+    }
+
+    /// Executes the command in `request` unless it's a retransmission of the last one, and returns
+    /// the ack to send back. Retransmissions get the ack of the original execution.
+    pub fn receive(
+        &mut self,
+        request: &CommandRequest,
+        execute: impl FnOnce(Command) -> CommandResult,
+    ) -> CommandAck {
+        if let Some(last) = self.last.as_ref().filter(|last| last.id == request.id) {
+            return last.clone();
+        }
+
+        let result = match Command::from_request(request) {
+            Some(command) => execute(command),
+            None => CommandResult::Invalid,
+        };
+        let ack = CommandAck {
+            id: request.id,
+            result: result as u8,
+        };
+        self.last = Some(ack.clone());
+
+        ack
+    }
+
+    /// Forgets the last executed command. Call this when a new session with the controller starts:
+    /// it gives up on the command in flight then, and if it restarted, its new sender starts over
+    /// from the first id.
+    pub fn reset(&mut self) {
+        self.last = None;
+    }
+}
+
+impl Default for CommandReceiver {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const COMMANDS: [Command; 8] = [
+        Command::Arm,
+        Command::Disarm,
+        Command::SetFlightMode(FlightMode::Horizon),
+        Command::ZeroBarometer,
+        Command::CalibrateGyro,
+        Command::Reboot,
+        Command::SetHopBlacklist(0x0100_0003),
+        Command::SetLinkProfile(LinkSwitch {
+            profile: LinkProfile::ROBUST,
+            counter: 0x1234,
+        }),
+    ];
+
+    fn at(ms: u64) -> Instant {
+        Instant::from_millis(ms)
+    }
+
+    #[test]
+    fn request_round_trip() {
+        for (id, command) in COMMANDS.iter().enumerate() {
+            let request = command.to_request(id as u16);
+            assert_eq!({ request.id }, id as u16);
+            assert_eq!(Command::from_request(&request), Some(*command));
+        }
+    }
+
+    #[test]
+    fn invalid_requests() {
+        let unknown = CommandRequest {
+            id: 1,
+            command: 0xEE,
+            args: [0; 4],
+        };
+        let bad_mode = CommandRequest {
+            id: 1,
+            command: Command::SET_FLIGHT_MODE,
+            args: [9, 0, 0, 0],
+        };
+        let motor_test = CommandRequest {
+            id: 1,
+            command: 7,
+            args: [0, 15, 0, 0],
+        };
+        let bad_profile = CommandRequest {
+            id: 1,
+            command: Command::SET_LINK_PROFILE,
+            args: [6, 0, 0, 0],
+        };
+
+        assert_eq!(Command::from_request(&unknown), None);
+        assert_eq!(Command::from_request(&bad_mode), None);
+        assert_eq!(Command::from_request(&motor_test), None);
+        assert_eq!(Command::from_request(&bad_profile), None);
+    }
+
+    #[test]
+    fn sender_retries_until_acked() {
+        let mut sender = CommandSender::new();
+        let id = sender.submit(Command::Arm, at(0)).unwrap();
+
+        assert_eq!(
+            sender.poll(at(0)),
+            CommandPoll::Transmit(Command::Arm.to_request(id))
+        );
+        assert_eq!(sender.poll(at(10)), CommandPoll::Idle);
+        assert_eq!(sender.poll(at(49)), CommandPoll::Idle);
+        assert_eq!(
+            sender.poll(at(50)),
+            CommandPoll::Transmit(Command::Arm.to_request(id))
+        );
+
+        let outcome = sender.acknowledge(&CommandAck {
+            id,
+            result: CommandResult::Accepted as u8,
+        });
+        assert_eq!(
+            outcome,
+            Some(CommandOutcome {
+                command: Command::Arm,
+                result: CommandResult::Accepted,
+            })
+        );
+        assert!(!sender.is_busy());
+        assert_eq!(sender.poll(at(100)), CommandPoll::Idle);
+    }
+
+    #[test]
+    fn sender_is_busy_while_pending() {
+        let mut sender = CommandSender::new();
+        let first = sender.submit(Command::Arm, at(0)).unwrap();
+
+        assert_eq!(
+            sender.submit(Command::Disarm, at(0)),
+            Err(CommandError::Busy)
+        );
+
+        sender.acknowledge(&CommandAck {
+            id: first,
+            result: 0,
+        });
+        let second = sender.submit(Command::Disarm, at(0)).unwrap();
+        assert_ne!(first, second);
+    }
+
+    #[test]
+    fn sender_preempts_the_pending_command() {
+        let mut sender = CommandSender::new();
+        let first = sender
+            .submit(Command::SetHopBlacklist(0x0100_0003), at(0))
+            .unwrap();
+        sender.poll(at(0));
+
+        let (second, abandoned) = sender.preempt(Command::Disarm, at(10));
+        assert_eq!(abandoned, Some(Command::SetHopBlacklist(0x0100_0003)));
+        assert_eq!(
+            sender.poll(at(10)),
+            CommandPoll::Transmit(Command::Disarm.to_request(second))
+        );
+        // The late ack of the command given up on completes nothing.
+        assert_eq!(
+            sender.acknowledge(&CommandAck {
+                id: first,
+                result: 0
+            }),
+            None
+        );
+        assert!(sender.is_busy());
+
+        sender.acknowledge(&CommandAck {
+            id: second,
+            result: 0,
+        });
+        assert_eq!(sender.preempt(Command::Disarm, at(20)).1, None);
+    }
+
+    #[test]
+    fn sender_abandons_the_pending_command() {
+        let mut sender = CommandSender::new();
+        let first = sender.submit(Command::Arm, at(0)).unwrap();
+        sender.poll(at(0));
+
+        assert_eq!(sender.abandon(), Some(Command::Arm));
+        assert!(!sender.is_busy());
+        assert_eq!(sender.poll(at(1000)), CommandPoll::Idle);
+        assert_eq!(sender.abandon(), None);
+
+        // Ids carry on, so a late ack of the abandoned command can't complete the next one.
+        let second = sender.submit(Command::Disarm, at(1000)).unwrap();
+        assert_ne!(second, first);
+    }
+
+    #[test]
+    fn sender_ignores_stale_acks() {
+        let mut sender = CommandSender::new();
+        let first = sender.submit(Command::Arm, at(0)).unwrap();
+        sender.acknowledge(&CommandAck {
+            id: first,
+            result: 0,
+        });
+        sender.submit(Command::Disarm, at(0)).unwrap();
+
+        assert_eq!(
+            sender.acknowledge(&CommandAck {
+                id: first,
+                result: 0
+            }),
+            None
+        );
+        assert!(sender.is_busy());
+    }
+
+    #[test]
+    fn sender_times_out() {
+        let mut sender = CommandSender::new();
+        sender.submit(Command::Reboot, at(0)).unwrap();
+
+        let mut transmissions = 0;
+        let mut ms = 0;
+        let timed_out = loop {
+            match sender.poll(at(ms)) {
+                CommandPoll::Idle => {}
+                CommandPoll::Transmit(_) => transmissions += 1,
+                CommandPoll::TimedOut(command) => break command,
+            }
+            ms += 10;
+        };
+
+        assert_eq!(timed_out, Command::Reboot);
+        assert_eq!(transmissions, COMMAND_MAX_ATTEMPTS);
+        assert!(!sender.is_busy());
+    }
+
+    #[test]
+    fn receiver_executes_duplicates_once() {
+        let mut receiver = CommandReceiver::new();
+        let request = Command::Arm.to_request(7);
+        let mut executions = 0;
+
+        for _ in 0..3 {
+            let ack = receiver.receive(&request, |_| {
+                executions += 1;
+                CommandResult::Accepted
+            });
+            assert_eq!({ ack.id }, 7);
+            assert_eq!(ack.result(), CommandResult::Accepted);
+        }
+
+        assert_eq!(executions, 1);
+    }
+
+    #[test]
+    fn receiver_acks_invalid_requests() {
+        let mut receiver = CommandReceiver::new();
+        let request = CommandRequest {
+            id: 3,
+            command: 0xEE,
+            args: [0; 4],
+        };
+
+        let ack = receiver.receive(&request, |_| unreachable!());
+        assert_eq!(ack.result(), CommandResult::Invalid);
+    }
+
+    #[test]
+    fn receiver_reset_forgets_last_command() {
+        let mut receiver = CommandReceiver::new();
+        let request = Command::Arm.to_request(1);
+        let mut executions = 0;
+
+        receiver.receive(&request, |_| {
+            executions += 1;
+            CommandResult::Accepted
+        });
+        receiver.reset();
+        receiver.receive(&request, |_| {
+            executions += 1;
+            CommandResult::Accepted
+        });
+
+        assert_eq!(executions, 2);
+    }
+
+    /// Runs a sequence of commands over a link that drops uplink frames and acks in a fixed
+    /// pattern, and checks that every command is executed exactly once and in order.
+    #[test]
+    fn exactly_once_over_lossy_link() {
+        let commands = [
+            Command::Arm,
+            Command::SetFlightMode(FlightMode::Angle),
+            Command::Disarm,
+            Command::ZeroBarometer,
+        ];
+        let mut sender = CommandSender::new();
+        let mut receiver = CommandReceiver::new();
+        let mut executed = [None; 4];
+        let mut executed_count = 0;
+        let mut outcomes = 0;
+        let mut next_command = 0;
+        let mut slot = 0u32;
+
+        while outcomes < commands.len() {
+            let now = at(slot as u64 * 10);
+            if !sender.is_busy() && next_command < commands.len() {
+                sender.submit(commands[next_command], now).unwrap();
+                next_command += 1;
+            }
+
+            if let CommandPoll::Transmit(request) = sender.poll(now) {
+                // Drops two out of three uplink frames, and every ack that survives that on
+                // even slots.
+                let uplink_lost = !slot.is_multiple_of(3);
+                let ack_lost = slot.is_multiple_of(2);
+                if !uplink_lost {
+                    let ack = receiver.receive(&request, |command| {
+                        executed[executed_count] = Some(command);
+                        executed_count += 1;
+                        CommandResult::Accepted
+                    });
+                    if !ack_lost && sender.acknowledge(&ack).is_some() {
+                        outcomes += 1;
+                    }
+                }
+            }
+
+            slot += 1;
+            assert!(slot < 10_000, "Commands were never delivered");
+        }
+
+        assert_eq!(executed_count, commands.len());
+        assert_eq!(executed, commands.map(Some));
+    }
+}
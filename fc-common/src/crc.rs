@@ -0,0 +1,35 @@
+/// Computes the CRC-16/CCITT-FALSE checksum (poly `0x1021`, init `0xFFFF`) of `data`.
+///
+/// This is the checksum of bind packets and stored records, which can't be authenticated with a link
+/// key. It's computed bit by bit rather than through a lookup table, since the data is at most a few
+/// dozen bytes and flash is more precious than cycles on both targets.
+pub fn crc16(data: &[u8]) -> u16 {
+    let mut crc: u16 = 0xFFFF;
+    for byte in data {
+        crc ^= (*byte as u16) << 8;
+        for _ in 0..8 {
+            if crc & 0x8000 != 0 {
+                crc = (crc << 1) ^ 0x1021;
+            } else {
+                crc <<= 1;
+            }
+        }
+    }
+
+    crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn crc16_check_value() {
+        assert_eq!(crc16(b"123456789"), 0x29B1);
+    }
+
+    #[test]
+    fn crc16_empty() {
+        assert_eq!(crc16(&[]), 0xFFFF);
+    }
+}
@@ -0,0 +1,523 @@
+//! Frequency hopping for the radio link.
+//!
+//! The controller sends every uplink frame on the next channel of a [`HopSequence`] derived from the
+//! hop seed both sides got when binding, one slot per uplink period. The drone follows with a
+//! [`HopReceiver`]: after every frame it hears it moves on to the next channel, and when a frame
+//! doesn't show up it moves on anyway once the frame is overdue. After missing too many frames in a
+//! row it stops guessing and parks on one channel for a whole cycle of the sequence, which is
+//! guaranteed to bring the controller by, and picks up the sequence from there.
+//!
+//! Channels that keep failing while the rest of the sequence works are blacklisted by the
+//! [`HopTransmitter`]: their slot moves to a spare channel. The controller sends the new blacklist
+//! to the drone as a [`crate::command::Command::SetHopBlacklist`] and switches over once it is
+//! acknowledged. Until then only the one slot is out of sync, every other slot still lines up.
+
+use embassy_time::{Duration, Instant};
+
+/// How often the controller sends an uplink frame, which is also how long each slot lasts.
+pub const UPLINK_PERIOD: Duration = Duration::from_millis(10);
+/// The lowest channel hopped to, 2402 MHz.
+pub const FIRST_CHANNEL: u8 = 2;
+/// The highest channel hopped to, 2480 MHz, which keeps the whole signal inside the ISM band.
+pub const LAST_CHANNEL: u8 = 80;
+/// The number of slots in a cycle of the sequence.
+pub const SEQUENCE_LENGTH: usize = 16;
+/// How many spare channels each slot can move to before coming back to its original channel.
+pub const MAX_BLACKLIST_LEVEL: u8 = 3;
+/// Consecutive slots are at least this many MHz apart, so one burst of interference doesn't take out
+/// several slots in a row.
+const MIN_HOP_DISTANCE: u8 = 12;
+const CHANNEL_COUNT: usize = (LAST_CHANNEL - FIRST_CHANNEL + 1) as usize;
+const SPARE_COUNT: usize = SEQUENCE_LENGTH * MAX_BLACKLIST_LEVEL as usize;
+const _: () = assert!(SEQUENCE_LENGTH + SPARE_COUNT <= CHANNEL_COUNT);
+const _: () = assert!(SEQUENCE_LENGTH * 2 <= u32::BITS as usize);
+
+/// The channel of every slot, derived from a seed and a blacklist.
+///
+/// No channel is ever used by more than one slot, whatever the blacklist, so a channel the drone
+/// hears the controller on tells it the slot.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HopSequence {
+    base: [u8; SEQUENCE_LENGTH],
+    spares: [u8; SPARE_COUNT],
+    blacklist: u32,
+}
+
+impl HopSequence {
+    pub fn new(seed: u32) -> Self {
+        let mut pool: [u8; CHANNEL_COUNT] = core::array::from_fn(|i| FIRST_CHANNEL + i as u8);
+        let mut random = XorShift::new(seed);
+        for i in (1..CHANNEL_COUNT).rev() {
+            pool.swap(i, random.below(i as u32 + 1) as usize);
+        }
+
+        // Take channels in shuffled order, skipping over the ones too close to the previous slot.
+        let mut base = [0; SEQUENCE_LENGTH];
+        for slot in 0..SEQUENCE_LENGTH {
+            let candidates = &pool[slot..];
+            let pick = match slot.checked_sub(1).map(|previous| base[previous]) {
+                Some(previous) => candidates
+                    .iter()
+                    .position(|channel| channel.abs_diff(previous) >= MIN_HOP_DISTANCE)
+                    .unwrap_or(0),
+                None => 0,
+            };
+            pool.swap(slot, slot + pick);
+            base[slot] = pool[slot];
+        }
+
+        let mut spares = [0; SPARE_COUNT];
+        spares.copy_from_slice(&pool[SEQUENCE_LENGTH..SEQUENCE_LENGTH + SPARE_COUNT]);
+
+        Self {
+            base,
+            spares,
+            blacklist: 0,
+        }
+    }
+
+    /// The channel of `slot`, which counts up forever.
+    pub fn channel(&self, slot: usize) -> u8 {
+        self.channel_at_level(slot, self.level(slot))
+    }
+
+    /// The channel `slot` moves to once `level` of its channels have been blacklisted.
+    pub fn channel_at_level(&self, slot: usize, level: u8) -> u8 {
+        let slot = slot % SEQUENCE_LENGTH;
+        match level % (MAX_BLACKLIST_LEVEL + 1) {
+            0 => self.base[slot],
+            level => self.spares[slot * MAX_BLACKLIST_LEVEL as usize + level as usize - 1],
+        }
+    }
+
+    /// The slot `channel` is used for, if any.
+    pub fn slot(&self, channel: u8) -> Option<usize> {
+        (0..SEQUENCE_LENGTH).find(|slot| self.channel(*slot) == channel)
+    }
+
+    /// How many channels of `slot` have been blacklisted, modulo [`MAX_BLACKLIST_LEVEL`] + 1.
+    pub fn level(&self, slot: usize) -> u8 {
+        (self.blacklist >> (2 * (slot % SEQUENCE_LENGTH)) & 0b11) as u8
+    }
+
+    /// The blacklist level of every slot, two bits per slot.
+    pub fn blacklist(&self) -> u32 {
+        self.blacklist
+    }
+
+    pub fn set_blacklist(&mut self, blacklist: u32) {
+        self.blacklist = blacklist;
+    }
+
+    /// The blacklist with the current channel of `slot` blacklisted too. Once all of its spares
+    /// have been blacklisted a slot goes back to its original channel, in case the interference has
+    /// moved on.
+    pub fn with_blacklisted(&self, slot: usize) -> u32 {
+        self.with_level(slot, self.level(slot) + 1)
+    }
+
+    /// The blacklist with `slot` moved to the channel of `level`.
+    pub fn with_level(&self, slot: usize, level: u8) -> u32 {
+        let slot = slot % SEQUENCE_LENGTH;
+        let level = level % (MAX_BLACKLIST_LEVEL + 1);
+        self.blacklist & !(0b11 << (2 * slot)) | (level as u32) << (2 * slot)
+    }
+}
+
+/// The controller's side of hopping. Counts slots and keeps track of how well each one delivers.
+pub struct HopTransmitter {
+    sequence: HopSequence,
+    slot: usize,
+    /// The outcome of the last transmissions in each slot, a set bit is a failure.
+    failures: [u16; SEQUENCE_LENGTH],
+    attempts: [u8; SEQUENCE_LENGTH],
+}
+
+impl HopTransmitter {
+    /// How many transmissions a slot needs before it can be judged.
+    const WINDOW: u8 = 16;
+    /// A slot failing at least this many of its last [`Self::WINDOW`] transmissions is blacklisted...
+    const BLACKLIST_FAILURES: u32 = 12;
+    /// ...unless the other slots fail at least this many on average. Then the drone is out of range
+    /// or switched off, and no channel is to blame.
+    const HEALTHY_FAILURES: u32 = 4;
+
+    pub fn new(sequence: HopSequence) -> Self {
+        Self {
+            sequence,
+            slot: 0,
+            failures: [0; SEQUENCE_LENGTH],
+            attempts: [0; SEQUENCE_LENGTH],
+        }
+    }
+
+    pub fn sequence(&self) -> &HopSequence {
+        &self.sequence
+    }
+
+    /// The channel to send the next frame on.
+    pub fn channel(&self) -> u8 {
+        self.sequence.channel(self.slot)
+    }
+
+    /// Records whether the frame sent on [`HopTransmitter::channel`] got through, and moves on to
+    /// the next slot. Returns a new blacklist when the slot keeps failing, to be sent to the drone
+    /// and applied with [`HopTransmitter::set_blacklist`] once acknowledged.
+    pub fn record(&mut self, delivered: bool) -> Option<u32> {
+        let slot = self.slot % SEQUENCE_LENGTH;
+        self.slot = self.slot.wrapping_add(1);
+        self.failures[slot] = self.failures[slot] << 1 | !delivered as u16;
+        self.attempts[slot] = self.attempts[slot].saturating_add(1);
+        if self.attempts[slot] < Self::WINDOW
+            || self.failures[slot].count_ones() < Self::BLACKLIST_FAILURES
+        {
+            return None;
+        }
+
+        let others = (0..SEQUENCE_LENGTH).filter(|other| *other != slot);
+        let (judged, failures) = others
+            .filter(|other| self.attempts[*other] >= Self::WINDOW)
+            .fold((0, 0), |(judged, failures), other| {
+                (judged + 1, failures + self.failures[other].count_ones())
+            });
+        if judged == 0 || failures > Self::HEALTHY_FAILURES * judged {
+            return None;
+        }
+
+        // Starts over, so the same slot isn't proposed again before its new channel has been tried.
+        self.attempts[slot] = 0;
+        self.failures[slot] = 0;
+        Some(self.sequence.with_blacklisted(slot))
+    }
+
+    pub fn set_blacklist(&mut self, blacklist: u32) {
+        for slot in 0..SEQUENCE_LENGTH {
+            if self.sequence.level(slot) != (blacklist >> (2 * slot) & 0b11) as u8 {
+                self.attempts[slot] = 0;
+                self.failures[slot] = 0;
+            }
+        }
+        self.sequence.set_blacklist(blacklist);
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HopState {
+    /// Following the controller from slot to slot.
+    Synced,
+    /// Parked on one channel, waiting for the controller to come by.
+    Searching,
+}
+
+impl defmt::Format for HopState {
+    fn format(&self, fmt: defmt::Formatter) {
+        match self {
+            HopState::Synced => defmt::write!(fmt, "Synced"),
+            HopState::Searching => defmt::write!(fmt, "Searching"),
+        }
+    }
+}
+
+/// The drone's side of hopping. Knows which channel to listen on and when to move on.
+pub struct HopReceiver {
+    sequence: HopSequence,
+    period: Duration,
+    slot: usize,
+    state: HopState,
+    deadline: Instant,
+    missed: usize,
+}
+
+impl HopReceiver {
+    /// How many frames in a row can be missed before the receiver assumes it lost track.
+    pub const MAX_MISSED: usize = 2 * SEQUENCE_LENGTH;
+
+    /// Starts out searching, `period` is the time between uplink frames.
+    pub fn new(sequence: HopSequence, period: Duration, now: Instant) -> Self {
+        let mut receiver = Self {
+            sequence,
+            period,
+            slot: 0,
+            state: HopState::Searching,
+            deadline: now,
+            missed: 0,
+        };
+        receiver.search(now);
+        receiver
+    }
+
+    /// The channel to listen on.
+    pub fn channel(&self) -> u8 {
+        self.sequence.channel(self.slot)
+    }
+
+    pub fn state(&self) -> HopState {
+        self.state
+    }
+
+    /// When [`HopReceiver::update`] has to be called next, unless a frame arrives before.
+    pub fn deadline(&self) -> Instant {
+        self.deadline
+    }
+
+    /// Records that a frame from the controller arrived on [`HopReceiver::channel`], and moves on
+    /// to the next slot.
+    pub fn received(&mut self, now: Instant) {
+        self.state = HopState::Synced;
+        self.missed = 0;
+        self.slot = (self.slot + 1) % SEQUENCE_LENGTH;
+        // Halfway between the next frame and the one after it, so the receiver moves on at the
+        // right time even if the controller is a bit early or late.
+        self.deadline = now + self.period + self.period / 2;
+    }
+
+    /// Moves on to the next channel if a frame is overdue.
+    pub fn update(&mut self, now: Instant) {
+        while now >= self.deadline {
+            match self.state {
+                HopState::Synced if self.missed + 1 >= Self::MAX_MISSED => {
+                    self.state = HopState::Searching;
+                    self.slot = (self.slot + 1) % SEQUENCE_LENGTH;
+                    self.search(now);
+                }
+                HopState::Synced => {
+                    self.missed += 1;
+                    self.slot = (self.slot + 1) % SEQUENCE_LENGTH;
+                    self.deadline += self.period;
+                }
+                HopState::Searching => {
+                    // The controller should have come by. Its slot might be blacklisted, or the
+                    // channel jammed here, so try another one.
+                    self.slot = (self.slot + 1) % SEQUENCE_LENGTH;
+                    self.search(now);
+                }
+            }
+        }
+    }
+
+    pub fn set_blacklist(&mut self, blacklist: u32) {
+        self.sequence.set_blacklist(blacklist);
+    }
+
+    pub fn blacklist(&self) -> u32 {
+        self.sequence.blacklist()
+    }
+
+    fn search(&mut self, now: Instant) {
+        self.missed = 0;
+        self.deadline = now + self.period * (SEQUENCE_LENGTH as u32 + 1);
+    }
+}
+
+/// Marsaglia's xorshift32. Only needs to be the same on both sides, not unpredictable: the
+/// sequence is no secret to anyone listening to a few cycles of it.
+struct XorShift(u32);
+
+impl XorShift {
+    fn new(seed: u32) -> Self {
+        // Zero is the one state xorshift never leaves.
+        Self(if seed == 0 { 0x9E37_79B9 } else { seed })
+    }
+
+    fn next(&mut self) -> u32 {
+        let mut x = self.0;
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.0 = x;
+        x
+    }
+
+    /// A number in `0..bound`, with a bias too small to matter here.
+    fn below(&mut self, bound: u32) -> u32 {
+        ((self.next() as u64 * bound as u64) >> 32) as u32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SEED: u32 = 0x1234_5678;
+    const PERIOD: Duration = Duration::from_millis(10);
+
+    fn at(ms: u64) -> Instant {
+        Instant::from_millis(ms)
+    }
+
+    /// A controller and a drone hopping in lockstep, one uplink frame per period.
+    struct Simulation {
+        transmitter: HopTransmitter,
+        receiver: HopReceiver,
+        now: Instant,
+        random: XorShift,
+        /// The last blacklist the transmitter proposed, delivered like a command on the next frame.
+        pending_blacklist: Option<u32>,
+    }
+
+    impl Simulation {
+        fn new() -> Self {
+            Self {
+                transmitter: HopTransmitter::new(HopSequence::new(SEED)),
+                receiver: HopReceiver::new(HopSequence::new(SEED), PERIOD, at(0)),
+                now: at(0),
+                random: XorShift::new(99),
+                pending_blacklist: None,
+            }
+        }
+
+        /// Sends one frame, lost with `loss_percent` probability or whenever `lost` says so.
+        /// Returns whether the drone got it.
+        fn step(&mut self, loss_percent: u32, lost: impl Fn(u8) -> bool) -> bool {
+            self.now += PERIOD;
+            self.receiver.update(self.now);
+
+            let channel = self.transmitter.channel();
+            let delivered = self.receiver.channel() == channel
+                && !lost(channel)
+                && self.random.below(100) >= loss_percent;
+            if delivered {
+                // A little after the controller's tick, the time it takes to send the frame.
+                self.receiver
+                    .received(self.now + Duration::from_micros(600));
+                if let Some(blacklist) = self.pending_blacklist.take() {
+                    self.receiver.set_blacklist(blacklist);
+                    self.transmitter.set_blacklist(blacklist);
+                }
+            }
+            if let Some(blacklist) = self.transmitter.record(delivered) {
+                self.pending_blacklist = Some(blacklist);
+            }
+
+            delivered
+        }
+
+        fn run(&mut self, frames: usize, loss_percent: u32, lost: impl Fn(u8) -> bool) -> usize {
+            (0..frames)
+                .filter(|_| self.step(loss_percent, &lost))
+                .count()
+        }
+    }
+
+    #[test]
+    fn sequence_is_deterministic_and_spread_out() {
+        let sequence = HopSequence::new(SEED);
+        assert_eq!(sequence, HopSequence::new(SEED));
+        assert_ne!(sequence, HopSequence::new(SEED + 1));
+
+        for slot in 0..SEQUENCE_LENGTH {
+            let channel = sequence.channel(slot);
+            assert!((FIRST_CHANNEL..=LAST_CHANNEL).contains(&channel));
+            assert_eq!(sequence.slot(channel), Some(slot));
+            if slot > 0 {
+                assert!(channel.abs_diff(sequence.channel(slot - 1)) >= MIN_HOP_DISTANCE);
+            }
+        }
+        assert_eq!(sequence.channel(SEQUENCE_LENGTH + 3), sequence.channel(3));
+    }
+
+    #[test]
+    fn blacklisting_moves_only_one_slot() {
+        let mut sequence = HopSequence::new(SEED);
+        let original = sequence.clone();
+        let mut seen = [false; 128];
+        for _ in 0..=MAX_BLACKLIST_LEVEL {
+            for slot in 0..SEQUENCE_LENGTH {
+                if slot != 5 {
+                    assert_eq!(sequence.channel(slot), original.channel(slot));
+                }
+            }
+            // No channel is shared with another slot or level.
+            let channel = sequence.channel(5);
+            assert!(!seen[channel as usize]);
+            seen[channel as usize] = true;
+            assert_eq!(sequence.slot(channel), Some(5));
+
+            sequence.set_blacklist(sequence.with_blacklisted(5));
+        }
+
+        // Back to the original channel.
+        assert_eq!(sequence.channel(5), original.channel(5));
+    }
+
+    #[test]
+    fn receiver_syncs_from_searching() {
+        let mut simulation = Simulation::new();
+        assert_eq!(simulation.receiver.state(), HopState::Searching);
+
+        // Parked on one channel, the controller comes by within a cycle.
+        simulation.run(SEQUENCE_LENGTH, 0, |_| false);
+        assert_eq!(simulation.receiver.state(), HopState::Synced);
+        assert_eq!(simulation.run(100, 0, |_| false), 100);
+    }
+
+    #[test]
+    fn receiver_stays_synced_through_loss() {
+        let mut simulation = Simulation::new();
+        simulation.run(SEQUENCE_LENGTH, 0, |_| false);
+
+        let delivered = simulation.run(1000, 30, |_| false);
+        assert_eq!(simulation.receiver.state(), HopState::Synced);
+        assert!(delivered > 600, "delivered {delivered}");
+    }
+
+    #[test]
+    fn receiver_resyncs_after_outage() {
+        let mut simulation = Simulation::new();
+        simulation.run(SEQUENCE_LENGTH, 0, |_| false);
+
+        simulation.run(200, 100, |_| false);
+        assert_eq!(simulation.receiver.state(), HopState::Searching);
+
+        // Worst case, the controller passes by just before the receiver moves on to another channel.
+        simulation.run(3 * SEQUENCE_LENGTH, 0, |_| false);
+        assert_eq!(simulation.receiver.state(), HopState::Synced);
+        assert_eq!(simulation.run(100, 0, |_| false), 100);
+    }
+
+    #[test]
+    fn receiver_tolerates_timing_jitter() {
+        let sequence = HopSequence::new(SEED);
+        let mut receiver = HopReceiver::new(sequence.clone(), PERIOD, at(0));
+        while receiver.channel() != sequence.channel(4) {
+            receiver.update(receiver.deadline());
+        }
+        receiver.received(at(1000));
+
+        // The next frame is a few milliseconds late, the one after that is missed.
+        receiver.update(at(1014));
+        assert_eq!(receiver.channel(), sequence.channel(5));
+        receiver.update(at(1016));
+        assert_eq!(receiver.channel(), sequence.channel(6));
+        receiver.update(at(1024));
+        assert_eq!(receiver.channel(), sequence.channel(6));
+    }
+
+    #[test]
+    fn jammed_channel_is_blacklisted() {
+        let mut simulation = Simulation::new();
+        let jammed = simulation.transmitter.sequence().channel(7);
+        simulation.run(SEQUENCE_LENGTH, 0, |_| false);
+
+        simulation.run(20 * SEQUENCE_LENGTH, 5, |channel| channel == jammed);
+        assert_ne!(simulation.transmitter.sequence().channel(7), jammed);
+        assert_eq!(
+            simulation.receiver.blacklist(),
+            simulation.transmitter.sequence().blacklist()
+        );
+
+        let frames = 10 * SEQUENCE_LENGTH;
+        assert_eq!(
+            simulation.run(frames, 0, |channel| channel == jammed),
+            frames
+        );
+    }
+
+    #[test]
+    fn nothing_is_blacklisted_without_a_drone() {
+        let mut transmitter = HopTransmitter::new(HopSequence::new(SEED));
+        for _ in 0..100 * SEQUENCE_LENGTH {
+            assert_eq!(transmitter.record(false), None);
+        }
+    }
+}
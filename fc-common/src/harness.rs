@@ -0,0 +1,72 @@
+//! Runs task logic on the host, on the mock clock.
+//!
+//! A task is just a future, polled after every simulated millisecond by [`run_for`]. Tests inject
+//! signal values with regular emitters and read what the task emitted with regular signals.
+//! Peripherals are mocked behind the task's hardware traits, answering with [`Scripted`] responses.
+
+extern crate std;
+
+use core::future::Future;
+use core::pin::Pin;
+use core::task::Poll;
+use embassy_futures::poll_once;
+use embassy_time::{Duration, MockDriver};
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::rc::Rc;
+use std::sync::{Mutex, MutexGuard};
+
+static LOCK: Mutex<()> = Mutex::new(());
+
+/// The mock time driver is shared by all tests, so the ones that advance it take turns.
+pub fn lock() -> MutexGuard<'static, ()> {
+    // A failed test doesn't leave the clock in a state that matters to the others.
+    LOCK.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+pub fn advance(ms: u64) {
+    MockDriver::get().advance(Duration::from_millis(ms));
+}
+
+/// Polls `task`, then advances the clock by `ms` one millisecond at a time, polling again after
+/// every step. Returns the task's output as soon as it finishes.
+pub fn run_for<F: Future>(mut task: Pin<&mut F>, ms: u64) -> Option<F::Output> {
+    for step in 0..=ms {
+        if step > 0 {
+            advance(1);
+        }
+        if let Poll::Ready(output) = poll_once(task.as_mut()) {
+            return Some(output);
+        }
+    }
+    None
+}
+
+/// Responses of a mocked peripheral, or what it was asked to do. Clones share the script, so the
+/// test keeps one to add to or read from while the task owns the mock.
+pub struct Scripted<T>(Rc<RefCell<VecDeque<T>>>);
+
+impl<T> Scripted<T> {
+    pub fn new() -> Self {
+        Self(Rc::new(RefCell::new(VecDeque::new())))
+    }
+
+    pub fn push(&self, value: T) {
+        self.0.borrow_mut().push_back(value);
+    }
+
+    /// The oldest entry, if there is any left.
+    pub fn next(&self) -> Option<T> {
+        self.0.borrow_mut().pop_front()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.borrow().is_empty()
+    }
+}
+
+impl<T> Clone for Scripted<T> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
@@ -0,0 +1,275 @@
+//! The recent values of a signal, for graphing them or looking back after something went wrong.
+//!
+//! A signal keeps a history when it's defined with one:
+//!
+//! ```rust,ignore
+//! define_signal!(Altitude, Length, 1, history = 64);
+//!
+//! let highest = altitude_history().lock(|history| history.max().copied());
+//! ```
+//!
+//! Every value emitted on it then also goes into a [`History`] of the last 64, and the
+//! [registry](crate::registry) can dump it with [`SignalInfo::history`](crate::SignalInfo::history)
+//! without knowing its type.
+
+use core::cell::RefCell;
+use core::fmt::Debug;
+
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_time::Instant;
+
+use crate::Timestamped;
+use crate::combinators::Window;
+
+/// The last `N` samples, or fewer until `N` were pushed.
+#[derive(Clone, Debug, PartialEq)]
+pub struct History<T, const N: usize> {
+    samples: Window<Timestamped<T>, N>,
+}
+
+impl<T, const N: usize> Default for History<T, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T, const N: usize> History<T, N> {
+    pub const fn new() -> Self {
+        Self {
+            samples: Window::new(),
+        }
+    }
+
+    /// Adds `sample`, dropping the oldest one if the history is full.
+    pub fn push(&mut self, sample: Timestamped<T>) {
+        self.samples.push(sample);
+    }
+
+    pub fn clear(&mut self) {
+        *self = Self::new();
+    }
+
+    pub fn len(&self) -> usize {
+        self.samples.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.samples.is_empty()
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.samples.capacity()
+    }
+
+    /// From the oldest sample to the newest.
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = &Timestamped<T>> {
+        self.samples.iter()
+    }
+
+    /// The values alone, from the oldest to the newest.
+    pub fn values(&self) -> impl DoubleEndedIterator<Item = &T> {
+        self.iter().map(|sample| &sample.value)
+    }
+
+    /// The samples emitted at or after `since`, from the oldest to the newest.
+    pub fn since(&self, since: Instant) -> impl Iterator<Item = &Timestamped<T>> {
+        self.iter()
+            .skip_while(move |sample| sample.emitted_at < since)
+    }
+
+    pub fn newest(&self) -> Option<&Timestamped<T>> {
+        self.samples.newest()
+    }
+
+    /// The smallest value. Values that don't compare, like NaN, are skipped.
+    pub fn min(&self) -> Option<&T>
+    where
+        T: PartialOrd,
+    {
+        self.comparable()
+            .reduce(|min, value| if value < min { value } else { min })
+    }
+
+    /// The largest value. Values that don't compare, like NaN, are skipped.
+    pub fn max(&self) -> Option<&T>
+    where
+        T: PartialOrd,
+    {
+        self.comparable()
+            .reduce(|max, value| if value > max { value } else { max })
+    }
+
+    pub fn mean(&self) -> Option<f32>
+    where
+        T: Copy + Into<f32>,
+    {
+        self.mean_by(|value| (*value).into())
+    }
+
+    /// The mean of what `f` makes of every value, for values that aren't numbers themselves.
+    pub fn mean_by(&self, mut f: impl FnMut(&T) -> f32) -> Option<f32> {
+        if self.is_empty() {
+            return None;
+        }
+        Some(self.values().map(&mut f).sum::<f32>() / self.len() as f32)
+    }
+
+    fn comparable(&self) -> impl Iterator<Item = &T>
+    where
+        T: PartialOrd,
+    {
+        self.values()
+            .filter(|value| value.partial_cmp(value).is_some())
+    }
+}
+
+/// A [`History`] shared between a signal's emitter and whoever looks at it.
+pub struct SignalHistory<T, const N: usize> {
+    history: Mutex<CriticalSectionRawMutex, RefCell<History<T, N>>>,
+}
+
+impl<T, const N: usize> Default for SignalHistory<T, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T, const N: usize> SignalHistory<T, N> {
+    pub const fn new() -> Self {
+        Self {
+            history: Mutex::new(RefCell::new(History::new())),
+        }
+    }
+
+    /// Calls `f` with the history. Nothing can be emitted on the signal meanwhile, so keep it short.
+    pub fn lock<R>(&self, f: impl FnOnce(&History<T, N>) -> R) -> R {
+        self.history.lock(|history| f(&history.borrow()))
+    }
+
+    pub fn clear(&self) {
+        self.history.lock(|history| history.borrow_mut().clear());
+    }
+
+    /// Adds a value emitted on the signal.
+    pub fn record(&self, sample: &Timestamped<T>)
+    where
+        T: Clone,
+    {
+        self.history
+            .lock(|history| history.borrow_mut().push(sample.clone()));
+    }
+
+    /// Calls `f` with every sample, from the oldest to the newest.
+    pub fn dump(&self, f: &mut dyn FnMut(&dyn Debug, Instant))
+    where
+        T: Debug,
+    {
+        self.lock(|history| {
+            for sample in history.iter() {
+                f(&sample.value, sample.emitted_at);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::harness::{self, advance};
+    use crate::registry;
+    use crate::{Signal, SignalBase, SignalEmitter, define_signal};
+    use embassy_time::Duration;
+
+    extern crate std;
+    use std::format;
+    use std::string::String;
+    use std::vec::Vec;
+
+    fn sample(value: f32, at_ms: u64) -> Timestamped<f32> {
+        Timestamped {
+            value,
+            emitted_at: Instant::from_millis(at_ms),
+        }
+    }
+
+    #[test]
+    fn ring_and_queries() {
+        let mut history: History<f32, 3> = History::new();
+        assert!(history.is_empty());
+        assert_eq!(history.min(), None);
+        assert_eq!(history.mean(), None);
+
+        history.push(sample(f32::NAN, 10));
+        history.push(sample(2.0, 20));
+        history.push(sample(1.0, 30));
+        assert_eq!(history.min(), Some(&1.0));
+        assert_eq!(history.max(), Some(&2.0));
+
+        // The oldest sample makes room for the newest.
+        history.push(sample(4.0, 40));
+        assert_eq!(history.len(), 3);
+        assert_eq!(history.capacity(), 3);
+        history.push(sample(6.0, 50));
+        assert!(history.values().eq([1.0, 4.0, 6.0].iter()));
+        assert_eq!(history.newest(), Some(&sample(6.0, 50)));
+        assert_eq!(history.mean(), Some(11.0 / 3.0));
+        assert_eq!(history.mean_by(|value| value * 3.0), Some(11.0));
+        assert!(
+            history
+                .since(Instant::from_millis(35))
+                .map(|sample| sample.value)
+                .eq([4.0, 6.0])
+        );
+
+        history.clear();
+        assert!(history.is_empty());
+        assert_eq!(history.newest(), None);
+    }
+
+    define_signal!(Recorded, u8, 1, history = 3);
+    #[test]
+    fn signals_keep_their_history() {
+        let _time = harness::lock();
+        let mut emitter = new_recorded_signal_emitter();
+        let mut signal = recorded_signal().unwrap();
+        for value in 1..=4 {
+            emitter.emit(value);
+            advance(10);
+        }
+        // Repeats that aren't emitted aren't recorded either.
+        emitter.emit_if_changed(4);
+        assert_eq!(signal.get(), 4);
+
+        let start = Instant::now() - Duration::from_millis(40);
+        recorded_history().lock(|history| {
+            assert!(history.values().eq([2, 3, 4].iter()));
+            assert_eq!(
+                history.newest().unwrap().emitted_at,
+                start + Duration::from_millis(30)
+            );
+        });
+
+        let mut dump = Vec::new();
+        registry::find("Recorded")
+            .unwrap()
+            .history(|value, at| dump.push(format!("{value:?}@{}", (at - start).as_millis())));
+        assert_eq!(dump, ["2@10", "3@20", "4@30"]);
+        assert!(recorded_signal_info().has_history());
+
+        recorded_history().clear();
+        assert_eq!(recorded_history().lock(|history| history.len()), 0);
+    }
+
+    define_signal!(Forgetful, u8, 1);
+    #[test]
+    fn history_is_opt_in() {
+        let mut emitter = new_forgetful_signal_emitter();
+        emitter.emit(1);
+        let info = forgetful_signal_info();
+        assert!(!info.has_history());
+        let mut dump = String::new();
+        info.history(|value, _| dump = format!("{value:?}"));
+        assert_eq!(dump, "");
+    }
+}
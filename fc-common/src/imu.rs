@@ -0,0 +1,695 @@
+//! The ICM-20948 IMU, read over SPI, and the task that turns its samples into gyro and
+//! accelerometer signals.
+//!
+//! The chip writes a sample to its FIFO at every tick of its output data rate and pulses its
+//! interrupt pin. The task waits for the pulse and drains the FIFO, so a pulse missed while it was
+//! busy only delays the samples behind it. The magnetometer sits behind the chip's I2C master and
+//! isn't used.
+
+use core::convert::Infallible;
+
+use embassy_time::Timer;
+use embedded_hal_async::digital::Wait;
+use embedded_hal_async::spi::{Operation, SpiDevice};
+use uom::si::acceleration::standard_gravity;
+use uom::si::angular_velocity::degree_per_second;
+use uom::si::f32::{Acceleration, AngularVelocity};
+
+use crate::param::{Param, ParamStore};
+use crate::{EmitterBase, SignalBase};
+
+/// The output data rate with a divider of 0.
+pub const BASE_RATE_HZ: f32 = 1125.0;
+/// Accelerometer then gyro, three big-endian axes each.
+const SAMPLE_SIZE: usize = 12;
+/// How many samples are read from the FIFO before they're emitted.
+const BATCH_SIZE: usize = 8;
+/// How many gyro samples are averaged into the bias when calibrating.
+const CALIBRATION_SAMPLES: u16 = 1024;
+const WHO_AM_I_VALUE: u8 = 0xEA;
+const RESET_TIME_MS: u64 = 100;
+
+/// A register and the bank it's in.
+#[derive(Clone, Copy)]
+struct Register {
+    bank: u8,
+    address: u8,
+}
+
+const fn bank0(address: u8) -> Register {
+    Register { bank: 0, address }
+}
+
+const fn bank2(address: u8) -> Register {
+    Register { bank: 2, address }
+}
+
+const WHO_AM_I: Register = bank0(0x00);
+const USER_CTRL: Register = bank0(0x03);
+const PWR_MGMT_1: Register = bank0(0x06);
+const PWR_MGMT_2: Register = bank0(0x07);
+const INT_PIN_CFG: Register = bank0(0x0F);
+const INT_ENABLE_1: Register = bank0(0x11);
+const INT_STATUS_2: Register = bank0(0x1B);
+const FIFO_EN_2: Register = bank0(0x67);
+const FIFO_RST: Register = bank0(0x68);
+const FIFO_COUNTH: Register = bank0(0x70);
+const FIFO_R_W: Register = bank0(0x72);
+const GYRO_SMPLRT_DIV: Register = bank2(0x00);
+const GYRO_CONFIG_1: Register = bank2(0x01);
+const ODR_ALIGN_EN: Register = bank2(0x09);
+const ACCEL_SMPLRT_DIV_1: Register = bank2(0x10);
+const ACCEL_SMPLRT_DIV_2: Register = bank2(0x11);
+const ACCEL_CONFIG: Register = bank2(0x14);
+/// Selects the bank of every other register, and is there in all of them.
+const REG_BANK_SEL: u8 = 0x7F;
+const READ: u8 = 0x80;
+
+const DEVICE_RESET: u8 = 0x80;
+const CLKSEL_AUTO: u8 = 0x01;
+const FIFO_EN: u8 = 0x40;
+const I2C_IF_DIS: u8 = 0x10;
+const RAW_DATA_0_RDY_EN: u8 = 0x01;
+const ACCEL_GYRO_FIFO_EN: u8 = 0x1E;
+const FIFO_RESET_ALL: u8 = 0x1F;
+const FCHOICE: u8 = 0x01;
+/// Low-pass filters at about 197 Hz for the gyro and 111 Hz for the accelerometer.
+const GYRO_DLPFCFG: u8 = 0;
+const ACCEL_DLPFCFG: u8 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum GyroRange {
+    Dps250 = 0,
+    Dps500 = 1,
+    Dps1000 = 2,
+    Dps2000 = 3,
+}
+
+impl GyroRange {
+    /// Maps [`Param::ImuGyroRange`] to the range.
+    pub fn from_param(value: u8) -> Self {
+        match value {
+            0 => GyroRange::Dps250,
+            1 => GyroRange::Dps500,
+            2 => GyroRange::Dps1000,
+            _ => GyroRange::Dps2000,
+        }
+    }
+
+    fn lsb_per_dps(self) -> f32 {
+        match self {
+            GyroRange::Dps250 => 131.0,
+            GyroRange::Dps500 => 65.5,
+            GyroRange::Dps1000 => 32.8,
+            GyroRange::Dps2000 => 16.4,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum AccelRange {
+    G2 = 0,
+    G4 = 1,
+    G8 = 2,
+    G16 = 3,
+}
+
+impl AccelRange {
+    /// Maps [`Param::ImuAccelRange`] to the range.
+    pub fn from_param(value: u8) -> Self {
+        match value {
+            0 => AccelRange::G2,
+            1 => AccelRange::G4,
+            2 => AccelRange::G8,
+            _ => AccelRange::G16,
+        }
+    }
+
+    fn lsb_per_g(self) -> f32 {
+        16_384.0 / (1 << self as u8) as f32
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ImuConfig {
+    pub gyro_range: GyroRange,
+    pub accel_range: AccelRange,
+    /// Divides [`BASE_RATE_HZ`] by one more than this.
+    pub rate_divider: u8,
+}
+
+impl ImuConfig {
+    pub fn from_parameters(parameters: &ParamStore) -> Self {
+        Self {
+            gyro_range: GyroRange::from_param(parameters.get_u8(Param::ImuGyroRange)),
+            accel_range: AccelRange::from_param(parameters.get_u8(Param::ImuAccelRange)),
+            rate_divider: parameters.get_u8(Param::ImuRateDivider),
+        }
+    }
+
+    pub fn rate_hz(&self) -> f32 {
+        BASE_RATE_HZ / (1.0 + self.rate_divider as f32)
+    }
+
+    fn scale(&self, raw: &[u8; SAMPLE_SIZE]) -> ImuSample {
+        let axis = |i: usize| i16::from_be_bytes([raw[2 * i], raw[2 * i + 1]]) as f32;
+        let accel =
+            |i| Acceleration::new::<standard_gravity>(axis(i) / self.accel_range.lsb_per_g());
+        let gyro =
+            |i| AngularVelocity::new::<degree_per_second>(axis(i) / self.gyro_range.lsb_per_dps());
+        ImuSample {
+            accel: Vector3::new(accel(0), accel(1), accel(2)),
+            gyro: Vector3::new(gyro(3), gyro(4), gyro(5)),
+        }
+    }
+}
+
+/// A quantity along the sensor's x, y and z axes.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Vector3<Q> {
+    pub x: Q,
+    pub y: Q,
+    pub z: Q,
+}
+
+impl<Q> Vector3<Q> {
+    pub const fn new(x: Q, y: Q, z: Q) -> Self {
+        Self { x, y, z }
+    }
+
+    /// Applies `f` to every axis.
+    pub fn map<R>(self, mut f: impl FnMut(Q) -> R) -> Vector3<R> {
+        Vector3::new(f(self.x), f(self.y), f(self.z))
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ImuSample {
+    pub accel: Vector3<Acceleration>,
+    pub gyro: Vector3<AngularVelocity>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ImuError<E> {
+    Spi(E),
+    /// Something other than an ICM-20948 answered, with this id.
+    UnknownChip(u8),
+}
+
+pub struct Icm20948<SPI> {
+    spi: SPI,
+    /// The selected register bank, as far as we know.
+    bank: Option<u8>,
+    config: ImuConfig,
+}
+
+impl<SPI: SpiDevice> Icm20948<SPI> {
+    pub fn new(spi: SPI) -> Self {
+        Self {
+            spi,
+            bank: None,
+            config: ImuConfig {
+                gyro_range: GyroRange::Dps2000,
+                accel_range: AccelRange::G16,
+                rate_divider: 0,
+            },
+        }
+    }
+
+    /// Resets the chip and starts sampling into the FIFO, pulsing the interrupt pin for every
+    /// sample.
+    pub async fn init(&mut self, config: ImuConfig) -> Result<(), ImuError<SPI::Error>> {
+        self.write(PWR_MGMT_1, DEVICE_RESET).await?;
+        // The reset selects bank 0 again.
+        self.bank = Some(0);
+        Timer::after_millis(RESET_TIME_MS).await;
+        let id = self.read(WHO_AM_I).await?;
+        if id != WHO_AM_I_VALUE {
+            return Err(ImuError::UnknownChip(id));
+        }
+
+        self.write(PWR_MGMT_1, CLKSEL_AUTO).await?;
+        self.write(USER_CTRL, I2C_IF_DIS).await?;
+        // Every axis of both sensors on.
+        self.write(PWR_MGMT_2, 0).await?;
+
+        let gyro_config = GYRO_DLPFCFG << 3 | (config.gyro_range as u8) << 1 | FCHOICE;
+        let accel_config = ACCEL_DLPFCFG << 3 | (config.accel_range as u8) << 1 | FCHOICE;
+        self.write(GYRO_SMPLRT_DIV, config.rate_divider).await?;
+        self.write(GYRO_CONFIG_1, gyro_config).await?;
+        self.write(ACCEL_SMPLRT_DIV_1, 0).await?;
+        self.write(ACCEL_SMPLRT_DIV_2, config.rate_divider).await?;
+        self.write(ACCEL_CONFIG, accel_config).await?;
+        self.write(ODR_ALIGN_EN, 1).await?;
+
+        // Active high, push-pull, 50 µs pulses.
+        self.write(INT_PIN_CFG, 0).await?;
+        self.write(INT_ENABLE_1, RAW_DATA_0_RDY_EN).await?;
+        self.write(FIFO_EN_2, ACCEL_GYRO_FIFO_EN).await?;
+        self.reset_fifo().await?;
+        self.write(USER_CTRL, I2C_IF_DIS | FIFO_EN).await?;
+
+        self.config = config;
+        Ok(())
+    }
+
+    /// Reads the samples waiting in the FIFO into `samples`, oldest first, and returns how many it
+    /// read. Those that don't fit are left for the next call. A FIFO that overflowed no longer
+    /// starts at a sample boundary, so it's emptied instead.
+    pub async fn read_fifo(
+        &mut self,
+        samples: &mut [ImuSample],
+    ) -> Result<usize, ImuError<SPI::Error>> {
+        // Reading the status clears it.
+        if self.read(INT_STATUS_2).await? != 0 {
+            self.reset_fifo().await?;
+            return Ok(0);
+        }
+
+        let mut count = [0; 2];
+        self.read_into(FIFO_COUNTH, &mut count).await?;
+        let available = (u16::from_be_bytes(count) & 0x1FFF) as usize / SAMPLE_SIZE;
+        let read = available.min(samples.len());
+        for sample in &mut samples[..read] {
+            let mut raw = [0; SAMPLE_SIZE];
+            self.read_into(FIFO_R_W, &mut raw).await?;
+            *sample = self.config.scale(&raw);
+        }
+        Ok(read)
+    }
+
+    async fn reset_fifo(&mut self) -> Result<(), ImuError<SPI::Error>> {
+        self.write(FIFO_RST, FIFO_RESET_ALL).await?;
+        self.write(FIFO_RST, 0).await
+    }
+
+    async fn read(&mut self, register: Register) -> Result<u8, ImuError<SPI::Error>> {
+        let mut value = [0];
+        self.read_into(register, &mut value).await?;
+        Ok(value[0])
+    }
+
+    /// Reads consecutive registers starting at `register`, or `buf.len()` bytes of the FIFO.
+    async fn read_into(
+        &mut self,
+        register: Register,
+        buf: &mut [u8],
+    ) -> Result<(), ImuError<SPI::Error>> {
+        self.select_bank(register.bank).await?;
+        self.spi
+            .transaction(&mut [
+                Operation::Write(&[register.address | READ]),
+                Operation::Read(buf),
+            ])
+            .await
+            .map_err(ImuError::Spi)
+    }
+
+    async fn write(&mut self, register: Register, value: u8) -> Result<(), ImuError<SPI::Error>> {
+        self.select_bank(register.bank).await?;
+        self.spi
+            .write(&[register.address, value])
+            .await
+            .map_err(ImuError::Spi)
+    }
+
+    async fn select_bank(&mut self, bank: u8) -> Result<(), ImuError<SPI::Error>> {
+        if self.bank != Some(bank) {
+            self.spi
+                .write(&[REG_BANK_SEL, bank << 4])
+                .await
+                .map_err(ImuError::Spi)?;
+            self.bank = Some(bank);
+        }
+        Ok(())
+    }
+}
+
+/// Averages the gyro while the drone sits still, to find what it reads at rest.
+struct GyroCalibration {
+    sum: Vector3<AngularVelocity>,
+    count: u16,
+}
+
+impl GyroCalibration {
+    fn new() -> Self {
+        Self {
+            sum: Vector3::default(),
+            count: 0,
+        }
+    }
+
+    /// Adds a sample, and returns the bias once there are enough.
+    fn add(&mut self, gyro: &Vector3<AngularVelocity>) -> Option<Vector3<AngularVelocity>> {
+        self.sum = Vector3::new(
+            self.sum.x + gyro.x,
+            self.sum.y + gyro.y,
+            self.sum.z + gyro.z,
+        );
+        self.count += 1;
+        (self.count == CALIBRATION_SAMPLES).then(|| self.sum.map(|sum| sum / self.count as f32))
+    }
+}
+
+/// Configures the IMU from the parameters, then emits every sample it takes. Only returns if the
+/// IMU fails.
+///
+/// Every change of `gyro_calibration_signal` is a request to calibrate the gyro: the samples that
+/// follow are averaged, and from then on the average is taken off every gyro reading. The drone
+/// must sit still until it's done, and the readings go out uncorrected by the new bias meanwhile.
+pub async fn run<SPI: SpiDevice>(
+    mut imu: Icm20948<SPI>,
+    mut data_ready: impl Wait<Error = Infallible>,
+    mut gyro_emitter: impl EmitterBase<Vector3<AngularVelocity>>,
+    mut accel_emitter: impl EmitterBase<Vector3<Acceleration>>,
+    mut gyro_calibration_signal: impl SignalBase<u8>,
+    mut parameters_signal: impl SignalBase<ParamStore>,
+) -> Result<Infallible, ImuError<SPI::Error>> {
+    imu.init(ImuConfig::from_parameters(&parameters_signal.get()))
+        .await?;
+
+    let mut samples = [ImuSample::default(); BATCH_SIZE];
+    let mut calibration_request = gyro_calibration_signal.get();
+    let mut calibration = None;
+    let mut bias = Vector3::<AngularVelocity>::default();
+    loop {
+        let Ok(()) = data_ready.wait_for_rising_edge().await;
+        if gyro_calibration_signal.get() != calibration_request {
+            calibration_request = gyro_calibration_signal.get();
+            calibration = Some(GyroCalibration::new());
+        }
+        loop {
+            let read = imu.read_fifo(&mut samples).await?;
+            for sample in &samples[..read] {
+                if let Some(new_bias) = calibration.as_mut().and_then(|c| c.add(&sample.gyro)) {
+                    bias = new_bias;
+                    calibration = None;
+                }
+                gyro_emitter.emit(Vector3::new(
+                    sample.gyro.x - bias.x,
+                    sample.gyro.y - bias.y,
+                    sample.gyro.z - bias.z,
+                ));
+                accel_emitter.emit(sample.accel);
+            }
+            if read < samples.len() {
+                break;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::harness::{self, run_for};
+    use crate::param::ParamValue;
+    use crate::{Signal, SignalEmitter, define_signal};
+    use core::pin::pin;
+    use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+    use embedded_hal::digital::ErrorType as PinErrorType;
+    use embedded_hal_async::spi::ErrorType;
+
+    extern crate std;
+    use std::cell::RefCell;
+    use std::collections::VecDeque;
+    use std::rc::Rc;
+
+    const SAMPLE_PERIOD_MS: u64 = 2;
+
+    /// The chip's registers, and the FIFO the test fills.
+    struct Chip {
+        registers: [[u8; 128]; 4],
+        bank: usize,
+        fifo: VecDeque<u8>,
+        overflowed: bool,
+        id: u8,
+        resets: usize,
+    }
+
+    impl Chip {
+        fn new(id: u8) -> Rc<RefCell<Chip>> {
+            Rc::new(RefCell::new(Chip {
+                registers: [[0; 128]; 4],
+                bank: 0,
+                fifo: VecDeque::new(),
+                overflowed: false,
+                id,
+                resets: 0,
+            }))
+        }
+
+        fn register(&self, register: Register) -> u8 {
+            self.registers[register.bank as usize][register.address as usize]
+        }
+
+        fn push_sample(&mut self, accel: [i16; 3], gyro: [i16; 3]) {
+            for axis in accel.iter().chain(&gyro) {
+                self.fifo.extend(axis.to_be_bytes());
+            }
+        }
+
+        fn read(&mut self, address: u8) -> u8 {
+            match (self.bank, address) {
+                (0, 0x00) => self.id,
+                (0, 0x1B) => core::mem::take(&mut self.overflowed) as u8,
+                (0, 0x70) => (self.fifo.len() >> 8) as u8,
+                (0, 0x71) => self.fifo.len() as u8,
+                (0, 0x72) => self.fifo.pop_front().unwrap(),
+                (bank, address) => self.registers[bank][address as usize],
+            }
+        }
+
+        fn write(&mut self, address: u8, value: u8) {
+            match (self.bank, address, value) {
+                (_, REG_BANK_SEL, _) => self.bank = (value >> 4) as usize,
+                (0, 0x06, DEVICE_RESET) => {
+                    self.registers = [[0; 128]; 4];
+                    self.fifo.clear();
+                    self.resets += 1;
+                }
+                (0, 0x68, FIFO_RESET_ALL) => self.fifo.clear(),
+                (bank, address, value) => self.registers[bank][address as usize] = value,
+            }
+        }
+    }
+
+    struct MockSpi(Rc<RefCell<Chip>>);
+
+    impl ErrorType for MockSpi {
+        type Error = Infallible;
+    }
+
+    impl SpiDevice for MockSpi {
+        async fn transaction(
+            &mut self,
+            operations: &mut [Operation<'_, u8>],
+        ) -> Result<(), Infallible> {
+            let mut chip = self.0.borrow_mut();
+            let mut address = None;
+            for operation in operations {
+                match operation {
+                    Operation::Write(bytes) => {
+                        for byte in bytes.iter() {
+                            match address {
+                                None => address = Some(*byte),
+                                Some(register) => {
+                                    chip.write(register, *byte);
+                                    address = Some(register + 1);
+                                }
+                            }
+                        }
+                    }
+                    Operation::Read(buf) => {
+                        let register = address.unwrap() & !READ;
+                        for (i, byte) in buf.iter_mut().enumerate() {
+                            // The FIFO is read through a single register.
+                            let offset = if register == FIFO_R_W.address {
+                                0
+                            } else {
+                                i as u8
+                            };
+                            *byte = chip.read(register + offset);
+                        }
+                    }
+                    _ => unimplemented!(),
+                }
+            }
+            Ok(())
+        }
+    }
+
+    /// Pulses every sample period, whether the chip has anything or not.
+    struct DataReady;
+
+    impl DataReady {
+        async fn pulse(&mut self) -> Result<(), Infallible> {
+            Timer::after_millis(SAMPLE_PERIOD_MS).await;
+            Ok(())
+        }
+    }
+
+    impl PinErrorType for DataReady {
+        type Error = Infallible;
+    }
+
+    impl Wait for DataReady {
+        async fn wait_for_high(&mut self) -> Result<(), Infallible> {
+            self.pulse().await
+        }
+
+        async fn wait_for_low(&mut self) -> Result<(), Infallible> {
+            self.pulse().await
+        }
+
+        async fn wait_for_rising_edge(&mut self) -> Result<(), Infallible> {
+            self.pulse().await
+        }
+
+        async fn wait_for_falling_edge(&mut self) -> Result<(), Infallible> {
+            self.pulse().await
+        }
+
+        async fn wait_for_any_edge(&mut self) -> Result<(), Infallible> {
+            self.pulse().await
+        }
+    }
+
+    fn assert_near(actual: f32, expected: f32) {
+        assert!((actual - expected).abs() < 1e-4, "{actual} != {expected}");
+    }
+
+    define_signal!(ImuGyro, Vector3<AngularVelocity>, 1);
+    define_signal!(ImuCalibratedGyro, Vector3<AngularVelocity>, 1);
+    define_signal!(ImuAccel, Vector3<Acceleration>, 1);
+    define_signal!(ImuGyroCalibration, u8, 3);
+    define_signal!(ImuParameters, ParamStore, 3);
+    #[test]
+    fn configures_and_drains_the_fifo() {
+        let _time = harness::lock();
+        let chip = Chip::new(WHO_AM_I_VALUE);
+        let mut gyro = imu_gyro_signal().unwrap();
+        let mut accel = imu_accel_signal().unwrap();
+        let mut parameters = ParamStore::default();
+        parameters
+            .set(Param::ImuGyroRange, ParamValue::U8(1))
+            .unwrap();
+        parameters
+            .set(Param::ImuAccelRange, ParamValue::U8(0))
+            .unwrap();
+        new_imu_parameters_signal_emitter().emit(parameters);
+
+        let mut task = pin!(run(
+            Icm20948::new(MockSpi(chip.clone())),
+            DataReady,
+            new_imu_gyro_signal_emitter(),
+            new_imu_accel_signal_emitter(),
+            imu_gyro_calibration_signal().unwrap(),
+            imu_parameters_signal().unwrap(),
+        ));
+        assert!(run_for(task.as_mut(), RESET_TIME_MS).is_none());
+        {
+            let chip = chip.borrow();
+            assert_eq!(chip.resets, 1);
+            assert_eq!(chip.register(PWR_MGMT_1), CLKSEL_AUTO);
+            assert_eq!(chip.register(USER_CTRL), I2C_IF_DIS | FIFO_EN);
+            assert_eq!(chip.register(GYRO_SMPLRT_DIV), 1);
+            assert_eq!(chip.register(ACCEL_SMPLRT_DIV_2), 1);
+            // 500 °/s and 2 g, with the low-pass filters on.
+            assert_eq!(chip.register(GYRO_CONFIG_1), 0x03);
+            assert_eq!(chip.register(ACCEL_CONFIG), 0x11);
+            assert_eq!(chip.register(INT_ENABLE_1), RAW_DATA_0_RDY_EN);
+            assert_eq!(chip.register(FIFO_EN_2), ACCEL_GYRO_FIFO_EN);
+        }
+        assert_eq!(gyro.age(), None);
+
+        // Everything that piled up is emitted, the newest last.
+        for i in 0..10 {
+            chip.borrow_mut()
+                .push_sample([0, -8_192, 16_384], [655 * i, -655, 0]);
+        }
+        run_for(task.as_mut(), SAMPLE_PERIOD_MS);
+        assert!(chip.borrow().fifo.is_empty());
+        let value = gyro.get();
+        assert_near(value.x.get::<degree_per_second>(), 90.0);
+        assert_near(value.y.get::<degree_per_second>(), -10.0);
+        let value = accel.get();
+        assert_near(value.y.get::<standard_gravity>(), -0.5);
+        assert_near(value.z.get::<standard_gravity>(), 1.0);
+
+        // An overflowed FIFO is thrown away, partial samples and all.
+        chip.borrow_mut().overflowed = true;
+        chip.borrow_mut().fifo.extend([1, 2, 3]);
+        run_for(task.as_mut(), SAMPLE_PERIOD_MS);
+        assert!(chip.borrow().fifo.is_empty());
+        chip.borrow_mut().push_sample([0; 3], [-655, 0, 0]);
+        run_for(task.as_mut(), SAMPLE_PERIOD_MS);
+        assert_near(gyro.get().x.get::<degree_per_second>(), -10.0);
+    }
+
+    #[test]
+    fn calibrates_the_gyro() {
+        let _time = harness::lock();
+        let chip = Chip::new(WHO_AM_I_VALUE);
+        let mut gyro = imu_calibrated_gyro_signal().unwrap();
+        let mut calibration = new_imu_gyro_calibration_signal_emitter();
+        let mut task = pin!(run(
+            Icm20948::new(MockSpi(chip.clone())),
+            DataReady,
+            new_imu_calibrated_gyro_signal_emitter(),
+            new_imu_accel_signal_emitter(),
+            imu_gyro_calibration_signal().unwrap(),
+            imu_parameters_signal().unwrap(),
+        ));
+        run_for(task.as_mut(), RESET_TIME_MS);
+        let sample = |gyro: [i16; 3]| {
+            chip.borrow_mut().push_sample([0, 0, 16_384], gyro);
+        };
+        sample([100, -50, 0]);
+        run_for(task.as_mut(), SAMPLE_PERIOD_MS);
+        let drifting = gyro.get();
+        assert!(drifting.x.get::<degree_per_second>() > 0.0);
+
+        // Sitting still, slightly off zero.
+        calibration.emit(1);
+        for _ in 0..CALIBRATION_SAMPLES / 64 {
+            for _ in 0..64 {
+                sample([100, -50, 0]);
+            }
+            run_for(task.as_mut(), SAMPLE_PERIOD_MS);
+        }
+
+        // At rest it now reads zero, and a turn reads as much as it did before.
+        sample([100, -50, 0]);
+        run_for(task.as_mut(), SAMPLE_PERIOD_MS);
+        let value = gyro.get();
+        assert_near(value.x.get::<degree_per_second>(), 0.0);
+        assert_near(value.y.get::<degree_per_second>(), 0.0);
+        sample([300, -50, 0]);
+        run_for(task.as_mut(), SAMPLE_PERIOD_MS);
+        assert_near(
+            gyro.get().x.get::<degree_per_second>(),
+            2.0 * drifting.x.get::<degree_per_second>(),
+        );
+    }
+
+    #[test]
+    fn refuses_other_chips() {
+        let _time = harness::lock();
+        let chip = Chip::new(0x71);
+        let mut task = pin!(run(
+            Icm20948::new(MockSpi(chip.clone())),
+            DataReady,
+            new_imu_gyro_signal_emitter(),
+            new_imu_accel_signal_emitter(),
+            imu_gyro_calibration_signal().unwrap(),
+            imu_parameters_signal().unwrap(),
+        ));
+        let Some(Err(error)) = run_for(task.as_mut(), RESET_TIME_MS) else {
+            panic!("The task kept running");
+        };
+        assert_eq!(error, ImuError::UnknownChip(0x71));
+    }
+}
@@ -0,0 +1,822 @@
+//! Session, sequence and round-trip bookkeeping for one end of the radio link.
+//!
+//! Both the controller and the drone own a [`Link`]. It seals outgoing frames with the link key and
+//! the current [`Session`], stamps them with a counter and timestamps, and feeds the counters of
+//! incoming frames into a [`SequenceTracker`] to account for lost, duplicated and reordered frames.
+//!
+//! Neither end remembers counters across a restart, so the link starts a new session, with keys of
+//! its own, whenever it comes up:
+//!
+//! - The controller offers a session with a random nonce in [`SessionOffer`]s, until the drone
+//!   accepts it. It offers a new one whenever it restarts or stops hearing the drone.
+//! - The drone answers every new offer with a [`SessionAccept`], adding its boot count and how many
+//!   sessions it accepted since booting. It's the controller's to take up from then on.
+//! - The drone keeps its current session until a frame sealed with the new one arrives. That proves
+//!   the offer came from the controller, and not from a recording.
+//!
+//! Counters start over with every session. Frames outside of a session are rejected, except for
+//! the offers and acceptances themselves. They are sealed with [`Session::HANDSHAKE`] and replaying
+//! them gets an attacker nowhere: an acceptance only counts for the offer it answers, and every
+//! offer gets a session the drone never had before, in which nothing recorded is valid.
+//!
+//! Within a session the tracker doubles as replay protection. Duplicates are reported as such, and
+//! frames too old to tell are rejected as stale.
+
+use zerocopy::{FromBytes, Immutable, IntoBytes};
+
+use crate::auth::{Direction, LinkKey, Session};
+use crate::protocol::{self, Frame, FrameStamp, Message, ProtocolError};
+
+/// Number of counters, counted back from the highest one received, that are remembered to detect
+/// duplicates and late arrivals.
+pub const SEQUENCE_WINDOW: u32 = 64;
+
+/// What a received counter means relative to the ones received before it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SequenceStatus {
+    /// The newest frame so far. Any counters skipped on the way are counted as lost.
+    New,
+    /// An older frame that was previously counted as lost and has now arrived.
+    Reordered,
+    /// A frame that has already been received.
+    Duplicate,
+    /// A frame behind the window, which may or may not have been received before. [`Link`] rejects
+    /// these with [`ProtocolError::StaleCounter`].
+    Stale,
+}
+
+impl SequenceStatus {
+    /// Whether the frame carries information the receiver has not seen yet.
+    pub fn is_fresh(&self) -> bool {
+        matches!(self, SequenceStatus::New | SequenceStatus::Reordered)
+    }
+}
+
+/// Offers the drone a session with the controller's nonce, see [the module docs](self).
+#[derive(IntoBytes, FromBytes, Immutable, Debug, PartialEq, Default, Clone)]
+#[repr(C, packed)]
+pub struct SessionOffer {
+    pub controller_nonce: u32,
+}
+
+impl defmt::Format for SessionOffer {
+    fn format(&self, fmt: defmt::Formatter) {
+        defmt::write!(fmt, "session_offer({:x})", { self.controller_nonce })
+    }
+}
+
+/// The drone's answer to a [`SessionOffer`], which completes the session.
+#[derive(IntoBytes, FromBytes, Immutable, Debug, PartialEq, Default, Clone)]
+#[repr(C, packed)]
+pub struct SessionAccept {
+    pub controller_nonce: u32,
+    pub drone_boot: u32,
+    pub drone_sequence: u16,
+}
+
+impl SessionAccept {
+    pub fn new(session: &Session) -> Self {
+        Self {
+            controller_nonce: session.controller_nonce,
+            drone_boot: session.drone_boot,
+            drone_sequence: session.drone_sequence,
+        }
+    }
+
+    pub fn session(&self) -> Session {
+        Session {
+            controller_nonce: self.controller_nonce,
+            drone_boot: self.drone_boot,
+            drone_sequence: self.drone_sequence,
+        }
+    }
+}
+
+impl defmt::Format for SessionAccept {
+    fn format(&self, fmt: defmt::Formatter) {
+        defmt::write!(fmt, "session_accept({})", self.session())
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LinkStatistics {
+    pub received: u32,
+    pub lost: u32,
+    pub duplicates: u32,
+    pub reordered: u32,
+    /// Percentage of the last [`SEQUENCE_WINDOW`] sequence numbers that never arrived.
+    pub loss_percent: f32,
+    /// Smoothed round-trip time in milliseconds, once the peer has echoed one of our timestamps.
+    pub rtt_ms: Option<u16>,
+}
+
+impl defmt::Format for LinkStatistics {
+    fn format(&self, fmt: defmt::Formatter) {
+        defmt::write!(
+            fmt,
+            "received({}) lost({}) duplicates({}) reordered({}) loss({}%) rtt({}ms)",
+            self.received,
+            self.lost,
+            self.duplicates,
+            self.reordered,
+            self.loss_percent,
+            self.rtt_ms,
+        )
+    }
+}
+
+/// Classifies incoming counters using a sliding window, much like the anti-replay window in IPsec.
+pub struct SequenceTracker {
+    highest: Option<u32>,
+    /// Bit `n` is set if `highest - n` has been received.
+    window: u64,
+    /// Number of valid bits in `window`. Less than [`SEQUENCE_WINDOW`] right after starting.
+    span: u32,
+    received: u32,
+    lost: u32,
+    duplicates: u32,
+    reordered: u32,
+}
+
+impl SequenceTracker {
+    pub const fn new() -> Self {
+        Self {
+            highest: None,
+            window: 0,
+            span: 0,
+            received: 0,
+            lost: 0,
+            duplicates: 0,
+            reordered: 0,
+        }
+    }
+
+    pub fn record(&mut self, counter: u32) -> SequenceStatus {
+        let Some(highest) = self.highest else {
+            self.highest = Some(counter);
+            self.window = 1;
+            self.span = 1;
+            self.received += 1;
+            return SequenceStatus::New;
+        };
+
+        if counter > highest {
+            let distance = counter - highest;
+            self.lost += distance - 1;
+            self.window = if distance >= SEQUENCE_WINDOW {
+                0
+            } else {
+                self.window << distance
+            };
+            self.window |= 1;
+            self.span = self.span.saturating_add(distance).min(SEQUENCE_WINDOW);
+            self.highest = Some(counter);
+            self.received += 1;
+            return SequenceStatus::New;
+        }
+
+        let behind = highest - counter;
+        if behind >= self.span {
+            return SequenceStatus::Stale;
+        }
+
+        let bit = 1u64 << behind;
+        if self.window & bit != 0 {
+            self.duplicates += 1;
+            SequenceStatus::Duplicate
+        } else {
+            self.window |= bit;
+            self.received += 1;
+            self.reordered += 1;
+            self.lost = self.lost.saturating_sub(1);
+            SequenceStatus::Reordered
+        }
+    }
+
+    /// Forgets the counters received so far, for a peer that starts counting over. The statistics
+    /// carry on.
+    pub fn restart(&mut self) {
+        self.highest = None;
+        self.window = 0;
+        self.span = 0;
+    }
+
+    /// Percentage of the counters in the window that haven't been received.
+    pub fn loss_percent(&self) -> f32 {
+        if self.span == 0 {
+            return 0.0;
+        }
+
+        let mask = if self.span >= 64 {
+            u64::MAX
+        } else {
+            (1u64 << self.span) - 1
+        };
+        let missing = self.span - (self.window & mask).count_ones();
+        missing as f32 * 100.0 / self.span as f32
+    }
+}
+
+impl Default for SequenceTracker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A frame accepted by [`Link::receive`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct Received {
+    pub frame: Frame,
+    pub status: SequenceStatus,
+}
+
+/// One end of the radio link.
+pub struct Link {
+    key: LinkKey,
+    /// The direction of the frames this end sends.
+    direction: Direction,
+    encrypt: bool,
+    /// The session frames are sealed with, once there is one.
+    session: Option<Session>,
+    /// The controller's nonce while it offers a session, until the drone accepts it.
+    offer: Option<u32>,
+    /// The drone's boot count, if it accepts sessions.
+    drone_boot: Option<u32>,
+    /// How many sessions the drone accepted since it booted.
+    accepted: u16,
+    /// The session the drone accepted last, until a frame sealed with it arrives.
+    candidate: Option<Session>,
+    /// Set when the drone accepted an offer, until a [`SessionAccept`] has been handed out for it.
+    accept_pending: bool,
+    /// Set when a session started, until [`Link::take_new_session`] is called.
+    new_session: bool,
+    tx_counter: u32,
+    /// The timestamp of the last frame received from the peer, echoed back in every frame we send.
+    peer_timestamp: u16,
+    tracker: SequenceTracker,
+    /// Smoothed round-trip time in 1/8 ms, like TCP's SRTT.
+    srtt: Option<u32>,
+}
+
+impl Link {
+    /// A link end that sends frames in `direction`, sealed with `key`. Messages are authenticated
+    /// but not encrypted, see [`Link::set_encryption`].
+    ///
+    /// There is no session yet, see [`Link::offer_session`] and [`Link::accept_sessions`].
+    pub const fn new(key: LinkKey, direction: Direction) -> Self {
+        Self {
+            key,
+            direction,
+            encrypt: false,
+            session: None,
+            offer: None,
+            drone_boot: None,
+            accepted: 0,
+            candidate: None,
+            accept_pending: false,
+            new_session: false,
+            tx_counter: 0,
+            peer_timestamp: 0,
+            tracker: SequenceTracker::new(),
+            srtt: None,
+        }
+    }
+
+    /// Whether outgoing messages are encrypted. Incoming frames are accepted either way.
+    pub fn set_encryption(&mut self, encrypt: bool) {
+        self.encrypt = encrypt;
+    }
+
+    /// The controller's side of the handshake: drops the current session, if any, and offers a new
+    /// one with `nonce`, which should be random. Until the drone accepts it, only the offer can be
+    /// sent, and only the acceptance is received.
+    pub fn offer_session(&mut self, nonce: u32) {
+        self.session = None;
+        self.offer = Some(nonce);
+    }
+
+    /// The drone's side of the handshake: accepts the sessions the controller offers from now on.
+    /// `boot` must never be 0 and never repeat for the link key, it's meant to be the boot count.
+    pub fn accept_sessions(&mut self, boot: u32) {
+        self.drone_boot = Some(boot);
+    }
+
+    /// The session frames are sealed with, once one is established.
+    pub fn session(&self) -> Option<Session> {
+        self.session
+    }
+
+    /// The counter the next frame is stamped with.
+    pub fn next_counter(&self) -> u32 {
+        self.tx_counter
+    }
+
+    /// Encodes `message` into a frame stamped with the next counter.
+    ///
+    /// `now_ms` is the local millisecond clock, truncated to 16 bits. Fails with
+    /// [`ProtocolError::NoSession`] while there is no session, unless `message` is part of the
+    /// handshake.
+    pub fn encode(
+        &mut self,
+        message: &Message,
+        now_ms: u16,
+        buf: &mut [u8],
+    ) -> Result<usize, ProtocolError> {
+        let handshake = message.message_type().is_handshake();
+        let session = if handshake {
+            Session::HANDSHAKE
+        } else {
+            self.session.ok_or(ProtocolError::NoSession)?
+        };
+        let next_counter = self
+            .tx_counter
+            .checked_add(1)
+            .ok_or(ProtocolError::CounterExhausted)?;
+        let stamp = FrameStamp {
+            counter: self.tx_counter,
+            // 0 means "no timestamp" to the peer.
+            timestamp: now_ms.max(1),
+            echo_timestamp: self.peer_timestamp,
+        };
+        let len = protocol::encode(
+            message,
+            &stamp,
+            &self.key,
+            &session,
+            self.direction,
+            self.encrypt && !handshake,
+            buf,
+        )?;
+        self.tx_counter = next_counter;
+
+        Ok(len)
+    }
+
+    /// Decodes and authenticates a frame and updates the link statistics.
+    ///
+    /// Duplicates are returned as well, it's up to the caller to check [`Received::status`] and
+    /// discard them. Stale frames are rejected with [`ProtocolError::StaleCounter`], and frames
+    /// outside of a session with [`ProtocolError::NoSession`]. Offers and acceptances are handled
+    /// here, and returned as new frames.
+    pub fn receive(&mut self, buf: &[u8], now_ms: u16) -> Result<Received, ProtocolError> {
+        let peer = self.direction.reverse();
+        if protocol::decode_header(buf)?.message_type.is_handshake() {
+            // Their counters aren't part of any session, so they aren't tracked.
+            let frame = protocol::decode(buf, &self.key, &Session::HANDSHAKE, peer)?;
+            self.handshake(&frame.message);
+            let status = SequenceStatus::New;
+            return Ok(Received { frame, status });
+        }
+
+        let decoded = self
+            .session
+            .ok_or(ProtocolError::NoSession)
+            .and_then(|session| protocol::decode(buf, &self.key, &session, peer));
+        let frame = match (decoded, self.candidate) {
+            (Ok(frame), _) => frame,
+            (
+                Err(ProtocolError::NoSession | ProtocolError::AuthenticationFailed),
+                Some(candidate),
+            ) => {
+                let frame = protocol::decode(buf, &self.key, &candidate, peer)?;
+                self.start(candidate);
+                frame
+            }
+            (Err(e), _) => return Err(e),
+        };
+        let stamp = frame.header.stamp;
+        let status = self.tracker.record(stamp.counter);
+        if status == SequenceStatus::Stale {
+            return Err(ProtocolError::StaleCounter(stamp.counter));
+        }
+
+        // Late frames carry stale timestamps, only the newest frame is echoed and timed.
+        if status == SequenceStatus::New {
+            self.peer_timestamp = stamp.timestamp;
+            if stamp.echo_timestamp != 0 {
+                self.update_rtt(now_ms.wrapping_sub(stamp.echo_timestamp));
+            }
+        }
+
+        Ok(Received { frame, status })
+    }
+
+    /// Whether a session started since the last call. On the drone, that proves the controller
+    /// restarted or lost the drone for a while, and with it the state it kept about the link.
+    pub fn take_new_session(&mut self) -> bool {
+        core::mem::take(&mut self.new_session)
+    }
+
+    /// The handshake message to send next, if any. It should take priority over anything else.
+    pub fn take_handshake(&mut self) -> Option<Message> {
+        if let Some(controller_nonce) = self.offer {
+            return Some(Message::SessionOffer(SessionOffer { controller_nonce }));
+        }
+        if !self.accept_pending {
+            return None;
+        }
+
+        self.accept_pending = false;
+        self.candidate
+            .map(|candidate| Message::SessionAccept(SessionAccept::new(&candidate)))
+    }
+
+    fn handshake(&mut self, message: &Message) {
+        match message {
+            Message::SessionOffer(offer) => {
+                let Some(drone_boot) = self.drone_boot else {
+                    return;
+                };
+                let controller_nonce = offer.controller_nonce;
+                if self.session.map(|session| session.controller_nonce) == Some(controller_nonce) {
+                    // A late repetition of the offer of the current session.
+                    return;
+                }
+                // Offers are repeated until the acceptance gets through, they all get the same one.
+                if self.candidate.map(|candidate| candidate.controller_nonce)
+                    != Some(controller_nonce)
+                {
+                    let Some(accepted) = self.accepted.checked_add(1) else {
+                        return;
+                    };
+                    self.candidate = Some(Session {
+                        controller_nonce,
+                        drone_boot,
+                        drone_sequence: self.accepted,
+                    });
+                    self.accepted = accepted;
+                }
+                self.accept_pending = true;
+            }
+            Message::SessionAccept(accept) if self.offer == Some(accept.controller_nonce) => {
+                self.offer = None;
+                self.start(accept.session());
+            }
+            _ => {}
+        }
+    }
+
+    fn start(&mut self, session: Session) {
+        self.session = Some(session);
+        self.new_session = true;
+        self.candidate = None;
+        self.tx_counter = 0;
+        self.peer_timestamp = 0;
+        self.tracker.restart();
+    }
+
+    fn update_rtt(&mut self, rtt_ms: u16) {
+        let sample = (rtt_ms as u32) << 3;
+        self.srtt = Some(match self.srtt {
+            None => sample,
+            Some(srtt) => srtt - (srtt >> 3) + (sample >> 3),
+        });
+    }
+
+    pub fn statistics(&self) -> LinkStatistics {
+        LinkStatistics {
+            received: self.tracker.received,
+            lost: self.tracker.lost,
+            duplicates: self.tracker.duplicates,
+            reordered: self.tracker.reordered,
+            loss_percent: self.tracker.loss_percent(),
+            rtt_ms: self.srtt.map(|srtt| (srtt >> 3) as u16),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::MAX_FRAME_SIZE;
+    use crate::rc::{Channel, RcChannels};
+
+    #[test]
+    fn in_order_sequence() {
+        let mut tracker = SequenceTracker::new();
+        for sequence in 0..100 {
+            assert_eq!(tracker.record(sequence), SequenceStatus::New);
+        }
+
+        assert_eq!(tracker.received, 100);
+        assert_eq!(tracker.lost, 0);
+        assert_eq!(tracker.loss_percent(), 0.0);
+    }
+
+    #[test]
+    fn gaps_are_counted_as_lost() {
+        let mut tracker = SequenceTracker::new();
+        for sequence in [0, 1, 4, 5, 9] {
+            assert_eq!(tracker.record(sequence), SequenceStatus::New);
+        }
+
+        assert_eq!(tracker.received, 5);
+        assert_eq!(tracker.lost, 5);
+        assert_eq!(tracker.loss_percent(), 50.0);
+    }
+
+    #[test]
+    fn duplicates() {
+        let mut tracker = SequenceTracker::new();
+        tracker.record(10);
+        tracker.record(11);
+
+        assert_eq!(tracker.record(11), SequenceStatus::Duplicate);
+        assert_eq!(tracker.record(10), SequenceStatus::Duplicate);
+        assert_eq!(tracker.duplicates, 2);
+        assert_eq!(tracker.received, 2);
+    }
+
+    #[test]
+    fn late_frames_are_reordered_not_lost() {
+        let mut tracker = SequenceTracker::new();
+        tracker.record(0);
+        tracker.record(3);
+        assert_eq!(tracker.lost, 2);
+
+        assert_eq!(tracker.record(1), SequenceStatus::Reordered);
+        assert_eq!(tracker.record(2), SequenceStatus::Reordered);
+        assert_eq!(tracker.record(2), SequenceStatus::Duplicate);
+
+        assert_eq!(tracker.lost, 0);
+        assert_eq!(tracker.reordered, 2);
+        assert_eq!(tracker.received, 4);
+    }
+
+    #[test]
+    fn frames_behind_the_window_are_stale() {
+        let mut tracker = SequenceTracker::new();
+        for sequence in 5000..5100 {
+            tracker.record(sequence);
+        }
+
+        assert_eq!(tracker.record(0), SequenceStatus::Stale);
+        assert_eq!(
+            tracker.record(5100 - SEQUENCE_WINDOW - 1),
+            SequenceStatus::Stale
+        );
+        assert_eq!(tracker.record(5100), SequenceStatus::New);
+        assert_eq!(tracker.received, 101);
+    }
+
+    #[test]
+    fn frames_before_the_first_are_stale() {
+        let mut tracker = SequenceTracker::new();
+        tracker.record(100);
+
+        assert_eq!(tracker.record(99), SequenceStatus::Stale);
+    }
+
+    fn key() -> LinkKey {
+        LinkKey::new([0x42; 32])
+    }
+
+    /// Hands the controller's offer to the drone and its acceptance back.
+    fn handshake(controller: &mut Link, drone: &mut Link) {
+        let mut buf = [0u8; MAX_FRAME_SIZE];
+        let offer = controller.take_handshake().unwrap();
+        let len = controller.encode(&offer, 1, &mut buf).unwrap();
+        drone.receive(&buf[..len], 1).unwrap();
+        let accept = drone.take_handshake().unwrap();
+        let len = drone.encode(&accept, 1, &mut buf).unwrap();
+        controller.receive(&buf[..len], 1).unwrap();
+    }
+
+    /// A controller and a drone in a session.
+    fn link_pair() -> (Link, Link) {
+        let mut controller = Link::new(key(), Direction::Uplink);
+        let mut drone = Link::new(key(), Direction::Downlink);
+        controller.offer_session(0x1234);
+        drone.accept_sessions(1);
+        handshake(&mut controller, &mut drone);
+        (controller, drone)
+    }
+
+    #[test]
+    fn link_round_trip_time() {
+        let (mut controller, mut drone) = link_pair();
+        let message = Message::RcChannels(RcChannels::default());
+        let mut buf = [0u8; MAX_FRAME_SIZE];
+
+        // Nothing has been echoed yet, so there is no round-trip time.
+        let len = controller.encode(&message, 1000, &mut buf).unwrap();
+        drone.receive(&buf[..len], 50).unwrap();
+        assert_eq!(controller.statistics().rtt_ms, None);
+
+        // The drone echoes the controller's timestamp, which arrives 12 ms later.
+        let len = drone.encode(&message, 52, &mut buf).unwrap();
+        controller.receive(&buf[..len], 1012).unwrap();
+        assert_eq!(controller.statistics().rtt_ms, Some(12));
+
+        // The controller echoes the drone's timestamp in turn.
+        let len = controller.encode(&message, 1020, &mut buf).unwrap();
+        drone.receive(&buf[..len], 60).unwrap();
+        assert_eq!(drone.statistics().rtt_ms, Some(8));
+    }
+
+    #[test]
+    fn link_round_trip_time_is_smoothed() {
+        let mut link = Link::new(key(), Direction::Uplink);
+        link.update_rtt(10);
+        for _ in 0..3 {
+            link.update_rtt(50);
+        }
+
+        let rtt = link.statistics().rtt_ms.unwrap();
+        assert!(rtt > 10 && rtt < 50, "rtt = {}", rtt);
+    }
+
+    #[test]
+    fn link_duplicates_do_not_update_echo() {
+        let (mut controller, mut drone) = link_pair();
+        let message = Message::RcChannels(RcChannels::default());
+        let mut first = [0u8; MAX_FRAME_SIZE];
+        let mut second = [0u8; MAX_FRAME_SIZE];
+
+        let first_len = controller.encode(&message, 100, &mut first).unwrap();
+        let second_len = controller.encode(&message, 200, &mut second).unwrap();
+        drone.receive(&first[..first_len], 0).unwrap();
+        drone.receive(&second[..second_len], 0).unwrap();
+
+        let received = drone.receive(&first[..first_len], 0).unwrap();
+        assert_eq!(received.status, SequenceStatus::Duplicate);
+
+        let len = drone.encode(&message, 0, &mut first).unwrap();
+        let session = drone.session().unwrap();
+        let echoed =
+            protocol::decode(&first[..len], &key(), &session, Direction::Downlink).unwrap();
+        assert_eq!(echoed.header.stamp.echo_timestamp, 200);
+    }
+
+    #[test]
+    fn link_rejects_replayed_frames() {
+        let (mut controller, mut drone) = link_pair();
+        let message = Message::RcChannels(RcChannels::default());
+        let mut recorded = [0u8; MAX_FRAME_SIZE];
+        let recorded_len = controller.encode(&message, 1, &mut recorded).unwrap();
+        drone.receive(&recorded[..recorded_len], 0).unwrap();
+
+        for _ in 0..SEQUENCE_WINDOW {
+            let mut buf = [0u8; MAX_FRAME_SIZE];
+            let len = controller.encode(&message, 1, &mut buf).unwrap();
+            drone.receive(&buf[..len], 0).unwrap();
+        }
+
+        assert_eq!(
+            drone.receive(&recorded[..recorded_len], 0),
+            Err(ProtocolError::StaleCounter(0))
+        );
+        assert_eq!(drone.statistics().duplicates, 0);
+    }
+
+    #[test]
+    fn link_rejects_frames_without_a_session() {
+        let mut controller = Link::new(key(), Direction::Uplink);
+        let mut drone = Link::new(key(), Direction::Downlink);
+        drone.accept_sessions(1);
+        let message = Message::RcChannels(RcChannels::default());
+        let mut buf = [0u8; MAX_FRAME_SIZE];
+        assert_eq!(
+            controller.encode(&message, 1, &mut buf),
+            Err(ProtocolError::NoSession)
+        );
+
+        // Not even frames sealed with the handshake's keys, which repeat.
+        let stamp = FrameStamp::default();
+        let len = protocol::encode(
+            &message,
+            &stamp,
+            &key(),
+            &Session::HANDSHAKE,
+            Direction::Uplink,
+            false,
+            &mut buf,
+        )
+        .unwrap();
+        assert_eq!(drone.receive(&buf[..len], 0), Err(ProtocolError::NoSession));
+        assert_eq!(drone.take_handshake(), None);
+    }
+
+    #[test]
+    fn link_sessions_start_when_proven() {
+        let mut controller = Link::new(key(), Direction::Uplink);
+        let mut drone = Link::new(key(), Direction::Downlink);
+        drone.accept_sessions(7);
+        controller.offer_session(0x1234);
+        let message = Message::RcChannels(RcChannels::default());
+        let mut buf = [0u8; MAX_FRAME_SIZE];
+
+        // The controller keeps offering until the acceptance arrives, and the drone answers once
+        // per offer.
+        let offer = controller.take_handshake().unwrap();
+        for _ in 0..2 {
+            let len = controller.encode(&offer, 1, &mut buf).unwrap();
+            drone.receive(&buf[..len], 1).unwrap();
+        }
+        let accept = drone.take_handshake().unwrap();
+        assert_eq!(drone.take_handshake(), None);
+        assert_eq!(controller.take_handshake(), Some(offer));
+
+        let len = drone.encode(&accept, 1, &mut buf).unwrap();
+        controller.receive(&buf[..len], 1).unwrap();
+        let session = Session {
+            controller_nonce: 0x1234,
+            drone_boot: 7,
+            drone_sequence: 0,
+        };
+        assert_eq!(controller.session(), Some(session));
+        assert_eq!(controller.take_handshake(), None);
+        assert!(controller.take_new_session());
+
+        // The drone only switches once the controller seals a frame with the session.
+        assert_eq!(drone.session(), None);
+        assert!(!drone.take_new_session());
+        let len = controller.encode(&message, 1, &mut buf).unwrap();
+        let received = drone.receive(&buf[..len], 1).unwrap();
+        assert_eq!(received.status, SequenceStatus::New);
+        assert_eq!(received.frame.header.stamp.counter, 0);
+        assert_eq!(drone.session(), Some(session));
+        assert!(drone.take_new_session());
+
+        let len = controller.encode(&message, 1, &mut buf).unwrap();
+        drone.receive(&buf[..len], 1).unwrap();
+        assert!(!drone.take_new_session());
+    }
+
+    #[test]
+    fn link_restarted_controller_starts_a_new_session() {
+        let (mut controller, mut drone) = link_pair();
+        let message = Message::RcChannels(RcChannels::default());
+        let mut buf = [0u8; MAX_FRAME_SIZE];
+        for _ in 0..100 {
+            let len = controller.encode(&message, 1, &mut buf).unwrap();
+            drone.receive(&buf[..len], 0).unwrap();
+        }
+        let old = drone.session().unwrap();
+
+        // The restarted controller counts from 0 again, in a session of its own.
+        let mut controller = Link::new(key(), Direction::Uplink);
+        controller.offer_session(0x5678);
+        handshake(&mut controller, &mut drone);
+        let len = controller.encode(&message, 1, &mut buf).unwrap();
+        let received = drone.receive(&buf[..len], 0).unwrap();
+        assert_eq!(received.status, SequenceStatus::New);
+        assert_eq!(received.frame.header.stamp.counter, 0);
+
+        let session = drone.session().unwrap();
+        assert_ne!(session, old);
+        assert_eq!(session.drone_sequence, 1);
+        assert_eq!(drone.statistics().received, 101);
+    }
+
+    #[test]
+    fn link_rejects_frames_of_earlier_sessions() {
+        let mut controller = Link::new(key(), Direction::Uplink);
+        let mut drone = Link::new(key(), Direction::Downlink);
+        controller.offer_session(0x1234);
+        drone.accept_sessions(1);
+        let mut offer = [0u8; MAX_FRAME_SIZE];
+        let offer_message = controller.take_handshake().unwrap();
+        let offer_len = controller.encode(&offer_message, 1, &mut offer).unwrap();
+        handshake(&mut controller, &mut drone);
+        let message = Message::RcChannels(RcChannels::default());
+        let mut recorded = [0u8; MAX_FRAME_SIZE];
+        let recorded_len = controller.encode(&message, 1, &mut recorded).unwrap();
+        drone.receive(&recorded[..recorded_len], 0).unwrap();
+
+        // After a restart, the drone has no session the recording could belong to.
+        let mut drone = Link::new(key(), Direction::Downlink);
+        drone.accept_sessions(2);
+        assert_eq!(
+            drone.receive(&recorded[..recorded_len], 0),
+            Err(ProtocolError::NoSession)
+        );
+
+        // Replaying the offer as well gets a session the recording doesn't belong to either.
+        drone.receive(&offer[..offer_len], 0).unwrap();
+        assert!(drone.take_handshake().is_some());
+        assert_eq!(
+            drone.receive(&recorded[..recorded_len], 0),
+            Err(ProtocolError::AuthenticationFailed)
+        );
+        assert_eq!(drone.session(), None);
+        assert!(!drone.take_new_session());
+    }
+
+    #[test]
+    fn link_encrypted_round_trip() {
+        let (mut controller, mut drone) = link_pair();
+        controller.set_encryption(true);
+        let mut channels = RcChannels::default();
+        channels.set(Channel::Roll, 0x12);
+        let message = Message::RcChannels(channels);
+        let mut buf = [0u8; MAX_FRAME_SIZE];
+        let len = controller.encode(&message, 1, &mut buf).unwrap();
+
+        assert_ne!(buf[protocol::HEADER_SIZE], 0x12);
+        assert_eq!(
+            drone.receive(&buf[..len], 0).unwrap().frame.message,
+            message
+        );
+    }
+}
@@ -0,0 +1,740 @@
+//! A minimal MAVLink v2 implementation, enough for ground control software to show the drone.
+//!
+//! Only the handful of messages the controller bridges are implemented: HEARTBEAT, SYS_STATUS,
+//! ATTITUDE, VFR_HUD and RADIO_STATUS going out, COMMAND_LONG coming in and COMMAND_ACK answering
+//! it. Field order, ids and CRC extras follow `common.xml`. Signed packets are skipped, not verified.
+
+use crate::command::{Command, CommandResult};
+use crate::telemetry::FlightMode;
+
+pub const MAVLINK_V2_STX: u8 = 0xFD;
+/// Everything before the payload, starting with the STX byte.
+pub const HEADER_SIZE: usize = 10;
+pub const CHECKSUM_SIZE: usize = 2;
+pub const SIGNATURE_SIZE: usize = 13;
+pub const MAX_PAYLOAD_SIZE: usize = 255;
+pub const MAX_PACKET_SIZE: usize = HEADER_SIZE + MAX_PAYLOAD_SIZE + CHECKSUM_SIZE + SIGNATURE_SIZE;
+
+const INCOMPAT_FLAG_SIGNED: u8 = 0x01;
+
+pub const MAV_TYPE_QUADROTOR: u8 = 2;
+pub const MAV_AUTOPILOT_GENERIC: u8 = 0;
+pub const MAV_MODE_FLAG_CUSTOM_MODE_ENABLED: u8 = 1;
+pub const MAV_MODE_FLAG_SAFETY_ARMED: u8 = 128;
+pub const MAV_STATE_STANDBY: u8 = 3;
+pub const MAV_STATE_ACTIVE: u8 = 4;
+pub const MAV_STATE_CRITICAL: u8 = 5;
+
+pub const MAV_SYS_STATUS_SENSOR_ABSOLUTE_PRESSURE: u32 = 0x08;
+pub const MAV_SYS_STATUS_SENSOR_RC_RECEIVER: u32 = 0x1_0000;
+pub const MAV_SYS_STATUS_SENSOR_BATTERY: u32 = 0x200_0000;
+
+pub const MAV_CMD_DO_SET_MODE: u16 = 176;
+pub const MAV_CMD_PREFLIGHT_CALIBRATION: u16 = 241;
+pub const MAV_CMD_PREFLIGHT_REBOOT_SHUTDOWN: u16 = 246;
+pub const MAV_CMD_COMPONENT_ARM_DISARM: u16 = 400;
+
+pub const MAV_RESULT_ACCEPTED: u8 = 0;
+pub const MAV_RESULT_TEMPORARILY_REJECTED: u8 = 1;
+pub const MAV_RESULT_DENIED: u8 = 2;
+pub const MAV_RESULT_UNSUPPORTED: u8 = 3;
+pub const MAV_RESULT_FAILED: u8 = 4;
+
+/// Accumulates `data` into a CRC-16/MCRF4XX checksum, the "X.25" checksum of the MAVLink docs.
+pub fn crc_accumulate(crc: u16, data: &[u8]) -> u16 {
+    let mut crc = crc;
+    for byte in data {
+        let mut tmp = *byte ^ crc as u8;
+        tmp ^= tmp << 4;
+        crc = (crc >> 8) ^ ((tmp as u16) << 8) ^ ((tmp as u16) << 3) ^ ((tmp as u16) >> 4);
+    }
+
+    crc
+}
+
+pub fn crc(data: &[u8]) -> u16 {
+    crc_accumulate(0xFFFF, data)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MavlinkError {
+    BufferTooSmall,
+}
+
+/// A message that can be sent. `serialize` writes the fields in wire order, i.e. sorted by size.
+pub trait MavlinkMessage {
+    const ID: u32;
+    const CRC_EXTRA: u8;
+    /// The size of the payload before trailing zeroes are truncated.
+    const LEN: usize;
+
+    fn serialize(&self, payload: &mut [u8]);
+}
+
+/// Writes little-endian fields into a payload.
+struct Writer<'a> {
+    buf: &'a mut [u8],
+    pos: usize,
+}
+
+impl<'a> Writer<'a> {
+    fn new(buf: &'a mut [u8]) -> Self {
+        Self { buf, pos: 0 }
+    }
+
+    fn put(&mut self, bytes: &[u8]) {
+        self.buf[self.pos..self.pos + bytes.len()].copy_from_slice(bytes);
+        self.pos += bytes.len();
+    }
+}
+
+/// Reads little-endian fields from a payload, as if it was padded with zeroes to its full size.
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(buf: &'a [u8]) -> Self {
+        Self { buf, pos: 0 }
+    }
+
+    fn take<const N: usize>(&mut self) -> [u8; N] {
+        let mut bytes = [0; N];
+        for byte in bytes.iter_mut() {
+            *byte = self.buf.get(self.pos).copied().unwrap_or(0);
+            self.pos += 1;
+        }
+
+        bytes
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Heartbeat {
+    pub custom_mode: u32,
+    pub mav_type: u8,
+    pub autopilot: u8,
+    pub base_mode: u8,
+    pub system_status: u8,
+}
+
+impl MavlinkMessage for Heartbeat {
+    const ID: u32 = 0;
+    const CRC_EXTRA: u8 = 50;
+    const LEN: usize = 9;
+
+    fn serialize(&self, payload: &mut [u8]) {
+        let mut w = Writer::new(payload);
+        w.put(&self.custom_mode.to_le_bytes());
+        w.put(&[
+            self.mav_type,
+            self.autopilot,
+            self.base_mode,
+            self.system_status,
+        ]);
+        // mavlink_version
+        w.put(&[3]);
+    }
+}
+
+/// SYS_STATUS without the rarely used error counters and the v2 extension fields.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct SysStatus {
+    pub sensors_present: u32,
+    pub sensors_enabled: u32,
+    pub sensors_health: u32,
+    /// Main loop load in 0.1 percent.
+    pub load: u16,
+    pub voltage_battery_mv: u16,
+    /// In 10 mA, or -1 if unknown.
+    pub current_battery_ca: i16,
+    /// Communication drop rate in 0.01 percent.
+    pub drop_rate_comm: u16,
+    pub errors_comm: u16,
+    /// In percent, or -1 if unknown.
+    pub battery_remaining: i8,
+}
+
+impl MavlinkMessage for SysStatus {
+    const ID: u32 = 1;
+    const CRC_EXTRA: u8 = 124;
+    const LEN: usize = 31;
+
+    fn serialize(&self, payload: &mut [u8]) {
+        let mut w = Writer::new(payload);
+        w.put(&self.sensors_present.to_le_bytes());
+        w.put(&self.sensors_enabled.to_le_bytes());
+        w.put(&self.sensors_health.to_le_bytes());
+        w.put(&self.load.to_le_bytes());
+        w.put(&self.voltage_battery_mv.to_le_bytes());
+        w.put(&self.current_battery_ca.to_le_bytes());
+        w.put(&self.drop_rate_comm.to_le_bytes());
+        w.put(&self.errors_comm.to_le_bytes());
+        // errors_count1 to errors_count4
+        w.put(&[0; 8]);
+        w.put(&self.battery_remaining.to_le_bytes());
+    }
+}
+
+/// Angles in radians, rates in radians per second.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Attitude {
+    pub time_boot_ms: u32,
+    pub roll: f32,
+    pub pitch: f32,
+    pub yaw: f32,
+    pub rollspeed: f32,
+    pub pitchspeed: f32,
+    pub yawspeed: f32,
+}
+
+impl MavlinkMessage for Attitude {
+    const ID: u32 = 30;
+    const CRC_EXTRA: u8 = 39;
+    const LEN: usize = 28;
+
+    fn serialize(&self, payload: &mut [u8]) {
+        let mut w = Writer::new(payload);
+        w.put(&self.time_boot_ms.to_le_bytes());
+        for value in [
+            self.roll,
+            self.pitch,
+            self.yaw,
+            self.rollspeed,
+            self.pitchspeed,
+            self.yawspeed,
+        ] {
+            w.put(&value.to_le_bytes());
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct VfrHud {
+    pub airspeed: f32,
+    pub groundspeed: f32,
+    /// Altitude in meters.
+    pub alt: f32,
+    /// Climb rate in meters per second.
+    pub climb: f32,
+    /// Compass heading in degrees, 0..360.
+    pub heading: i16,
+    /// Throttle in percent.
+    pub throttle: u16,
+}
+
+impl MavlinkMessage for VfrHud {
+    const ID: u32 = 74;
+    const CRC_EXTRA: u8 = 20;
+    const LEN: usize = 20;
+
+    fn serialize(&self, payload: &mut [u8]) {
+        let mut w = Writer::new(payload);
+        w.put(&self.airspeed.to_le_bytes());
+        w.put(&self.groundspeed.to_le_bytes());
+        w.put(&self.alt.to_le_bytes());
+        w.put(&self.climb.to_le_bytes());
+        w.put(&self.heading.to_le_bytes());
+        w.put(&self.throttle.to_le_bytes());
+    }
+}
+
+/// Signal strengths are 0-254, with 255 meaning unknown.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct RadioStatus {
+    pub rxerrors: u16,
+    pub fixed: u16,
+    pub rssi: u8,
+    pub remrssi: u8,
+    /// Remaining free transmit buffer space in percent.
+    pub txbuf: u8,
+    pub noise: u8,
+    pub remnoise: u8,
+}
+
+impl MavlinkMessage for RadioStatus {
+    const ID: u32 = 109;
+    const CRC_EXTRA: u8 = 185;
+    const LEN: usize = 9;
+
+    fn serialize(&self, payload: &mut [u8]) {
+        let mut w = Writer::new(payload);
+        w.put(&self.rxerrors.to_le_bytes());
+        w.put(&self.fixed.to_le_bytes());
+        w.put(&[
+            self.rssi,
+            self.remrssi,
+            self.txbuf,
+            self.noise,
+            self.remnoise,
+        ]);
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct CommandLong {
+    pub params: [f32; 7],
+    pub command: u16,
+    pub target_system: u8,
+    pub target_component: u8,
+    pub confirmation: u8,
+}
+
+impl CommandLong {
+    pub const ID: u32 = 76;
+    pub const CRC_EXTRA: u8 = 152;
+
+    pub fn deserialize(payload: &[u8]) -> Self {
+        let mut r = Reader::new(payload);
+        let params = core::array::from_fn(|_| f32::from_le_bytes(r.take()));
+        let command = u16::from_le_bytes(r.take());
+        let [target_system, target_component, confirmation] = r.take();
+
+        Self {
+            params,
+            command,
+            target_system,
+            target_component,
+            confirmation,
+        }
+    }
+
+    /// The drone command this asks for, if it is one of the supported ones.
+    pub fn to_command(&self) -> Option<Command> {
+        let [p1, p2, p3, ..] = self.params;
+        match self.command {
+            MAV_CMD_COMPONENT_ARM_DISARM if p1 == 1.0 => Some(Command::Arm),
+            MAV_CMD_COMPONENT_ARM_DISARM if p1 == 0.0 => Some(Command::Disarm),
+            MAV_CMD_DO_SET_MODE => FlightMode::try_from(p2 as u8)
+                .ok()
+                .map(Command::SetFlightMode),
+            MAV_CMD_PREFLIGHT_CALIBRATION if p1 == 1.0 => Some(Command::CalibrateGyro),
+            MAV_CMD_PREFLIGHT_CALIBRATION if p3 == 1.0 => Some(Command::ZeroBarometer),
+            MAV_CMD_PREFLIGHT_REBOOT_SHUTDOWN if p1 == 1.0 => Some(Command::Reboot),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct CommandAck {
+    pub command: u16,
+    pub result: u8,
+}
+
+impl CommandAck {
+    /// The ack for a command that came in as a COMMAND_LONG. `result` is `None` if the drone never
+    /// answered.
+    pub fn new(command: &Command, result: Option<CommandResult>) -> Self {
+        let result = match result {
+            Some(CommandResult::Accepted) => MAV_RESULT_ACCEPTED,
+            Some(CommandResult::Rejected) => MAV_RESULT_TEMPORARILY_REJECTED,
+            Some(CommandResult::Unsupported) => MAV_RESULT_UNSUPPORTED,
+            Some(CommandResult::Invalid) => MAV_RESULT_DENIED,
+            None => MAV_RESULT_FAILED,
+        };
+
+        Self {
+            command: mav_cmd(command),
+            result,
+        }
+    }
+}
+
+impl MavlinkMessage for CommandAck {
+    const ID: u32 = 77;
+    const CRC_EXTRA: u8 = 143;
+    const LEN: usize = 3;
+
+    fn serialize(&self, payload: &mut [u8]) {
+        let mut w = Writer::new(payload);
+        w.put(&self.command.to_le_bytes());
+        w.put(&[self.result]);
+    }
+}
+
+/// The MAV_CMD that maps to `command`.
+fn mav_cmd(command: &Command) -> u16 {
+    match command {
+        Command::Arm | Command::Disarm => MAV_CMD_COMPONENT_ARM_DISARM,
+        Command::SetFlightMode(_) => MAV_CMD_DO_SET_MODE,
+        Command::ZeroBarometer | Command::CalibrateGyro => MAV_CMD_PREFLIGHT_CALIBRATION,
+        Command::Reboot => MAV_CMD_PREFLIGHT_REBOOT_SHUTDOWN,
+        // There is no MAVLink equivalent that takes the same arguments.
+        Command::SetHopBlacklist(_) | Command::SetLinkProfile(_) => 0,
+    }
+}
+
+/// The CRC extra of every message the parser accepts.
+fn crc_extra(message_id: u32) -> Option<u8> {
+    match message_id {
+        Heartbeat::ID => Some(Heartbeat::CRC_EXTRA),
+        CommandLong::ID => Some(CommandLong::CRC_EXTRA),
+        _ => None,
+    }
+}
+
+/// Frames messages as one source, numbering the packets it sends.
+pub struct MavlinkEncoder {
+    system_id: u8,
+    component_id: u8,
+    sequence: u8,
+}
+
+impl MavlinkEncoder {
+    pub fn new(system_id: u8, component_id: u8) -> Self {
+        Self {
+            system_id,
+            component_id,
+            sequence: 0,
+        }
+    }
+
+    /// Encodes `message` into a packet in `buf` and returns its size.
+    pub fn encode<M: MavlinkMessage>(
+        &mut self,
+        message: &M,
+        buf: &mut [u8],
+    ) -> Result<usize, MavlinkError> {
+        if buf.len() < HEADER_SIZE + M::LEN + CHECKSUM_SIZE {
+            return Err(MavlinkError::BufferTooSmall);
+        }
+
+        let payload = &mut buf[HEADER_SIZE..HEADER_SIZE + M::LEN];
+        payload.fill(0);
+        message.serialize(payload);
+        // MAVLink 2 drops trailing zeroes, but always keeps the first byte.
+        let len = payload.iter().rposition(|b| *b != 0).map_or(1, |i| i + 1);
+
+        let id = M::ID.to_le_bytes();
+        buf[..HEADER_SIZE].copy_from_slice(&[
+            MAVLINK_V2_STX,
+            len as u8,
+            0,
+            0,
+            self.sequence,
+            self.system_id,
+            self.component_id,
+            id[0],
+            id[1],
+            id[2],
+        ]);
+        self.sequence = self.sequence.wrapping_add(1);
+
+        let end = HEADER_SIZE + len;
+        let checksum = crc_accumulate(crc(&buf[1..end]), &[M::CRC_EXTRA]);
+        buf[end..end + CHECKSUM_SIZE].copy_from_slice(&checksum.to_le_bytes());
+
+        Ok(end + CHECKSUM_SIZE)
+    }
+}
+
+/// A received packet that passed its checksum.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MavlinkFrame {
+    pub sequence: u8,
+    pub system_id: u8,
+    pub component_id: u8,
+    pub message_id: u32,
+    payload: [u8; MAX_PAYLOAD_SIZE],
+    payload_len: usize,
+}
+
+impl MavlinkFrame {
+    /// The payload as received, i.e. with trailing zeroes possibly truncated.
+    pub fn payload(&self) -> &[u8] {
+        &self.payload[..self.payload_len]
+    }
+}
+
+/// Picks MAVLink v2 packets out of a byte stream.
+///
+/// Anything that isn't a valid packet of a known message is skipped, so the stream may be shared
+/// with other output.
+pub struct MavlinkParser {
+    buf: [u8; MAX_PACKET_SIZE],
+    len: usize,
+}
+
+impl MavlinkParser {
+    pub fn new() -> Self {
+        Self {
+            buf: [0; MAX_PACKET_SIZE],
+            len: 0,
+        }
+    }
+
+    /// Feeds one byte and returns a packet if it completed one.
+    pub fn push(&mut self, byte: u8) -> Option<MavlinkFrame> {
+        if self.len == 0 && byte != MAVLINK_V2_STX {
+            return None;
+        }
+        self.buf[self.len] = byte;
+        self.len += 1;
+
+        loop {
+            if self.len < HEADER_SIZE {
+                return None;
+            }
+            let payload_len = self.buf[1] as usize;
+            let signed = self.buf[2] & INCOMPAT_FLAG_SIGNED != 0;
+            let signature_len = if signed { SIGNATURE_SIZE } else { 0 };
+            let packet_len = HEADER_SIZE + payload_len + CHECKSUM_SIZE + signature_len;
+            if self.len < packet_len {
+                return None;
+            }
+
+            if let Some(frame) = self.check(payload_len, signed) {
+                self.consume(packet_len);
+                return Some(frame);
+            }
+            // A real packet may have started inside the rejected one, so continue from the next
+            // STX. The bytes after it may already hold a complete packet.
+            self.consume(1);
+        }
+    }
+
+    fn check(&self, payload_len: usize, signed: bool) -> Option<MavlinkFrame> {
+        let message_id = u32::from_le_bytes([self.buf[7], self.buf[8], self.buf[9], 0]);
+        let extra = crc_extra(message_id)?;
+        if signed {
+            return None;
+        }
+
+        let end = HEADER_SIZE + payload_len;
+        let checksum = crc_accumulate(crc(&self.buf[1..end]), &[extra]);
+        if checksum.to_le_bytes() != self.buf[end..end + CHECKSUM_SIZE] {
+            return None;
+        }
+
+        let mut payload = [0; MAX_PAYLOAD_SIZE];
+        payload[..payload_len].copy_from_slice(&self.buf[HEADER_SIZE..end]);
+        Some(MavlinkFrame {
+            sequence: self.buf[4],
+            system_id: self.buf[5],
+            component_id: self.buf[6],
+            message_id,
+            payload,
+            payload_len,
+        })
+    }
+
+    /// Drops the first `count` buffered bytes and anything up to the next STX after them.
+    fn consume(&mut self, count: usize) {
+        let next = self.buf[count..self.len]
+            .iter()
+            .position(|b| *b == MAVLINK_V2_STX)
+            .map_or(self.len, |i| count + i);
+        self.buf.copy_within(next..self.len, 0);
+        self.len -= next;
+    }
+}
+
+impl Default for MavlinkParser {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The expected packets were produced with an independent implementation of the MAVLink 2
+    // framing rules from the protocol documentation.
+    const HEARTBEAT: [u8; 21] = [
+        0xFD, 0x09, 0x00, 0x00, 0x00, 0x01, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02,
+        0x00, 0x81, 0x04, 0x03, 0x53, 0xFB,
+    ];
+    const ATTITUDE: [u8; 28] = [
+        0xFD, 0x10, 0x00, 0x00, 0x05, 0x01, 0x01, 0x1E, 0x00, 0x00, 0xE8, 0x03, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x3F, 0x00, 0x00, 0x80, 0xBE, 0x00, 0x00, 0x80, 0x3F, 0x59, 0xCE,
+    ];
+    const VFR_HUD: [u8; 28] = [
+        0xFD, 0x10, 0x00, 0x00, 0x01, 0x01, 0x01, 0x4A, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x48, 0x41, 0x00, 0x00, 0x00, 0xBF, 0x96, 0x3A,
+    ];
+    const COMMAND_ACK: [u8; 14] = [
+        0xFD, 0x02, 0x00, 0x00, 0x07, 0x01, 0x01, 0x4D, 0x00, 0x00, 0x90, 0x01, 0x42, 0xB1,
+    ];
+    /// COMMAND_LONG from a ground station (255/190): arm, targeting 1/1.
+    const ARM: [u8; 44] = [
+        0xFD, 0x20, 0x00, 0x00, 0x09, 0xFF, 0xBE, 0x4C, 0x00, 0x00, 0x00, 0x00, 0x80, 0x3F, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x90, 0x01, 0x01, 0x01, 0x22, 0x43,
+    ];
+
+    fn encode_at<M: MavlinkMessage>(sequence: u8, message: &M) -> ([u8; MAX_PACKET_SIZE], usize) {
+        let mut encoder = MavlinkEncoder::new(1, 1);
+        encoder.sequence = sequence;
+        let mut buf = [0; MAX_PACKET_SIZE];
+        let len = encoder.encode(message, &mut buf).unwrap();
+        (buf, len)
+    }
+
+    #[test]
+    fn crc_check_value() {
+        assert_eq!(crc(b"123456789"), 0x6F91);
+    }
+
+    #[test]
+    fn encode_heartbeat() {
+        let heartbeat = Heartbeat {
+            custom_mode: 0,
+            mav_type: MAV_TYPE_QUADROTOR,
+            autopilot: MAV_AUTOPILOT_GENERIC,
+            base_mode: MAV_MODE_FLAG_CUSTOM_MODE_ENABLED | MAV_MODE_FLAG_SAFETY_ARMED,
+            system_status: MAV_STATE_ACTIVE,
+        };
+        let (buf, len) = encode_at(0, &heartbeat);
+        assert_eq!(&buf[..len], &HEARTBEAT);
+    }
+
+    #[test]
+    fn encode_attitude_truncates_trailing_zeroes() {
+        let attitude = Attitude {
+            time_boot_ms: 1000,
+            roll: 0.5,
+            pitch: -0.25,
+            yaw: 1.0,
+            ..Default::default()
+        };
+        let (buf, len) = encode_at(5, &attitude);
+        assert_eq!(&buf[..len], &ATTITUDE);
+    }
+
+    #[test]
+    fn encode_vfr_hud() {
+        let hud = VfrHud {
+            alt: 12.5,
+            climb: -0.5,
+            ..Default::default()
+        };
+        let (buf, len) = encode_at(1, &hud);
+        assert_eq!(&buf[..len], &VFR_HUD);
+    }
+
+    #[test]
+    fn encode_command_ack() {
+        let ack = CommandAck::new(&Command::Arm, Some(CommandResult::Accepted));
+        let (buf, len) = encode_at(7, &ack);
+        assert_eq!(&buf[..len], &COMMAND_ACK);
+    }
+
+    #[test]
+    fn encode_keeps_first_payload_byte() {
+        let (buf, len) = encode_at(0, &RadioStatus::default());
+        assert_eq!(buf[1], 1);
+        assert_eq!(len, HEADER_SIZE + 1 + CHECKSUM_SIZE);
+    }
+
+    #[test]
+    fn encoder_numbers_packets() {
+        let mut encoder = MavlinkEncoder::new(1, 1);
+        let mut buf = [0; MAX_PACKET_SIZE];
+        for sequence in 0..3 {
+            encoder.encode(&Heartbeat::default(), &mut buf).unwrap();
+            assert_eq!(buf[4], sequence);
+        }
+    }
+
+    #[test]
+    fn encode_buffer_too_small() {
+        let mut encoder = MavlinkEncoder::new(1, 1);
+        let mut buf = [0; 20];
+        assert_eq!(
+            encoder.encode(&Heartbeat::default(), &mut buf),
+            Err(MavlinkError::BufferTooSmall)
+        );
+    }
+
+    fn parse(bytes: &[u8]) -> Option<MavlinkFrame> {
+        let mut parser = MavlinkParser::new();
+        let mut frame = None;
+        for byte in bytes {
+            if let Some(f) = parser.push(*byte) {
+                frame = Some(f);
+            }
+        }
+        frame
+    }
+
+    #[test]
+    fn parse_command_long() {
+        let frame = parse(&ARM).unwrap();
+        assert_eq!(frame.sequence, 9);
+        assert_eq!(frame.system_id, 255);
+        assert_eq!(frame.component_id, 190);
+        assert_eq!(frame.message_id, CommandLong::ID);
+
+        let command = CommandLong::deserialize(frame.payload());
+        assert_eq!(command.command, MAV_CMD_COMPONENT_ARM_DISARM);
+        assert_eq!(command.target_system, 1);
+        assert_eq!(command.params[0], 1.0);
+        assert_eq!(command.to_command(), Some(Command::Arm));
+    }
+
+    #[test]
+    fn parse_skips_noise_and_corrupt_packets() {
+        let mut stream = [0u8; 3 + 44 + 5 + 44];
+        stream[..3].copy_from_slice(b"hi\n");
+        stream[3..47].copy_from_slice(&ARM);
+        stream[20] ^= 0x01;
+        stream[47..52].copy_from_slice(&[0xFD, 0x00, 0x42, 0x13, 0x37]);
+        stream[52..].copy_from_slice(&ARM);
+
+        let mut parser = MavlinkParser::new();
+        let frames = stream.iter().filter_map(|b| parser.push(*b)).count();
+        assert_eq!(frames, 1);
+    }
+
+    #[test]
+    fn parse_ignores_unknown_messages() {
+        // Our own ATTITUDE is valid MAVLink, but not something the parser is meant to accept.
+        assert_eq!(parse(&ATTITUDE), None);
+    }
+
+    #[test]
+    fn round_trip_through_parser() {
+        let mut encoder = MavlinkEncoder::new(1, 1);
+        let mut buf = [0; MAX_PACKET_SIZE];
+        let heartbeat = Heartbeat {
+            custom_mode: FlightMode::Horizon as u32,
+            ..Default::default()
+        };
+        let len = encoder.encode(&heartbeat, &mut buf).unwrap();
+
+        let frame = parse(&buf[..len]).unwrap();
+        assert_eq!(frame.message_id, Heartbeat::ID);
+        assert_eq!(frame.payload()[0], FlightMode::Horizon as u8);
+    }
+
+    #[test]
+    fn command_mapping() {
+        let command = |command, params: [f32; 7]| CommandLong {
+            command,
+            params,
+            ..Default::default()
+        };
+
+        let disarm = command(MAV_CMD_COMPONENT_ARM_DISARM, [0.0; 7]);
+        let mode = command(MAV_CMD_DO_SET_MODE, [1.0, 1.0, 0.0, 0.0, 0.0, 0.0, 0.0]);
+        let baro = command(
+            MAV_CMD_PREFLIGHT_CALIBRATION,
+            [0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 0.0],
+        );
+        let shutdown = command(
+            MAV_CMD_PREFLIGHT_REBOOT_SHUTDOWN,
+            [2.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0],
+        );
+
+        assert_eq!(disarm.to_command(), Some(Command::Disarm));
+        assert_eq!(
+            mode.to_command(),
+            Some(Command::SetFlightMode(FlightMode::Angle))
+        );
+        assert_eq!(baro.to_command(), Some(Command::ZeroBarometer));
+        assert_eq!(shutdown.to_command(), None);
+    }
+}
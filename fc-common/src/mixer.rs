@@ -0,0 +1,461 @@
+//! Turns the throttle and the [rate controller](crate::rate)'s corrections into commands for the
+//! four motors of a quad in X configuration.
+//!
+//! Every motor gets the throttle plus its share of the corrections. When that doesn't fit between
+//! stopped and full power, the corrections win:
+//! - If they don't fit even on their own, they are all scaled down alike, so the drone still turns
+//!   the right way.
+//! - At the top, the throttle is lowered to make room.
+//! - At the bottom, airmode raises the throttle so the drone stays controllable with the stick
+//!   down, e.g. in a flip. Without airmode the corrections are scaled down instead, and vanish
+//!   with the throttle.
+//!
+//! Commands are from 0 to 1, the idle speed up to full. A disarmed drone's motors are stopped
+//! instead, which isn't up to the mixer.
+
+use libm::{cosf, sinf, sqrtf};
+
+use crate::imu::Vector3;
+use crate::param::{Param, ParamStore};
+
+pub const MOTOR_COUNT: usize = 4;
+
+/// Where a motor sits on the frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Position {
+    FrontLeft,
+    FrontRight,
+    RearRight,
+    RearLeft,
+}
+
+/// Which motor each ESC output drives, ESC1 first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MotorOrder {
+    /// Rear right, front right, rear left, front left.
+    Betaflight = 0,
+    /// Front right, rear left, front left, rear right.
+    ArduPilot = 1,
+    /// Clockwise from the front left.
+    Clockwise = 2,
+}
+
+impl MotorOrder {
+    /// `MOT_ORD`, falling back to the Betaflight order.
+    pub fn from_param(value: u8) -> Self {
+        match value {
+            1 => MotorOrder::ArduPilot,
+            2 => MotorOrder::Clockwise,
+            _ => MotorOrder::Betaflight,
+        }
+    }
+
+    pub fn positions(self) -> [Position; MOTOR_COUNT] {
+        use Position::*;
+        match self {
+            MotorOrder::Betaflight => [RearRight, FrontRight, RearLeft, FrontLeft],
+            MotorOrder::ArduPilot => [FrontRight, RearLeft, FrontLeft, RearRight],
+            MotorOrder::Clockwise => [FrontLeft, FrontRight, RearRight, RearLeft],
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MixerConfig {
+    pub order: MotorOrder,
+    /// Whether the props spin outwards at the front, i.e. the front left motor counterclockwise
+    /// seen from above. By default they spin inwards.
+    pub props_out: bool,
+    /// The angle between the front arms and the nose in degrees, 45 for a square X. Less is a
+    /// stretched X, longer than it's wide.
+    pub arm_angle: f32,
+    /// The command a running motor never goes below, from 0 to 1.
+    pub idle: f32,
+    pub airmode: bool,
+    /// How much the thrust grows with the square of the command rather than linearly, from 0 to 1.
+    /// The commands are bent the other way to make up for it.
+    pub thrust_linearization: f32,
+}
+
+impl Default for MixerConfig {
+    fn default() -> Self {
+        Self::from_parameters(&ParamStore::default())
+    }
+}
+
+impl MixerConfig {
+    pub fn from_parameters(parameters: &ParamStore) -> Self {
+        Self {
+            order: MotorOrder::from_param(parameters.get_u8(Param::MotorOrder)),
+            props_out: parameters.get_u8(Param::MotorsReversed) != 0,
+            arm_angle: parameters.get_u8(Param::FrameArmAngle) as f32,
+            idle: parameters.get_u8(Param::MotorIdlePercent) as f32 / 100.0,
+            airmode: parameters.get_u8(Param::Airmode) != 0,
+            thrust_linearization: parameters.get_u8(Param::ThrustLinearPercent) as f32 / 100.0,
+        }
+    }
+}
+
+/// How much of each correction a motor takes, from -1 to 1.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Factors {
+    roll: f32,
+    pitch: f32,
+    yaw: f32,
+}
+
+pub struct Mixer {
+    config: MixerConfig,
+    /// In ESC order.
+    factors: [Factors; MOTOR_COUNT],
+}
+
+impl Mixer {
+    pub fn new(config: MixerConfig) -> Self {
+        // The motors on the shorter lever make up for it with a larger share, so the same
+        // correction gives the same torque on either axis.
+        let angle = config.arm_angle.clamp(1.0, 89.0).to_radians();
+        let (roll_lever, pitch_lever) = (sinf(angle), cosf(angle));
+        let shorter = roll_lever.min(pitch_lever);
+        let (roll, pitch) = (shorter / roll_lever, shorter / pitch_lever);
+        let factors = config.order.positions().map(|position| {
+            let (left, front) = match position {
+                Position::FrontLeft => (true, true),
+                Position::FrontRight => (false, true),
+                Position::RearRight => (false, false),
+                Position::RearLeft => (true, false),
+            };
+            // Props in, the front left motor spins clockwise seen from above, and so do the ones
+            // diagonal to it. Their drag turns the frame the other way, to the left.
+            let clockwise = (left == front) != config.props_out;
+            Factors {
+                // Rolling right and pitching forward take more from the left and rear motors.
+                roll: if left { roll } else { -roll },
+                pitch: if front { -pitch } else { pitch },
+                // Speeding those up yaws left, the positive way about z.
+                yaw: if clockwise { 1.0 } else { -1.0 },
+            }
+        });
+        Self { config, factors }
+    }
+
+    pub fn config(&self) -> &MixerConfig {
+        &self.config
+    }
+
+    /// The command of every motor, in ESC order, for `throttle` from 0 to 1 and the corrections
+    /// about each axis within ±1. A correction of 1 on its own takes everything the motors have:
+    /// half of them at full power and the others stopped. Anything out of range is clamped, and
+    /// anything that isn't a number is taken as 0.
+    pub fn mix(&self, throttle: f32, torque: &Vector3<f32>) -> [f32; MOTOR_COUNT] {
+        let sane = |value: f32, min: f32| {
+            if value.is_nan() {
+                0.0
+            } else {
+                value.clamp(min, 1.0)
+            }
+        };
+        let mut throttle = sane(throttle, 0.0);
+        let torque = torque.map(|correction| sane(correction, -1.0));
+
+        let mut shares = self.factors.map(|factors| {
+            (factors.roll * torque.x + factors.pitch * torque.y + factors.yaw * torque.z) / 2.0
+        });
+        let (lowest, highest) = bounds(&shares);
+        let range = highest - lowest;
+        if range > 1.0 {
+            shares = shares.map(|share| share / range);
+        }
+        let (lowest, highest) = bounds(&shares);
+        if self.config.airmode {
+            // Not `clamp`, which rounding could leave with the bounds crossed by a hair.
+            throttle = throttle.max(-lowest).min(1.0 - highest);
+        } else {
+            throttle = throttle.min(1.0 - highest);
+            if throttle + lowest < 0.0 {
+                let room = throttle / -lowest;
+                shares = shares.map(|share| share * room);
+            }
+        }
+
+        shares.map(|share| {
+            let thrust = (throttle + share).clamp(0.0, 1.0);
+            let command = linearized(thrust, self.config.thrust_linearization);
+            self.config.idle + (1.0 - self.config.idle) * command
+        })
+    }
+}
+
+/// The lowest and highest of `shares`, counting 0 as one of them.
+fn bounds(shares: &[f32; MOTOR_COUNT]) -> (f32, f32) {
+    let lowest = shares.iter().copied().fold(0.0, f32::min);
+    let highest = shares.iter().copied().fold(0.0, f32::max);
+    (lowest, highest)
+}
+
+/// The command that gives `thrust`, for motors whose thrust is `(1 - k) * c + k * c²` at command
+/// `c`.
+fn linearized(thrust: f32, k: f32) -> f32 {
+    if k <= 0.0 {
+        return thrust;
+    }
+    let linear = 1.0 - k;
+    (sqrtf(linear * linear + 4.0 * k * thrust) - linear) / (2.0 * k)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    extern crate std;
+    use std::vec::Vec;
+
+    const EPSILON: f32 = 1e-5;
+
+    /// Square X, props in, no idle and no linearization, in clockwise order from the front left
+    /// to keep the tests readable.
+    fn config(airmode: bool) -> MixerConfig {
+        MixerConfig {
+            order: MotorOrder::Clockwise,
+            props_out: false,
+            arm_angle: 45.0,
+            idle: 0.0,
+            airmode,
+            thrust_linearization: 0.0,
+        }
+    }
+
+    fn torque(roll: f32, pitch: f32, yaw: f32) -> Vector3<f32> {
+        Vector3::new(roll, pitch, yaw)
+    }
+
+    fn assert_motors(actual: [f32; MOTOR_COUNT], expected: [f32; MOTOR_COUNT]) {
+        let close = actual
+            .iter()
+            .zip(expected)
+            .all(|(actual, expected)| (actual - expected).abs() < EPSILON);
+        assert!(close, "{actual:?} vs {expected:?}");
+    }
+
+    /// The throttle the motors average to, and the differences to it.
+    fn split(motors: [f32; MOTOR_COUNT]) -> (f32, [f32; MOTOR_COUNT]) {
+        let mean = motors.iter().sum::<f32>() / MOTOR_COUNT as f32;
+        (mean, motors.map(|motor| motor - mean))
+    }
+
+    #[test]
+    fn mixes_each_axis() {
+        let mixer = Mixer::new(config(true));
+        // Front left, front right, rear right, rear left.
+        assert_motors(mixer.mix(0.5, &torque(0.0, 0.0, 0.0)), [0.5; 4]);
+        assert_motors(mixer.mix(0.5, &torque(0.2, 0.0, 0.0)), [0.6, 0.4, 0.4, 0.6]);
+        assert_motors(mixer.mix(0.5, &torque(0.0, 0.2, 0.0)), [0.4, 0.4, 0.6, 0.6]);
+        assert_motors(mixer.mix(0.5, &torque(0.0, 0.0, 0.2)), [0.6, 0.4, 0.6, 0.4]);
+        // A full correction takes the whole range.
+        assert_motors(mixer.mix(0.5, &torque(1.0, 0.0, 0.0)), [1.0, 0.0, 0.0, 1.0]);
+    }
+
+    #[test]
+    fn props_out_reverse_yaw() {
+        let props_out = Mixer::new(MixerConfig {
+            props_out: true,
+            ..config(true)
+        });
+        assert_motors(
+            props_out.mix(0.5, &torque(0.0, 0.0, 0.2)),
+            [0.4, 0.6, 0.4, 0.6],
+        );
+        assert_motors(
+            props_out.mix(0.5, &torque(0.2, 0.0, 0.0)),
+            [0.6, 0.4, 0.4, 0.6],
+        );
+    }
+
+    #[test]
+    fn follows_the_motor_order() {
+        let demand = torque(0.3, -0.2, 0.1);
+        let clockwise = Mixer::new(config(true)).mix(0.5, &demand);
+        for order in [MotorOrder::Betaflight, MotorOrder::ArduPilot] {
+            let mixer = Mixer::new(MixerConfig {
+                order,
+                ..config(true)
+            });
+            let expected = order.positions().map(|position| {
+                let index = MotorOrder::Clockwise
+                    .positions()
+                    .iter()
+                    .position(|p| *p == position);
+                clockwise[index.unwrap()]
+            });
+            assert_motors(mixer.mix(0.5, &demand), expected);
+        }
+        assert_eq!(MotorOrder::from_param(1), MotorOrder::ArduPilot);
+        assert_eq!(MotorOrder::from_param(200), MotorOrder::Betaflight);
+    }
+
+    #[test]
+    fn stretched_frames_even_out_the_levers() {
+        // Front arms 30° off the nose: the motors are further apart front to back than side to
+        // side, so pitching takes a smaller difference than rolling.
+        let stretched = Mixer::new(MixerConfig {
+            arm_angle: 30.0,
+            ..config(true)
+        });
+        let (_, roll) = split(stretched.mix(0.5, &torque(0.4, 0.0, 0.0)));
+        let (_, pitch) = split(stretched.mix(0.5, &torque(0.0, 0.4, 0.0)));
+        assert!((roll[0] - 0.2).abs() < EPSILON, "{roll:?}");
+        let expected = 0.2 * 30f32.to_radians().tan();
+        assert!((pitch[2] - expected).abs() < EPSILON, "{pitch:?}");
+    }
+
+    #[test]
+    fn corrections_beyond_the_range_are_scaled_alike() {
+        let mixer = Mixer::new(config(true));
+        // Roll and yaw add up to a spread of 1.2 between the front motors.
+        let (throttle, differences) = split(mixer.mix(0.5, &torque(0.8, 0.0, 0.4)));
+        assert!((throttle - 0.5).abs() < EPSILON);
+        let spread = differences.iter().copied().fold(f32::MIN, f32::max)
+            - differences.iter().copied().fold(f32::MAX, f32::min);
+        assert!((spread - 1.0).abs() < EPSILON, "{differences:?}");
+        // Roll still makes up twice as much as yaw.
+        let roll = (differences[0] + differences[3] - differences[1] - differences[2]) / 4.0;
+        let yaw = (differences[0] + differences[2] - differences[1] - differences[3]) / 4.0;
+        assert!((roll / yaw - 2.0).abs() < EPSILON, "{roll} {yaw}");
+    }
+
+    #[test]
+    fn lowers_the_throttle_at_the_top() {
+        for airmode in [true, false] {
+            let mixer = Mixer::new(config(airmode));
+            assert_motors(mixer.mix(1.0, &torque(0.0, 0.0, 0.0)), [1.0; 4]);
+            // The roll difference is kept in full.
+            assert_motors(mixer.mix(1.0, &torque(0.4, 0.0, 0.0)), [1.0, 0.6, 0.6, 1.0]);
+            assert_motors(mixer.mix(0.9, &torque(0.4, 0.0, 0.0)), [1.0, 0.6, 0.6, 1.0]);
+            assert_motors(mixer.mix(0.7, &torque(0.4, 0.0, 0.0)), [0.9, 0.5, 0.5, 0.9]);
+        }
+    }
+
+    #[test]
+    fn airmode_raises_the_throttle_at_the_bottom() {
+        let mixer = Mixer::new(config(true));
+        assert_motors(mixer.mix(0.0, &torque(0.0, 0.0, 0.0)), [0.0; 4]);
+        assert_motors(mixer.mix(0.0, &torque(0.4, 0.0, 0.0)), [0.4, 0.0, 0.0, 0.4]);
+        assert_motors(mixer.mix(0.1, &torque(0.4, 0.0, 0.0)), [0.4, 0.0, 0.0, 0.4]);
+        assert_motors(mixer.mix(0.3, &torque(0.4, 0.0, 0.0)), [0.5, 0.1, 0.1, 0.5]);
+        // A full flip at zero throttle.
+        assert_motors(
+            mixer.mix(0.0, &torque(-1.0, 0.0, 0.0)),
+            [0.0, 1.0, 1.0, 0.0],
+        );
+    }
+
+    #[test]
+    fn without_airmode_corrections_fade_out_with_the_throttle() {
+        let mixer = Mixer::new(config(false));
+        assert_motors(mixer.mix(0.0, &torque(0.4, 0.3, 0.2)), [0.0; 4]);
+        assert_motors(mixer.mix(0.1, &torque(0.4, 0.0, 0.0)), [0.2, 0.0, 0.0, 0.2]);
+        assert_motors(mixer.mix(0.3, &torque(0.4, 0.0, 0.0)), [0.5, 0.1, 0.1, 0.5]);
+        // Still too much for the range, and too little throttle for what's left.
+        assert_motors(
+            mixer.mix(0.25, &torque(2.0, 0.0, 0.0)),
+            [0.5, 0.0, 0.0, 0.5],
+        );
+    }
+
+    #[test]
+    fn sanitizes_the_input() {
+        let mixer = Mixer::new(config(true));
+        assert_motors(mixer.mix(f32::NAN, &torque(f32::NAN, 0.0, 0.0)), [0.0; 4]);
+        assert_motors(mixer.mix(-1.0, &torque(0.0, 0.0, 0.0)), [0.0; 4]);
+        assert_motors(mixer.mix(2.0, &torque(0.0, 0.0, 0.0)), [1.0; 4]);
+        assert_eq!(
+            mixer.mix(0.5, &torque(f32::INFINITY, 0.0, 0.0)),
+            mixer.mix(0.5, &torque(1.0, 0.0, 0.0))
+        );
+        assert_eq!(
+            mixer.mix(0.5, &torque(0.0, f32::NEG_INFINITY, 0.0)),
+            mixer.mix(0.5, &torque(0.0, -1.0, 0.0))
+        );
+    }
+
+    #[test]
+    fn idles_and_linearizes() {
+        let mixer = Mixer::new(MixerConfig {
+            idle: 0.05,
+            thrust_linearization: 0.5,
+            ..config(true)
+        });
+        assert_motors(mixer.mix(0.0, &torque(0.0, 0.0, 0.0)), [0.05; 4]);
+        assert_motors(mixer.mix(1.0, &torque(0.0, 0.0, 0.0)), [1.0; 4]);
+
+        // Half the thrust takes more than half the command, as much as the model says.
+        let command = (mixer.mix(0.5, &torque(0.0, 0.0, 0.0))[0] - 0.05) / 0.95;
+        let thrust = 0.5 * command + 0.5 * command * command;
+        assert!(command > 0.5 && (thrust - 0.5).abs() < EPSILON, "{command}");
+
+        for k in [0.0, 0.3, 1.0] {
+            assert_eq!(linearized(0.0, k), 0.0);
+            assert!((linearized(1.0, k) - 1.0).abs() < EPSILON);
+        }
+        assert_eq!(linearized(0.3, 0.0), 0.3);
+    }
+
+    #[test]
+    fn defaults_from_the_parameters() {
+        let config = MixerConfig::default();
+        assert_eq!(config.order, MotorOrder::Betaflight);
+        assert!(config.airmode && !config.props_out);
+        assert_eq!((config.arm_angle, config.idle), (45.0, 0.05));
+        assert_eq!(config.thrust_linearization, 0.0);
+    }
+
+    /// Every combination of these, in every configuration.
+    #[test]
+    fn stays_in_range_everywhere() {
+        let steps = [-1.0, -0.7, -0.3, 0.0, 0.1, 0.5, 0.9, 1.0];
+        let throttles = [0.0, 0.05, 0.3, 0.5, 0.8, 0.95, 1.0];
+        let mut configs = Vec::new();
+        for airmode in [true, false] {
+            for (idle, thrust_linearization) in [(0.0, 0.0), (0.05, 0.0), (0.1, 0.7)] {
+                for arm_angle in [30.0, 45.0, 60.0] {
+                    configs.push(MixerConfig {
+                        arm_angle,
+                        idle,
+                        thrust_linearization,
+                        ..config(airmode)
+                    });
+                }
+            }
+        }
+
+        for config in configs {
+            let mixer = Mixer::new(config);
+            for throttle in throttles {
+                for roll in steps {
+                    for pitch in steps {
+                        for yaw in steps {
+                            let demand = torque(roll, pitch, yaw);
+                            let motors = mixer.mix(throttle, &demand);
+                            let in_range = motors.iter().all(|motor| {
+                                (config.idle - EPSILON..=1.0 + EPSILON).contains(motor)
+                            });
+                            assert!(in_range, "{config:?} {throttle} {demand:?}: {motors:?}");
+
+                            // With airmode, whatever fits in the range is never cut.
+                            let plain = Mixer::new(MixerConfig {
+                                idle: 0.0,
+                                thrust_linearization: 0.0,
+                                ..config
+                            });
+                            let (_, differences) = split(plain.mix(throttle, &demand));
+                            let (_, unmixed) = split(plain.mix(0.5, &demand));
+                            if config.airmode {
+                                assert_motors(differences, unmixed);
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
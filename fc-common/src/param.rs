@@ -0,0 +1,740 @@
+//! Tunable parameters, and the protocol used to read and change them over the radio link.
+//!
+//! [`PARAMS`] describes every parameter. The drone keeps the current values in a [`ParamStore`]
+//! and answers [`ParamRequest`]s with [`ParamResponse`]s. Requests are idempotent, so the
+//! controller's [`ParamClient`] simply resends them until a matching response arrives.
+
+use embassy_time::Instant;
+use zerocopy::{FromBytes, Immutable, IntoBytes};
+
+use crate::command::{COMMAND_MAX_ATTEMPTS, COMMAND_RETRY_INTERVAL};
+
+/// Parameter names are at most this many bytes of ASCII.
+pub const PARAM_NAME_SIZE: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ParamValue {
+    U8(u8),
+    U16(u16),
+    U32(u32),
+    F32(f32),
+}
+
+impl ParamValue {
+    const U8_TYPE: u8 = 0;
+    const U16_TYPE: u8 = 1;
+    const U32_TYPE: u8 = 2;
+    const F32_TYPE: u8 = 3;
+
+    /// The type tag and little-endian bytes used on the wire.
+    pub fn to_wire(&self) -> (u8, [u8; 4]) {
+        match *self {
+            ParamValue::U8(v) => (Self::U8_TYPE, (v as u32).to_le_bytes()),
+            ParamValue::U16(v) => (Self::U16_TYPE, (v as u32).to_le_bytes()),
+            ParamValue::U32(v) => (Self::U32_TYPE, v.to_le_bytes()),
+            ParamValue::F32(v) => (Self::F32_TYPE, v.to_le_bytes()),
+        }
+    }
+
+    pub fn from_wire(value_type: u8, bytes: [u8; 4]) -> Option<ParamValue> {
+        let raw = u32::from_le_bytes(bytes);
+        match value_type {
+            Self::U8_TYPE => u8::try_from(raw).ok().map(ParamValue::U8),
+            Self::U16_TYPE => u16::try_from(raw).ok().map(ParamValue::U16),
+            Self::U32_TYPE => Some(ParamValue::U32(raw)),
+            Self::F32_TYPE => Some(ParamValue::F32(f32::from_bits(raw))),
+            _ => None,
+        }
+    }
+
+    fn same_type(&self, other: &ParamValue) -> bool {
+        core::mem::discriminant(self) == core::mem::discriminant(other)
+    }
+
+    /// Whether `self` lies within `min..=max`. Values of another type are never in range.
+    fn in_range(&self, min: &ParamValue, max: &ParamValue) -> bool {
+        match (*self, *min, *max) {
+            (ParamValue::U8(v), ParamValue::U8(min), ParamValue::U8(max)) => {
+                (min..=max).contains(&v)
+            }
+            (ParamValue::U16(v), ParamValue::U16(min), ParamValue::U16(max)) => {
+                (min..=max).contains(&v)
+            }
+            (ParamValue::U32(v), ParamValue::U32(min), ParamValue::U32(max)) => {
+                (min..=max).contains(&v)
+            }
+            (ParamValue::F32(v), ParamValue::F32(min), ParamValue::F32(max)) => {
+                (min..=max).contains(&v)
+            }
+            _ => false,
+        }
+    }
+}
+
+impl defmt::Format for ParamValue {
+    fn format(&self, fmt: defmt::Formatter) {
+        match self {
+            ParamValue::U8(v) => defmt::write!(fmt, "{}", v),
+            ParamValue::U16(v) => defmt::write!(fmt, "{}", v),
+            ParamValue::U32(v) => defmt::write!(fmt, "{}", v),
+            ParamValue::F32(v) => defmt::write!(fmt, "{}", v),
+        }
+    }
+}
+
+/// Everything known about a parameter at compile time.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ParamInfo {
+    pub param: Param,
+    pub name: &'static str,
+    pub default: ParamValue,
+    pub min: ParamValue,
+    pub max: ParamValue,
+    /// The parameter is only read at boot. The drone doesn't store parameters, so a change would
+    /// never take effect, and setting it is refused with [`ParamStatus::ReadOnly`].
+    pub reboot_required: bool,
+}
+
+/// Declares the parameter table.
+///
+/// Each entry gives a [`Param`] variant, its id, name, type, default and range. The id doubles as
+/// the position in [`PARAMS`], so new parameters must be appended to keep existing ids stable.
+macro_rules! params {
+    ($($Variant:ident = $id:literal, $name:literal, $Ty:ident, $default:literal, $min:literal..=$max:literal, $reboot:literal;)*) => {
+        #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+        #[repr(u16)]
+        pub enum Param {
+            $($Variant = $id,)*
+        }
+
+        pub const PARAMS: &[ParamInfo] = &[
+            $(ParamInfo {
+                param: Param::$Variant,
+                name: $name,
+                default: ParamValue::$Ty($default),
+                min: ParamValue::$Ty($min),
+                max: ParamValue::$Ty($max),
+                reboot_required: $reboot,
+            },)*
+        ];
+
+        const _: () = {
+            let mut i = 0;
+            while i < PARAMS.len() {
+                assert!(PARAMS[i].param as usize == i, "Parameter ids must match their position");
+                assert!(PARAMS[i].name.len() <= PARAM_NAME_SIZE, "Parameter name is too long");
+                i += 1;
+            }
+        };
+    };
+}
+
+params! {
+    // Battery thresholds, see `fc::bms`.
+    BatteryCutoffMv = 0, "BAT_CUT", U16, 7000, 6000..=8400, false;
+    BatteryCriticalMv = 1, "BAT_CRIT", U16, 7300, 6000..=8400, false;
+    BatteryLowMv = 2, "BAT_LOW", U16, 7500, 6000..=8400, false;
+    // Barometer pressure oversampling as a power of two, i.e. 3 means 8x.
+    BaroOversampling = 3, "BARO_OSR", U8, 3, 0..=5, true;
+    // IMU full-scale ranges as the sensor's setting, 0 to 3 for 250 to 2000 °/s and 2 to 16 g.
+    ImuGyroRange = 4, "GYRO_FS", U8, 3, 0..=3, true;
+    ImuAccelRange = 5, "ACC_FS", U8, 3, 0..=3, true;
+    // The IMU samples at 1125 Hz / (1 + IMU_DIV).
+    ImuRateDivider = 6, "IMU_DIV", U8, 1, 0..=255, true;
+    // Attitude estimator gains, see `attitude::MahonyGains`.
+    AttitudeKp = 7, "ATT_KP", F32, 2.0, 0.0..=20.0, false;
+    AttitudeKi = 8, "ATT_KI", F32, 0.05, 0.0..=1.0, false;
+    // Rate controller gains, see `rate::RateGains`. Yaw has no D term.
+    RateRollPitchP = 9, "RATE_P", F32, 0.1, 0.0..=1.0, false;
+    RateRollPitchI = 10, "RATE_I", F32, 0.5, 0.0..=5.0, false;
+    RateRollPitchD = 11, "RATE_D", F32, 0.002, 0.0..=0.05, false;
+    RateYawP = 12, "YAW_P", F32, 0.2, 0.0..=2.0, false;
+    RateYawI = 13, "YAW_I", F32, 1.0, 0.0..=10.0, false;
+    RateFeedforward = 14, "RATE_FF", F32, 0.005, 0.0..=0.05, false;
+    // The rotation rate asked for with a stick all the way out, in °/s.
+    MaxRateDps = 15, "MAX_RATE", U16, 360, 30..=1800, false;
+    // Throttle PID attenuation: P and D are cut by up to TPA % at full throttle, starting from
+    // TPA_BP % throttle.
+    TpaPercent = 16, "TPA", U8, 50, 0..=100, false;
+    TpaBreakpoint = 17, "TPA_BP", U8, 60, 0..=100, false;
+    // Cutoff of the D term's low-pass filter, in Hz.
+    DTermCutoffHz = 18, "DTERM_HZ", U16, 80, 10..=250, false;
+    // Angle and horizon mode, see `angle::AngleGains`. The gain is in °/s per degree off target.
+    AngleP = 19, "ANG_P", F32, 5.0, 0.0..=20.0, false;
+    MaxAngleDeg = 20, "MAX_ANG", U8, 45, 10..=80, false;
+    // Motor mixer, see `mixer::MixerConfig`. MOT_REV spins every motor the other way, i.e. props
+    // out, ARM_ANG is the angle between the front arms and the nose, and AIRMODE and THR_LIN are
+    // 0 for off.
+    MotorOrder = 21, "MOT_ORD", U8, 0, 0..=2, false;
+    MotorsReversed = 22, "MOT_REV", U8, 0, 0..=1, false;
+    FrameArmAngle = 23, "ARM_ANG", U8, 45, 20..=70, false;
+    MotorIdlePercent = 24, "MOT_IDLE", U8, 5, 0..=20, false;
+    Airmode = 25, "AIRMODE", U8, 1, 0..=1, false;
+    ThrustLinearPercent = 26, "THR_LIN", U8, 0, 0..=100, false;
+}
+
+pub const PARAM_COUNT: usize = PARAMS.len();
+
+impl Param {
+    pub fn info(&self) -> &'static ParamInfo {
+        &PARAMS[*self as usize]
+    }
+
+    pub fn from_id(id: u16) -> Option<Param> {
+        PARAMS.get(id as usize).map(|info| info.param)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum ParamStatus {
+    Ok = 0,
+    UnknownParam = 1,
+    TypeMismatch = 2,
+    OutOfRange = 3,
+    /// The request couldn't be decoded.
+    Invalid = 4,
+    /// The parameter can only be read, see [`ParamInfo::reboot_required`].
+    ReadOnly = 5,
+}
+
+impl TryFrom<u8> for ParamStatus {
+    type Error = u8;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(ParamStatus::Ok),
+            1 => Ok(ParamStatus::UnknownParam),
+            2 => Ok(ParamStatus::TypeMismatch),
+            3 => Ok(ParamStatus::OutOfRange),
+            4 => Ok(ParamStatus::Invalid),
+            5 => Ok(ParamStatus::ReadOnly),
+            _ => Err(value),
+        }
+    }
+}
+
+impl defmt::Format for ParamStatus {
+    fn format(&self, fmt: defmt::Formatter) {
+        match self {
+            ParamStatus::Ok => defmt::write!(fmt, "Ok"),
+            ParamStatus::UnknownParam => defmt::write!(fmt, "UnknownParam"),
+            ParamStatus::TypeMismatch => defmt::write!(fmt, "TypeMismatch"),
+            ParamStatus::OutOfRange => defmt::write!(fmt, "OutOfRange"),
+            ParamStatus::Invalid => defmt::write!(fmt, "Invalid"),
+            ParamStatus::ReadOnly => defmt::write!(fmt, "ReadOnly"),
+        }
+    }
+}
+
+/// The current value of every parameter.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParamStore {
+    values: [ParamValue; PARAM_COUNT],
+}
+
+impl Default for ParamStore {
+    fn default() -> Self {
+        Self {
+            values: core::array::from_fn(|i| PARAMS[i].default),
+        }
+    }
+}
+
+impl ParamStore {
+    pub fn get(&self, param: Param) -> ParamValue {
+        self.values[param as usize]
+    }
+
+    pub fn set(&mut self, param: Param, value: ParamValue) -> Result<(), ParamStatus> {
+        let info = param.info();
+        if !value.same_type(&info.default) {
+            return Err(ParamStatus::TypeMismatch);
+        }
+        if !value.in_range(&info.min, &info.max) {
+            return Err(ParamStatus::OutOfRange);
+        }
+
+        self.values[param as usize] = value;
+        Ok(())
+    }
+
+    /// The value of a `u8` parameter. Panics if `param` is of another type.
+    pub fn get_u8(&self, param: Param) -> u8 {
+        match self.get(param) {
+            ParamValue::U8(value) => value,
+            _ => panic!("{} is not a u8", param.info().name),
+        }
+    }
+
+    /// The value of a `u16` parameter. Panics if `param` is of another type.
+    pub fn get_u16(&self, param: Param) -> u16 {
+        match self.get(param) {
+            ParamValue::U16(value) => value,
+            _ => panic!("{} is not a u16", param.info().name),
+        }
+    }
+
+    /// The value of a `u32` parameter. Panics if `param` is of another type.
+    pub fn get_u32(&self, param: Param) -> u32 {
+        match self.get(param) {
+            ParamValue::U32(value) => value,
+            _ => panic!("{} is not a u32", param.info().name),
+        }
+    }
+
+    /// The value of an `f32` parameter. Panics if `param` is of another type.
+    pub fn get_f32(&self, param: Param) -> f32 {
+        match self.get(param) {
+            ParamValue::F32(value) => value,
+            _ => panic!("{} is not an f32", param.info().name),
+        }
+    }
+
+    /// Carries out `request` and returns the response to send back.
+    pub fn handle(&mut self, request: &ParamRequest) -> ParamResponse {
+        let Some(param) = Param::from_id(request.id) else {
+            return ParamResponse::error(request, ParamStatus::UnknownParam);
+        };
+
+        match ParamOp::try_from(request.op) {
+            Ok(ParamOp::Get) | Ok(ParamOp::List) => ParamResponse::new(request, param, self),
+            Ok(ParamOp::Set) if param.info().reboot_required => {
+                ParamResponse::error(request, ParamStatus::ReadOnly)
+            }
+            Ok(ParamOp::Set) => {
+                let Some(value) = ParamValue::from_wire(request.value_type, request.value) else {
+                    return ParamResponse::error(request, ParamStatus::Invalid);
+                };
+                match self.set(param, value) {
+                    Ok(()) => ParamResponse::new(request, param, self),
+                    Err(status) => ParamResponse::error(request, status),
+                }
+            }
+            Err(_) => ParamResponse::error(request, ParamStatus::Invalid),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum ParamOp {
+    Get = 0,
+    Set = 1,
+    /// Like [`ParamOp::Get`], but the response also carries the name and the parameter count, so
+    /// the controller can walk the table without knowing it.
+    List = 2,
+}
+
+impl TryFrom<u8> for ParamOp {
+    type Error = u8;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(ParamOp::Get),
+            1 => Ok(ParamOp::Set),
+            2 => Ok(ParamOp::List),
+            _ => Err(value),
+        }
+    }
+}
+
+#[derive(IntoBytes, FromBytes, Immutable, Debug, PartialEq, Default, Clone)]
+#[repr(C, packed)]
+pub struct ParamRequest {
+    /// A [`ParamOp`].
+    pub op: u8,
+    pub id: u16,
+    /// Only used by [`ParamOp::Set`], see [`ParamValue::to_wire`].
+    pub value_type: u8,
+    pub value: [u8; 4],
+}
+
+impl ParamRequest {
+    pub fn get(param: Param) -> Self {
+        Self {
+            op: ParamOp::Get as u8,
+            id: param as u16,
+            ..Default::default()
+        }
+    }
+
+    pub fn set(param: Param, value: ParamValue) -> Self {
+        let (value_type, value) = value.to_wire();
+        Self {
+            op: ParamOp::Set as u8,
+            id: param as u16,
+            value_type,
+            value,
+        }
+    }
+
+    /// Requests the parameter at position `index` of the drone's table.
+    pub fn list(index: u16) -> Self {
+        Self {
+            op: ParamOp::List as u8,
+            id: index,
+            ..Default::default()
+        }
+    }
+}
+
+impl defmt::Format for ParamRequest {
+    fn format(&self, fmt: defmt::Formatter) {
+        defmt::write!(fmt, "param_request({}, #{})", self.op, { self.id })
+    }
+}
+
+#[derive(IntoBytes, FromBytes, Immutable, Debug, PartialEq, Default, Clone)]
+#[repr(C, packed)]
+pub struct ParamResponse {
+    /// The op of the request this answers.
+    pub op: u8,
+    /// A [`ParamStatus`].
+    pub status: u8,
+    pub id: u16,
+    /// The number of parameters on the drone.
+    pub count: u8,
+    /// The current value, see [`ParamValue::to_wire`].
+    pub value_type: u8,
+    pub value: [u8; 4],
+    /// ASCII, padded with zeroes. Only filled in for [`ParamOp::List`].
+    pub name: [u8; PARAM_NAME_SIZE],
+}
+
+impl ParamResponse {
+    fn new(request: &ParamRequest, param: Param, store: &ParamStore) -> Self {
+        let (value_type, value) = store.get(param).to_wire();
+        let mut name = [0; PARAM_NAME_SIZE];
+        if request.op == ParamOp::List as u8 {
+            let info = param.info().name.as_bytes();
+            name[..info.len()].copy_from_slice(info);
+        }
+
+        Self {
+            op: request.op,
+            status: ParamStatus::Ok as u8,
+            id: request.id,
+            count: PARAM_COUNT as u8,
+            value_type,
+            value,
+            name,
+        }
+    }
+
+    fn error(request: &ParamRequest, status: ParamStatus) -> Self {
+        Self {
+            op: request.op,
+            status: status as u8,
+            id: request.id,
+            count: PARAM_COUNT as u8,
+            ..Default::default()
+        }
+    }
+
+    /// Unknown values are reported as [`ParamStatus::Invalid`].
+    pub fn status(&self) -> ParamStatus {
+        ParamStatus::try_from(self.status).unwrap_or(ParamStatus::Invalid)
+    }
+
+    /// The value, if the request succeeded.
+    pub fn value(&self) -> Option<ParamValue> {
+        match self.status() {
+            ParamStatus::Ok => ParamValue::from_wire(self.value_type, self.value),
+            _ => None,
+        }
+    }
+
+    /// The name, if this answers a [`ParamOp::List`].
+    pub fn name(&self) -> Option<&str> {
+        let len = self
+            .name
+            .iter()
+            .position(|b| *b == 0)
+            .unwrap_or(PARAM_NAME_SIZE);
+        match len {
+            0 => None,
+            len => core::str::from_utf8(&self.name[..len]).ok(),
+        }
+    }
+
+    fn answers(&self, request: &ParamRequest) -> bool {
+        self.op == request.op && self.id == request.id
+    }
+}
+
+impl defmt::Format for ParamResponse {
+    fn format(&self, fmt: defmt::Formatter) {
+        defmt::write!(
+            fmt,
+            "param_response({}, #{}, {}, {})",
+            self.op,
+            { self.id },
+            self.status(),
+            self.value()
+        )
+    }
+}
+
+/// What the client wants to do in the current uplink slot.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ParamPoll {
+    Idle,
+    Transmit(ParamRequest),
+    /// The request was sent [`COMMAND_MAX_ATTEMPTS`] times without a response.
+    TimedOut(ParamRequest),
+}
+
+struct PendingRequest {
+    request: ParamRequest,
+    attempts: u8,
+    next_attempt: Instant,
+}
+
+/// The controller's side of the parameter protocol. It has one request in flight at a time and
+/// retransmits it with the same timing as commands.
+pub struct ParamClient {
+    pending: Option<PendingRequest>,
+}
+
+impl ParamClient {
+    pub fn new() -> Self {
+        Self { pending: None }
+    }
+
+    /// Sends `request`, replacing any request still in flight.
+    pub fn request(&mut self, request: ParamRequest, now: Instant) {
+        self.pending = Some(PendingRequest {
+            request,
+            attempts: 0,
+            next_attempt: now,
+        });
+    }
+
+    /// Fetches the whole table. Every response to [`ParamOp::List`] requests the next entry.
+    pub fn list(&mut self, now: Instant) {
+        self.request(ParamRequest::list(0), now);
+    }
+
+    pub fn is_busy(&self) -> bool {
+        self.pending.is_some()
+    }
+
+    pub fn poll(&mut self, now: Instant) -> ParamPoll {
+        let Some(pending) = &mut self.pending else {
+            return ParamPoll::Idle;
+        };
+        if now < pending.next_attempt {
+            return ParamPoll::Idle;
+        }
+        if pending.attempts >= COMMAND_MAX_ATTEMPTS {
+            let request = pending.request.clone();
+            self.pending = None;
+            return ParamPoll::TimedOut(request);
+        }
+
+        pending.attempts += 1;
+        pending.next_attempt = now + COMMAND_RETRY_INTERVAL;
+        ParamPoll::Transmit(pending.request.clone())
+    }
+
+    /// Returns `response` if it answers the request in flight, and ignores it otherwise.
+    pub fn receive(&mut self, response: &ParamResponse, now: Instant) -> Option<ParamResponse> {
+        let pending = self.pending.as_ref()?;
+        if !response.answers(&pending.request) {
+            return None;
+        }
+
+        self.pending = None;
+        let next = response.id + 1;
+        if response.op == ParamOp::List as u8 && next < response.count as u16 {
+            self.request(ParamRequest::list(next), now);
+        }
+
+        Some(response.clone())
+    }
+}
+
+impl Default for ParamClient {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(ms: u64) -> Instant {
+        Instant::from_millis(ms)
+    }
+
+    #[test]
+    fn defaults_are_in_range() {
+        for info in PARAMS {
+            assert!(
+                info.default.in_range(&info.min, &info.max),
+                "{} default out of range",
+                info.name
+            );
+        }
+    }
+
+    #[test]
+    fn wire_round_trip() {
+        let values = [
+            ParamValue::U8(76),
+            ParamValue::U16(7300),
+            ParamValue::U32(123_456),
+            ParamValue::F32(-0.25),
+        ];
+        for value in values {
+            let (value_type, bytes) = value.to_wire();
+            assert_eq!(ParamValue::from_wire(value_type, bytes), Some(value));
+        }
+
+        assert_eq!(ParamValue::from_wire(0, [0, 1, 0, 0]), None);
+        assert_eq!(ParamValue::from_wire(9, [0; 4]), None);
+    }
+
+    #[test]
+    fn store_set_checks_type_and_range() {
+        let mut store = ParamStore::default();
+        assert_eq!(store.get_u8(Param::MaxAngleDeg), 45);
+
+        assert_eq!(
+            store.set(Param::MaxAngleDeg, ParamValue::U16(60)),
+            Err(ParamStatus::TypeMismatch)
+        );
+        assert_eq!(
+            store.set(Param::MaxAngleDeg, ParamValue::U8(81)),
+            Err(ParamStatus::OutOfRange)
+        );
+        assert_eq!(store.get_u8(Param::MaxAngleDeg), 45);
+
+        store.set(Param::MaxAngleDeg, ParamValue::U8(60)).unwrap();
+        assert_eq!(store.get_u8(Param::MaxAngleDeg), 60);
+    }
+
+    #[test]
+    fn handle_get_and_set() {
+        let mut store = ParamStore::default();
+
+        let response = store.handle(&ParamRequest::get(Param::BatteryLowMv));
+        assert_eq!(response.status(), ParamStatus::Ok);
+        assert_eq!(response.value(), Some(ParamValue::U16(7500)));
+        assert_eq!(response.name(), None);
+
+        let response = store.handle(&ParamRequest::set(
+            Param::BatteryLowMv,
+            ParamValue::U16(7600),
+        ));
+        assert_eq!(response.status(), ParamStatus::Ok);
+        assert_eq!(response.value(), Some(ParamValue::U16(7600)));
+        assert_eq!(store.get_u16(Param::BatteryLowMv), 7600);
+
+        let response = store.handle(&ParamRequest::set(
+            Param::BatteryLowMv,
+            ParamValue::U16(9000),
+        ));
+        assert_eq!(response.status(), ParamStatus::OutOfRange);
+        assert_eq!(response.value(), None);
+    }
+
+    #[test]
+    fn handle_refuses_to_set_reboot_required() {
+        let mut store = ParamStore::default();
+
+        let response = store.handle(&ParamRequest::set(Param::ImuGyroRange, ParamValue::U8(1)));
+        assert_eq!(response.status(), ParamStatus::ReadOnly);
+        assert_eq!(response.value(), None);
+        assert_eq!(store.get_u8(Param::ImuGyroRange), 3);
+
+        let response = store.handle(&ParamRequest::get(Param::ImuGyroRange));
+        assert_eq!(response.value(), Some(ParamValue::U8(3)));
+    }
+
+    #[test]
+    fn handle_rejects_bad_requests() {
+        let mut store = ParamStore::default();
+
+        let unknown = ParamRequest {
+            id: PARAM_COUNT as u16,
+            ..ParamRequest::get(Param::MaxAngleDeg)
+        };
+        assert_eq!(store.handle(&unknown).status(), ParamStatus::UnknownParam);
+
+        let bad_op = ParamRequest {
+            op: 9,
+            ..ParamRequest::get(Param::MaxAngleDeg)
+        };
+        assert_eq!(store.handle(&bad_op).status(), ParamStatus::Invalid);
+
+        let bad_value = ParamRequest {
+            value_type: 9,
+            ..ParamRequest::set(Param::MaxAngleDeg, ParamValue::U8(1))
+        };
+        assert_eq!(store.handle(&bad_value).status(), ParamStatus::Invalid);
+    }
+
+    #[test]
+    fn client_lists_whole_table() {
+        let mut store = ParamStore::default();
+        let mut client = ParamClient::new();
+        client.list(at(0));
+
+        let mut names = [""; PARAM_COUNT];
+        let mut ms = 0;
+        while client.is_busy() {
+            if let ParamPoll::Transmit(request) = client.poll(at(ms)) {
+                let response = store.handle(&request);
+                let response = client.receive(&response, at(ms)).unwrap();
+                names[response.id as usize] = PARAMS[response.id as usize].name;
+                assert_eq!(response.name(), Some(names[response.id as usize]));
+            }
+            ms += 10;
+        }
+
+        assert_eq!(names, core::array::from_fn(|i| PARAMS[i].name));
+    }
+
+    #[test]
+    fn client_retries_and_ignores_other_responses() {
+        let mut store = ParamStore::default();
+        let mut client = ParamClient::new();
+        let request = ParamRequest::get(Param::MaxAngleDeg);
+        client.request(request.clone(), at(0));
+
+        assert_eq!(client.poll(at(0)), ParamPoll::Transmit(request.clone()));
+        assert_eq!(client.poll(at(20)), ParamPoll::Idle);
+        assert_eq!(client.poll(at(50)), ParamPoll::Transmit(request.clone()));
+
+        let other = store.handle(&ParamRequest::get(Param::BatteryLowMv));
+        assert_eq!(client.receive(&other, at(60)), None);
+        assert!(client.is_busy());
+
+        let response = store.handle(&request);
+        assert_eq!(client.receive(&response, at(60)), Some(response));
+        assert!(!client.is_busy());
+    }
+
+    #[test]
+    fn client_times_out() {
+        let mut client = ParamClient::new();
+        client.request(ParamRequest::get(Param::MaxAngleDeg), at(0));
+
+        let mut ms = 0;
+        let mut transmissions = 0;
+        loop {
+            match client.poll(at(ms)) {
+                ParamPoll::Idle => {}
+                ParamPoll::Transmit(_) => transmissions += 1,
+                ParamPoll::TimedOut(_) => break,
+            }
+            ms += 10;
+        }
+
+        assert_eq!(transmissions, COMMAND_MAX_ATTEMPTS);
+        assert!(!client.is_busy());
+    }
+}
@@ -0,0 +1,627 @@
+//! Framing for everything sent over the nRF24 link.
+//!
+//! Every payload, in either direction, is wrapped in a frame:
+//!
+//! ```text
+//! | version | type | counter | timestamp | echo timestamp | message (0-18 bytes) | MAC |
+//! ```
+//!
+//! The counter is a little-endian `u32`, the timestamps are little-endian `u16`s, see
+//! [`FrameStamp`] for their meaning. The top bit of the type is set if the message is encrypted.
+//!
+//! The MAC covers the header and the message as sent, see [`crate::auth`] for how it's computed
+//! from the link key and the [`Session`] the frame belongs to. A frame is only accepted if the
+//! version equals [`PROTOCOL_VERSION`], the MAC matches, the message type is known and the message
+//! has the exact size of its type. The MAC also takes the place of a checksum, a corrupted frame
+//! fails it just like a forged one.
+
+use zerocopy::{FromBytes, IntoBytes};
+
+use crate::auth::{Direction, FrameKeys, LinkKey, MAC_SIZE, Session};
+use crate::command::{CommandAck, CommandRequest};
+use crate::link::{SessionAccept, SessionOffer};
+use crate::param::{ParamRequest, ParamResponse};
+use crate::rc::RcChannels;
+use crate::telemetry::{
+    AltitudeTelemetry, AttitudeTelemetry, BatteryTelemetry, DiagnosticsTelemetry,
+};
+
+/// Bump this whenever the layout of a frame or any message changes. Frames with a different version
+/// are rejected, so a controller and a drone running mismatched firmware will refuse to talk to
+/// each other.
+pub const PROTOCOL_VERSION: u8 = 11;
+
+/// The nRF24L01+ can't carry more than 32 bytes in a single payload.
+pub const MAX_FRAME_SIZE: usize = 32;
+pub const HEADER_SIZE: usize = 10;
+pub const MAX_MESSAGE_SIZE: usize = MAX_FRAME_SIZE - HEADER_SIZE - MAC_SIZE;
+
+/// Set in the type byte of frames whose message is encrypted.
+const ENCRYPTED_FLAG: u8 = 0x80;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ProtocolError {
+    /// The output buffer can't hold the encoded frame.
+    BufferTooSmall,
+    /// The frame is shorter than a header and a MAC.
+    FrameTooShort(usize),
+    /// The MAC doesn't match. The frame was corrupted, or sealed with another key.
+    AuthenticationFailed,
+    /// An authentic frame whose counter is too old to tell whether it was received before, most
+    /// likely recorded and replayed.
+    StaleCounter(u32),
+    /// A frame that belongs to a session, while none has been set up.
+    NoSession,
+    /// Every counter value has been used, nothing more can be sent with this key.
+    CounterExhausted,
+    /// The frame was produced by firmware speaking another protocol version.
+    VersionMismatch(u8),
+    UnknownMessageType(u8),
+    InvalidMessageLength {
+        message_type: MessageType,
+        len: usize,
+    },
+}
+
+impl defmt::Format for ProtocolError {
+    fn format(&self, fmt: defmt::Formatter) {
+        match self {
+            ProtocolError::BufferTooSmall => defmt::write!(fmt, "BufferTooSmall"),
+            ProtocolError::FrameTooShort(len) => defmt::write!(fmt, "FrameTooShort({})", len),
+            ProtocolError::AuthenticationFailed => defmt::write!(fmt, "AuthenticationFailed"),
+            ProtocolError::StaleCounter(counter) => defmt::write!(fmt, "StaleCounter({})", counter),
+            ProtocolError::CounterExhausted => defmt::write!(fmt, "CounterExhausted"),
+            ProtocolError::NoSession => defmt::write!(fmt, "NoSession"),
+            ProtocolError::VersionMismatch(version) => {
+                defmt::write!(fmt, "VersionMismatch({})", version)
+            }
+            ProtocolError::UnknownMessageType(ty) => {
+                defmt::write!(fmt, "UnknownMessageType({:x})", ty)
+            }
+            ProtocolError::InvalidMessageLength { message_type, len } => {
+                defmt::write!(
+                    fmt,
+                    "InvalidMessageLength({:x}, {})",
+                    *message_type as u8,
+                    len
+                )
+            }
+        }
+    }
+}
+
+/// The per-frame link bookkeeping carried in every header.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct FrameStamp {
+    /// Incremented by the sender for every frame it sends, and never reused with the same key. Also
+    /// serves as the sequence number for loss statistics.
+    pub counter: u32,
+    /// The sender's millisecond clock at the time the frame was sent, truncated to 16 bits.
+    /// Never 0, since 0 is reserved for "nothing to echo" in `echo_timestamp`.
+    pub timestamp: u16,
+    /// The `timestamp` of the most recent frame the sender received from its peer, or 0 if it has
+    /// not received anything yet. This lets the peer compute the round-trip time.
+    pub echo_timestamp: u16,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FrameHeader {
+    pub version: u8,
+    pub message_type: MessageType,
+    pub stamp: FrameStamp,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Frame {
+    pub header: FrameHeader,
+    pub message: Message,
+}
+
+/// Declares the set of messages that can be carried in a frame.
+///
+/// Each entry maps a [`Message`] variant to its wire id and to a fixed-size `zerocopy` struct. It
+/// generates [`MessageType`], [`Message`] and the functions used to convert between a message and
+/// its bytes.
+macro_rules! messages {
+    ($($Variant:ident = $id:literal => $Ty:ty,)*) => {
+        #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+        #[repr(u8)]
+        pub enum MessageType {
+            $($Variant = $id,)*
+        }
+
+        impl TryFrom<u8> for MessageType {
+            type Error = ProtocolError;
+
+            fn try_from(value: u8) -> Result<Self, Self::Error> {
+                match value {
+                    $($id => Ok(MessageType::$Variant),)*
+                    _ => Err(ProtocolError::UnknownMessageType(value)),
+                }
+            }
+        }
+
+        #[derive(Debug, Clone, PartialEq)]
+        pub enum Message {
+            $($Variant($Ty),)*
+        }
+
+        impl Message {
+            pub fn message_type(&self) -> MessageType {
+                match self {
+                    $(Message::$Variant(_) => MessageType::$Variant,)*
+                }
+            }
+
+            fn as_bytes(&self) -> &[u8] {
+                match self {
+                    $(Message::$Variant(message) => message.as_bytes(),)*
+                }
+            }
+
+            fn read_from_bytes(message_type: MessageType, bytes: &[u8]) -> Result<Self, ProtocolError> {
+                let invalid_length = ProtocolError::InvalidMessageLength {
+                    message_type,
+                    len: bytes.len(),
+                };
+                match message_type {
+                    $(MessageType::$Variant => <$Ty>::read_from_bytes(bytes)
+                        .map(Message::$Variant)
+                        .map_err(|_| invalid_length),)*
+                }
+            }
+        }
+
+        const _: () = {
+            $(assert!(size_of::<$Ty>() <= MAX_MESSAGE_SIZE, "Message does not fit in a frame");)*
+        };
+    };
+}
+
+messages! {
+    RcChannels = 0x01 => RcChannels,
+    CommandRequest = 0x02 => CommandRequest,
+    ParamRequest = 0x03 => ParamRequest,
+    AttitudeTelemetry = 0x10 => AttitudeTelemetry,
+    BatteryTelemetry = 0x11 => BatteryTelemetry,
+    AltitudeTelemetry = 0x12 => AltitudeTelemetry,
+    DiagnosticsTelemetry = 0x13 => DiagnosticsTelemetry,
+    CommandAck = 0x20 => CommandAck,
+    ParamResponse = 0x21 => ParamResponse,
+    SessionOffer = 0x30 => SessionOffer,
+    SessionAccept = 0x31 => SessionAccept,
+}
+
+impl MessageType {
+    /// Whether messages of this type set up a session. They're sealed with
+    /// [`Session::HANDSHAKE`] instead of the session, and never encrypted.
+    pub fn is_handshake(self) -> bool {
+        matches!(self, MessageType::SessionOffer | MessageType::SessionAccept)
+    }
+}
+
+/// Encodes `message` into a frame in `buf`, sealed for `session` and `direction`, and returns the
+/// number of bytes written.
+pub fn encode(
+    message: &Message,
+    stamp: &FrameStamp,
+    key: &LinkKey,
+    session: &Session,
+    direction: Direction,
+    encrypt: bool,
+    buf: &mut [u8],
+) -> Result<usize, ProtocolError> {
+    let payload = message.as_bytes();
+    let len = HEADER_SIZE + payload.len() + MAC_SIZE;
+    if buf.len() < len {
+        return Err(ProtocolError::BufferTooSmall);
+    }
+
+    let keys = FrameKeys::new(key, session, direction, stamp.counter);
+    let mut message_type = message.message_type() as u8;
+    if encrypt {
+        message_type |= ENCRYPTED_FLAG;
+    }
+    buf[0] = PROTOCOL_VERSION;
+    buf[1] = message_type;
+    buf[2..6].copy_from_slice(&stamp.counter.to_le_bytes());
+    buf[6..8].copy_from_slice(&stamp.timestamp.to_le_bytes());
+    buf[8..10].copy_from_slice(&stamp.echo_timestamp.to_le_bytes());
+    let body = &mut buf[HEADER_SIZE..HEADER_SIZE + payload.len()];
+    body.copy_from_slice(payload);
+    if encrypt {
+        keys.apply_keystream(body);
+    }
+
+    let mac = keys.mac(&buf[..len - MAC_SIZE]);
+    buf[len - MAC_SIZE..len].copy_from_slice(&mac);
+
+    Ok(len)
+}
+
+/// Validates and decodes a single frame sent in `session` and `direction`. `buf` must contain
+/// exactly one frame.
+pub fn decode(
+    buf: &[u8],
+    key: &LinkKey,
+    session: &Session,
+    direction: Direction,
+) -> Result<Frame, ProtocolError> {
+    if buf.len() < HEADER_SIZE + MAC_SIZE {
+        return Err(ProtocolError::FrameTooShort(buf.len()));
+    }
+
+    let version = buf[0];
+    if version != PROTOCOL_VERSION {
+        return Err(ProtocolError::VersionMismatch(version));
+    }
+
+    let (content, mac) = buf.split_at(buf.len() - MAC_SIZE);
+    let stamp = FrameStamp {
+        counter: u32::from_le_bytes([content[2], content[3], content[4], content[5]]),
+        timestamp: u16::from_le_bytes([content[6], content[7]]),
+        echo_timestamp: u16::from_le_bytes([content[8], content[9]]),
+    };
+    let keys = FrameKeys::new(key, session, direction, stamp.counter);
+    if !keys.verify(content, mac) {
+        return Err(ProtocolError::AuthenticationFailed);
+    }
+
+    let message_type = MessageType::try_from(content[1] & !ENCRYPTED_FLAG)?;
+    let body = &content[HEADER_SIZE..];
+    if body.len() > MAX_MESSAGE_SIZE {
+        return Err(ProtocolError::InvalidMessageLength {
+            message_type,
+            len: body.len(),
+        });
+    }
+    let mut plaintext = [0; MAX_MESSAGE_SIZE];
+    let plaintext = &mut plaintext[..body.len()];
+    plaintext.copy_from_slice(body);
+    if content[1] & ENCRYPTED_FLAG != 0 {
+        keys.apply_keystream(plaintext);
+    }
+    let message = Message::read_from_bytes(message_type, plaintext)?;
+
+    Ok(Frame {
+        header: FrameHeader {
+            version,
+            message_type,
+            stamp,
+        },
+        message,
+    })
+}
+
+/// Reads the header of an encoded frame, which is never encrypted, without checking the MAC.
+pub fn decode_header(buf: &[u8]) -> Result<FrameHeader, ProtocolError> {
+    if buf.len() < HEADER_SIZE {
+        return Err(ProtocolError::FrameTooShort(buf.len()));
+    }
+
+    Ok(FrameHeader {
+        version: buf[0],
+        message_type: MessageType::try_from(buf[1] & !ENCRYPTED_FLAG)?,
+        stamp: FrameStamp {
+            counter: u32::from_le_bytes([buf[2], buf[3], buf[4], buf[5]]),
+            timestamp: u16::from_le_bytes([buf[6], buf[7]]),
+            echo_timestamp: u16::from_le_bytes([buf[8], buf[9]]),
+        },
+    })
+}
+
+/// Writes `frame` the way it's laid out on air, but with the message in the clear and without a
+/// MAC, and returns the number of bytes written. This is how captures record frames, so they can be
+/// decoded without the link key.
+pub fn encode_unsealed(frame: &Frame, buf: &mut [u8]) -> Result<usize, ProtocolError> {
+    let payload = frame.message.as_bytes();
+    let len = HEADER_SIZE + payload.len();
+    if buf.len() < len {
+        return Err(ProtocolError::BufferTooSmall);
+    }
+
+    let stamp = &frame.header.stamp;
+    buf[0] = frame.header.version;
+    buf[1] = frame.message.message_type() as u8;
+    buf[2..6].copy_from_slice(&stamp.counter.to_le_bytes());
+    buf[6..8].copy_from_slice(&stamp.timestamp.to_le_bytes());
+    buf[8..10].copy_from_slice(&stamp.echo_timestamp.to_le_bytes());
+    buf[HEADER_SIZE..len].copy_from_slice(payload);
+
+    Ok(len)
+}
+
+/// Decodes a frame written by [`encode_unsealed`].
+pub fn decode_unsealed(buf: &[u8]) -> Result<Frame, ProtocolError> {
+    let header = decode_header(buf)?;
+    if header.version != PROTOCOL_VERSION {
+        return Err(ProtocolError::VersionMismatch(header.version));
+    }
+    let message = Message::read_from_bytes(header.message_type, &buf[HEADER_SIZE..])?;
+
+    Ok(Frame { header, message })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command::Command;
+    use crate::param::{Param, ParamStore, ParamValue};
+    use crate::telemetry::FlightMode;
+
+    fn rc_channels() -> RcChannels {
+        RcChannels::from_values(&core::array::from_fn(|i| (i as u16 + 1) * 0x55))
+    }
+
+    const STAMP: FrameStamp = FrameStamp {
+        counter: 0x12345678,
+        timestamp: 0x9ABC,
+        echo_timestamp: 0xDEF0,
+    };
+
+    const SESSION: Session = Session {
+        controller_nonce: 0x0BADCAFE,
+        drone_boot: 7,
+        drone_sequence: 2,
+    };
+
+    fn key() -> LinkKey {
+        LinkKey::new(core::array::from_fn(|i| i as u8))
+    }
+
+    fn encode_input(encrypt: bool, buf: &mut [u8]) -> usize {
+        let message = Message::RcChannels(rc_channels());
+        encode(
+            &message,
+            &STAMP,
+            &key(),
+            &SESSION,
+            Direction::Uplink,
+            encrypt,
+            buf,
+        )
+        .unwrap()
+    }
+
+    // The expected frames were produced with an independent implementation of the construction
+    // described in `auth`.
+    #[test]
+    fn encode_layout() {
+        let mut buf = [0u8; MAX_FRAME_SIZE];
+        let len = encode_input(false, &mut buf);
+
+        assert_eq!(
+            &buf[..len],
+            &[
+                0x0B, 0x01, 0x78, 0x56, 0x34, 0x12, 0xBC, 0x9A, 0xF0, 0xDE, 0x55, 0x50, 0xC5, 0x3F,
+                0xA8, 0x92, 0x1A, 0xFF, 0x4C, 0x09, 0x55, 0xFD, 0x92, 0xDA, 0xE9, 0xF8, 0x07, 0x7C,
+                0x5F, 0x1D, 0xE8,
+            ]
+        );
+    }
+
+    #[test]
+    fn encode_encrypted_layout() {
+        let mut buf = [0u8; MAX_FRAME_SIZE];
+        let len = encode_input(true, &mut buf);
+
+        assert_eq!(
+            &buf[..len],
+            &[
+                0x0B, 0x81, 0x78, 0x56, 0x34, 0x12, 0xBC, 0x9A, 0xF0, 0xDE, 0x69, 0x35, 0x90, 0xBA,
+                0x90, 0x0E, 0x05, 0x23, 0x35, 0xB4, 0xDF, 0x72, 0x14, 0x06, 0x04, 0xA6, 0xF0, 0x3D,
+                0x5D, 0xDA, 0x9B,
+            ]
+        );
+    }
+
+    #[test]
+    fn round_trip() {
+        let messages = [
+            Message::RcChannels(rc_channels()),
+            Message::AttitudeTelemetry(AttitudeTelemetry {
+                roll_cdeg: 4500,
+                armed: 1,
+                ..Default::default()
+            }),
+            Message::BatteryTelemetry(BatteryTelemetry {
+                voltage_mv: 7900,
+                battery_level: 87,
+                battery_status: 0,
+            }),
+            Message::AltitudeTelemetry(AltitudeTelemetry {
+                altitude_cm: -12,
+                vertical_speed_cms: 30,
+            }),
+            Message::DiagnosticsTelemetry(DiagnosticsTelemetry {
+                uptime_s: 3600,
+                uplink_rtt_ms: 9,
+                ..Default::default()
+            }),
+            Message::CommandRequest(Command::SetFlightMode(FlightMode::Angle).to_request(42)),
+            Message::CommandAck(CommandAck { id: 42, result: 1 }),
+            Message::ParamRequest(ParamRequest::set(Param::MaxAngleDeg, ParamValue::U8(30))),
+            Message::ParamResponse(ParamStore::default().handle(&ParamRequest::list(3))),
+            Message::SessionOffer(SessionOffer {
+                controller_nonce: 0x5eed,
+            }),
+            Message::SessionAccept(SessionAccept::new(&SESSION)),
+        ];
+
+        for message in messages {
+            for encrypt in [false, true] {
+                let mut buf = [0u8; MAX_FRAME_SIZE];
+                let len = encode(
+                    &message,
+                    &STAMP,
+                    &key(),
+                    &SESSION,
+                    Direction::Downlink,
+                    encrypt,
+                    &mut buf,
+                )
+                .unwrap();
+                let frame = decode(&buf[..len], &key(), &SESSION, Direction::Downlink).unwrap();
+
+                assert_eq!(frame.header.version, PROTOCOL_VERSION);
+                assert_eq!(frame.header.message_type, message.message_type());
+                assert_eq!(frame.header.stamp, STAMP);
+                assert_eq!(frame.message, message);
+            }
+        }
+    }
+
+    #[test]
+    fn encode_buffer_too_small() {
+        let mut buf = [0u8; 12];
+        assert_eq!(
+            encode(
+                &Message::RcChannels(rc_channels()),
+                &STAMP,
+                &key(),
+                &SESSION,
+                Direction::Uplink,
+                false,
+                &mut buf
+            ),
+            Err(ProtocolError::BufferTooSmall)
+        );
+    }
+
+    #[test]
+    fn decode_too_short() {
+        assert_eq!(
+            decode(&[1, 2, 3], &key(), &SESSION, Direction::Uplink),
+            Err(ProtocolError::FrameTooShort(3))
+        );
+    }
+
+    #[test]
+    fn decode_rejects_tampered_frame() {
+        for encrypt in [false, true] {
+            let mut buf = [0u8; MAX_FRAME_SIZE];
+            let len = encode_input(encrypt, &mut buf);
+
+            // The version is checked before the MAC.
+            for i in 1..len {
+                let mut tampered = buf;
+                tampered[i] ^= 0x04;
+                assert_eq!(
+                    decode(&tampered[..len], &key(), &SESSION, Direction::Uplink),
+                    Err(ProtocolError::AuthenticationFailed)
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn decode_rejects_other_key() {
+        let mut buf = [0u8; MAX_FRAME_SIZE];
+        let len = encode_input(false, &mut buf);
+        let other = LinkKey::new([0x55; 32]);
+
+        assert_eq!(
+            decode(&buf[..len], &other, &SESSION, Direction::Uplink),
+            Err(ProtocolError::AuthenticationFailed)
+        );
+    }
+
+    #[test]
+    fn decode_rejects_other_session() {
+        let mut buf = [0u8; MAX_FRAME_SIZE];
+        let len = encode_input(false, &mut buf);
+        let next = Session {
+            drone_sequence: SESSION.drone_sequence + 1,
+            ..SESSION
+        };
+
+        assert_eq!(
+            decode(&buf[..len], &key(), &next, Direction::Uplink),
+            Err(ProtocolError::AuthenticationFailed)
+        );
+    }
+
+    #[test]
+    fn decode_rejects_other_direction() {
+        // A frame from the controller can't be reflected back to it as if the drone had sent it.
+        let mut buf = [0u8; MAX_FRAME_SIZE];
+        let len = encode_input(false, &mut buf);
+
+        assert_eq!(
+            decode(&buf[..len], &key(), &SESSION, Direction::Downlink),
+            Err(ProtocolError::AuthenticationFailed)
+        );
+    }
+
+    /// Re-computes the MAC after tampering with a frame, so that the MAC check passes and later
+    /// checks are reached.
+    fn reseal(buf: &mut [u8]) {
+        let len = buf.len();
+        let keys = FrameKeys::new(&key(), &SESSION, Direction::Uplink, STAMP.counter);
+        let mac = keys.mac(&buf[..len - MAC_SIZE]);
+        buf[len - MAC_SIZE..].copy_from_slice(&mac);
+    }
+
+    #[test]
+    fn decode_rejects_other_version() {
+        let mut buf = [0u8; MAX_FRAME_SIZE];
+        let len = encode_input(false, &mut buf);
+        buf[0] = PROTOCOL_VERSION + 1;
+        reseal(&mut buf[..len]);
+
+        assert_eq!(
+            decode(&buf[..len], &key(), &SESSION, Direction::Uplink),
+            Err(ProtocolError::VersionMismatch(PROTOCOL_VERSION + 1))
+        );
+    }
+
+    #[test]
+    fn decode_rejects_unknown_message_type() {
+        let mut buf = [0u8; MAX_FRAME_SIZE];
+        let len = encode_input(false, &mut buf);
+        buf[1] = 0x6E;
+        reseal(&mut buf[..len]);
+
+        assert_eq!(
+            decode(&buf[..len], &key(), &SESSION, Direction::Uplink),
+            Err(ProtocolError::UnknownMessageType(0x6E))
+        );
+    }
+
+    #[test]
+    fn unsealed_round_trip() {
+        let mut sealed = [0u8; MAX_FRAME_SIZE];
+        let len = encode_input(true, &mut sealed);
+        let frame = decode(&sealed[..len], &key(), &SESSION, Direction::Uplink).unwrap();
+        assert_eq!(decode_header(&sealed[..len]), Ok(frame.header));
+
+        let mut buf = [0u8; MAX_FRAME_SIZE];
+        let len = encode_unsealed(&frame, &mut buf).unwrap();
+        assert_eq!(len, HEADER_SIZE + size_of::<RcChannels>());
+        assert_eq!(&buf[HEADER_SIZE..len], rc_channels().as_bytes());
+        assert_eq!(decode_unsealed(&buf[..len]), Ok(frame));
+
+        buf[0] = PROTOCOL_VERSION - 1;
+        assert_eq!(
+            decode_unsealed(&buf[..len]),
+            Err(ProtocolError::VersionMismatch(PROTOCOL_VERSION - 1))
+        );
+    }
+
+    #[test]
+    fn decode_rejects_wrong_message_length() {
+        // A well-formed frame that claims to be battery telemetry but carries RC channels.
+        let mut buf = [0u8; MAX_FRAME_SIZE];
+        let len = encode_input(false, &mut buf);
+        buf[1] = MessageType::BatteryTelemetry as u8;
+        reseal(&mut buf[..len]);
+
+        assert_eq!(
+            decode(&buf[..len], &key(), &SESSION, Direction::Uplink),
+            Err(ProtocolError::InvalidMessageLength {
+                message_type: MessageType::BatteryTelemetry,
+                len: 17,
+            })
+        );
+    }
+}
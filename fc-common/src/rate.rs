@@ -0,0 +1,608 @@
+//! Rate mode: the sticks ask for rotation rates, and a PID controller per axis turns the
+//! difference to what the gyro measures into a correction for the mixer.
+//!
+//! On top of the textbook controller:
+//! - The I term is limited and stops growing while the output is saturated, so it doesn't wind up
+//!   while the drone can't follow, e.g. on the ground. It also relaxes while the sticks move fast,
+//!   when the error is the drone lagging behind the pilot rather than something pushing it off.
+//! - The D term works on the measured rate alone, so stick moves don't kick it, and it's low-pass
+//!   filtered since differentiating the gyro amplifies its noise.
+//! - Feedforward from how fast the setpoint changes gets the drone moving before an error builds
+//!   up.
+//! - TPA (throttle PID attenuation) cuts P and D at high throttle, where the motors have more
+//!   authority and the same gains would oscillate.
+//!
+//! Rates are about the gyro's axes, as in [`attitude`](crate::attitude), with x pointing forward
+//! and y to the left.
+
+use core::f32::consts::PI;
+
+use embassy_time::{Duration, Ticker};
+use uom::si::angular_velocity::{degree_per_second, radian_per_second};
+use uom::si::f32::AngularVelocity;
+
+use crate::angle::{self, AngleGains};
+use crate::attitude::Quaternion;
+use crate::imu::Vector3;
+use crate::param::{Param, ParamStore};
+use crate::rc::{Channel, RcChannels};
+use crate::telemetry::FlightMode;
+use crate::{EmitterBase, SignalBase};
+
+/// The controller runs at a fixed 500 Hz, just below the IMU's default sample rate so that every
+/// iteration sees a fresh gyro sample.
+pub const LOOP_PERIOD: Duration = Duration::from_millis(2);
+/// Without pilot input for this long, the controller stops asking anything of the motors.
+pub const RC_TIMEOUT: Duration = Duration::from_millis(250);
+/// The drone only arms with the [throttle] at or below this, so arming never spins the motors up.
+pub const ARM_MAX_THROTTLE: f32 = 0.05;
+/// Without a gyro sample for this long, there's nothing to control on.
+const GYRO_TIMEOUT: Duration = Duration::from_millis(10);
+/// The largest correction asked for on any axis.
+const OUTPUT_LIMIT: f32 = 1.0;
+/// The largest share of the output the I term may make up.
+const ITERM_LIMIT: f32 = 0.3;
+/// Below this throttle the drone is most likely on the ground, so the I term is kept at zero.
+const ITERM_MIN_THROTTLE: f32 = 0.1;
+/// The I term stops growing altogether once the setpoint moves this fast, in rad/s away from its
+/// recent average.
+const ITERM_RELAX_THRESHOLD: f32 = 0.7;
+/// The cutoff of the recent average the I term relax compares the setpoint to.
+const ITERM_RELAX_CUTOFF_HZ: f32 = 15.0;
+/// The cutoff of the filter smoothing the feedforward, since the setpoint only changes when an
+/// uplink frame arrives.
+const FEEDFORWARD_CUTOFF_HZ: f32 = 20.0;
+
+/// What the rate controller asks of the motors, for the [mixer](crate::mixer).
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct RateOutput {
+    /// The correction about each axis, within ±1.
+    pub torque: Vector3<f32>,
+    /// From 0 to 1.
+    pub throttle: f32,
+}
+
+/// The gains of one axis. The rates are in rad/s, and the output is a share of the motors'
+/// authority.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AxisGains {
+    pub kp: f32,
+    pub ki: f32,
+    pub kd: f32,
+    /// Output per rad/s² the setpoint changes by.
+    pub kff: f32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RateGains {
+    pub roll_pitch: AxisGains,
+    pub yaw: AxisGains,
+    /// The rate asked for with a stick all the way out.
+    pub max_rate: AngularVelocity,
+    /// How much P and D are cut at full throttle, from 0 to 1.
+    pub tpa: f32,
+    /// The throttle from which P and D are cut, from 0 to 1.
+    pub tpa_breakpoint: f32,
+    pub d_cutoff_hz: f32,
+}
+
+impl RateGains {
+    pub fn from_parameters(parameters: &ParamStore) -> Self {
+        let kff = parameters.get_f32(Param::RateFeedforward);
+        Self {
+            roll_pitch: AxisGains {
+                kp: parameters.get_f32(Param::RateRollPitchP),
+                ki: parameters.get_f32(Param::RateRollPitchI),
+                kd: parameters.get_f32(Param::RateRollPitchD),
+                kff,
+            },
+            yaw: AxisGains {
+                kp: parameters.get_f32(Param::RateYawP),
+                ki: parameters.get_f32(Param::RateYawI),
+                kd: 0.0,
+                kff,
+            },
+            max_rate: AngularVelocity::new::<degree_per_second>(
+                parameters.get_u16(Param::MaxRateDps) as f32,
+            ),
+            tpa: parameters.get_u8(Param::TpaPercent) as f32 / 100.0,
+            tpa_breakpoint: parameters.get_u8(Param::TpaBreakpoint) as f32 / 100.0,
+            d_cutoff_hz: parameters.get_u16(Param::DTermCutoffHz) as f32,
+        }
+    }
+
+    /// What P and D are multiplied by at `throttle`.
+    fn tpa_factor(&self, throttle: f32) -> f32 {
+        if throttle <= self.tpa_breakpoint {
+            return 1.0;
+        }
+        let above = (throttle - self.tpa_breakpoint) / (1.0 - self.tpa_breakpoint);
+        1.0 - self.tpa * above.min(1.0)
+    }
+}
+
+/// The rates the sticks ask for: roll right, pitch forward and yaw right at the channels' maximum.
+pub fn setpoint(channels: &RcChannels, max_rate: AngularVelocity) -> Vector3<AngularVelocity> {
+    Vector3::new(
+        max_rate * channels.normalized(Channel::Roll),
+        max_rate * channels.normalized(Channel::Pitch),
+        // Yawing right turns clockwise seen from above, the negative way about z.
+        -max_rate * channels.normalized(Channel::Yaw),
+    )
+}
+
+/// The throttle from 0 to 1, over the upper half of the stick's travel.
+///
+/// A gamepad stick springs back to its center, and that's also where the controller holds the
+/// channel when it loses the gamepad, so the center has to mean no throttle. Below it is 0 too.
+pub fn throttle(channels: &RcChannels) -> f32 {
+    channels.normalized(Channel::Throttle).max(0.0)
+}
+
+/// A first-order low-pass filter, which starts out at its first input.
+#[derive(Debug, Clone, Copy, Default)]
+struct LowPass {
+    value: Option<f32>,
+}
+
+impl LowPass {
+    fn update(&mut self, input: f32, cutoff_hz: f32, dt: f32) -> f32 {
+        let value = match self.value {
+            None => input,
+            Some(value) => {
+                let rc = 1.0 / (2.0 * PI * cutoff_hz);
+                value + (input - value) * dt / (rc + dt)
+            }
+        };
+        self.value = Some(value);
+        value
+    }
+}
+
+/// The controller of one axis, in rad/s.
+#[derive(Debug, Clone, Copy, Default)]
+struct AxisPid {
+    integral: f32,
+    last_setpoint: Option<f32>,
+    last_measurement: Option<f32>,
+    derivative: LowPass,
+    feedforward: LowPass,
+    setpoint_average: LowPass,
+}
+
+impl AxisPid {
+    fn update(
+        &mut self,
+        gains: &AxisGains,
+        setpoint: f32,
+        measurement: f32,
+        tpa_factor: f32,
+        d_cutoff_hz: f32,
+        dt: f32,
+    ) -> f32 {
+        let error = setpoint - measurement;
+        let p = gains.kp * tpa_factor * error;
+
+        // The measurement turning towards the setpoint is damped just like the error shrinking.
+        let measured_change = (measurement - self.last_measurement.unwrap_or(measurement)) / dt;
+        self.last_measurement = Some(measurement);
+        let d = -gains.kd * tpa_factor * self.derivative.update(measured_change, d_cutoff_hz, dt);
+
+        let setpoint_change = (setpoint - self.last_setpoint.unwrap_or(setpoint)) / dt;
+        self.last_setpoint = Some(setpoint);
+        let ff = gains.kff
+            * self
+                .feedforward
+                .update(setpoint_change, FEEDFORWARD_CUTOFF_HZ, dt);
+
+        let average = self
+            .setpoint_average
+            .update(setpoint, ITERM_RELAX_CUTOFF_HZ, dt);
+        let relax = (1.0 - (setpoint - average).abs() / ITERM_RELAX_THRESHOLD).max(0.0);
+        let unsaturated = p + self.integral + d + ff;
+        let winding_up = unsaturated.abs() >= OUTPUT_LIMIT && unsaturated * error > 0.0;
+        if !winding_up {
+            self.integral =
+                (self.integral + gains.ki * relax * error * dt).clamp(-ITERM_LIMIT, ITERM_LIMIT);
+        }
+
+        (p + self.integral + d + ff).clamp(-OUTPUT_LIMIT, OUTPUT_LIMIT)
+    }
+}
+
+pub struct RateController {
+    gains: RateGains,
+    axes: Vector3<AxisPid>,
+}
+
+impl RateController {
+    pub fn new(gains: RateGains) -> Self {
+        Self {
+            gains,
+            axes: Vector3::default(),
+        }
+    }
+
+    pub fn gains(&self) -> &RateGains {
+        &self.gains
+    }
+
+    pub fn set_gains(&mut self, gains: RateGains) {
+        self.gains = gains;
+    }
+
+    /// Forgets the I term and the filters, for when the controller starts over, e.g. on arming.
+    pub fn reset(&mut self) {
+        self.axes = Vector3::default();
+    }
+
+    /// The correction about each axis that brings the `gyro` rates to the `setpoint`, `dt` after
+    /// the previous update.
+    pub fn update(
+        &mut self,
+        setpoint: &Vector3<AngularVelocity>,
+        gyro: &Vector3<AngularVelocity>,
+        throttle: f32,
+        dt: Duration,
+    ) -> Vector3<f32> {
+        if throttle < ITERM_MIN_THROTTLE {
+            for axis in [&mut self.axes.x, &mut self.axes.y, &mut self.axes.z] {
+                axis.integral = 0.0;
+            }
+        }
+        let gains = &self.gains;
+        let tpa_factor = gains.tpa_factor(throttle);
+        let dt = dt.as_micros() as f32 / 1e6;
+        let rad = |rate: AngularVelocity| rate.get::<radian_per_second>();
+        let update = |axis: &mut AxisPid, axis_gains: &AxisGains, setpoint, gyro| {
+            axis.update(
+                axis_gains,
+                rad(setpoint),
+                rad(gyro),
+                tpa_factor,
+                gains.d_cutoff_hz,
+                dt,
+            )
+        };
+        Vector3::new(
+            update(&mut self.axes.x, &gains.roll_pitch, setpoint.x, gyro.x),
+            update(&mut self.axes.y, &gains.roll_pitch, setpoint.y, gyro.y),
+            update(&mut self.axes.z, &gains.yaw, setpoint.z, gyro.z),
+        )
+    }
+}
+
+/// Runs the rate controller every [`LOOP_PERIOD`] on the latest gyro sample and pilot input. In
+/// angle and horizon mode the [angle loop](crate::angle) sets the rates from the attitude. The
+/// gains follow the parameters.
+///
+/// While disarmed, or without fresh pilot input or gyro samples, it asks for nothing and starts
+/// over once they're back.
+pub async fn run(
+    mut gyro_signal: impl SignalBase<Vector3<AngularVelocity>>,
+    mut rc_channels_signal: impl SignalBase<RcChannels>,
+    mut armed_signal: impl SignalBase<bool>,
+    mut flight_mode_signal: impl SignalBase<FlightMode>,
+    mut attitude_signal: impl SignalBase<Quaternion>,
+    mut output_emitter: impl EmitterBase<RateOutput>,
+    mut parameters_signal: impl SignalBase<ParamStore>,
+) -> ! {
+    let mut controller = RateController::new(RateGains::from_parameters(&parameters_signal.get()));
+    let mut ticker = Ticker::every(LOOP_PERIOD);
+    loop {
+        ticker.next().await;
+        let input = armed_signal
+            .get()
+            .then(|| rc_channels_signal.get_if_fresh(RC_TIMEOUT))
+            .flatten()
+            .zip(gyro_signal.get_if_fresh(GYRO_TIMEOUT));
+        let Some((channels, gyro)) = input else {
+            controller.reset();
+            output_emitter.emit_if_changed(RateOutput::default());
+            continue;
+        };
+
+        let parameters = parameters_signal.get();
+        controller.set_gains(RateGains::from_parameters(&parameters));
+        let setpoint = angle::setpoint(
+            flight_mode_signal.get(),
+            &channels,
+            &attitude_signal.get(),
+            &AngleGains::from_parameters(&parameters),
+            controller.gains().max_rate,
+        );
+        let throttle = throttle(&channels);
+        let torque = controller.update(&setpoint, &gyro, throttle, LOOP_PERIOD);
+        output_emitter.emit(RateOutput { torque, throttle });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::harness::{self, run_for};
+    use crate::rc::{CHANNEL_MAX, CHANNEL_MIN};
+    use crate::{Signal, SignalEmitter, define_signal};
+    use core::pin::{Pin, pin};
+    use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+    use libm::{cosf, sinf};
+
+    const DT: f32 = 0.002;
+    const GAINS: AxisGains = AxisGains {
+        kp: 0.1,
+        ki: 0.5,
+        kd: 0.002,
+        kff: 0.005,
+    };
+
+    /// One axis of a quad: the motors follow the command with a lag, and the torque they make
+    /// accelerates the rotation against a little drag and whatever else pushes the drone.
+    #[derive(Default)]
+    struct Plant {
+        /// In rad/s.
+        rate: f32,
+        torque: f32,
+        /// In rad/s².
+        disturbance: f32,
+    }
+
+    impl Plant {
+        /// rad/s² at full output.
+        const AUTHORITY: f32 = 200.0;
+        const MOTOR_LAG: f32 = 0.02;
+        const DRAG: f32 = 1.0;
+
+        fn step(&mut self, output: f32) {
+            self.torque += (output - self.torque) * DT / (Self::MOTOR_LAG + DT);
+            let acceleration = Self::AUTHORITY * self.torque + self.disturbance;
+            self.rate += (acceleration - Self::DRAG * self.rate) * DT;
+        }
+    }
+
+    /// Runs the controller on `plant` for `steps`, at a throttle that neither attenuates nor
+    /// resets anything. Returns the highest rate seen.
+    fn fly(
+        pid: &mut AxisPid,
+        gains: &AxisGains,
+        plant: &mut Plant,
+        setpoint: f32,
+        steps: usize,
+    ) -> f32 {
+        let mut highest = f32::MIN;
+        for _ in 0..steps {
+            let output = pid.update(gains, setpoint, plant.rate, 1.0, 80.0, DT);
+            plant.step(output);
+            highest = highest.max(plant.rate);
+        }
+        highest
+    }
+
+    #[test]
+    fn tracks_the_setpoint() {
+        // The sticks start out centered, then ask for 3 rad/s.
+        let (mut pid, mut plant) = (AxisPid::default(), Plant::default());
+        fly(&mut pid, &GAINS, &mut plant, 0.0, 10);
+        let highest = fly(&mut pid, &GAINS, &mut plant, 3.0, 500);
+        assert!((plant.rate - 3.0).abs() < 0.03, "{}", plant.rate);
+        assert!(highest < 3.0 * 1.15, "{highest}");
+
+        // Feedforward gets there sooner.
+        let rise_time = |gains: &AxisGains| {
+            let (mut pid, mut plant) = (AxisPid::default(), Plant::default());
+            fly(&mut pid, gains, &mut plant, 0.0, 10);
+            (1..)
+                .find(|_| fly(&mut pid, gains, &mut plant, 3.0, 1) > 2.7)
+                .unwrap()
+        };
+        let without_ff = AxisGains { kff: 0.0, ..GAINS };
+        assert!(rise_time(&GAINS) < rise_time(&without_ff));
+    }
+
+    #[test]
+    fn the_i_term_holds_against_a_disturbance() {
+        let mut plant = Plant {
+            disturbance: 20.0,
+            ..Plant::default()
+        };
+        fly(&mut AxisPid::default(), &GAINS, &mut plant, 0.0, 2_000);
+        assert!(plant.rate.abs() < 0.01, "{}", plant.rate);
+
+        // P alone leaves the drone turning.
+        let mut plant = Plant {
+            disturbance: 20.0,
+            ..Plant::default()
+        };
+        let p_only = AxisGains { ki: 0.0, ..GAINS };
+        fly(&mut AxisPid::default(), &p_only, &mut plant, 0.0, 2_000);
+        assert!(plant.rate > 0.5, "{}", plant.rate);
+    }
+
+    #[test]
+    fn the_i_term_does_not_wind_up() {
+        // Held down on the ground, the drone can't follow no matter what.
+        let mut pid = AxisPid::default();
+        for _ in 0..2_000 {
+            pid.update(&GAINS, 6.0, 0.0, 1.0, 80.0, DT);
+        }
+        assert!(pid.integral <= ITERM_LIMIT);
+
+        // Saturated by P alone, it doesn't grow at all.
+        let strong = AxisGains { kp: 1.0, ..GAINS };
+        let mut pid = AxisPid::default();
+        for _ in 0..100 {
+            pid.update(&strong, 6.0, 0.0, 1.0, 80.0, DT);
+        }
+        assert_eq!(pid.integral, 0.0);
+    }
+
+    #[test]
+    fn the_i_term_relaxes_while_the_sticks_move() {
+        let (mut pid, mut plant) = (AxisPid::default(), Plant::default());
+        fly(&mut pid, &GAINS, &mut plant, 0.0, 10);
+        fly(&mut pid, &GAINS, &mut plant, 3.0, 25);
+        let relaxed = pid.integral;
+
+        // What it would have been had it integrated all of the lag.
+        let (mut pid, mut plant) = (AxisPid::default(), Plant::default());
+        let mut unrelaxed = 0.0;
+        for _ in 0..25 {
+            unrelaxed += GAINS.ki * (3.0 - plant.rate) * DT;
+            fly(&mut pid, &GAINS, &mut plant, 3.0, 1);
+        }
+        assert!(relaxed < unrelaxed / 4.0, "{relaxed} vs {unrelaxed}");
+    }
+
+    #[test]
+    fn the_d_term_damps_the_measurement_only() {
+        let d_only = AxisGains {
+            kp: 0.0,
+            ki: 0.0,
+            kd: 0.002,
+            kff: 0.0,
+        };
+        let mut pid = AxisPid::default();
+        pid.update(&d_only, 0.0, 0.0, 1.0, 80.0, DT);
+        // No kick when the stick moves.
+        assert_eq!(pid.update(&d_only, 3.0, 0.0, 1.0, 80.0, DT), 0.0);
+
+        // The drone starting to turn is resisted, through the filter.
+        let output = pid.update(&d_only, 3.0, 0.1, 1.0, 80.0, DT);
+        let unfiltered = -d_only.kd * 0.1 / DT;
+        assert!(output < 0.0 && output > unfiltered * 0.75, "{output}");
+        let settled = (0..100)
+            .map(|i| pid.update(&d_only, 3.0, 0.1 * (i + 2) as f32, 1.0, 80.0, DT))
+            .last()
+            .unwrap();
+        assert!((settled - unfiltered).abs() < 1e-3, "{settled}");
+    }
+
+    fn gains() -> RateGains {
+        RateGains::from_parameters(&ParamStore::default())
+    }
+
+    #[test]
+    fn tpa_cuts_p_and_d_at_high_throttle() {
+        let gains = gains();
+        assert_eq!(gains.tpa_factor(0.3), 1.0);
+        assert_eq!(gains.tpa_factor(0.6), 1.0);
+        assert!((gains.tpa_factor(0.8) - 0.75).abs() < 1e-6);
+        assert!((gains.tpa_factor(1.0) - 0.5).abs() < 1e-6);
+
+        let output = |throttle| {
+            let mut controller = RateController::new(RateGains {
+                roll_pitch: AxisGains {
+                    ki: 0.0,
+                    ..gains.roll_pitch
+                },
+                ..gains
+            });
+            let setpoint =
+                Vector3::new(1.0, 0.0, 0.0).map(AngularVelocity::new::<radian_per_second>);
+            controller
+                .update(&setpoint, &Vector3::default(), throttle, LOOP_PERIOD)
+                .x
+        };
+        // Without I, only P acts on the first update.
+        assert!((output(1.0) - output(0.5) / 2.0).abs() < 1e-6);
+    }
+
+    fn sticks(roll: u16, pitch: u16, throttle: u16, yaw: u16) -> RcChannels {
+        let mut channels = RcChannels::default();
+        channels.set(Channel::Roll, roll);
+        channels.set(Channel::Pitch, pitch);
+        channels.set(Channel::Throttle, throttle);
+        channels.set(Channel::Yaw, yaw);
+        channels
+    }
+
+    #[test]
+    fn setpoint_from_the_sticks() {
+        let max_rate = AngularVelocity::new::<degree_per_second>(360.0);
+        let channels = sticks(CHANNEL_MAX, CHANNEL_MIN, CHANNEL_MIN, CHANNEL_MAX);
+        let full = setpoint(&channels, max_rate).map(|rate| rate.get::<degree_per_second>());
+        assert_eq!(full, Vector3::new(360.0, -360.0, -360.0));
+        assert_eq!(throttle(&channels), 0.0);
+        assert_eq!(throttle(&RcChannels::default()), 0.0);
+        assert_eq!(throttle(&sticks(1024, 1024, CHANNEL_MAX, 1024)), 1.0);
+        let centered = setpoint(&RcChannels::default(), max_rate);
+        assert_eq!(centered, Vector3::default());
+    }
+
+    /// Runs `task` for 20 ms with the sticks at `channels` and the drone not turning.
+    fn hold<F: Future>(
+        mut task: Pin<&mut F>,
+        gyro: &mut impl EmitterBase<Vector3<AngularVelocity>>,
+        rc: &mut impl EmitterBase<RcChannels>,
+        channels: &RcChannels,
+    ) {
+        for _ in 0..10 {
+            gyro.emit(Vector3::default());
+            rc.emit(channels.clone());
+            run_for(task.as_mut(), 2);
+        }
+    }
+
+    define_signal!(RateGyro, Vector3<AngularVelocity>, 1);
+    define_signal!(RateRc, RcChannels, 1);
+    define_signal!(RateArmed, bool, 1);
+    define_signal!(RateFlightMode, FlightMode, 1);
+    define_signal!(RateAttitude, Quaternion, 1);
+    define_signal!(RateOutputs, RateOutput, 1);
+    define_signal!(RateParameters, ParamStore, 1);
+    #[test]
+    fn flies_on_the_signals() {
+        let _time = harness::lock();
+        let mut gyro = new_rate_gyro_signal_emitter();
+        let mut rc = new_rate_rc_signal_emitter();
+        let mut armed = new_rate_armed_signal_emitter();
+        let mut flight_mode = new_rate_flight_mode_signal_emitter();
+        let mut attitude = new_rate_attitude_signal_emitter();
+        let mut output = rate_outputs_signal().unwrap();
+        new_rate_parameters_signal_emitter().emit(ParamStore::default());
+
+        let mut task = pin!(run(
+            rate_gyro_signal().unwrap(),
+            rate_rc_signal().unwrap(),
+            rate_armed_signal().unwrap(),
+            rate_flight_mode_signal().unwrap(),
+            rate_attitude_signal().unwrap(),
+            new_rate_outputs_signal_emitter(),
+            rate_parameters_signal().unwrap(),
+        ));
+        // Nothing happens while disarmed.
+        let roll_right = sticks(CHANNEL_MAX, 1024, 1536, 1024);
+        hold(task.as_mut(), &mut gyro, &mut rc, &roll_right);
+        assert_eq!(output.get(), RateOutput::default());
+
+        armed.emit(true);
+        hold(task.as_mut(), &mut gyro, &mut rc, &roll_right);
+        let RateOutput { torque, throttle } = output.get();
+        assert!(torque.x > 0.5, "{torque:?}");
+        assert_eq!((torque.y, torque.z), (0.0, 0.0));
+        assert!((throttle - 0.5).abs() < 0.01);
+
+        // In angle mode, a drone rolled right with the sticks centered rolls back.
+        flight_mode.emit(FlightMode::Angle);
+        let half_roll = 10.0f32.to_radians();
+        attitude.emit(Quaternion {
+            w: cosf(half_roll),
+            x: sinf(half_roll),
+            y: 0.0,
+            z: 0.0,
+        });
+        hold(
+            task.as_mut(),
+            &mut gyro,
+            &mut rc,
+            &sticks(1024, 1024, 1536, 1024),
+        );
+        assert!(output.get().torque.x < 0.0, "{:?}", output.get());
+
+        // Losing the pilot's input lets go of the motors.
+        for _ in 0..150 {
+            gyro.emit(Vector3::default());
+            run_for(task.as_mut(), 2);
+        }
+        assert_eq!(output.get(), RateOutput::default());
+    }
+}
@@ -0,0 +1,225 @@
+//! The pilot's input as RC channels, sent with every uplink frame.
+//!
+//! There are [`CHANNEL_COUNT`] channels of [`CHANNEL_BITS`] bits each, packed like CRSF and SBUS
+//! do it: channel 0 takes the lowest bits of the first byte and every following channel continues
+//! right where the previous one stopped, least significant bits first.
+//!
+//! ```text
+//! byte 0   | byte 1   | byte 2   | ...
+//! 76543210 | 76543210 | 76543210 |
+//! 00000000 | 11111000 | 22111111 | ...
+//! ```
+
+use zerocopy::{FromBytes, Immutable, IntoBytes};
+
+pub const CHANNEL_COUNT: usize = 12;
+pub const CHANNEL_BITS: usize = 11;
+pub const CHANNEL_MIN: u16 = 0;
+pub const CHANNEL_CENTER: u16 = 1024;
+pub const CHANNEL_MAX: u16 = (1 << CHANNEL_BITS) - 1;
+const PACKED_SIZE: usize = (CHANNEL_COUNT * CHANNEL_BITS).div_ceil(8);
+
+/// What each channel carries, in the AETR order most receivers use.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Channel {
+    Roll = 0,
+    Pitch = 1,
+    Throttle = 2,
+    Yaw = 3,
+    LeftTrigger = 4,
+    RightTrigger = 5,
+    /// Two-position switches, at [`CHANNEL_MIN`] when off and [`CHANNEL_MAX`] when on.
+    Aux1 = 6,
+    Aux2 = 7,
+    Aux3 = 8,
+    Aux4 = 9,
+    Aux5 = 10,
+    Aux6 = 11,
+}
+
+impl Channel {
+    pub const AUX: [Channel; 6] = [
+        Channel::Aux1,
+        Channel::Aux2,
+        Channel::Aux3,
+        Channel::Aux4,
+        Channel::Aux5,
+        Channel::Aux6,
+    ];
+}
+
+#[derive(IntoBytes, FromBytes, Immutable, Debug, PartialEq, Clone)]
+#[repr(C, packed)]
+pub struct RcChannels {
+    packed: [u8; PACKED_SIZE],
+}
+
+impl Default for RcChannels {
+    /// Sticks centered, triggers released and switches off.
+    fn default() -> Self {
+        let mut channels = RcChannels {
+            packed: [0; PACKED_SIZE],
+        };
+        for channel in [
+            Channel::Roll,
+            Channel::Pitch,
+            Channel::Throttle,
+            Channel::Yaw,
+        ] {
+            channels.set(channel, CHANNEL_CENTER);
+        }
+        channels
+    }
+}
+
+impl RcChannels {
+    pub fn from_values(values: &[u16; CHANNEL_COUNT]) -> Self {
+        let mut channels = RcChannels {
+            packed: [0; PACKED_SIZE],
+        };
+        for (index, value) in values.iter().enumerate() {
+            channels.set_index(index, *value);
+        }
+        channels
+    }
+
+    pub fn values(&self) -> [u16; CHANNEL_COUNT] {
+        core::array::from_fn(|index| self.index(index))
+    }
+
+    pub fn get(&self, channel: Channel) -> u16 {
+        self.index(channel as usize)
+    }
+
+    /// Values above [`CHANNEL_MAX`] are clamped.
+    pub fn set(&mut self, channel: Channel, value: u16) {
+        self.set_index(channel as usize, value);
+    }
+
+    /// The channel's value in `[-1.0, 1.0]`, [`CHANNEL_CENTER`] being 0.
+    pub fn normalized(&self, channel: Channel) -> f32 {
+        let offset = self.get(channel) as f32 - CHANNEL_CENTER as f32;
+        (offset / (CHANNEL_MAX - CHANNEL_CENTER) as f32).clamp(-1.0, 1.0)
+    }
+
+    /// Whether a two-position switch is on, i.e. past the center.
+    pub fn switch(&self, channel: Channel) -> bool {
+        self.get(channel) > CHANNEL_CENTER
+    }
+
+    fn index(&self, index: usize) -> u16 {
+        let bit = index * CHANNEL_BITS;
+        let word = (0..3)
+            .filter_map(|i| self.packed.get(bit / 8 + i))
+            .enumerate()
+            .fold(0u32, |word, (i, byte)| word | (*byte as u32) << (8 * i));
+        (word >> (bit % 8)) as u16 & CHANNEL_MAX
+    }
+
+    fn set_index(&mut self, index: usize, value: u16) {
+        let bit = index * CHANNEL_BITS;
+        let value = (value.min(CHANNEL_MAX) as u32) << (bit % 8);
+        let mask = (CHANNEL_MAX as u32) << (bit % 8);
+        for i in 0..3 {
+            if let Some(byte) = self.packed.get_mut(bit / 8 + i) {
+                let shift = 8 * i;
+                *byte = (*byte & !(mask >> shift) as u8) | (value >> shift) as u8;
+            }
+        }
+    }
+}
+
+impl defmt::Format for RcChannels {
+    fn format(&self, fmt: defmt::Formatter) {
+        defmt::write!(fmt, "rc({})", self.values())
+    }
+}
+
+/// A gamepad axis reading, 0 to 255 and centered at 0x7F, as a channel value. The center maps to
+/// [`CHANNEL_CENTER`] exactly, so a stick at rest doesn't drift.
+pub fn axis_from_u8(value: u8) -> u16 {
+    const CENTER: u32 = 0x7F;
+    let value = value as u32;
+    let (min, center, max) = (
+        CHANNEL_MIN as u32,
+        CHANNEL_CENTER as u32,
+        CHANNEL_MAX as u32,
+    );
+    let channel = if value <= CENTER {
+        min + value * (center - min) / CENTER
+    } else {
+        center + (value - CENTER) * (max - center) / (0xFF - CENTER)
+    };
+    channel as u16
+}
+
+/// A gamepad trigger reading, 0 when released and 255 when pulled all the way, as a channel value.
+pub fn trigger_from_u8(value: u8) -> u16 {
+    CHANNEL_MIN + (value as u32 * (CHANNEL_MAX - CHANNEL_MIN) as u32 / 0xFF) as u16
+}
+
+/// A switch as a channel value.
+pub fn from_switch(on: bool) -> u16 {
+    if on { CHANNEL_MAX } else { CHANNEL_MIN }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn packs_eleven_bits_per_channel() {
+        assert_eq!(size_of::<RcChannels>(), 17);
+
+        let mut values = [0; CHANNEL_COUNT];
+        values[0] = CHANNEL_MAX;
+        values[1] = 0b101;
+        let channels = RcChannels::from_values(&values);
+        assert_eq!(
+            channels.as_bytes()[..4],
+            [0xFF, 0b0010_1111, 0b0000_0000, 0]
+        );
+        assert!(channels.as_bytes()[4..].iter().all(|byte| *byte == 0));
+    }
+
+    #[test]
+    fn values_round_trip() {
+        let values: [u16; CHANNEL_COUNT] =
+            core::array::from_fn(|i| (i as u16 * 331 + 7) % (CHANNEL_MAX + 1));
+        let mut channels = RcChannels::from_values(&values);
+        assert_eq!(channels.values(), values);
+
+        // Setting one channel leaves its neighbours alone.
+        channels.set(Channel::Yaw, CHANNEL_MAX);
+        channels.set(Channel::Aux6, u16::MAX);
+        let mut expected = values;
+        expected[Channel::Yaw as usize] = CHANNEL_MAX;
+        expected[Channel::Aux6 as usize] = CHANNEL_MAX;
+        assert_eq!(channels.values(), expected);
+
+        let bytes = channels.as_bytes();
+        assert_eq!(RcChannels::read_from_bytes(bytes).unwrap(), channels);
+    }
+
+    #[test]
+    fn gamepad_conversions() {
+        assert_eq!(axis_from_u8(0), CHANNEL_MIN);
+        assert_eq!(axis_from_u8(0x7F), CHANNEL_CENTER);
+        assert_eq!(axis_from_u8(0xFF), CHANNEL_MAX);
+        assert!(axis_from_u8(0x40) < axis_from_u8(0x41));
+        assert_eq!(trigger_from_u8(0), CHANNEL_MIN);
+        assert_eq!(trigger_from_u8(0xFF), CHANNEL_MAX);
+
+        let mut channels = RcChannels::default();
+        assert_eq!(channels.normalized(Channel::Throttle), 0.0);
+        assert_eq!(channels.normalized(Channel::LeftTrigger), -1.0);
+        assert!(!channels.switch(Channel::Aux1));
+
+        channels.set(Channel::Roll, axis_from_u8(0xFF));
+        channels.set(Channel::Pitch, axis_from_u8(0));
+        channels.set(Channel::Aux1, from_switch(true));
+        assert_eq!(channels.normalized(Channel::Roll), 1.0);
+        assert_eq!(channels.normalized(Channel::Pitch), -1.0);
+        assert!(channels.switch(Channel::Aux1));
+    }
+}
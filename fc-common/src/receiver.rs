@@ -0,0 +1,429 @@
+//! The drone's end of the radio link once it's bound. Every uplink frame is answered with an ack
+//! payload, the drone follows the controller's hops and link profile changes, and it keeps the link
+//! statistics up to date.
+//!
+//! The radio is behind [`ReceiverRadio`] and the rest of the drone behind [`CommandHandler`] and
+//! [`TelemetrySource`], so all of it runs on the host as well.
+
+use embassy_time::Instant;
+
+use crate::EmitterBase;
+use crate::adapt::{LinkFollower, LinkProfile};
+use crate::auth::Direction;
+use crate::bind::BindInfo;
+use crate::command::{Command, CommandReceiver, CommandResult};
+use crate::fhss::{HopReceiver, HopSequence, UPLINK_PERIOD};
+use crate::link::{Link, LinkStatistics, Received};
+use crate::param::ParamStore;
+use crate::protocol::{MAX_FRAME_SIZE, Message, ProtocolError};
+use crate::rc::RcChannels;
+use crate::telemetry::{DEFAULT_TELEMETRY_SCHEDULE, TelemetryKind, TelemetryScheduler};
+
+#[allow(async_fn_in_trait)]
+pub trait ReceiverRadio {
+    /// Waits until a frame may have arrived, or until `deadline` at the latest.
+    async fn wait(&mut self, deadline: Instant);
+
+    /// Reads the next frame that arrived into `buf` and returns its length, or `None` once there
+    /// are no more.
+    async fn read(&mut self, buf: &mut [u8; MAX_FRAME_SIZE]) -> Option<usize>;
+
+    /// Queues `payload` to go out with the ack of the next frame.
+    async fn queue_ack(&mut self, payload: &[u8]);
+
+    async fn set_channel(&mut self, channel: u8);
+
+    async fn set_profile(&mut self, profile: LinkProfile);
+
+    /// Called with every frame that arrived as `bytes` on `channel`, accepted or not.
+    fn arrived(
+        &mut self,
+        _channel: u8,
+        _bytes: &[u8],
+        _received: &Result<Received, ProtocolError>,
+    ) {
+    }
+
+    /// Called with every ack payload queued as `sealed`, the output of [`Link::encode`] for
+    /// `message`.
+    fn queued(&mut self, _channel: u8, _sealed: &[u8], _message: &Message) {}
+}
+
+/// Carries out the commands that aren't about the radio link itself.
+pub trait CommandHandler {
+    fn execute(&mut self, command: Command) -> CommandResult;
+
+    /// Set once a reboot has been accepted. [`run`] returns as soon as the ack for it has been
+    /// sent.
+    ///
+    /// A reboot wipes the record of executed commands, so if that ack is lost, the controller's
+    /// retransmission reboots the drone a second time.
+    fn reboot_requested(&self) -> bool;
+}
+
+/// Builds the telemetry sent whenever there is nothing else to send.
+pub trait TelemetrySource {
+    /// `uplink` is how the uplink is doing, for the diagnostics.
+    fn message(&mut self, kind: TelemetryKind, uplink: &LinkStatistics) -> Message;
+}
+
+/// Runs the link with the controller `binding` was made with, until a reboot is due. The radio has
+/// to be listening on the binding's address already. The pilot's input goes out on
+/// `rc_channels_emitter` as it arrives.
+///
+/// `boot_count` sets the sessions of this boot apart from those of every other, see
+/// [`Link::accept_sessions`]. Nothing but the handshake is answered until the controller has set
+/// one up.
+#[allow(clippy::too_many_arguments)]
+pub async fn run(
+    radio: &mut impl ReceiverRadio,
+    binding: &BindInfo,
+    boot_count: u32,
+    parameters: &mut ParamStore,
+    executor: &mut impl CommandHandler,
+    telemetry: &mut impl TelemetrySource,
+    mut rc_channels_emitter: impl EmitterBase<RcChannels>,
+    mut uplink_statistics_emitter: impl EmitterBase<LinkStatistics>,
+    mut parameters_emitter: impl EmitterBase<ParamStore>,
+) {
+    let mut hop = HopReceiver::new(
+        HopSequence::new(binding.hop_seed),
+        UPLINK_PERIOD,
+        Instant::now(),
+    );
+    let mut follower = LinkFollower::new(UPLINK_PERIOD, Instant::now());
+    let mut profile = follower.profile();
+    let mut channel = hop.channel();
+    radio.set_channel(channel).await;
+
+    let mut link = Link::new(binding.key.clone(), Direction::Downlink);
+    link.set_encryption(true);
+    link.accept_sessions(boot_count);
+    let mut scheduler = TelemetryScheduler::new(DEFAULT_TELEMETRY_SCHEDULE);
+    let mut commands = CommandReceiver::new();
+    loop {
+        // The counter of the last frame heard.
+        let mut heard = None;
+        let mut buf = [0u8; MAX_FRAME_SIZE];
+        while let Some(len) = radio.read(&mut buf).await {
+            // Receiving a frame means the ack payload queued for it has been sent.
+            if executor.reboot_requested() {
+                return;
+            }
+
+            let received = link.receive(&buf[..len], now_ms());
+            radio.arrived(channel, &buf[..len], &received);
+            if link.take_new_session() {
+                // The controller restarted or lost the drone for a while. Either way it gave up on
+                // the command in flight and any link switch, and went back to the full hop
+                // sequence.
+                commands.reset();
+                hop.set_blacklist(0);
+                follower.unstage();
+            }
+            let mut reply = None;
+            match received {
+                Ok(Received { frame, status }) if !status.is_fresh() => {
+                    heard = Some(frame.header.stamp.counter)
+                }
+                Ok(Received { frame, .. }) => {
+                    heard = Some(frame.header.stamp.counter);
+                    match frame.message {
+                        Message::RcChannels(channels) => rc_channels_emitter.emit(channels),
+                        Message::CommandRequest(request) => {
+                            let ack = commands.receive(&request, |command| {
+                                execute(executor, &mut hop, &mut follower, command)
+                            });
+                            reply = Some(Message::CommandAck(ack));
+                        }
+                        Message::ParamRequest(request) => {
+                            let response = parameters.handle(&request);
+                            parameters_emitter.emit_if_changed(parameters.clone());
+                            reply = Some(Message::ParamResponse(response));
+                        }
+                        _ => {}
+                    }
+                }
+                Err(_) => {}
+            }
+
+            // The ack payload is sent along with the ack of the *next* uplink frame.
+            // Replies to commands and parameter requests take priority over telemetry.
+            let message = match link.take_handshake().or(reply) {
+                Some(message) => message,
+                None if link.session().is_none() => continue,
+                None => {
+                    let kind = scheduler.next(Instant::now());
+                    telemetry.message(kind, &link.statistics())
+                }
+            };
+            let mut ack = [0u8; MAX_FRAME_SIZE];
+            let ack_len = link.encode(&message, now_ms(), &mut ack).unwrap();
+            radio.queued(channel, &ack[..ack_len], &message);
+            radio.queue_ack(&ack[..ack_len]).await;
+        }
+
+        uplink_statistics_emitter.emit_if_changed(link.statistics());
+        if let Some(counter) = heard {
+            hop.received(Instant::now());
+            follower.received(counter, Instant::now());
+        }
+
+        hop.update(Instant::now());
+        if hop.channel() != channel {
+            channel = hop.channel();
+            radio.set_channel(channel).await;
+        }
+        follower.update(Instant::now());
+        if follower.profile() != profile {
+            profile = follower.profile();
+            radio.set_profile(profile).await;
+        }
+
+        // Timing out just means it's time to hop or to switch link profiles.
+        radio.wait(hop.deadline().min(follower.deadline())).await;
+    }
+}
+
+/// Carries out `command`, unless it's about the radio link itself.
+fn execute(
+    executor: &mut impl CommandHandler,
+    hop: &mut HopReceiver,
+    follower: &mut LinkFollower,
+    command: Command,
+) -> CommandResult {
+    match command {
+        Command::SetHopBlacklist(blacklist) => {
+            hop.set_blacklist(blacklist);
+            CommandResult::Accepted
+        }
+        Command::SetLinkProfile(switch) => {
+            follower.stage(switch);
+            CommandResult::Accepted
+        }
+        command => executor.execute(command),
+    }
+}
+
+/// The millisecond clock used to stamp frames. Only differences between stamps matter, so it's fine
+/// for it to wrap.
+fn now_ms() -> u16 {
+    Instant::now().as_millis() as u16
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::LinkKey;
+    use crate::harness::{self, Scripted, run_for};
+    use crate::link::SEQUENCE_WINDOW;
+    use crate::rc::Channel;
+    use crate::telemetry::AltitudeTelemetry;
+    use crate::{Signal, SignalBase, SignalEmitter, define_signal};
+    use core::pin::{Pin, pin};
+    use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+    use embassy_time::Timer;
+
+    extern crate std;
+    use std::vec::Vec;
+
+    /// Hands out the frames the test pushes, and records what the receiver does with the radio.
+    struct MockRadio {
+        frames: Scripted<Vec<u8>>,
+        acks: Scripted<Vec<u8>>,
+        channels: Scripted<u8>,
+    }
+
+    impl ReceiverRadio for MockRadio {
+        async fn wait(&mut self, deadline: Instant) {
+            while self.frames.is_empty() && Instant::now() < deadline {
+                Timer::after_millis(1).await;
+            }
+        }
+
+        async fn read(&mut self, buf: &mut [u8; MAX_FRAME_SIZE]) -> Option<usize> {
+            let frame = self.frames.next()?;
+            buf[..frame.len()].copy_from_slice(&frame);
+            Some(frame.len())
+        }
+
+        async fn queue_ack(&mut self, payload: &[u8]) {
+            self.acks.push(payload.to_vec());
+        }
+
+        async fn set_channel(&mut self, channel: u8) {
+            self.channels.push(channel);
+        }
+
+        async fn set_profile(&mut self, _profile: LinkProfile) {}
+    }
+
+    struct MockExecutor {
+        executed: Scripted<Command>,
+        reboot_requested: bool,
+    }
+
+    impl CommandHandler for MockExecutor {
+        fn execute(&mut self, command: Command) -> CommandResult {
+            self.executed.push(command);
+            self.reboot_requested |= command == Command::Reboot;
+            CommandResult::Accepted
+        }
+
+        fn reboot_requested(&self) -> bool {
+            self.reboot_requested
+        }
+    }
+
+    struct MockTelemetry;
+
+    impl TelemetrySource for MockTelemetry {
+        fn message(&mut self, _kind: TelemetryKind, uplink: &LinkStatistics) -> Message {
+            Message::AltitudeTelemetry(AltitudeTelemetry {
+                altitude_cm: uplink.received as i32,
+                vertical_speed_cms: 0,
+            })
+        }
+    }
+
+    /// The controller's end of the link.
+    struct Controller {
+        link: Link,
+        frames: Scripted<Vec<u8>>,
+        acks: Scripted<Vec<u8>>,
+    }
+
+    impl Controller {
+        /// Sends `message` and returns the frame it went out in.
+        fn send(&mut self, message: &Message) -> Vec<u8> {
+            let mut buf = [0u8; MAX_FRAME_SIZE];
+            let len = self.link.encode(message, now_ms(), &mut buf).unwrap();
+            self.frames.push(buf[..len].to_vec());
+            buf[..len].to_vec()
+        }
+
+        /// Offers a new session and hands the drone's acceptance to the link.
+        fn start_session(&mut self, task: Pin<&mut impl Future<Output = ()>>, nonce: u32) {
+            self.link.offer_session(nonce);
+            let offer = self.link.take_handshake().unwrap();
+            self.send(&offer);
+            run_for(task, 1);
+            assert!(matches!(self.ack(), Message::SessionAccept(_)));
+        }
+
+        /// The message in the ack payload queued last.
+        fn ack(&mut self) -> Message {
+            let mut last = None;
+            while let Some(ack) = self.acks.next() {
+                last = Some(ack);
+            }
+            let ack = last.expect("An ack payload was queued");
+            self.link.receive(&ack, now_ms()).unwrap().frame.message
+        }
+    }
+
+    define_signal!(ReceiverRc, RcChannels, 1);
+    define_signal!(ReceiverStatistics, LinkStatistics, 1);
+    define_signal!(ReceiverParameters, ParamStore, 1);
+    #[test]
+    fn answers_the_controller() {
+        let _time = harness::lock();
+        let key = LinkKey::new([3; 32]);
+        let binding = BindInfo {
+            address: [1, 2, 3, 4, 5],
+            hop_seed: 0x1234_5678,
+            key: key.clone(),
+        };
+        let mut controller = Controller {
+            link: Link::new(key.clone(), Direction::Uplink),
+            frames: Scripted::new(),
+            acks: Scripted::new(),
+        };
+        let channels = Scripted::new();
+        let mut radio = MockRadio {
+            frames: controller.frames.clone(),
+            acks: controller.acks.clone(),
+            channels: channels.clone(),
+        };
+        let mut rc = receiver_rc_signal().unwrap();
+        let mut statistics = receiver_statistics_signal().unwrap();
+        let mut parameters = ParamStore::default();
+        let executed = Scripted::new();
+        let mut executor = MockExecutor {
+            executed: executed.clone(),
+            reboot_requested: false,
+        };
+        let mut telemetry = MockTelemetry;
+        let mut task = pin!(run(
+            &mut radio,
+            &binding,
+            5,
+            &mut parameters,
+            &mut executor,
+            &mut telemetry,
+            new_receiver_rc_signal_emitter(),
+            new_receiver_statistics_signal_emitter(),
+            new_receiver_parameters_signal_emitter(),
+        ));
+
+        assert_eq!(run_for(task.as_mut(), 0), None);
+        let first_channel = channels.next().unwrap();
+
+        // The controller sets up a session first. Hearing it moves the drone on to the next hop.
+        controller.start_session(task.as_mut(), 0x1234);
+        assert_eq!(controller.link.session().unwrap().drone_boot, 5);
+        assert_ne!(channels.next().unwrap(), first_channel);
+
+        // Telemetry goes out when there is nothing else to say, and the pilot's input is passed on.
+        let mut input = RcChannels::default();
+        input.set(Channel::Roll, 1500);
+        controller.send(&Message::RcChannels(input.clone()));
+        run_for(task.as_mut(), 1);
+        assert_eq!(rc.get(), input);
+        assert_eq!(
+            controller.ack(),
+            Message::AltitudeTelemetry(AltitudeTelemetry {
+                altitude_cm: 1,
+                vertical_speed_cms: 0
+            })
+        );
+        assert_eq!(statistics.get().received, 1);
+
+        // Commands are executed once and acknowledged.
+        let request = Command::Arm.to_request(1);
+        let recorded = controller.send(&Message::CommandRequest(request.clone()));
+        run_for(task.as_mut(), 1);
+        controller.send(&Message::CommandRequest(request.clone()));
+        run_for(task.as_mut(), 1);
+        let Message::CommandAck(ack) = controller.ack() else {
+            panic!("Commands are acknowledged");
+        };
+        assert_eq!((ack.id, ack.result()), (1, CommandResult::Accepted));
+        assert_eq!(executed.next(), Some(Command::Arm));
+        assert!(executed.is_empty());
+
+        // Replaying the request once it's stale doesn't make the drone forget it executed it.
+        for _ in 0..SEQUENCE_WINDOW {
+            controller.send(&Message::RcChannels(input.clone()));
+            run_for(task.as_mut(), 1);
+        }
+        controller.frames.push(recorded);
+        run_for(task.as_mut(), 1);
+        controller.send(&Message::CommandRequest(request.clone()));
+        run_for(task.as_mut(), 1);
+        assert!(executed.is_empty());
+
+        // A controller that restarted starts its ids over in a new session.
+        controller.link = Link::new(key, Direction::Uplink);
+        controller.start_session(task.as_mut(), 0x5678);
+        controller.send(&Message::CommandRequest(request));
+        run_for(task.as_mut(), 1);
+        assert_eq!(executed.next(), Some(Command::Arm));
+
+        // The drone reboots once the ack of the reboot has gone out with the next frame.
+        controller.send(&Message::CommandRequest(Command::Reboot.to_request(2)));
+        assert_eq!(run_for(task.as_mut(), 1), None);
+        controller.send(&Message::RcChannels(RcChannels::default()));
+        assert_eq!(run_for(task.as_mut(), 1), Some(()));
+    }
+}
@@ -0,0 +1,134 @@
+//! Every signal in use, for debug tooling to list and look into.
+//!
+//! A signal registers itself the first time it's claimed or an emitter is created for it, so
+//! signals nothing uses stay out of the list. Any signal can be tapped with
+//! [`SignalInfo::set_tapped`], which hands every value emitted on it to the tap set with
+//! [`set_tap`], be it a log or a serial port.
+
+use core::cell::Cell;
+use core::fmt::Debug;
+use core::iter;
+use core::sync::atomic::Ordering;
+
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+
+use crate::SignalInfo;
+
+/// Receives the values emitted on tapped signals.
+pub type Tap = fn(&'static SignalInfo, &dyn Debug);
+
+static SIGNALS: Mutex<CriticalSectionRawMutex, Cell<Option<&'static SignalInfo>>> =
+    Mutex::new(Cell::new(None));
+static TAP: Mutex<CriticalSectionRawMutex, Cell<Option<Tap>>> = Mutex::new(Cell::new(None));
+
+/// The registered signals, the most recently registered first.
+pub fn signals() -> impl Iterator<Item = &'static SignalInfo> {
+    iter::successors(SIGNALS.lock(Cell::get), |info| info.next.lock(Cell::get))
+}
+
+/// The registered signal called `name`, as passed to [`define_signal!`](crate::define_signal).
+pub fn find(name: &str) -> Option<&'static SignalInfo> {
+    signals().find(|info| info.name == name)
+}
+
+/// Sets where the values of tapped signals go, or stops tapping altogether with `None`.
+pub fn set_tap(tap: Option<Tap>) {
+    TAP.lock(|current| current.set(tap));
+}
+
+pub(crate) fn register(info: &'static SignalInfo) {
+    if info.registered.swap(true, Ordering::Relaxed) {
+        return;
+    }
+    SIGNALS.lock(|head| {
+        info.next.lock(|next| next.set(head.get()));
+        head.set(Some(info));
+    });
+}
+
+pub(crate) fn tap(info: &'static SignalInfo, value: &dyn Debug) {
+    if let Some(tap) = TAP.lock(Cell::get) {
+        tap(info, value);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Signal, SignalBase, SignalEmitter, define_signal};
+    use embassy_time::Instant;
+
+    extern crate std;
+    use std::format;
+    use std::string::String;
+    use std::sync::Mutex;
+    use std::vec::Vec;
+
+    define_signal!(Registered, u8, 1);
+    define_signal!(Unused, u8, 1);
+    #[test]
+    fn signals_register_when_used() {
+        assert!(find("Registered").is_none());
+        let _emitter = new_registered_signal_emitter();
+        let _signal = registered_signal().unwrap();
+
+        let info = find("Registered").unwrap();
+        assert!(core::ptr::eq(info, registered_signal_info()));
+        assert_eq!(
+            signals().filter(|info| info.name == "Registered").count(),
+            1
+        );
+        // Merely looking at a signal doesn't count as using it.
+        assert_eq!(unused_signal_info().name, "Unused");
+        assert!(find("Unused").is_none());
+    }
+
+    define_signal!(Snapshot, u8, 1);
+    #[test]
+    fn snapshot() {
+        let info = snapshot_signal_info();
+        let mut snapshot = String::new();
+        info.snapshot(|value, _| snapshot = format!("{value:?}"));
+        assert_eq!(snapshot, "");
+
+        let mut emitter = new_snapshot_signal_emitter();
+        emitter.emit(42);
+        let mut emitted_at = None;
+        info.snapshot(|value, at| {
+            snapshot = format!("{value:?}");
+            emitted_at = Some(at);
+        });
+        assert_eq!(snapshot, "42");
+        assert!(emitted_at.unwrap() <= Instant::now());
+    }
+
+    static TAPPED: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+    fn record(info: &'static SignalInfo, value: &dyn Debug) {
+        // Other tests emit concurrently.
+        if info.name == "Tapped" {
+            TAPPED
+                .lock()
+                .unwrap()
+                .push(format!("{} = {:?}", info.name, value));
+        }
+    }
+
+    define_signal!(Tapped, u8, 1);
+    #[test]
+    fn tapping() {
+        let mut emitter = new_tapped_signal_emitter();
+        emitter.emit(1);
+        tapped_signal_info().set_tapped(true);
+        emitter.emit(2);
+        set_tap(Some(record));
+        emitter.emit(3);
+        emitter.emit_if_changed(3);
+        find("Tapped").unwrap().set_tapped(false);
+        emitter.emit(4);
+        set_tap(None);
+
+        assert_eq!(*TAPPED.lock().unwrap(), ["Tapped = 3"]);
+    }
+}
@@ -1,9 +1,17 @@
+use core::cell::Cell;
+use core::fmt::Debug;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use embassy_sync::blocking_mutex::Mutex;
 use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
-use embassy_sync::watch::{Receiver, Sender};
+use embassy_sync::watch::{Receiver, Sender, Watch};
+use embassy_time::{Duration, Instant, with_timeout};
+
+use crate::registry;
 
+#[allow(async_fn_in_trait)]
 pub trait SignalBase<T> {
     /// Awaits and returns the next value emitted on this Signal.
-
     async fn next_value(&mut self) -> T;
 
     /// Awaits and returns the next *distinct* value emitted on this Signal.
@@ -11,82 +19,321 @@ pub trait SignalBase<T> {
     /// For instance, if the source emits the values `1, 2, 2, 3`, any consumers using this method will only see `1, 2, 3`.
     async fn next_distinct(&mut self) -> T;
     fn get(&mut self) -> T;
+
+    /// How long ago the current value was emitted, or `None` if nothing was emitted yet.
+    fn age(&mut self) -> Option<Duration>;
+
+    /// Returns the current value, unless it was emitted more than `max_age` ago or nothing was emitted yet.
+    fn get_if_fresh(&mut self, max_age: Duration) -> Option<T> {
+        let value = self.get();
+        self.age().filter(|age| *age <= max_age).map(|_| value)
+    }
+
+    /// Like [`next_value`](SignalBase::next_value), but gives up with `None` after `timeout`.
+    async fn next_value_timeout(&mut self, timeout: Duration) -> Option<T> {
+        with_timeout(timeout, self.next_value()).await.ok()
+    }
+}
+
+/// The sending end of a signal. Task logic that takes `impl EmitterBase<T>` runs against a test's recorder just as well.
+pub trait EmitterBase<T> {
+    fn emit(&mut self, value: T);
+
+    /// Emits `value` unless it's the value emitted last.
+    fn emit_if_changed(&mut self, value: T);
+}
+
+impl<T, E: EmitterBase<T>> EmitterBase<T> for &mut E {
+    fn emit(&mut self, value: T) {
+        (**self).emit(value);
+    }
+
+    fn emit_if_changed(&mut self, value: T) {
+        (**self).emit_if_changed(value);
+    }
+}
+
+/// A value sent over a signal, along with when it was emitted.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Timestamped<T> {
+    pub value: T,
+    pub emitted_at: Instant,
+}
+
+/// Calls the given function with a signal's current value and when it was emitted, unless nothing was emitted yet.
+pub type Snapshot = fn(&mut dyn FnMut(&dyn Debug, Instant));
+
+/// A signal's name, how many subscribers it was declared with, and how many of them are claimed. Also its entry in
+/// the [registry](crate::registry).
+pub struct SignalInfo {
+    pub name: &'static str,
+    pub subscribers: usize,
+    claimed: AtomicUsize,
+    tapped: AtomicBool,
+    snapshot: Snapshot,
+    /// Like `snapshot`, but calls the function for every value in the signal's [history](crate::history).
+    history: Option<Snapshot>,
+    pub(crate) registered: AtomicBool,
+    /// The signal registered before this one.
+    pub(crate) next: Mutex<CriticalSectionRawMutex, Cell<Option<&'static SignalInfo>>>,
+}
+
+impl SignalInfo {
+    pub const fn new(name: &'static str, subscribers: usize, snapshot: Snapshot) -> Self {
+        Self {
+            name,
+            subscribers,
+            claimed: AtomicUsize::new(0),
+            tapped: AtomicBool::new(false),
+            snapshot,
+            history: None,
+            registered: AtomicBool::new(false),
+            next: Mutex::new(Cell::new(None)),
+        }
+    }
+
+    /// For signals that keep a [history](crate::history), so it can be dumped without knowing the signal's type.
+    pub const fn with_history(mut self, history: Snapshot) -> Self {
+        self.history = Some(history);
+        self
+    }
+
+    /// The subscribers currently claimed. A dropped [`Signal`] gives its subscriber back.
+    pub fn claimed(&self) -> usize {
+        self.claimed.load(Ordering::Relaxed)
+    }
+
+    /// Calls `f` with the current value and when it was emitted, without claiming a subscriber. Nothing is called if
+    /// nothing was emitted yet.
+    pub fn snapshot(&self, mut f: impl FnMut(&dyn Debug, Instant)) {
+        (self.snapshot)(&mut f)
+    }
+
+    pub fn has_history(&self) -> bool {
+        self.history.is_some()
+    }
+
+    /// Calls `f` with every value in the signal's history and when it was emitted, from the oldest to the newest.
+    /// Nothing is called if the signal keeps no history.
+    pub fn history(&self, mut f: impl FnMut(&dyn Debug, Instant)) {
+        if let Some(history) = self.history {
+            history(&mut f)
+        }
+    }
+
+    /// Whether emitted values go to the [tap](crate::registry::set_tap).
+    pub fn is_tapped(&self) -> bool {
+        self.tapped.load(Ordering::Relaxed)
+    }
+
+    pub fn set_tapped(&self, tapped: bool) {
+        self.tapped.store(tapped, Ordering::Relaxed);
+    }
+}
+
+impl defmt::Format for SignalInfo {
+    fn format(&self, fmt: defmt::Formatter) {
+        defmt::write!(
+            fmt,
+            "{}: {}/{} subscribers claimed",
+            self.name,
+            self.claimed(),
+            self.subscribers
+        )
+    }
+}
+
+/// More subscribers were claimed than the signal was declared with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SubscribersExhausted {
+    pub signal: &'static str,
+    pub subscribers: usize,
+}
+
+impl defmt::Format for SubscribersExhausted {
+    fn format(&self, fmt: defmt::Formatter) {
+        defmt::write!(
+            fmt,
+            "All {} subscribers of signal {} are claimed",
+            self.subscribers,
+            self.signal
+        )
+    }
 }
 
 pub struct Signal<T: Clone + Default + PartialEq + 'static, const N: usize> {
-    receiver: Receiver<'static, CriticalSectionRawMutex, T, N>,
+    receiver: Receiver<'static, CriticalSectionRawMutex, Timestamped<T>, N>,
+    info: &'static SignalInfo,
     last_value: T,
+    /// `None` until something was emitted.
+    last_emitted_at: Option<Instant>,
 }
 
 impl<T: Clone + Default + PartialEq + 'static, const N: usize> SignalBase<T> for Signal<T, N> {
     async fn next_value(&mut self) -> T {
-        self.last_value = self.receiver.changed().await;
+        let stamped = self.receiver.changed().await;
+        self.update(stamped);
         self.last_value.clone()
     }
 
     async fn next_distinct(&mut self) -> T {
         loop {
-            let value = self.receiver.changed().await;
-            if value != self.last_value {
-                self.last_value = value.clone();
-                return value;
+            let stamped = self.receiver.changed().await;
+            let distinct = stamped.value != self.last_value;
+            // A repeated value still counts as a sign of life.
+            self.update(stamped);
+            if distinct {
+                return self.last_value.clone();
             }
         }
     }
 
     fn get(&mut self) -> T {
-        if let Some(value) = self.receiver.try_get() {
-            self.last_value = value;
-        }
-
+        self.refresh();
         self.last_value.clone()
     }
+
+    fn age(&mut self) -> Option<Duration> {
+        self.refresh();
+        self.last_emitted_at
+            .map(|emitted_at| Instant::now().saturating_duration_since(emitted_at))
+    }
 }
 
 impl<T: Clone + Default + PartialEq + 'static, const N: usize> Signal<T, N> {
-    pub fn new(
-        receiver: Receiver<'static, CriticalSectionRawMutex, T, N>,
-        default_value: T,
-    ) -> Self {
-        Self {
+    /// Claims one of the `N` subscribers of `watch`.
+    pub fn claim(
+        watch: &'static Watch<CriticalSectionRawMutex, Timestamped<T>, N>,
+        info: &'static SignalInfo,
+    ) -> Result<Self, SubscribersExhausted> {
+        let receiver = watch.receiver().ok_or(SubscribersExhausted {
+            signal: info.name,
+            subscribers: N,
+        })?;
+        registry::register(info);
+        info.claimed.fetch_add(1, Ordering::Relaxed);
+        Ok(Self {
             receiver,
-            last_value: default_value,
+            info,
+            last_value: T::default(),
+            last_emitted_at: None,
+        })
+    }
+
+    /// Claims all `N` subscribers of `watch` at once, or none of them if any is claimed already.
+    pub fn claim_all(
+        watch: &'static Watch<CriticalSectionRawMutex, Timestamped<T>, N>,
+        info: &'static SignalInfo,
+    ) -> Result<[Self; N], SubscribersExhausted> {
+        let signals: [Option<Self>; N] = core::array::from_fn(|_| Self::claim(watch, info).ok());
+        if signals.iter().any(Option::is_none) {
+            return Err(SubscribersExhausted {
+                signal: info.name,
+                subscribers: N,
+            });
+        }
+        Ok(signals.map(|signal| signal.expect("Every subscriber was claimed")))
+    }
+
+    fn refresh(&mut self) {
+        if let Some(stamped) = self.receiver.try_get() {
+            self.update(stamped);
         }
     }
+
+    fn update(&mut self, stamped: Timestamped<T>) {
+        self.last_value = stamped.value;
+        self.last_emitted_at = Some(stamped.emitted_at);
+    }
+}
+
+impl<T: Clone + Default + PartialEq + 'static, const N: usize> Drop for Signal<T, N> {
+    fn drop(&mut self) {
+        self.info.claimed.fetch_sub(1, Ordering::Relaxed);
+    }
 }
 
 pub struct SignalEmitter<T: Clone + Default + PartialEq + 'static, const N: usize> {
-    sender: Sender<'static, CriticalSectionRawMutex, T, N>,
+    sender: Sender<'static, CriticalSectionRawMutex, Timestamped<T>, N>,
+    info: &'static SignalInfo,
     last_emitted_value: T,
+    record: Option<fn(&Timestamped<T>)>,
 }
 
-impl<T: Clone + Default + PartialEq + 'static, const N: usize> SignalEmitter<T, N> {
-    pub fn new(sender: Sender<'static, CriticalSectionRawMutex, T, N>) -> Self {
+impl<T: Clone + Debug + Default + PartialEq + 'static, const N: usize> SignalEmitter<T, N> {
+    pub fn new(
+        sender: Sender<'static, CriticalSectionRawMutex, Timestamped<T>, N>,
+        info: &'static SignalInfo,
+    ) -> Self {
+        registry::register(info);
         Self {
             sender,
+            info,
             last_emitted_value: T::default(),
+            record: None,
         }
     }
 
+    /// Has `record` called with every value sent, to keep the signal's [history](crate::history).
+    pub fn with_history(mut self, record: fn(&Timestamped<T>)) -> Self {
+        self.record = Some(record);
+        self
+    }
+
     pub fn emit(&mut self, value: T) {
         self.last_emitted_value = value.clone();
-        self.sender.send(value);
+        self.send(value);
     }
 
+    /// Since nothing is sent when the value didn't change, the signal's age says how long ago it last changed
+    /// rather than how long ago the source was last heard from.
     pub fn emit_if_changed(&mut self, value: T) {
         if self.last_emitted_value != value {
             self.last_emitted_value = value.clone();
-            self.sender.send(value);
+            self.send(value);
+        }
+    }
+
+    fn send(&mut self, value: T) {
+        if self.info.is_tapped() {
+            registry::tap(self.info, &value);
         }
+        let stamped = Timestamped {
+            value,
+            emitted_at: Instant::now(),
+        };
+        if let Some(record) = self.record {
+            record(&stamped);
+        }
+        self.sender.send(stamped);
+    }
+}
+
+impl<T: Clone + Debug + Default + PartialEq + 'static, const N: usize> EmitterBase<T>
+    for SignalEmitter<T, N>
+{
+    fn emit(&mut self, value: T) {
+        SignalEmitter::emit(self, value);
+    }
+
+    fn emit_if_changed(&mut self, value: T) {
+        SignalEmitter::emit_if_changed(self, value);
     }
 }
 
-/// Creates a Watch and type aliases for a new signal.
+/// Creates a Watch and type aliases for a new signal. Every value sent over it is [`Timestamped`].
 /// Takes three arguments:
 /// - Signal name
 /// - The datatype of the signal.
 /// - The total number of receivers.
 ///
+/// Receivers are claimed with `foo_signal()`, or all at once with `foo_signals()`, and both fail with
+/// [`SubscribersExhausted`] rather than panic. `foo_signal_info()` tells how many are claimed and whether the signal
+/// is tapped. The signal's type must implement `Debug` for the [registry](crate::registry).
+///
+/// An optional fourth argument, `history = 64`, keeps the last 64 values in a [history](crate::history) that
+/// `foo_history()` returns.
+///
 /// ## Example
 /// ```rust,ignore
 /// define_signal!(Foo, u8, 2);
@@ -96,24 +343,26 @@ impl<T: Clone + Default + PartialEq + 'static, const N: usize> SignalEmitter<T,
 /// ```rust
 /// # use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
 /// # use embassy_sync::watch::{Watch, Receiver, Sender};
+/// # use fc_common::Timestamped;
 /// const FOO_SUBSCRIBERS: usize = 2;
 ///
-/// pub static FOO_WATCH: Watch<CriticalSectionRawMutex, u8, FOO_SUBSCRIBERS> = Watch::new();
+/// pub static FOO_WATCH: Watch<CriticalSectionRawMutex, Timestamped<u8>, FOO_SUBSCRIBERS> = Watch::new();
 ///
-/// pub type FooSender = Sender<'static, CriticalSectionRawMutex, u8, FOO_SUBSCRIBERS>;
+/// pub type FooSender = Sender<'static, CriticalSectionRawMutex, Timestamped<u8>, FOO_SUBSCRIBERS>;
 ///
-/// pub type FooReceiver = Receiver<'static, CriticalSectionRawMutex, u8, FOO_SUBSCRIBERS>;
+/// pub type FooReceiver = Receiver<'static, CriticalSectionRawMutex, Timestamped<u8>, FOO_SUBSCRIBERS>;
 /// ```
 #[macro_export]
 macro_rules! define_signal {
-    ($NAME:ident, $Ty:ty, $subs:expr) => {
+    (@signal $NAME:ident, $Ty:ty, $subs:expr $(, $record:expr, $history:expr)?) => {
         ::paste::paste! {
             const [<$NAME:snake:upper _SUBSCRIBERS>]: usize = $subs;
 
-            static [<$NAME:snake:upper _WATCH>]: embassy_sync::watch::Watch<CriticalSectionRawMutex, $Ty, [<$NAME:snake:upper _SUBSCRIBERS>]> = embassy_sync::watch::Watch::new();
+            static [<$NAME:snake:upper _WATCH>]: embassy_sync::watch::Watch<CriticalSectionRawMutex, $crate::Timestamped<$Ty>, [<$NAME:snake:upper _SUBSCRIBERS>]> = embassy_sync::watch::Watch::new();
 
             pub struct [<$NAME:camel Emitter>](SignalEmitter<$Ty, [<$NAME:snake:upper _SUBSCRIBERS>]>);
 
+            #[allow(dead_code)]
             impl [<$NAME:camel Emitter>]{
                 pub fn emit(&mut self, value: $Ty) {
                     self.0.emit(value);
@@ -124,6 +373,16 @@ macro_rules! define_signal {
                 }
             }
 
+            impl $crate::EmitterBase<$Ty> for [<$NAME:camel Emitter>] {
+                fn emit(&mut self, value: $Ty) {
+                    self.0.emit(value);
+                }
+
+                fn emit_if_changed(&mut self, value: $Ty) {
+                    self.0.emit_if_changed(value);
+                }
+            }
+
             pub struct [<$NAME:camel Signal>](Signal<$Ty, [<$NAME:snake:upper _SUBSCRIBERS>]>);
 
             impl SignalBase<$Ty> for [<$NAME:camel Signal>]{
@@ -138,25 +397,79 @@ macro_rules! define_signal {
                 fn get(&mut self) -> $Ty {
                     self.0.get()
                 }
+
+                fn age(&mut self) -> Option<embassy_time::Duration> {
+                    self.0.age()
+                }
+            }
+
+            static [<$NAME:snake:upper _SIGNAL_INFO>]: $crate::SignalInfo = $crate::SignalInfo::new(
+                stringify!($NAME),
+                [<$NAME:snake:upper _SUBSCRIBERS>],
+                |f| {
+                    if let Some(stamped) = [<$NAME:snake:upper _WATCH>].try_get() {
+                        f(&stamped.value, stamped.emitted_at);
+                    }
+                },
+            )$(.with_history($history))?;
+
+            /// Claims one subscriber of the signal.
+            #[allow(dead_code)]
+            pub fn [<$NAME:snake _signal>]() -> Result<[<$NAME:camel Signal>], $crate::SubscribersExhausted> {
+                Signal::claim(&[<$NAME:snake:upper _WATCH>], &[<$NAME:snake:upper _SIGNAL_INFO>]).map([<$NAME:camel Signal>])
+            }
+
+            /// Claims every subscriber of the signal at once, so a wrong subscriber count fails to compile where
+            /// the array is destructured.
+            #[allow(dead_code)]
+            pub fn [<$NAME:snake _signals>]() -> Result<[[<$NAME:camel Signal>]; [<$NAME:snake:upper _SUBSCRIBERS>]], $crate::SubscribersExhausted> {
+                Signal::claim_all(&[<$NAME:snake:upper _WATCH>], &[<$NAME:snake:upper _SIGNAL_INFO>])
+                    .map(|signals| signals.map([<$NAME:camel Signal>]))
             }
 
-            pub fn [<$NAME:snake _signal>]() -> [<$NAME:camel Signal>] {
-               let signal = Signal::new([<$NAME:snake:upper _WATCH>].receiver().unwrap(), $Ty::default());
-                [<$NAME:camel Signal>](signal)
+            #[allow(dead_code)]
+            pub fn [<$NAME:snake _signal_info>]() -> &'static $crate::SignalInfo {
+                &[<$NAME:snake:upper _SIGNAL_INFO>]
             }
 
+            #[allow(dead_code)]
             pub fn [<new_$NAME:snake _signal_emitter>]() -> [<$NAME:camel Emitter>] {
-                let emitter = SignalEmitter::new([<$NAME:snake:upper _WATCH>].sender());
-                [<$NAME:camel Emitter>](emitter)
+                let emitter = SignalEmitter::new([<$NAME:snake:upper _WATCH>].sender(), &[<$NAME:snake:upper _SIGNAL_INFO>]);
+                [<$NAME:camel Emitter>](emitter$(.with_history($record))?)
+            }
+        }
+    };
+    ($NAME:ident, $Ty:ty, $subs:expr) => {
+        $crate::define_signal!(@signal $NAME, $Ty, $subs);
+    };
+    ($NAME:ident, $Ty:ty, $subs:expr, history = $len:expr) => {
+        ::paste::paste! {
+            static [<$NAME:snake:upper _HISTORY>]: $crate::history::SignalHistory<$Ty, { $len }> =
+                $crate::history::SignalHistory::new();
+
+            /// The last values emitted on the signal.
+            #[allow(dead_code)]
+            pub fn [<$NAME:snake _history>]() -> &'static $crate::history::SignalHistory<$Ty, { $len }> {
+                &[<$NAME:snake:upper _HISTORY>]
             }
+
+            $crate::define_signal!(
+                @signal $NAME, $Ty, $subs,
+                |stamped| [<$NAME:snake:upper _HISTORY>].record(stamped),
+                |f| [<$NAME:snake:upper _HISTORY>].dump(f)
+            );
         }
     };
 }
 
 #[cfg(test)]
+#[allow(clippy::single_match)]
 mod tests {
     use super::*;
+    use crate::harness::{self, advance};
+    use core::pin::pin;
     use core::time::Duration;
+    use embassy_futures::{block_on, poll_once};
     use tokio::time::timeout;
 
     extern crate std;
@@ -164,7 +477,7 @@ mod tests {
     define_signal!(Test1, u8, 1);
     #[tokio::test]
     async fn signal_next_value_when_none_emitted() {
-        let mut signal = test1_signal();
+        let mut signal = test1_signal().unwrap();
         match timeout(Duration::from_millis(100), signal.next_value()).await {
             Ok(_) => {
                 panic!("next_value returned a value when not expected to");
@@ -177,7 +490,7 @@ mod tests {
     #[tokio::test]
     async fn signal_next_value() {
         let mut emitter = new_test2_signal_emitter();
-        let mut signal = test2_signal();
+        let mut signal = test2_signal().unwrap();
 
         emitter.emit(0);
         assert_eq!(signal.next_value().await, 0);
@@ -193,7 +506,7 @@ mod tests {
     define_signal!(Test3, u8, 1);
     #[tokio::test]
     async fn signal_next_distinct_when_none_emitted() {
-        let mut signal = test3_signal();
+        let mut signal = test3_signal().unwrap();
 
         match timeout(Duration::from_millis(100), signal.next_distinct()).await {
             Ok(_) => {
@@ -207,7 +520,7 @@ mod tests {
     #[tokio::test]
     async fn signal_next_distinct() {
         let mut emitter = new_test4_signal_emitter();
-        let mut signal = test4_signal();
+        let mut signal = test4_signal().unwrap();
 
         emitter.emit(5);
         assert_eq!(signal.next_distinct().await, 5);
@@ -223,4 +536,86 @@ mod tests {
         emitter.emit(6);
         assert_eq!(signal.next_distinct().await, 6);
     }
+
+    define_signal!(Test5, u8, 1);
+    #[test]
+    fn signal_age() {
+        let _time = harness::lock();
+        let mut emitter = new_test5_signal_emitter();
+        let mut signal = test5_signal().unwrap();
+        let max_age = embassy_time::Duration::from_millis(100);
+
+        assert_eq!(signal.age(), None);
+        assert_eq!(signal.get_if_fresh(max_age), None);
+
+        emitter.emit(7);
+        advance(60);
+        assert_eq!(signal.age(), Some(embassy_time::Duration::from_millis(60)));
+        assert_eq!(signal.get_if_fresh(max_age), Some(7));
+
+        advance(60);
+        assert_eq!(signal.get_if_fresh(max_age), None);
+        assert_eq!(signal.get(), 7);
+
+        // Re-emitting the same value refreshes it, unless it's only emitted if changed.
+        emitter.emit_if_changed(7);
+        assert_eq!(signal.get_if_fresh(max_age), None);
+        emitter.emit(7);
+        assert_eq!(signal.get_if_fresh(max_age), Some(7));
+    }
+
+    define_signal!(Test6, u8, 1);
+    #[test]
+    fn signal_next_value_timeout() {
+        let _time = harness::lock();
+        let mut emitter = new_test6_signal_emitter();
+        let mut signal = test6_signal().unwrap();
+        let timeout = embassy_time::Duration::from_millis(100);
+
+        emitter.emit(3);
+        assert_eq!(block_on(signal.next_value_timeout(timeout)), Some(3));
+
+        let mut next = pin!(signal.next_value_timeout(timeout));
+        assert!(poll_once(next.as_mut()).is_pending());
+        advance(99);
+        assert!(poll_once(next.as_mut()).is_pending());
+        advance(1);
+        assert_eq!(block_on(next), None);
+    }
+
+    define_signal!(Test7, u8, 2);
+    #[test]
+    fn subscriber_claims() {
+        let info = test7_signal_info();
+        assert_eq!(
+            (info.name, info.subscribers, info.claimed()),
+            ("Test7", 2, 0)
+        );
+
+        let first = test7_signal().unwrap();
+        assert_eq!(info.claimed(), 1);
+        // Claiming them all fails without holding on to any.
+        assert_eq!(
+            test7_signals().err(),
+            Some(SubscribersExhausted {
+                signal: "Test7",
+                subscribers: 2
+            })
+        );
+        assert_eq!(info.claimed(), 1);
+
+        let _second = test7_signal().unwrap();
+        assert!(test7_signal().is_err());
+        drop(first);
+        assert_eq!(info.claimed(), 1);
+        assert!(test7_signal().is_ok());
+    }
+
+    define_signal!(Test8, u8, 2);
+    #[test]
+    fn claim_all_subscribers() {
+        let [_first, _second] = test8_signals().unwrap();
+        assert_eq!(test8_signal_info().claimed(), 2);
+        assert!(test8_signal().is_err());
+    }
 }
@@ -0,0 +1,360 @@
+//! Settings that survive a power cycle, kept in a region of NOR flash.
+//!
+//! The region is a log of fixed size slots, each holding one record and a checksum. Records are only
+//! ever appended, and the last record of a kind wins. This spreads wear over the whole region and
+//! makes every update a single write, so losing power halfway through leaves a slot that fails its
+//! checksum instead of a corrupted setting. Once the region is full it is erased and the current
+//! state rewritten.
+//!
+//! Erasing stalls the CPU on both targets for up to a couple of seconds, which is why the drone only
+//! writes around boot.
+
+use embedded_storage::nor_flash::NorFlash;
+
+use crate::bind::{BIND_INFO_SIZE, BindInfo};
+use crate::crc::crc16;
+
+pub const SLOT_SIZE: usize = 64;
+const CHECKSUM_OFFSET: usize = SLOT_SIZE - 2;
+const ERASED: u8 = 0xFF;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+#[repr(u8)]
+enum RecordKind {
+    Binding = 0x01,
+    Unbound = 0x02,
+    /// Payload: the number of boots since the last settled one, including this one, then the number
+    /// of boots ever as a little-endian `u32`.
+    BootStarted = 0x03,
+    BootSettled = 0x04,
+}
+
+impl RecordKind {
+    fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0x01 => Some(Self::Binding),
+            0x02 => Some(Self::Unbound),
+            0x03 => Some(Self::BootStarted),
+            0x04 => Some(Self::BootSettled),
+            _ => None,
+        }
+    }
+}
+
+/// The binding and boot history, stored in `size` bytes of `flash` starting at `offset`.
+///
+/// `offset` and `size` must be multiples of the flash's erase size, which must be a multiple of
+/// [`SLOT_SIZE`].
+pub struct Store<F> {
+    flash: F,
+    offset: u32,
+    size: u32,
+    next_slot: u32,
+    binding: Option<BindInfo>,
+    unsettled_boots: u8,
+    boot_count: u32,
+}
+
+impl<F: NorFlash> Store<F> {
+    /// Reads the current state from flash.
+    pub fn new(flash: F, offset: u32, size: u32) -> Result<Self, F::Error> {
+        let mut store = Self {
+            flash,
+            offset,
+            size,
+            next_slot: 0,
+            binding: None,
+            unsettled_boots: 0,
+            boot_count: 0,
+        };
+
+        let mut slot = [0u8; SLOT_SIZE];
+        while store.next_slot < store.slot_count() {
+            store
+                .flash
+                .read(store.slot_offset(store.next_slot), &mut slot)?;
+            if slot.iter().all(|byte| *byte == ERASED) {
+                break;
+            }
+            store.next_slot += 1;
+            if crc16(&slot[..CHECKSUM_OFFSET]).to_le_bytes() != slot[CHECKSUM_OFFSET..] {
+                // Power was lost while writing it.
+                continue;
+            }
+            store.apply(&slot);
+        }
+
+        Ok(store)
+    }
+
+    /// The controller or drone this one is bound to.
+    pub fn binding(&self) -> Option<&BindInfo> {
+        self.binding.as_ref()
+    }
+
+    pub fn save_binding(&mut self, info: &BindInfo) -> Result<(), F::Error> {
+        let mut slot = [0u8; SLOT_SIZE];
+        slot[1..1 + BIND_INFO_SIZE].copy_from_slice(&info.to_bytes());
+        self.append(RecordKind::Binding, slot)
+    }
+
+    pub fn clear_binding(&mut self) -> Result<(), F::Error> {
+        self.append(RecordKind::Unbound, [0u8; SLOT_SIZE])
+    }
+
+    /// Records that the system has booted, and returns how many boots in a row haven't been followed
+    /// by [`Store::record_settled`], this one included.
+    pub fn record_boot(&mut self) -> Result<u8, F::Error> {
+        let mut slot = [0u8; SLOT_SIZE];
+        slot[1] = self.unsettled_boots.saturating_add(1);
+        slot[2..6].copy_from_slice(&self.boot_count.saturating_add(1).to_le_bytes());
+        self.append(RecordKind::BootStarted, slot)?;
+        Ok(self.unsettled_boots)
+    }
+
+    /// How many boots have been recorded, ever. It's never reset, so it only goes back if the
+    /// binding is lost along with it.
+    pub fn boot_count(&self) -> u32 {
+        self.boot_count
+    }
+
+    /// Records that the system has been running for long enough not to count the boot as a quick
+    /// power cycle.
+    pub fn record_settled(&mut self) -> Result<(), F::Error> {
+        self.append(RecordKind::BootSettled, [0u8; SLOT_SIZE])
+    }
+
+    fn slot_count(&self) -> u32 {
+        self.size / SLOT_SIZE as u32
+    }
+
+    fn slot_offset(&self, slot: u32) -> u32 {
+        self.offset + slot * SLOT_SIZE as u32
+    }
+
+    fn apply(&mut self, slot: &[u8; SLOT_SIZE]) {
+        match RecordKind::from_u8(slot[0]) {
+            Some(RecordKind::Binding) => {
+                let mut info = [0u8; BIND_INFO_SIZE];
+                info.copy_from_slice(&slot[1..1 + BIND_INFO_SIZE]);
+                self.binding = Some(BindInfo::from_bytes(&info));
+            }
+            Some(RecordKind::Unbound) => self.binding = None,
+            Some(RecordKind::BootStarted) => {
+                self.unsettled_boots = slot[1];
+                self.boot_count = u32::from_le_bytes([slot[2], slot[3], slot[4], slot[5]]);
+            }
+            Some(RecordKind::BootSettled) => self.unsettled_boots = 0,
+            // Written by a newer firmware.
+            None => {}
+        }
+    }
+
+    /// Writes a record with the payload in `slot[1..CHECKSUM_OFFSET]`.
+    fn append(&mut self, kind: RecordKind, mut slot: [u8; SLOT_SIZE]) -> Result<(), F::Error> {
+        if self.next_slot == self.slot_count() {
+            self.compact()?;
+        }
+        self.write(kind, &mut slot)?;
+        self.apply(&slot);
+        Ok(())
+    }
+
+    fn write(&mut self, kind: RecordKind, slot: &mut [u8; SLOT_SIZE]) -> Result<(), F::Error> {
+        slot[0] = kind as u8;
+        let crc = crc16(&slot[..CHECKSUM_OFFSET]);
+        slot[CHECKSUM_OFFSET..].copy_from_slice(&crc.to_le_bytes());
+        self.flash.write(self.slot_offset(self.next_slot), slot)?;
+        self.next_slot += 1;
+        Ok(())
+    }
+
+    /// Erases the region and writes back the current state.
+    fn compact(&mut self) -> Result<(), F::Error> {
+        self.flash.erase(self.offset, self.offset + self.size)?;
+        self.next_slot = 0;
+
+        // The boot count goes first: losing power before the binding is written back loses both, so
+        // the count never goes back for a binding that's kept.
+        if self.unsettled_boots > 0 || self.boot_count > 0 {
+            let mut slot = [0u8; SLOT_SIZE];
+            slot[1] = self.unsettled_boots;
+            slot[2..6].copy_from_slice(&self.boot_count.to_le_bytes());
+            self.write(RecordKind::BootStarted, &mut slot)?;
+        }
+        if let Some(info) = &self.binding {
+            let mut slot = [0u8; SLOT_SIZE];
+            slot[1..1 + BIND_INFO_SIZE].copy_from_slice(&info.to_bytes());
+            self.write(RecordKind::Binding, &mut slot)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use embedded_storage::nor_flash::{
+        ErrorType, NorFlashError, NorFlashErrorKind, ReadNorFlash, check_erase, check_read,
+        check_write,
+    };
+
+    const SECTOR_SIZE: usize = 256;
+    const SECTORS: usize = 4;
+
+    /// A flash that, like the real thing, can only clear bits until erased.
+    struct MockFlash {
+        data: [u8; SECTOR_SIZE * SECTORS],
+        erases: usize,
+    }
+
+    impl MockFlash {
+        fn new() -> Self {
+            Self {
+                data: [ERASED; SECTOR_SIZE * SECTORS],
+                erases: 0,
+            }
+        }
+    }
+
+    #[derive(Debug)]
+    struct MockError(NorFlashErrorKind);
+
+    impl NorFlashError for MockError {
+        fn kind(&self) -> NorFlashErrorKind {
+            self.0
+        }
+    }
+
+    impl ErrorType for MockFlash {
+        type Error = MockError;
+    }
+
+    impl ReadNorFlash for MockFlash {
+        const READ_SIZE: usize = 1;
+
+        fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+            check_read(self, offset, bytes.len()).map_err(MockError)?;
+            let offset = offset as usize;
+            bytes.copy_from_slice(&self.data[offset..offset + bytes.len()]);
+            Ok(())
+        }
+
+        fn capacity(&self) -> usize {
+            self.data.len()
+        }
+    }
+
+    impl NorFlash for MockFlash {
+        const WRITE_SIZE: usize = 4;
+        const ERASE_SIZE: usize = SECTOR_SIZE;
+
+        fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+            check_erase(self, from, to).map_err(MockError)?;
+            self.data[from as usize..to as usize].fill(ERASED);
+            self.erases += 1;
+            Ok(())
+        }
+
+        fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+            check_write(self, offset, bytes.len()).map_err(MockError)?;
+            for (cell, byte) in self.data[offset as usize..].iter_mut().zip(bytes) {
+                *cell &= byte;
+            }
+            Ok(())
+        }
+    }
+
+    const OFFSET: u32 = SECTOR_SIZE as u32;
+    const SIZE: u32 = 2 * SECTOR_SIZE as u32;
+
+    fn info(byte: u8) -> BindInfo {
+        BindInfo::from_random(&[byte; BIND_INFO_SIZE])
+    }
+
+    fn reopen(store: Store<MockFlash>) -> Store<MockFlash> {
+        Store::new(store.flash, OFFSET, SIZE).unwrap()
+    }
+
+    #[test]
+    fn empty_flash_is_unbound() {
+        let store = Store::new(MockFlash::new(), OFFSET, SIZE).unwrap();
+        assert!(store.binding().is_none());
+    }
+
+    #[test]
+    fn binding_survives_reopening() {
+        let mut store = Store::new(MockFlash::new(), OFFSET, SIZE).unwrap();
+        store.save_binding(&info(1)).unwrap();
+        store.save_binding(&info(2)).unwrap();
+        assert_eq!(store.binding(), Some(&info(2)));
+
+        let mut store = reopen(store);
+        assert_eq!(store.binding(), Some(&info(2)));
+
+        store.clear_binding().unwrap();
+        assert!(reopen(store).binding().is_none());
+    }
+
+    #[test]
+    fn counts_unsettled_boots() {
+        let mut store = Store::new(MockFlash::new(), OFFSET, SIZE).unwrap();
+        assert_eq!(store.record_boot().unwrap(), 1);
+        let mut store = reopen(store);
+        assert_eq!(store.record_boot().unwrap(), 2);
+        store.record_settled().unwrap();
+
+        let mut store = reopen(store);
+        assert_eq!(store.record_boot().unwrap(), 1);
+        assert_eq!(reopen(store).boot_count(), 3);
+    }
+
+    #[test]
+    fn compacts_when_full() {
+        let mut store = Store::new(MockFlash::new(), OFFSET, SIZE).unwrap();
+        store.save_binding(&info(3)).unwrap();
+        for _ in 0..20 {
+            store.record_boot().unwrap();
+        }
+        assert!(store.flash.erases > 0);
+        // The sectors outside the region are left alone.
+        assert!(
+            store.flash.data[..OFFSET as usize]
+                .iter()
+                .all(|byte| *byte == ERASED)
+        );
+        assert!(
+            store.flash.data[(OFFSET + SIZE) as usize..]
+                .iter()
+                .all(|byte| *byte == ERASED)
+        );
+
+        let mut store = reopen(store);
+        assert_eq!(store.binding(), Some(&info(3)));
+        assert_eq!(store.unsettled_boots, 20);
+        assert_eq!(store.boot_count(), 20);
+
+        // The count survives compactions after the boots have settled.
+        for _ in 0..20 {
+            store.record_settled().unwrap();
+        }
+        assert_eq!(reopen(store).boot_count(), 20);
+    }
+
+    #[test]
+    fn torn_write_is_skipped() {
+        let mut store = Store::new(MockFlash::new(), OFFSET, SIZE).unwrap();
+        store.save_binding(&info(4)).unwrap();
+        store.save_binding(&info(5)).unwrap();
+        // Power lost halfway through the second record.
+        let torn = (OFFSET as usize + SLOT_SIZE + SLOT_SIZE / 2)..(OFFSET as usize + 2 * SLOT_SIZE);
+        store.flash.data[torn].fill(ERASED);
+
+        let mut store = reopen(store);
+        assert_eq!(store.binding(), Some(&info(4)));
+        // New records go after the torn one.
+        store.save_binding(&info(6)).unwrap();
+        assert_eq!(reopen(store).binding(), Some(&info(6)));
+    }
+}
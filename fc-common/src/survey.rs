@@ -0,0 +1,162 @@
+//! Finding the quiet channels with the nRF24's received power detector.
+//!
+//! The detector latches when a signal stronger than -64 dBm shows up while the radio listens on a
+//! channel. Listening briefly on every channel is a sweep, and over many sweeps the survey learns how
+//! often each channel is busy. Wi-Fi is bursty, so a single sweep says very little.
+
+use crate::fhss::{HopSequence, MAX_BLACKLIST_LEVEL, SEQUENCE_LENGTH};
+
+/// The number of nRF24 channels, 2400 to 2525 MHz.
+pub const CHANNEL_COUNT: usize = 126;
+/// How many sweeps make a survey.
+pub const SURVEY_SWEEPS: u8 = 50;
+
+/// How often every channel was busy during the sweeps so far.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ChannelSurvey {
+    busy: [u8; CHANNEL_COUNT],
+    sweeps: u8,
+}
+
+impl Default for ChannelSurvey {
+    fn default() -> Self {
+        Self {
+            busy: [0; CHANNEL_COUNT],
+            sweeps: 0,
+        }
+    }
+}
+
+impl ChannelSurvey {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records a sweep, `busy(channel)` being whether power was detected on the channel.
+    pub fn record_sweep(&mut self, mut busy: impl FnMut(u8) -> bool) {
+        for channel in 0..CHANNEL_COUNT as u8 {
+            if busy(channel) {
+                self.busy[channel as usize] = self.busy[channel as usize].saturating_add(1);
+            }
+        }
+        self.sweeps = self.sweeps.saturating_add(1);
+    }
+
+    pub fn sweeps(&self) -> u8 {
+        self.sweeps
+    }
+
+    /// How often `channel` was busy, in percent of the sweeps.
+    pub fn occupancy(&self, channel: u8) -> u8 {
+        match (self.busy.get(channel as usize), self.sweeps) {
+            (Some(busy), sweeps) if sweeps > 0 => (*busy as u16 * 100 / sweeps as u16) as u8,
+            _ => 0,
+        }
+    }
+
+    /// Lower is quieter. A channel counts its own occupancy and half of that of its direct
+    /// neighbours, since a transmitter a channel away still bleeds into it.
+    pub fn score(&self, channel: u8) -> u16 {
+        let neighbours = [channel.checked_sub(1), channel.checked_add(1)]
+            .into_iter()
+            .flatten()
+            .map(|neighbour| self.occupancy(neighbour) as u16)
+            .sum::<u16>();
+        2 * self.occupancy(channel) as u16 + neighbours
+    }
+
+    /// Every channel, from the quietest to the busiest.
+    pub fn ranking(&self) -> [u8; CHANNEL_COUNT] {
+        let mut ranking: [u8; CHANNEL_COUNT] = core::array::from_fn(|channel| channel as u8);
+        ranking.sort_unstable_by_key(|channel| (self.score(*channel), *channel));
+        ranking
+    }
+
+    /// The quietest channel of `channels`.
+    pub fn cleanest(&self, channels: core::ops::RangeInclusive<u8>) -> u8 {
+        let start = *channels.start();
+        channels
+            .min_by_key(|channel| (self.score(*channel), *channel))
+            .unwrap_or(start)
+    }
+
+    /// The hop blacklist that moves every slot of `sequence` to the quietest of its channels.
+    pub fn hop_blacklist(&self, sequence: &HopSequence) -> u32 {
+        let mut blacklisted = sequence.clone();
+        blacklisted.set_blacklist(0);
+        for slot in 0..SEQUENCE_LENGTH {
+            // Ties go to the lowest level, so a quiet band leaves the sequence alone.
+            let level = (0..=MAX_BLACKLIST_LEVEL)
+                .min_by_key(|level| (self.score(sequence.channel_at_level(slot, *level)), *level))
+                .unwrap_or(0);
+            blacklisted.set_blacklist(blacklisted.with_level(slot, level));
+        }
+
+        blacklisted.blacklist()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A survey of a band with a Wi-Fi network around `wifi_center`, busy half the time.
+    fn survey(wifi_center: u8) -> ChannelSurvey {
+        let mut survey = ChannelSurvey::new();
+        for sweep in 0..SURVEY_SWEEPS {
+            survey.record_sweep(|channel| sweep % 2 == 0 && channel.abs_diff(wifi_center) <= 10);
+        }
+        survey
+    }
+
+    #[test]
+    fn occupancy_in_percent() {
+        let survey = survey(37);
+        assert_eq!(survey.sweeps(), SURVEY_SWEEPS);
+        assert_eq!(survey.occupancy(37), 50);
+        assert_eq!(survey.occupancy(80), 0);
+        assert_eq!(survey.occupancy(200), 0);
+        assert_eq!(ChannelSurvey::new().occupancy(37), 0);
+    }
+
+    #[test]
+    fn ranking_puts_quiet_channels_first() {
+        let survey = survey(37);
+        let ranking = survey.ranking();
+
+        // Quiet channels first, lowest first among equals.
+        assert_eq!(ranking[0], 0);
+        assert!(
+            ranking[..CHANNEL_COUNT - 21]
+                .iter()
+                .all(|c| c.abs_diff(37) > 10)
+        );
+        // The edges of the busy band are less busy than its middle, thanks to their neighbours.
+        assert_eq!(ranking[CHANNEL_COUNT - 21], 27);
+        assert!(survey.score(27) < survey.score(28));
+
+        assert_eq!(survey.cleanest(30..=80), 49);
+    }
+
+    #[test]
+    fn hop_blacklist_avoids_busy_channels() {
+        let sequence = HopSequence::new(0xC0FFEE);
+        let quiet = ChannelSurvey::new();
+        assert_eq!(quiet.hop_blacklist(&sequence), 0);
+
+        let survey = survey(37);
+        assert_ne!(survey.hop_blacklist(&sequence), 0);
+        let mut blacklisted = sequence.clone();
+        blacklisted.set_blacklist(survey.hop_blacklist(&sequence));
+        for slot in 0..SEQUENCE_LENGTH {
+            let quietest = (0..=MAX_BLACKLIST_LEVEL)
+                .map(|level| survey.score(sequence.channel_at_level(slot, level)))
+                .min()
+                .unwrap();
+            assert_eq!(survey.score(blacklisted.channel(slot)), quietest);
+            if survey.score(sequence.channel(slot)) == 0 {
+                assert_eq!(blacklisted.level(slot), 0);
+            }
+        }
+    }
+}
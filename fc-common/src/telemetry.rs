@@ -0,0 +1,441 @@
+//! Telemetry sent from the drone to the controller.
+
+use embassy_time::{Duration, Instant};
+use zerocopy::{FromBytes, Immutable, IntoBytes};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[repr(u8)]
+pub enum BatteryStatus {
+    Ok = 0,
+    Low = 1, // 7.5 V
+    #[default]
+    Critical = 2, // 7.3 V
+    Cutoff = 3, // 7.0 V
+}
+
+impl TryFrom<u8> for BatteryStatus {
+    type Error = u8;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(BatteryStatus::Ok),
+            1 => Ok(BatteryStatus::Low),
+            2 => Ok(BatteryStatus::Critical),
+            3 => Ok(BatteryStatus::Cutoff),
+            _ => Err(value),
+        }
+    }
+}
+
+impl defmt::Format for BatteryStatus {
+    fn format(&self, fmt: defmt::Formatter) {
+        match self {
+            BatteryStatus::Ok => defmt::write!(fmt, "Ok"),
+            BatteryStatus::Low => defmt::write!(fmt, "Low"),
+            BatteryStatus::Critical => defmt::write!(fmt, "Critical"),
+            BatteryStatus::Cutoff => defmt::write!(fmt, "Cutoff"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[repr(u8)]
+pub enum FlightMode {
+    /// Sticks command rotation rates.
+    #[default]
+    Rate = 0,
+    /// Sticks command roll and pitch angles, the drone levels itself when they are centered.
+    Angle = 1,
+    /// Angle mode around the center of the sticks, rate mode at full deflection.
+    Horizon = 2,
+}
+
+impl TryFrom<u8> for FlightMode {
+    type Error = u8;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(FlightMode::Rate),
+            1 => Ok(FlightMode::Angle),
+            2 => Ok(FlightMode::Horizon),
+            _ => Err(value),
+        }
+    }
+}
+
+impl defmt::Format for FlightMode {
+    fn format(&self, fmt: defmt::Formatter) {
+        match self {
+            FlightMode::Rate => defmt::write!(fmt, "Rate"),
+            FlightMode::Angle => defmt::write!(fmt, "Angle"),
+            FlightMode::Horizon => defmt::write!(fmt, "Horizon"),
+        }
+    }
+}
+
+/// A set of fault flags.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Faults(pub u16);
+
+impl Faults {
+    pub const NONE: Faults = Faults(0);
+    pub const BATTERY_LOW: Faults = Faults(1 << 0);
+    pub const BATTERY_CRITICAL: Faults = Faults(1 << 1);
+    pub const BAROMETER: Faults = Faults(1 << 2);
+    pub const IMU: Faults = Faults(1 << 3);
+    /// No valid uplink frame has been received for a while.
+    pub const FAILSAFE: Faults = Faults(1 << 4);
+
+    pub fn contains(&self, other: Faults) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn insert(&mut self, other: Faults) {
+        self.0 |= other.0;
+    }
+
+    pub fn remove(&mut self, other: Faults) {
+        self.0 &= !other.0;
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0 == 0
+    }
+}
+
+impl core::ops::BitOr for Faults {
+    type Output = Faults;
+
+    fn bitor(self, rhs: Self) -> Self::Output {
+        Faults(self.0 | rhs.0)
+    }
+}
+
+impl defmt::Format for Faults {
+    fn format(&self, fmt: defmt::Formatter) {
+        defmt::write!(fmt, "Faults({=u16:b})", self.0)
+    }
+}
+
+/// Attitude and flight state. This is the fastest telemetry frame.
+///
+/// Fields are kept as plain integers so the frames can be sent as-is. Use the accessors to get
+/// typed values.
+#[derive(IntoBytes, FromBytes, Immutable, Debug, PartialEq, Default, Clone)]
+#[repr(C, packed)]
+pub struct AttitudeTelemetry {
+    /// Roll, pitch and yaw in hundredths of a degree.
+    pub roll_cdeg: i16,
+    pub pitch_cdeg: i16,
+    pub yaw_cdeg: i16,
+    /// 1 if the motors are armed.
+    pub armed: u8,
+    /// A [`FlightMode`].
+    pub flight_mode: u8,
+    /// A set of [`Faults`].
+    pub faults: u16,
+}
+
+impl AttitudeTelemetry {
+    pub fn is_armed(&self) -> bool {
+        self.armed != 0
+    }
+
+    pub fn flight_mode(&self) -> FlightMode {
+        FlightMode::try_from(self.flight_mode).unwrap_or_default()
+    }
+
+    pub fn faults(&self) -> Faults {
+        Faults(self.faults)
+    }
+
+    /// Roll, pitch and yaw in degrees.
+    pub fn attitude_degrees(&self) -> (f32, f32, f32) {
+        (
+            self.roll_cdeg as f32 / 100.0,
+            self.pitch_cdeg as f32 / 100.0,
+            self.yaw_cdeg as f32 / 100.0,
+        )
+    }
+}
+
+impl defmt::Format for AttitudeTelemetry {
+    fn format(&self, fmt: defmt::Formatter) {
+        defmt::write!(
+            fmt,
+            "attitude({}, {}, {}) armed({}) mode({}) {}",
+            { self.roll_cdeg },
+            { self.pitch_cdeg },
+            { self.yaw_cdeg },
+            self.is_armed(),
+            self.flight_mode(),
+            self.faults(),
+        )
+    }
+}
+
+#[derive(IntoBytes, FromBytes, Immutable, Debug, PartialEq, Default, Clone)]
+#[repr(C, packed)]
+pub struct BatteryTelemetry {
+    /// Battery pack voltage in millivolts.
+    pub voltage_mv: u16,
+    /// Battery charge in percent.
+    pub battery_level: u8,
+    /// A [`BatteryStatus`].
+    pub battery_status: u8,
+}
+
+impl BatteryTelemetry {
+    /// Unknown values are reported as [`BatteryStatus::Critical`], the same as the default.
+    pub fn battery_status(&self) -> BatteryStatus {
+        BatteryStatus::try_from(self.battery_status).unwrap_or_default()
+    }
+}
+
+impl defmt::Format for BatteryTelemetry {
+    fn format(&self, fmt: defmt::Formatter) {
+        defmt::write!(
+            fmt,
+            "voltage({}mV) battery({}%, {})",
+            { self.voltage_mv },
+            self.battery_level,
+            self.battery_status(),
+        )
+    }
+}
+
+#[derive(IntoBytes, FromBytes, Immutable, Debug, PartialEq, Default, Clone)]
+#[repr(C, packed)]
+pub struct AltitudeTelemetry {
+    /// Altitude above the take-off point in centimeters.
+    pub altitude_cm: i32,
+    /// Vertical speed in centimeters per second, positive upwards.
+    pub vertical_speed_cms: i16,
+}
+
+impl defmt::Format for AltitudeTelemetry {
+    fn format(&self, fmt: defmt::Formatter) {
+        defmt::write!(fmt, "altitude({}cm, {}cm/s)", { self.altitude_cm }, {
+            self.vertical_speed_cms
+        },)
+    }
+}
+
+/// Rarely changing information about the drone and the uplink as seen from the drone.
+#[derive(IntoBytes, FromBytes, Immutable, Debug, PartialEq, Default, Clone)]
+#[repr(C, packed)]
+pub struct DiagnosticsTelemetry {
+    pub uptime_s: u32,
+    pub uplink_received: u32,
+    pub uplink_lost: u32,
+    /// Uplink loss over the last [`crate::link::SEQUENCE_WINDOW`] frames, in percent.
+    pub uplink_loss_percent: u8,
+    /// Round-trip time measured by the drone, or 0 if unknown.
+    pub uplink_rtt_ms: u16,
+}
+
+impl defmt::Format for DiagnosticsTelemetry {
+    fn format(&self, fmt: defmt::Formatter) {
+        defmt::write!(
+            fmt,
+            "uptime({}s) uplink(received {}, lost {}, loss {}%, rtt {}ms)",
+            { self.uptime_s },
+            { self.uplink_received },
+            { self.uplink_lost },
+            self.uplink_loss_percent,
+            { self.uplink_rtt_ms },
+        )
+    }
+}
+
+/// The telemetry frames the drone rotates through its ack payloads.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TelemetryKind {
+    Attitude,
+    Battery,
+    Altitude,
+    Diagnostics,
+}
+
+impl defmt::Format for TelemetryKind {
+    fn format(&self, fmt: defmt::Formatter) {
+        match self {
+            TelemetryKind::Attitude => defmt::write!(fmt, "Attitude"),
+            TelemetryKind::Battery => defmt::write!(fmt, "Battery"),
+            TelemetryKind::Altitude => defmt::write!(fmt, "Altitude"),
+            TelemetryKind::Diagnostics => defmt::write!(fmt, "Diagnostics"),
+        }
+    }
+}
+
+/// The default rotation: fast attitude, slower altitude and battery, and rare diagnostics.
+pub const DEFAULT_TELEMETRY_SCHEDULE: [(TelemetryKind, Duration); 4] = [
+    (TelemetryKind::Attitude, Duration::from_millis(20)),
+    (TelemetryKind::Altitude, Duration::from_millis(100)),
+    (TelemetryKind::Battery, Duration::from_millis(500)),
+    (TelemetryKind::Diagnostics, Duration::from_millis(2000)),
+];
+
+struct ScheduleEntry {
+    kind: TelemetryKind,
+    period: Duration,
+    deadline: Instant,
+}
+
+/// Decides which telemetry frame goes into the next ack payload.
+///
+/// Each frame type is due once per its period. Of the frames that are due, the one with the
+/// earliest deadline is picked. When nothing is due, the first frame of the schedule is sent
+/// early, so the fastest frame should be listed first to fill the spare ack payloads.
+pub struct TelemetryScheduler<const N: usize> {
+    entries: [ScheduleEntry; N],
+}
+
+impl<const N: usize> TelemetryScheduler<N> {
+    /// Creates a scheduler where every frame type is due immediately. On ties, frames listed first
+    /// win.
+    pub fn new(schedule: [(TelemetryKind, Duration); N]) -> Self {
+        const { assert!(N > 0, "The telemetry schedule can't be empty") };
+        Self {
+            entries: schedule.map(|(kind, period)| ScheduleEntry {
+                kind,
+                period,
+                deadline: Instant::from_ticks(0),
+            }),
+        }
+    }
+
+    pub fn next(&mut self, now: Instant) -> TelemetryKind {
+        let mut due: Option<usize> = None;
+        for (i, entry) in self.entries.iter().enumerate() {
+            let earlier = due.is_none_or(|d| entry.deadline < self.entries[d].deadline);
+            if entry.deadline <= now && earlier {
+                due = Some(i);
+            }
+        }
+
+        let next = &mut self.entries[due.unwrap_or(0)];
+        next.deadline += next.period;
+        // A frame that fell more than a period behind is rescheduled from now rather than from its
+        // missed deadline, so a stalled link doesn't cause a burst of catch-up frames.
+        if next.deadline <= now {
+            next.deadline = now + next.period;
+        }
+
+        next.kind
+    }
+}
+
+/// Converts degrees into the hundredths of a degree used on the wire, saturating at the limits of
+/// an `i16`.
+pub fn to_centidegrees(degrees: f32) -> i16 {
+    (degrees * 100.0).clamp(i16::MIN as f32, i16::MAX as f32) as i16
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn typed_accessors() {
+        let attitude = AttitudeTelemetry {
+            armed: 1,
+            flight_mode: FlightMode::Horizon as u8,
+            faults: (Faults::IMU | Faults::BATTERY_LOW).0,
+            roll_cdeg: to_centidegrees(-12.5),
+            pitch_cdeg: to_centidegrees(3.0),
+            yaw_cdeg: to_centidegrees(179.25),
+        };
+        let battery = BatteryTelemetry {
+            battery_status: BatteryStatus::Low as u8,
+            ..Default::default()
+        };
+
+        assert_eq!(battery.battery_status(), BatteryStatus::Low);
+        assert!(attitude.is_armed());
+        assert_eq!(attitude.flight_mode(), FlightMode::Horizon);
+        assert!(attitude.faults().contains(Faults::IMU));
+        assert!(!attitude.faults().contains(Faults::BAROMETER));
+        assert_eq!(attitude.attitude_degrees(), (-12.5, 3.0, 179.25));
+    }
+
+    #[test]
+    fn unknown_enum_values_fall_back_to_defaults() {
+        let attitude = AttitudeTelemetry {
+            flight_mode: 0xFF,
+            ..Default::default()
+        };
+        let battery = BatteryTelemetry {
+            battery_status: 0xFF,
+            ..Default::default()
+        };
+
+        assert_eq!(battery.battery_status(), BatteryStatus::Critical);
+        assert_eq!(attitude.flight_mode(), FlightMode::Rate);
+    }
+
+    /// Runs the scheduler for `duration_ms` with one ack payload every `slot_ms` and counts how
+    /// often each frame type was picked.
+    fn simulate<const N: usize>(
+        scheduler: &mut TelemetryScheduler<N>,
+        slot_ms: u64,
+        duration_ms: u64,
+    ) -> [usize; 4] {
+        let mut counts = [0; 4];
+        for t in (0..duration_ms).step_by(slot_ms as usize) {
+            let index = match scheduler.next(Instant::from_millis(t)) {
+                TelemetryKind::Attitude => 0,
+                TelemetryKind::Battery => 1,
+                TelemetryKind::Altitude => 2,
+                TelemetryKind::Diagnostics => 3,
+            };
+            counts[index] += 1;
+        }
+
+        counts
+    }
+
+    #[test]
+    fn scheduler_honors_rates() {
+        let mut scheduler = TelemetryScheduler::new(DEFAULT_TELEMETRY_SCHEDULE);
+        let [attitude, battery, altitude, diagnostics] = simulate(&mut scheduler, 10, 10_000);
+
+        // 1000 slots in total. The slower frames go out at their configured rate, attitude gets
+        // the rest.
+        assert_eq!(battery, 20);
+        assert_eq!(altitude, 100);
+        assert_eq!(diagnostics, 5);
+        assert_eq!(attitude, 1000 - 20 - 100 - 5);
+    }
+
+    #[test]
+    fn scheduler_first_round_sends_everything() {
+        let mut scheduler = TelemetryScheduler::new(DEFAULT_TELEMETRY_SCHEDULE);
+        let now = Instant::from_millis(0);
+
+        assert_eq!(scheduler.next(now), TelemetryKind::Attitude);
+        assert_eq!(scheduler.next(now), TelemetryKind::Altitude);
+        assert_eq!(scheduler.next(now), TelemetryKind::Battery);
+        assert_eq!(scheduler.next(now), TelemetryKind::Diagnostics);
+    }
+
+    #[test]
+    fn scheduler_shares_slow_link_fairly() {
+        // With one ack payload every 200 ms, the faster frames can't keep their rates. Every frame
+        // still gets through, and the rare diagnostics are not starved.
+        let mut scheduler = TelemetryScheduler::new(DEFAULT_TELEMETRY_SCHEDULE);
+        let [attitude, battery, altitude, diagnostics] = simulate(&mut scheduler, 200, 20_000);
+
+        // 100 slots in total, diagnostics keep their rate and the rest is shared.
+        assert_eq!(diagnostics, 10);
+        assert!(battery >= 20, "battery = {}", battery);
+        assert!(altitude >= 30, "altitude = {}", altitude);
+        assert!(attitude >= 30, "attitude = {}", attitude);
+    }
+
+    #[test]
+    fn centidegrees_saturate() {
+        assert_eq!(to_centidegrees(1000.0), i16::MAX);
+        assert_eq!(to_centidegrees(-1000.0), i16::MIN);
+    }
+}
@@ -0,0 +1,18 @@
+use crate::signal::{AccelSignal, AttitudeEmitter, GyroSignal, ParametersSignal};
+use fc_common::attitude;
+
+#[embassy_executor::task]
+pub async fn run(
+    gyro_signal: GyroSignal,
+    accel_signal: AccelSignal,
+    attitude_emitter: AttitudeEmitter,
+    parameters_signal: ParametersSignal,
+) {
+    attitude::run(
+        gyro_signal,
+        accel_signal,
+        attitude_emitter,
+        parameters_signal,
+    )
+    .await
+}
@@ -1,11 +1,10 @@
 use crate::signal::{
-    BatteryLevel, BatteryStatus, DroneBatteryLevelEmitter, DroneBatteryStatusEmitter,
+    DroneBatteryLevelEmitter, DroneBatteryStatusEmitter, DroneBatteryVoltageEmitter,
+    ParametersSignal,
 };
-use defmt::info;
 use embassy_stm32::adc::{Adc, AnyAdcChannel, Resolution, SampleTime};
 use embassy_stm32::peripherals::ADC1;
-use embassy_time::{Duration, Ticker};
-use num_traits::float::Float;
+use fc_common::bms::{self, VoltageSensor};
 
 const ADC_MAX: u32 = 4095; // 12-bit
 const VDDA_MV: u32 = 3300; // assume 3.3V
@@ -14,49 +13,42 @@ const VDDA_MV: u32 = 3300; // assume 3.3V
 const R_TOP: u32 = 20_000;
 const R_BOT: u32 = 10_000;
 
-const BATTERY_CUTOFF_MV: u32 = 7_000; // 3500mV per cell for a 2S LiPo.
-const BATTERY_CRITICAL_MV: u32 = 7_300;
-const BATTERY_LOW_MV: u32 = 7_500;
-const BATTERY_MAX_MV: u32 = 8_400;
-const BATTERY_RANGE_MV: u32 = BATTERY_MAX_MV - BATTERY_CUTOFF_MV;
+/// The pack voltage, through the divider on an ADC pin.
+struct BatteryAdc {
+    adc: Adc<'static, ADC1>,
+    channel: AnyAdcChannel<ADC1>,
+}
+
+impl VoltageSensor for BatteryAdc {
+    fn read_mv(&mut self) -> u32 {
+        let raw = self.adc.blocking_read(&mut self.channel) as u32;
+        let pin_mv = raw * VDDA_MV / ADC_MAX;
+        pin_mv * (R_TOP + R_BOT) / R_BOT
+    }
+}
 
 #[embassy_executor::task]
 pub async fn run(
-    mut adc_channel: AnyAdcChannel<ADC1>,
+    adc_channel: AnyAdcChannel<ADC1>,
     mut adc: Adc<'static, ADC1>,
-    mut battery_level_emitter: DroneBatteryLevelEmitter,
-    mut battery_status_emitter: DroneBatteryStatusEmitter,
+    battery_level_emitter: DroneBatteryLevelEmitter,
+    battery_voltage_emitter: DroneBatteryVoltageEmitter,
+    battery_status_emitter: DroneBatteryStatusEmitter,
+    parameters_signal: ParametersSignal,
 ) {
     adc.set_resolution(Resolution::BITS12);
     adc.set_sample_time(SampleTime::CYCLES112);
 
-    // TODO: Re-write this to sample at 200-500 HZ. Sample vrefint and battery each time, average the samples and signal every 500ms.
-    let mut ticker = Ticker::every(Duration::from_millis(500));
-    loop {
-        let raw = adc.blocking_read(&mut adc_channel) as u32;
-        let pin_mv = raw * VDDA_MV / ADC_MAX;
-        let mut battery_mv = pin_mv * (R_TOP + R_BOT) / R_BOT;
-        info!("Read Battery (adjusted): {} ({})", pin_mv, battery_mv);
-        if battery_mv < BATTERY_CUTOFF_MV {
-            battery_mv = BATTERY_CUTOFF_MV;
-        }
-
-        let voltage_range = (battery_mv - BATTERY_CUTOFF_MV) as f32 / BATTERY_RANGE_MV as f32;
-        let level = BatteryLevel((voltage_range.clamp(0.0, 1.0) * 100.0).ceil() as u8);
-
-        let status = if battery_mv <= BATTERY_CUTOFF_MV {
-            BatteryStatus::Cutoff
-        } else if battery_mv <= BATTERY_CRITICAL_MV {
-            BatteryStatus::Critical
-        } else if battery_mv <= BATTERY_LOW_MV {
-            BatteryStatus::Low
-        } else {
-            BatteryStatus::Ok
-        };
-
-        battery_level_emitter.emit(level);
-        battery_status_emitter.emit(status);
-
-        ticker.next().await;
-    }
+    let sensor = BatteryAdc {
+        adc,
+        channel: adc_channel,
+    };
+    bms::run(
+        sensor,
+        battery_level_emitter,
+        battery_voltage_emitter,
+        battery_status_emitter,
+        parameters_signal,
+    )
+    .await
 }
@@ -1,3 +1,4 @@
+use crate::signal::{AltitudeEmitter, BarometerZeroSignal, ParametersSignal, VerticalSpeedEmitter};
 use bmp390_rs::ResetPolicy;
 use bmp390_rs::register::osr::{OsrCfg, Oversampling};
 use bmp390_rs::typestate::Bmp390Builder;
@@ -8,13 +9,25 @@ use embassy_stm32::gpio::Output;
 use embassy_stm32::mode::Async;
 use embassy_stm32::spi::Spi;
 use embassy_sync::blocking_mutex::raw::NoopRawMutex;
-use embassy_time::{Delay, Timer};
+use embassy_time::{Delay, Instant, Timer};
+use fc_common::SignalBase;
+use fc_common::param::Param;
 use libm::powf;
+use uom::si::f32::{Length, Velocity};
+use uom::si::length::meter;
+use uom::si::velocity::meter_per_second;
+
+/// Weight of the newest sample in the low-pass filtered vertical speed.
+const VERTICAL_SPEED_ALPHA: f32 = 0.3;
 
 #[embassy_executor::task]
 pub async fn run(
     spi_device: SpiDevice<'static, NoopRawMutex, Spi<'static, Async>, Output<'static>>,
     irq: ExtiInput<'static>,
+    mut altitude_emitter: AltitudeEmitter,
+    mut vertical_speed_emitter: VerticalSpeedEmitter,
+    mut barometer_zero_signal: BarometerZeroSignal,
+    mut parameters_signal: ParametersSignal,
 ) {
     info!("Altimeter init");
     let mut device = Bmp390Builder::new()
@@ -29,21 +42,55 @@ pub async fn run(
 
     device
         .set_oversampling_config(&OsrCfg {
-            osr_p: Oversampling::X8,
+            osr_p: oversampling(parameters_signal.get().get_u8(Param::BaroOversampling)),
             osr_t: Oversampling::X1,
         })
         .await
         .unwrap();
 
     let initial_measurement = device.read_measurement().await.unwrap();
-    let reference_pressure = initial_measurement.pressure_pascal();
+    let mut reference_pressure = initial_measurement.pressure_pascal();
+    let mut zero_request = barometer_zero_signal.get();
+    let mut last_altitude = 0.0;
+    let mut last_measurement = Instant::now();
+    let mut vertical_speed = 0.0;
 
     loop {
         let measurement = device.read_measurement().await.unwrap();
+        let now = Instant::now();
         let pressure = measurement.pressure_pascal();
+        if barometer_zero_signal.get() != zero_request {
+            zero_request = barometer_zero_signal.get();
+            reference_pressure = pressure;
+            // Keeps the jump to zero altitude out of the vertical speed.
+            last_altitude = 0.0;
+            info!("Barometer zeroed");
+        }
         let altitude = 44330.0 * (1.0 - powf(pressure / reference_pressure, 1.0 / 5.255));
-        info!("Altitude: {}", altitude);
+
+        let dt = (now - last_measurement).as_micros() as f32 / 1_000_000.0;
+        if dt > 0.0 {
+            let speed = (altitude - last_altitude) / dt;
+            vertical_speed += VERTICAL_SPEED_ALPHA * (speed - vertical_speed);
+        }
+        last_altitude = altitude;
+        last_measurement = now;
+
+        altitude_emitter.emit(Length::new::<meter>(altitude));
+        vertical_speed_emitter.emit(Velocity::new::<meter_per_second>(vertical_speed));
 
         Timer::after_millis(500).await;
     }
 }
+
+/// Maps the power of two stored in [`Param::BaroOversampling`] to the sensor setting.
+fn oversampling(exponent: u8) -> Oversampling {
+    match exponent {
+        0 => Oversampling::X1,
+        1 => Oversampling::X2,
+        2 => Oversampling::X4,
+        3 => Oversampling::X8,
+        4 => Oversampling::X16,
+        _ => Oversampling::X32,
+    }
+}
@@ -0,0 +1,31 @@
+use crate::signal::{AccelEmitter, GyroCalibrationSignal, GyroEmitter, ParametersSignal};
+use defmt::{error, info, Debug2Format};
+use embassy_embedded_hal::shared_bus::asynch::spi::SpiDevice;
+use embassy_stm32::exti::ExtiInput;
+use embassy_stm32::gpio::Output;
+use embassy_stm32::mode::Async;
+use embassy_stm32::spi::Spi;
+use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+use fc_common::imu::{self, Icm20948};
+
+#[embassy_executor::task]
+pub async fn run(
+    spi_device: SpiDevice<'static, NoopRawMutex, Spi<'static, Async>, Output<'static>>,
+    irq: ExtiInput<'static>,
+    gyro_emitter: GyroEmitter,
+    accel_emitter: AccelEmitter,
+    gyro_calibration_signal: GyroCalibrationSignal,
+    parameters_signal: ParametersSignal,
+) {
+    info!("IMU init");
+    let Err(e) = imu::run(
+        Icm20948::new(spi_device),
+        irq,
+        gyro_emitter,
+        accel_emitter,
+        gyro_calibration_signal,
+        parameters_signal,
+    )
+    .await;
+    error!("IMU failed: {}", Debug2Format(&e));
+}
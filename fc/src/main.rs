@@ -1,13 +1,23 @@
 #![no_std]
 #![no_main]
+mod attitude;
 mod bms;
 mod env;
+mod imu;
 mod radio;
+mod rate;
 mod signal;
 
 use crate::signal::{
-    altitude_signal, drone_battery_level_signal, drone_battery_status_signal, new_drone_battery_level_signal_emitter,
-    new_drone_battery_status_signal_emitter, BatteryStatus,
+    accel_signal, altitude_signal, armed_signals, attitude_signals, barometer_zero_signal, boot_settled_signal,
+    drone_battery_level_signal, drone_battery_status_signals, drone_battery_voltage_signal, flight_mode_signals,
+    gyro_calibration_signal, gyro_signals, new_accel_signal_emitter, new_altitude_signal_emitter,
+    new_armed_signal_emitter, new_attitude_signal_emitter, new_barometer_zero_signal_emitter,
+    new_boot_settled_signal_emitter, new_drone_battery_level_signal_emitter, new_drone_battery_status_signal_emitter,
+    new_drone_battery_voltage_signal_emitter, new_flight_mode_signal_emitter, new_gyro_calibration_signal_emitter,
+    new_gyro_signal_emitter, new_parameters_signal_emitter, new_rate_output_signal_emitter,
+    new_rc_channels_signal_emitter, new_uplink_statistics_signal_emitter, new_vertical_speed_signal_emitter,
+    parameters_signals, rc_channels_signals, vertical_speed_signal, BatteryStatus, DroneBatteryStatusSignal,
 };
 use defmt::*;
 use embassy_embedded_hal::shared_bus::asynch::spi::SpiDevice;
@@ -15,6 +25,7 @@ use embassy_executor::Spawner;
 use embassy_futures::select::{select, Either};
 use embassy_stm32::adc::{Adc, AdcChannel, VREF_CALIB_MV};
 use embassy_stm32::exti::ExtiInput;
+use embassy_stm32::flash::Flash;
 use embassy_stm32::gpio::{Level, Output, Pull, Speed};
 use embassy_stm32::mode::Async;
 use embassy_stm32::peripherals::ADC1;
@@ -24,11 +35,17 @@ use embassy_sync::blocking_mutex::raw::NoopRawMutex;
 use embassy_sync::mutex::Mutex;
 use embassy_time::{Duration, Timer};
 use fc_common::SignalBase;
+use fc_common::registry;
+use fc_common::storage::Store;
 use static_cell::StaticCell;
 use {defmt_rtt as _, panic_probe as _};
 
 static SPI_BUS: StaticCell<Mutex<NoopRawMutex, Spi<Async>>> = StaticCell::new();
 
+/// Sector 7, the last 128 KiB of flash. The firmware is nowhere near big enough to reach it.
+const STORAGE_OFFSET: u32 = 0x6_0000;
+const STORAGE_SIZE: u32 = 0x2_0000;
+
 fn read_calibrated_vdda(adc: &mut Adc<ADC1>) -> u32 {
     let mut vref = adc.enable_vrefint();
 
@@ -46,13 +63,15 @@ async fn timeout<A: Future>(duration: Duration, awaitable: A) -> Option<A::Outpu
     }
 }
 
-async fn battery_power_on_self_test() {
-    let mut battery_status = drone_battery_status_signal();
+async fn battery_power_on_self_test(mut battery_status: DroneBatteryStatusSignal) {
     let battery_status = timeout(Duration::from_secs(1), battery_status.next_value()).await;
 
     match battery_status {
         None => defmt::panic!("Battery status timeout"),
-        Some(BatteryStatus::Critical) => defmt::panic!("Battery level critical."),
+        Some(BatteryStatus::Critical) => {
+            signal::log_histories();
+            defmt::panic!("Battery level critical.")
+        }
         Some(_) => {}
     };
 }
@@ -61,6 +80,26 @@ async fn battery_power_on_self_test() {
 async fn main(spawner: Spawner) {
     let p = embassy_stm32::init(Default::default());
     info!("Flight controller starting.");
+    signal::start_telemetry_tap();
+
+    // Claiming every subscriber of the shared signals at once keeps their declared counts honest.
+    let [
+        self_test_battery_status,
+        telemetry_battery_status,
+        command_battery_status,
+    ] = unwrap!(drone_battery_status_signals());
+    let [
+        bms_parameters,
+        env_parameters,
+        imu_parameters,
+        attitude_parameters,
+        rate_parameters,
+    ] = unwrap!(parameters_signals());
+    let [attitude_gyro, rate_gyro] = unwrap!(gyro_signals());
+    let [telemetry_armed, rate_armed] = unwrap!(armed_signals());
+    let [telemetry_flight_mode, rate_flight_mode] = unwrap!(flight_mode_signals());
+    let [telemetry_attitude, rate_attitude] = unwrap!(attitude_signals());
+    let [command_rc_channels, rate_rc_channels] = unwrap!(rc_channels_signals());
 
     // Start-up BMS (Battery Management Subsystem) first
     spawner
@@ -68,12 +107,14 @@ async fn main(spawner: Spawner) {
             p.PA0.degrade_adc(),
             Adc::new(p.ADC1),
             new_drone_battery_level_signal_emitter(),
+            new_drone_battery_voltage_signal_emitter(),
             new_drone_battery_status_signal_emitter(),
+            bms_parameters,
         ))
         .unwrap();
 
     // Halt start-up if battery level is critical
-    battery_power_on_self_test().await;
+    battery_power_on_self_test(self_test_battery_status).await;
 
     // Setup SPI
     let mut spi_config = Config::default();
@@ -90,13 +131,36 @@ async fn main(spawner: Spawner) {
 
     let radio_ce = Output::new(p.PB12, Level::High, Speed::Low);
     let radio_irq = ExtiInput::new(p.PB1, p.EXTI1, Pull::Up);
+    let store = unwrap!(Store::new(Flash::new_blocking(p.FLASH), STORAGE_OFFSET, STORAGE_SIZE));
     spawner
         .spawn(radio::run(
             radio_device,
             radio_ce,
             radio_irq,
-            drone_battery_level_signal(),
-            altitude_signal(),
+            radio::TelemetrySources {
+                battery_level: unwrap!(drone_battery_level_signal()),
+                battery_voltage: unwrap!(drone_battery_voltage_signal()),
+                battery_status: telemetry_battery_status,
+                altitude: unwrap!(altitude_signal()),
+                vertical_speed: unwrap!(vertical_speed_signal()),
+                attitude: telemetry_attitude,
+                armed: telemetry_armed,
+                flight_mode: telemetry_flight_mode,
+            },
+            radio::CommandExecutor::new(
+                new_armed_signal_emitter(),
+                new_flight_mode_signal_emitter(),
+                new_barometer_zero_signal_emitter(),
+                new_gyro_calibration_signal_emitter(),
+                command_battery_status,
+                command_rc_channels,
+                unwrap!(boot_settled_signal()),
+            ),
+            new_rc_channels_signal_emitter(),
+            new_uplink_statistics_signal_emitter(),
+            new_parameters_signal_emitter(),
+            new_boot_settled_signal_emitter(),
+            store,
         ))
         .unwrap();
 
@@ -104,7 +168,55 @@ async fn main(spawner: Spawner) {
     let bmp390_device = SpiDevice::new(spi_bus, bmp390_cs);
     let bmp390_irq = ExtiInput::new(p.PB6, p.EXTI6, Pull::Up);
 
-    spawner.spawn(env::run(bmp390_device, bmp390_irq)).unwrap();
+    spawner
+        .spawn(env::run(
+            bmp390_device,
+            bmp390_irq,
+            new_altitude_signal_emitter(),
+            new_vertical_speed_signal_emitter(),
+            unwrap!(barometer_zero_signal()),
+            env_parameters,
+        ))
+        .unwrap();
+
+    // ICM-20948, on the same bus through level shifters.
+    let icm_cs = Output::new(p.PB10, Level::High, Speed::Low);
+    let icm_device = SpiDevice::new(spi_bus, icm_cs);
+    let icm_irq = ExtiInput::new(p.PB2, p.EXTI2, Pull::Down);
+
+    spawner
+        .spawn(imu::run(
+            icm_device,
+            icm_irq,
+            new_gyro_signal_emitter(),
+            new_accel_signal_emitter(),
+            unwrap!(gyro_calibration_signal()),
+            imu_parameters,
+        ))
+        .unwrap();
+    spawner
+        .spawn(attitude::run(
+            attitude_gyro,
+            unwrap!(accel_signal()),
+            new_attitude_signal_emitter(),
+            attitude_parameters,
+        ))
+        .unwrap();
+    spawner
+        .spawn(rate::run(
+            rate_gyro,
+            rate_rc_channels,
+            rate_armed,
+            rate_flight_mode,
+            rate_attitude,
+            new_rate_output_signal_emitter(),
+            rate_parameters,
+        ))
+        .unwrap();
+
+    for info in registry::signals() {
+        info!("Signal {}", info);
+    }
     /*
     let r = adc.blocking_read(&mut battery);
 
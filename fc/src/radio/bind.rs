@@ -0,0 +1,79 @@
+use super::Radio;
+use defmt::*;
+use embassy_stm32::exti::ExtiInput;
+use embassy_time::{Duration, Instant, with_deadline};
+use fc_common::bind::{BIND_ADDRESS, BIND_CHANNEL, BindInfo, BindListener};
+use nrf24_rs::config::DataPipe;
+
+/// How many times the drone has to be powered up in a row, each time for less than
+/// [`BOOT_SETTLE_TIME`], to enter bind mode.
+pub const BIND_POWER_CYCLES: u8 = 3;
+pub const BOOT_SETTLE_TIME: Duration = Duration::from_secs(3);
+/// How long a drone that is already bound waits for a controller before going back to its binding.
+const BIND_TIMEOUT: Duration = Duration::from_secs(60);
+/// How long the drone keeps listening after accepting an offer. The accept packet is sent in the ack
+/// of the next offer packet, and the controller stops offering once it has it.
+const BIND_LINGER: Duration = Duration::from_millis(500);
+
+/// Listens for a controller offering to bind, and returns what it offered. Gives up after
+/// [`BIND_TIMEOUT`] if there is a `previous` binding to go back to.
+pub async fn listen(
+    radio: &mut Radio,
+    irq: &mut ExtiInput<'static>,
+    previous: Option<&BindInfo>,
+) -> Option<BindInfo> {
+    info!("Bind mode");
+    radio.stop_listening().await.unwrap();
+    radio.set_channel(BIND_CHANNEL).await.unwrap();
+    radio
+        .open_reading_pipe(DataPipe::DP0, &BIND_ADDRESS)
+        .await
+        .unwrap();
+    radio.flush_rx().await.unwrap();
+    radio.flush_tx().await.unwrap();
+    radio.start_listening().await.unwrap();
+
+    let mut listener = BindListener::new();
+    let mut accepted: Option<BindInfo> = None;
+    let mut deadline = match previous {
+        Some(_) => Instant::now() + BIND_TIMEOUT,
+        None => Instant::MAX,
+    };
+    loop {
+        if with_deadline(deadline, irq.wait_for_low()).await.is_err() {
+            if accepted.is_none() {
+                info!("No controller offered to bind");
+            }
+            break;
+        }
+
+        let status = radio.status().await.unwrap();
+        if status.data_ready() {
+            while !radio.rx_fifo_empty().await.unwrap() {
+                let mut buf = [0u8; 32];
+                let Ok(len) = radio.read(&mut buf).await else {
+                    continue;
+                };
+                let Some(offer) = listener.receive(&buf[..len]) else {
+                    continue;
+                };
+                if accepted.as_ref() != Some(&offer.info) {
+                    info!("Accepting {:?}", offer.info);
+                    accepted = Some(offer.info.clone());
+                }
+                // Replaces the accept packet of a previous offer, if it is still queued.
+                radio.flush_tx().await.unwrap();
+                radio
+                    .write_ack_payload(DataPipe::DP0, offer.packet())
+                    .await
+                    .unwrap();
+                deadline = Instant::now() + BIND_LINGER;
+            }
+        }
+        radio.reset_status().await.unwrap();
+    }
+
+    radio.stop_listening().await.unwrap();
+    radio.flush_tx().await.unwrap();
+    accepted
+}
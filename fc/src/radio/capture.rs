@@ -0,0 +1,42 @@
+//! Mirrors the frames the radio task sends and receives into the log, for the `capture-decode` tool
+//! to pick out. Only built with the `capture` feature, see [`fc_common::capture`].
+
+use defmt::*;
+use embassy_time::Instant;
+use fc_common::auth::Direction;
+use fc_common::capture::{CaptureRecord, MAX_RECORD_SIZE};
+use fc_common::link::Received;
+use fc_common::protocol::{Message, ProtocolError};
+
+/// An uplink frame that arrived as `bytes` on `channel`.
+pub fn arrived(channel: u8, bytes: &[u8], received: &Result<Received, ProtocolError>) {
+    let frame = received.as_ref().ok().map(|received| &received.frame);
+    write(CaptureRecord::arrived(
+        now_ms(),
+        Direction::Uplink,
+        channel,
+        bytes,
+        frame,
+    ));
+}
+
+/// An ack payload queued as `sealed` on `channel`. It goes out with the ack of the next uplink frame.
+pub fn queued(channel: u8, sealed: &[u8], message: &Message) {
+    write(CaptureRecord::sent(
+        now_ms(),
+        Direction::Downlink,
+        channel,
+        sealed,
+        message,
+    ));
+}
+
+fn write(record: CaptureRecord) {
+    let mut buf = [0u8; MAX_RECORD_SIZE];
+    let len = record.encode(&mut buf);
+    info!("capture {=[u8]:x}", buf[..len]);
+}
+
+fn now_ms() -> u32 {
+    Instant::now().as_millis() as u32
+}
@@ -0,0 +1,113 @@
+use crate::signal::{
+    ArmedEmitter, BarometerZeroEmitter, BatteryStatus, BootSettledSignal, DroneBatteryStatusSignal,
+    FlightModeEmitter, GyroCalibrationEmitter, RcChannelsSignal,
+};
+use defmt::*;
+use fc_common::SignalBase;
+use fc_common::command::{Command, CommandResult};
+use fc_common::rate::{self, ARM_MAX_THROTTLE, RC_TIMEOUT};
+use fc_common::receiver::CommandHandler;
+
+/// Carries out the commands received over the uplink.
+pub struct CommandExecutor {
+    armed: bool,
+    barometer_zero_requests: u8,
+    gyro_calibration_requests: u8,
+    reboot_requested: bool,
+    armed_emitter: ArmedEmitter,
+    flight_mode_emitter: FlightModeEmitter,
+    barometer_zero_emitter: BarometerZeroEmitter,
+    gyro_calibration_emitter: GyroCalibrationEmitter,
+    battery_status_signal: DroneBatteryStatusSignal,
+    rc_channels_signal: RcChannelsSignal,
+    boot_settled_signal: BootSettledSignal,
+}
+
+impl CommandExecutor {
+    pub fn new(
+        armed_emitter: ArmedEmitter,
+        flight_mode_emitter: FlightModeEmitter,
+        barometer_zero_emitter: BarometerZeroEmitter,
+        gyro_calibration_emitter: GyroCalibrationEmitter,
+        battery_status_signal: DroneBatteryStatusSignal,
+        rc_channels_signal: RcChannelsSignal,
+        boot_settled_signal: BootSettledSignal,
+    ) -> Self {
+        Self {
+            armed: false,
+            barometer_zero_requests: 0,
+            gyro_calibration_requests: 0,
+            reboot_requested: false,
+            armed_emitter,
+            flight_mode_emitter,
+            barometer_zero_emitter,
+            gyro_calibration_emitter,
+            battery_status_signal,
+            rc_channels_signal,
+            boot_settled_signal,
+        }
+    }
+
+    /// Whether the pilot's sticks are coming in, with the throttle down.
+    fn throttle_down(&mut self) -> bool {
+        self.rc_channels_signal
+            .get_if_fresh(RC_TIMEOUT)
+            .is_some_and(|channels| rate::throttle(&channels) <= ARM_MAX_THROTTLE)
+    }
+
+    fn set_armed(&mut self, armed: bool) {
+        self.armed = armed;
+        self.armed_emitter.emit_if_changed(armed);
+    }
+}
+
+impl CommandHandler for CommandExecutor {
+    fn execute(&mut self, command: Command) -> CommandResult {
+        let result = match command {
+            Command::Arm => match self.battery_status_signal.get() {
+                BatteryStatus::Critical | BatteryStatus::Cutoff => CommandResult::Rejected,
+                // The flash write that records the boot as settled would stall the rate loop.
+                _ if !self.boot_settled_signal.get() => CommandResult::Rejected,
+                // The motors would spin up as soon as it armed, or the pilot couldn't stop them.
+                _ if !self.throttle_down() => CommandResult::Rejected,
+                _ => {
+                    self.set_armed(true);
+                    CommandResult::Accepted
+                }
+            },
+            Command::Disarm => {
+                self.set_armed(false);
+                CommandResult::Accepted
+            }
+            Command::SetFlightMode(mode) => {
+                self.flight_mode_emitter.emit_if_changed(mode);
+                CommandResult::Accepted
+            }
+            // Everything below would upset the drone mid-flight.
+            _ if self.armed => CommandResult::Rejected,
+            Command::ZeroBarometer => {
+                self.barometer_zero_requests = self.barometer_zero_requests.wrapping_add(1);
+                self.barometer_zero_emitter.emit(self.barometer_zero_requests);
+                CommandResult::Accepted
+            }
+            Command::CalibrateGyro => {
+                self.gyro_calibration_requests = self.gyro_calibration_requests.wrapping_add(1);
+                self.gyro_calibration_emitter.emit(self.gyro_calibration_requests);
+                CommandResult::Accepted
+            }
+            Command::Reboot => {
+                self.reboot_requested = true;
+                CommandResult::Accepted
+            }
+            // The radio task handles these before they get here.
+            Command::SetHopBlacklist(_) | Command::SetLinkProfile(_) => CommandResult::Unsupported,
+        };
+        info!("Command {} -> {}", command, result);
+
+        result
+    }
+
+    fn reboot_requested(&self) -> bool {
+        self.reboot_requested
+    }
+}
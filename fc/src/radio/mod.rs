@@ -1,32 +1,67 @@
-use crate::signal::{AltitudeSignal, DroneBatteryLevelSignal};
+mod bind;
+#[cfg(feature = "capture")]
+mod capture;
+mod command;
+mod telemetry;
+
+pub use command::CommandExecutor;
+pub use telemetry::TelemetrySources;
+
+use crate::signal::{
+    BootSettledEmitter, ParametersEmitter, RcChannelsEmitter, UplinkStatisticsEmitter,
+};
 use defmt::*;
 use embassy_embedded_hal::shared_bus::asynch::spi::SpiDevice;
+use embassy_futures::select::select;
 use embassy_stm32::exti::ExtiInput;
+use embassy_stm32::flash::{Blocking, Flash};
 use embassy_stm32::gpio::Output;
 use embassy_stm32::mode::Async;
 use embassy_stm32::spi::Spi;
 use embassy_sync::blocking_mutex::raw::NoopRawMutex;
-use embassy_time::Delay;
-use fc_common::{DroneStatus, FlightInput, SignalBase, FLIGHT_INPUT_SIZE};
-use nrf24_rs::config::{DataPipe, NrfConfig, PALevel, PayloadSize};
+use embassy_time::{Delay, Instant, Timer, with_deadline};
+use fc_common::adapt::{self, LinkProfile};
+use fc_common::link::Received;
+use fc_common::param::ParamStore;
+use fc_common::protocol::{Message, ProtocolError, MAX_FRAME_SIZE};
+use fc_common::receiver::{self, ReceiverRadio};
+use fc_common::storage::Store;
+use nrf24_rs::config::{DataPipe, DataRate, NrfConfig, PALevel, PayloadSize};
 use nrf24_rs::Nrf24l01;
-use uom::si::length::centimeter;
-use zerocopy::{FromBytes, IntoBytes};
+
+type Radio = Nrf24l01<
+    SpiDevice<'static, NoopRawMutex, Spi<'static, Async>, Output<'static>>,
+    Output<'static>,
+    nrf24_rs::Async,
+>;
 
 #[embassy_executor::task]
 pub async fn run(
     spi_device: SpiDevice<'static, NoopRawMutex, Spi<'static, Async>, Output<'static>>,
     ce: Output<'static>,
     mut irq: ExtiInput<'static>,
-    mut battery_level_signal: DroneBatteryLevelSignal,
-    mut altitude_signal: AltitudeSignal,
+    mut telemetry: TelemetrySources,
+    mut executor: CommandExecutor,
+    rc_channels_emitter: RcChannelsEmitter,
+    uplink_statistics_emitter: UplinkStatisticsEmitter,
+    parameters_emitter: ParametersEmitter,
+    mut boot_settled_emitter: BootSettledEmitter,
+    mut store: Store<Flash<'static, Blocking>>,
 ) {
     info!("Radio init");
+    // The boot count sets the link's sessions apart from those of earlier boots, so the link can't
+    // start without it.
+    let unsettled_boots = unwrap!(store.record_boot());
     let mut delay = Delay {};
+    // Parameters are deliberately not kept in the store: it only writes around boot, since erasing
+    // stalls the CPU, and a tune that reverts on a power cycle can't strand the drone in a bad one.
+    // Every boot starts from the defaults.
+    let mut parameters = ParamStore::default();
 
     let config = NrfConfig::default()
         .channel(76)
-        .pa_level(PALevel::Min)
+        .pa_level(pa_level(LinkProfile::ROBUST))
+        .data_rate(data_rate(LinkProfile::ROBUST))
         .payload_size(PayloadSize::Dynamic)
         .ack_payloads_enabled(true);
 
@@ -39,97 +74,163 @@ pub async fn run(
     }
     info!("RX Radio connected");
 
+    let previous = store.binding().cloned();
+    let bind_requested = previous.is_none() || unsettled_boots >= bind::BIND_POWER_CYCLES;
+    if bind_requested {
+        // Otherwise every boot after the bind would count as one more quick power cycle.
+        if let Err(e) = store.record_settled() {
+            error!("Unable to record boot: {:?}", e);
+        }
+        let offered = bind::listen(&mut radio, &mut irq, previous.as_ref()).await;
+        if let Some(info) = offered.filter(|info| previous.as_ref() != Some(info)) {
+            if let Err(e) = store.save_binding(&info) {
+                error!("Unable to store binding: {:?}", e);
+            }
+        }
+    }
+    let Some(binding) = store.binding().cloned() else {
+        // Only if storing the first binding failed, since a drone that was never bound listens
+        // until a controller offers to bind.
+        error!("Not bound. Radio disabled");
+        return;
+    };
+    info!("Bound to {:?}", binding);
+
     radio
-        .open_reading_pipe(DataPipe::DP0, b"Node1")
+        .open_reading_pipe(DataPipe::DP0, &binding.address)
         .await
         .unwrap();
     radio.start_listening().await.unwrap();
 
     info!("Radio RX started!");
-    let mut i = 0u32;
-    loop {
-        if irq.is_low() {
-            let status = radio.status().await.unwrap();
-
-            if status.data_ready() {
-                // Drain RX FIFO
-                while !radio.rx_fifo_empty().await.unwrap() {
-                    let mut buf = [0u8; 32];
-                    match radio.read(&mut buf).await {
-                        Ok(len) => {
-                            if len != FLIGHT_INPUT_SIZE {
-                                info!("Received {} bytes. Discarding", len);
-                            } else {
-                                let input = FlightInput::read_from_bytes(&buf[0..len]).unwrap();
-                                // TODO COmment below 1 line back in
-                                info!("{} - RX {:?}", i, input);
-                                //info!("{} RX {} bytes: {:?}", i, len, core::str::from_utf8(&buf[..len]).unwrap());
-                                i = i.wrapping_add(1);
-                            }
-                            let altitude_in_cm: u32 =
-                                altitude_signal.get().get::<centimeter>() as u32;
-                            let drone_status = DroneStatus {
-                                battery_level: battery_level_signal.get().0,
-                                altitude: (altitude_in_cm / 25) as u8,
-                                temp: 0,
-                            };
-                            radio
-                                .write_ack_payload(DataPipe::DP0, drone_status.as_bytes())
-                                .await
-                                .unwrap();
-                        }
-                        Err(e) => {
-                            info!("Error while reading: {:?}", &e);
-                        }
-                    }
-                }
+    let settle_at = Instant::now() + bind::BOOT_SETTLE_TIME;
+    let mut listener = Listener { radio, irq };
+    let link = receiver::run(
+        &mut listener,
+        &binding,
+        store.boot_count(),
+        &mut parameters,
+        &mut executor,
+        &mut telemetry,
+        rc_channels_emitter,
+        uplink_statistics_emitter,
+        parameters_emitter,
+    );
+    let settle = async {
+        // Writing to flash stalls the CPU, so the drone doesn't arm until it's done.
+        if !bind_requested {
+            Timer::at(settle_at).await;
+            if let Err(e) = store.record_settled() {
+                error!("Unable to record boot: {:?}", e);
             }
-
-            radio.reset_status().await.unwrap();
         }
+        boot_settled_emitter.emit(true);
+        core::future::pending::<()>().await
+    };
+    select(link, settle).await;
 
+    info!("Rebooting");
+    cortex_m::peripheral::SCB::sys_reset();
+}
+
+/// The radio listening for the controller.
+struct Listener {
+    radio: Radio,
+    irq: ExtiInput<'static>,
+}
+
+impl ReceiverRadio for Listener {
+    async fn wait(&mut self, deadline: Instant) {
         info!("Waiting for IRQ...");
-        irq.wait_for_low().await;
-        /*while irq.is_low() {
-            let status = radio.status().await.unwrap();
-
-            if status.data_ready() {
-                // Drain RX FIFO
-                while !radio.rx_fifo_empty().await.unwrap() {
-                    let mut buf = [0u8; 32];
-                    match radio.read(&mut buf).await {
-                        Ok(len) => {
-                            if len != FLIGHT_INPUT_SIZE {
-                                info!("Received {} bytes. Discarding", len);
-                            } else {
-                                let input = FlightInput::read_from_bytes(&buf[0..len]).unwrap();
-                                // TODO COmment below 1 line back in
-                                info!("{} - RX {:?}", i, input);
-                                //info!("{} RX {} bytes: {:?}", i, len, core::str::from_utf8(&buf[..len]).unwrap());
-                                i = i.wrapping_add(1);
-                            }
-                            let altitude_in_cm: u32 =
-                                altitude_signal.get().get::<centimeter>() as u32;
-                            let drone_status = DroneStatus {
-                                battery_level: battery_level_signal.get().0,
-                                altitude: (altitude_in_cm / 25) as u8,
-                                temp: 0,
-                            };
-                            radio
-                                .write_ack_payload(DataPipe::DP0, drone_status.as_bytes())
-                                .await
-                                .unwrap();
-                        }
-                        Err(e) => {
-                            info!("Error while reading: {:?}", &e);
-                        }
-                    }
+        let _ = with_deadline(deadline, self.irq.wait_for_low()).await;
+    }
+
+    async fn read(&mut self, buf: &mut [u8; MAX_FRAME_SIZE]) -> Option<usize> {
+        while self.irq.is_low() {
+            if self.radio.rx_fifo_empty().await.unwrap() {
+                self.radio.reset_status().await.unwrap();
+                continue;
+            }
+            match self.radio.read(buf).await {
+                Ok(len) => return Some(len),
+                Err(e) => info!("Error while reading: {:?}", &e),
+            }
+        }
+        None
+    }
+
+    async fn queue_ack(&mut self, payload: &[u8]) {
+        self.radio
+            .write_ack_payload(DataPipe::DP0, payload)
+            .await
+            .unwrap();
+    }
+
+    async fn set_channel(&mut self, channel: u8) {
+        self.radio.set_channel(channel).await.unwrap();
+    }
+
+    async fn set_profile(&mut self, profile: LinkProfile) {
+        info!("Link profile {}", profile);
+        self.radio.set_data_rate(data_rate(profile)).await.unwrap();
+        self.radio.set_pa_level(pa_level(profile)).await.unwrap();
+    }
+
+    fn arrived(&mut self, channel: u8, bytes: &[u8], received: &Result<Received, ProtocolError>) {
+        #[cfg(feature = "capture")]
+        capture::arrived(channel, bytes, received);
+        #[cfg(not(feature = "capture"))]
+        let _ = channel;
+
+        match received {
+            Ok(Received { status, .. }) if !status.is_fresh() => {
+                info!("Discarding duplicate frame");
+            }
+            Ok(Received { frame, .. }) => {
+                let sequence = frame.header.stamp.counter;
+                match &frame.message {
+                    Message::RcChannels(channels) => info!("RX #{} {:?}", sequence, channels),
+                    Message::CommandRequest(request) => info!("RX #{} {:?}", sequence, request),
+                    Message::ParamRequest(request) => info!("RX #{} {:?}", sequence, request),
+                    Message::SessionOffer(offer) => info!("RX #{} {:?}", sequence, offer),
+                    message => info!("Unexpected message {:x}", message.message_type() as u8),
                 }
             }
-            radio.reset_status().await.unwrap();
+            Err(ProtocolError::StaleCounter(counter)) => {
+                info!("Discarding stale frame #{}", counter);
+            }
+            Err(ProtocolError::VersionMismatch(version)) => {
+                warn!("Controller speaks protocol version {}. Discarding", version);
+            }
+            Err(e) => {
+                info!(
+                    "Received invalid frame ({} bytes): {:?}. Discarding",
+                    bytes.len(),
+                    e
+                );
+            }
         }
+    }
 
-        info!("Waiting for IRQ...");
-        irq.wait_for_falling_edge().await;*/
+    #[cfg(feature = "capture")]
+    fn queued(&mut self, channel: u8, sealed: &[u8], message: &Message) {
+        capture::queued(channel, sealed, message);
+    }
+}
+
+fn data_rate(profile: LinkProfile) -> DataRate {
+    match profile.data_rate() {
+        adapt::DataRate::Kbps250 => DataRate::R250Kbps,
+        adapt::DataRate::Mbps1 => DataRate::R1Mbps,
+        adapt::DataRate::Mbps2 => DataRate::R2Mbps,
+    }
+}
+
+fn pa_level(profile: LinkProfile) -> PALevel {
+    match profile.power_level() {
+        adapt::PowerLevel::Min => PALevel::Min,
+        adapt::PowerLevel::Low => PALevel::Low,
+        adapt::PowerLevel::High => PALevel::High,
+        adapt::PowerLevel::Max => PALevel::Max,
     }
 }
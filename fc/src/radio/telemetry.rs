@@ -0,0 +1,72 @@
+use crate::signal::{
+    AltitudeSignal, ArmedSignal, AttitudeSignal, BatteryStatus, DroneBatteryLevelSignal,
+    DroneBatteryStatusSignal, DroneBatteryVoltageSignal, FlightModeSignal, VerticalSpeedSignal,
+};
+use embassy_time::Instant;
+use fc_common::SignalBase;
+use fc_common::link::LinkStatistics;
+use fc_common::protocol::Message;
+use fc_common::receiver::TelemetrySource;
+use fc_common::telemetry::{
+    AltitudeTelemetry, AttitudeTelemetry, BatteryTelemetry, DiagnosticsTelemetry, Faults,
+    TelemetryKind,
+};
+use uom::si::angle::degree;
+use uom::si::f32::Angle;
+use uom::si::length::centimeter;
+use uom::si::velocity::centimeter_per_second;
+
+/// The signals telemetry frames are built from.
+pub struct TelemetrySources {
+    pub battery_level: DroneBatteryLevelSignal,
+    pub battery_voltage: DroneBatteryVoltageSignal,
+    pub battery_status: DroneBatteryStatusSignal,
+    pub altitude: AltitudeSignal,
+    pub vertical_speed: VerticalSpeedSignal,
+    pub attitude: AttitudeSignal,
+    pub armed: ArmedSignal,
+    pub flight_mode: FlightModeSignal,
+}
+
+impl TelemetrySource for TelemetrySources {
+    fn message(&mut self, kind: TelemetryKind, uplink: &LinkStatistics) -> Message {
+        match kind {
+            TelemetryKind::Attitude => {
+                let attitude = self.attitude.get().euler();
+                let cdeg = |angle: Angle| (angle.get::<degree>() * 100.0) as i16;
+                Message::AttitudeTelemetry(AttitudeTelemetry {
+                    roll_cdeg: cdeg(attitude.roll),
+                    pitch_cdeg: cdeg(attitude.pitch),
+                    yaw_cdeg: cdeg(attitude.yaw),
+                    armed: self.armed.get() as u8,
+                    flight_mode: self.flight_mode.get() as u8,
+                    faults: battery_faults(self.battery_status.get()).0,
+                })
+            }
+            TelemetryKind::Battery => Message::BatteryTelemetry(BatteryTelemetry {
+                voltage_mv: self.battery_voltage.get().0,
+                battery_level: self.battery_level.get().0,
+                battery_status: self.battery_status.get() as u8,
+            }),
+            TelemetryKind::Altitude => Message::AltitudeTelemetry(AltitudeTelemetry {
+                altitude_cm: self.altitude.get().get::<centimeter>() as i32,
+                vertical_speed_cms: self.vertical_speed.get().get::<centimeter_per_second>() as i16,
+            }),
+            TelemetryKind::Diagnostics => Message::DiagnosticsTelemetry(DiagnosticsTelemetry {
+                uptime_s: Instant::now().as_secs() as u32,
+                uplink_received: uplink.received,
+                uplink_lost: uplink.lost,
+                uplink_loss_percent: uplink.loss_percent as u8,
+                uplink_rtt_ms: uplink.rtt_ms.unwrap_or(0),
+            }),
+        }
+    }
+}
+
+fn battery_faults(status: BatteryStatus) -> Faults {
+    match status {
+        BatteryStatus::Ok => Faults::NONE,
+        BatteryStatus::Low => Faults::BATTERY_LOW,
+        BatteryStatus::Critical | BatteryStatus::Cutoff => Faults::BATTERY_CRITICAL,
+    }
+}
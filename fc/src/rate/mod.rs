@@ -0,0 +1,27 @@
+use crate::signal::{
+    ArmedSignal, AttitudeSignal, FlightModeSignal, GyroSignal, ParametersSignal, RateOutputEmitter,
+    RcChannelsSignal,
+};
+use fc_common::rate;
+
+#[embassy_executor::task]
+pub async fn run(
+    gyro_signal: GyroSignal,
+    rc_channels_signal: RcChannelsSignal,
+    armed_signal: ArmedSignal,
+    flight_mode_signal: FlightModeSignal,
+    attitude_signal: AttitudeSignal,
+    rate_output_emitter: RateOutputEmitter,
+    parameters_signal: ParametersSignal,
+) {
+    rate::run(
+        gyro_signal,
+        rc_channels_signal,
+        armed_signal,
+        flight_mode_signal,
+        attitude_signal,
+        rate_output_emitter,
+        parameters_signal,
+    )
+    .await
+}
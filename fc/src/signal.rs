@@ -1,23 +1,62 @@
+use core::fmt::Debug;
+use defmt::{info, Debug2Format};
 use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
-use fc_common::{define_signal, Signal, SignalBase, SignalEmitter};
+use fc_common::attitude::Quaternion;
+pub use fc_common::bms::{BatteryLevel, BatteryVoltage};
+use fc_common::imu::Vector3;
+use fc_common::link::LinkStatistics;
+use fc_common::param::ParamStore;
+use fc_common::rate::RateOutput;
+use fc_common::rc::RcChannels;
+pub use fc_common::telemetry::{BatteryStatus, FlightMode};
+use fc_common::{define_signal, registry, Signal, SignalBase, SignalEmitter, SignalInfo};
 
 define_signal!(DroneBatteryLevel, BatteryLevel, 1);
-define_signal!(DroneBatteryStatus, BatteryStatus, 2);
-define_signal!(Altitude, uom::si::f32::Length, 1);
+// A minute of samples at 2 Hz, to look back on when the battery fails.
+define_signal!(DroneBatteryVoltage, BatteryVoltage, 1, history = 120);
+define_signal!(DroneBatteryStatus, BatteryStatus, 3);
+define_signal!(Altitude, uom::si::f32::Length, 1, history = 64);
+define_signal!(VerticalSpeed, uom::si::f32::Velocity, 1);
+define_signal!(Gyro, Vector3<uom::si::f32::AngularVelocity>, 2);
+define_signal!(Accel, Vector3<uom::si::f32::Acceleration>, 1);
+define_signal!(Attitude, Quaternion, 2);
+define_signal!(RcChannels, RcChannels, 2);
+define_signal!(RateOutput, RateOutput, 1);
+define_signal!(UplinkStatistics, LinkStatistics, 1);
+define_signal!(Armed, bool, 2);
+define_signal!(FlightMode, FlightMode, 2);
+// Bumped for every request to make the current barometer reading the zero altitude.
+define_signal!(BarometerZero, u8, 1);
+// Bumped for every request to calibrate the gyro at rest.
+define_signal!(GyroCalibration, u8, 1);
+// Set once the boot has been recorded as settled. That writes to flash, which stalls the CPU, so the
+// drone doesn't arm before.
+define_signal!(BootSettled, bool, 1);
+define_signal!(Parameters, ParamStore, 5);
 
-#[derive(Debug, Clone, Default, PartialEq)]
-pub struct BatteryLevel(pub u8);
+/// Logs the values of the signals worth watching as they're emitted. Others can be tapped with
+/// [`SignalInfo::set_tapped`] while debugging.
+pub fn start_telemetry_tap() {
+    registry::set_tap(Some(log_value));
+    for info in [drone_battery_voltage_signal_info(), altitude_signal_info()] {
+        info.set_tapped(true);
+    }
+}
 
-#[derive(Debug, Clone, PartialEq)]
-pub enum BatteryStatus {
-    Ok,
-    Low,      // 7.5 V
-    Critical, // 7.3 V
-    Cutoff,   // 7.0 V
+fn log_value(info: &'static SignalInfo, value: &dyn Debug) {
+    info!("{} = {}", info.name, Debug2Format(value));
 }
 
-impl Default for BatteryStatus {
-    fn default() -> Self {
-        BatteryStatus::Critical
+/// Logs the history of every signal that keeps one, for a post-mortem before giving up.
+pub fn log_histories() {
+    for info in registry::signals().filter(|info| info.has_history()) {
+        info.history(|value, emitted_at| {
+            info!(
+                "{} @ {} ms = {}",
+                info.name,
+                emitted_at.as_millis(),
+                Debug2Format(value)
+            );
+        });
     }
 }
@@ -0,0 +1,77 @@
+//! Getting capture records out of what the firmwares log.
+
+use fc_common::capture::{CaptureParser, CaptureRecord};
+
+/// The records in a raw byte stream, like the controller's serial output. Anything between them is skipped.
+pub fn from_bytes(bytes: &[u8]) -> Vec<CaptureRecord> {
+    let mut parser = CaptureParser::new();
+    bytes.iter().filter_map(|byte| parser.push(*byte)).collect()
+}
+
+/// The records in decoded defmt output, like the flight controller's RTT log, where every record is a
+/// `capture [ca, 2b, ..]` line.
+pub fn from_defmt(text: &str) -> Vec<CaptureRecord> {
+    let mut parser = CaptureParser::new();
+    text.lines()
+        .filter_map(defmt_bytes)
+        .flat_map(|bytes| {
+            bytes
+                .into_iter()
+                .filter_map(|byte| parser.push(byte))
+                .collect::<Vec<_>>()
+        })
+        .collect()
+}
+
+/// The bytes of a capture line, with or without `0x` prefixes.
+fn defmt_bytes(line: &str) -> Option<Vec<u8>> {
+    let start = line.find("capture [")? + "capture [".len();
+    let end = start + line[start..].find(']')?;
+    line[start..end]
+        .split(',')
+        .map(|byte| {
+            let byte = byte.trim();
+            u8::from_str_radix(byte.strip_prefix("0x").unwrap_or(byte), 16).ok()
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use fc_common::auth::Direction;
+    use fc_common::capture::MAX_RECORD_SIZE;
+
+    use super::*;
+
+    fn encoded(record: &CaptureRecord) -> Vec<u8> {
+        let mut buf = [0u8; MAX_RECORD_SIZE];
+        let len = record.encode(&mut buf);
+        buf[..len].to_vec()
+    }
+
+    #[test]
+    fn picks_records_out_of_logs() {
+        let first = CaptureRecord::rejected(1000, Direction::Uplink, 12, &[1, 2, 3]);
+        let second = CaptureRecord::rejected(1020, Direction::Uplink, 40, &[4, 5]);
+
+        let mut serial = b"tick! 1   fail: 0\n".to_vec();
+        serial.extend(encoded(&first));
+        serial.extend(b"tick! 2   fail: 0\n");
+        serial.extend(encoded(&second));
+        assert_eq!(from_bytes(&serial), [first.clone(), second.clone()]);
+
+        let line = |record: &CaptureRecord, prefix: &str| {
+            let bytes: Vec<_> = encoded(record)
+                .iter()
+                .map(|byte| format!("{prefix}{byte:02x}"))
+                .collect();
+            format!("0.123456 INFO  capture [{}]\n", bytes.join(", "))
+        };
+        let log = format!(
+            "0.100000 INFO  Waiting for IRQ...\n{}0.110000 INFO  capture [zz]\n{}",
+            line(&first, ""),
+            line(&second, "0x")
+        );
+        assert_eq!(from_defmt(&log), [first, second]);
+    }
+}
@@ -0,0 +1,103 @@
+//! Decodes radio traffic captured by the controller or the flight controller, see `fc_common::capture`.
+//!
+//! ```text
+//! capture-decode [--defmt] [--csv] [--timeline] [FILE]
+//! ```
+//!
+//! Reads the capture from FILE, or from stdin. The controller's captures are its raw serial output, the flight
+//! controller's are its decoded defmt log, which `--defmt` reads. Prints one line per frame, or CSV with `--csv`,
+//! and `--timeline` adds the retries, acks and missing frames per second.
+
+mod input;
+mod report;
+
+use std::io::Read;
+use std::process::ExitCode;
+
+use report::{GapTracker, Timeline};
+
+const USAGE: &str = "usage: capture-decode [--defmt] [--csv] [--timeline] [FILE]";
+
+#[derive(Default)]
+struct Options {
+    defmt: bool,
+    csv: bool,
+    timeline: bool,
+    path: Option<String>,
+}
+
+fn parse_args(args: impl Iterator<Item = String>) -> Result<Options, String> {
+    let mut options = Options::default();
+    for arg in args {
+        match arg.as_str() {
+            "--defmt" => options.defmt = true,
+            "--csv" => options.csv = true,
+            "--timeline" => options.timeline = true,
+            "-h" | "--help" => return Err(USAGE.into()),
+            _ if arg.starts_with('-') => return Err(format!("unknown option {arg}\n{USAGE}")),
+            _ if options.path.is_some() => return Err(USAGE.into()),
+            _ => options.path = Some(arg),
+        }
+    }
+    Ok(options)
+}
+
+fn read_input(path: Option<&str>) -> std::io::Result<Vec<u8>> {
+    match path {
+        Some(path) => std::fs::read(path),
+        None => {
+            let mut bytes = Vec::new();
+            std::io::stdin().read_to_end(&mut bytes)?;
+            Ok(bytes)
+        }
+    }
+}
+
+fn main() -> ExitCode {
+    let options = match parse_args(std::env::args().skip(1)) {
+        Ok(options) => options,
+        Err(message) => {
+            eprintln!("{message}");
+            return ExitCode::FAILURE;
+        }
+    };
+    let bytes = match read_input(options.path.as_deref()) {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            eprintln!("Unable to read capture: {e}");
+            return ExitCode::FAILURE;
+        }
+    };
+    let records = if options.defmt {
+        input::from_defmt(&String::from_utf8_lossy(&bytes))
+    } else {
+        input::from_bytes(&bytes)
+    };
+
+    if options.csv {
+        println!("{}", report::CSV_HEADER);
+    }
+    let mut gaps = GapTracker::default();
+    let mut timeline = Timeline::default();
+    for record in &records {
+        let gap = gaps.track(record);
+        timeline.record(record, gap);
+        if options.csv {
+            println!("{}", report::csv_row(record, gap));
+        } else {
+            println!("{}", report::text_row(record, gap));
+        }
+    }
+    if options.timeline {
+        // Keeps CSV output parseable.
+        let out = timeline.render();
+        if options.csv {
+            eprint!("{out}");
+        } else {
+            print!("\n{out}");
+        }
+    }
+    eprintln!("{} records", records.len());
+
+    ExitCode::SUCCESS
+}
@@ -0,0 +1,265 @@
+//! Turning capture records into text, CSV and timelines.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+use fc_common::auth::Direction;
+use fc_common::capture::{CaptureEvent, CaptureRecord};
+
+/// How many frames went missing before a frame, judging by the frame counters of each direction.
+#[derive(Default)]
+pub struct GapTracker {
+    last: [Option<u32>; 2],
+}
+
+impl GapTracker {
+    /// Only records with a frame count. A counter that goes backwards means the sender restarted.
+    pub fn track(&mut self, record: &CaptureRecord) -> Option<u32> {
+        let counter = record.frame()?.header.stamp.counter;
+        let last = self.last[record.direction as usize].replace(counter);
+        Some(match last {
+            Some(last) if counter > last => counter - last - 1,
+            _ => 0,
+        })
+    }
+}
+
+pub const CSV_HEADER: &str =
+    "time_ms,event,direction,channel,retries,acked,counter,gap,message_type,message";
+
+pub fn csv_row(record: &CaptureRecord, gap: Option<u32>) -> String {
+    let frame = record.frame();
+    let message = match &frame {
+        Some(frame) => format!("{:?}", frame.message),
+        None => hex(record.data()),
+    };
+    format!(
+        "{},{},{},{},{},{},{},{},{},\"{}\"",
+        record.time_ms,
+        event(record.event),
+        direction(record.direction),
+        record.channel,
+        optional(record.transmission.map(|transmission| transmission.retries)),
+        optional(record.transmission.map(|transmission| transmission.acked)),
+        optional(frame.as_ref().map(|frame| frame.header.stamp.counter)),
+        optional(gap),
+        optional(
+            frame
+                .as_ref()
+                .map(|frame| format!("{:?}", frame.header.message_type))
+        ),
+        message.replace('"', "\"\""),
+    )
+}
+
+pub fn text_row(record: &CaptureRecord, gap: Option<u32>) -> String {
+    let mut row = format!(
+        "{:>10.3} {:<8} {:<8} ch {:>3}",
+        record.time_ms as f64 / 1000.0,
+        direction(record.direction),
+        event(record.event),
+        record.channel
+    );
+    if let Some(transmission) = record.transmission {
+        let outcome = if transmission.acked { "acked" } else { "lost" };
+        let _ = write!(row, " {:>2} retries {:<5}", transmission.retries, outcome);
+    }
+    match record.frame() {
+        Some(frame) => {
+            let _ = write!(row, " #{} {:?}", frame.header.stamp.counter, frame.message);
+        }
+        None => {
+            let _ = write!(
+                row,
+                " {} bytes: {}",
+                record.data().len(),
+                hex(record.data())
+            );
+        }
+    }
+    if let Some(gap) = gap.filter(|gap| *gap > 0) {
+        let _ = write!(row, "  <-- {gap} missing");
+    }
+    row
+}
+
+/// What happened during one second of a capture.
+#[derive(Default, Debug, PartialEq)]
+pub struct Second {
+    pub sent: u32,
+    pub acked: u32,
+    pub retries: u32,
+    pub max_retries: u8,
+    pub received: u32,
+    pub rejected: u32,
+    pub missing: u32,
+}
+
+/// The capture summarized per second.
+#[derive(Default)]
+pub struct Timeline {
+    seconds: BTreeMap<u32, Second>,
+}
+
+impl Timeline {
+    pub fn record(&mut self, record: &CaptureRecord, gap: Option<u32>) {
+        let second = self.seconds.entry(record.time_ms / 1000).or_default();
+        match record.event {
+            CaptureEvent::Sent => second.sent += 1,
+            CaptureEvent::Received => second.received += 1,
+            CaptureEvent::Rejected => second.rejected += 1,
+        }
+        if let Some(transmission) = record.transmission {
+            second.acked += transmission.acked as u32;
+            second.retries += transmission.retries as u32;
+            second.max_retries = second.max_retries.max(transmission.retries);
+        }
+        second.missing += gap.unwrap_or(0);
+    }
+
+    pub fn render(&self) -> String {
+        let mut text =
+            String::from("    second  sent acked retries max received rejected missing\n");
+        for (second, s) in &self.seconds {
+            let _ = writeln!(
+                text,
+                "{:>10} {:>5} {:>5} {:>7} {:>3} {:>8} {:>8} {:>7}",
+                second,
+                s.sent,
+                s.acked,
+                s.retries,
+                s.max_retries,
+                s.received,
+                s.rejected,
+                s.missing
+            );
+        }
+        text
+    }
+}
+
+fn event(event: CaptureEvent) -> &'static str {
+    match event {
+        CaptureEvent::Sent => "sent",
+        CaptureEvent::Received => "received",
+        CaptureEvent::Rejected => "rejected",
+    }
+}
+
+fn direction(direction: Direction) -> &'static str {
+    match direction {
+        Direction::Uplink => "uplink",
+        Direction::Downlink => "downlink",
+    }
+}
+
+fn optional<T: ToString>(value: Option<T>) -> String {
+    value.map(|value| value.to_string()).unwrap_or_default()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use fc_common::auth::LinkKey;
+    use fc_common::capture::Transmission;
+    use fc_common::link::Link;
+    use fc_common::protocol::{MAX_FRAME_SIZE, Message};
+    use fc_common::rc::RcChannels;
+
+    use super::*;
+
+    /// The controller's end of a link the drone has accepted a session on.
+    fn controller_link() -> Link {
+        let key = LinkKey::new([3; 32]);
+        let mut link = Link::new(key.clone(), Direction::Uplink);
+        let mut drone = Link::new(key, Direction::Downlink);
+        link.offer_session(1);
+        drone.accept_sessions(1);
+        let mut buf = [0u8; MAX_FRAME_SIZE];
+        let offer = link.take_handshake().unwrap();
+        let len = link.encode(&offer, 1, &mut buf).unwrap();
+        drone.receive(&buf[..len], 1).unwrap();
+        let accept = drone.take_handshake().unwrap();
+        let len = drone.encode(&accept, 1, &mut buf).unwrap();
+        link.receive(&buf[..len], 1).unwrap();
+        link
+    }
+
+    /// Uplink frames as the controller captures them, skipping the frames whose number is in `skipped`.
+    fn uplink(count: u32, skipped: &[u32]) -> Vec<CaptureRecord> {
+        let mut link = controller_link();
+        let message = Message::RcChannels(RcChannels::default());
+        (0..count)
+            .filter_map(|i| {
+                let mut sealed = [0u8; MAX_FRAME_SIZE];
+                let len = link.encode(&message, 1, &mut sealed).unwrap();
+                let record =
+                    CaptureRecord::sent(i * 250, Direction::Uplink, 20, &sealed[..len], &message)
+                        .with_transmission(Transmission {
+                            retries: i as u8,
+                            acked: i % 2 == 0,
+                        });
+                (!skipped.contains(&i)).then_some(record)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn tracks_gaps_per_direction() {
+        let mut gaps = GapTracker::default();
+        let found: Vec<_> = uplink(6, &[2, 3])
+            .iter()
+            .map(|record| gaps.track(record))
+            .collect();
+        assert_eq!(found, [Some(0), Some(0), Some(2), Some(0)]);
+
+        // The other direction counts on its own, and rejected frames have no counter.
+        let rejected = CaptureRecord::rejected(0, Direction::Downlink, 20, &[1, 2]);
+        assert_eq!(gaps.track(&rejected), None);
+        assert_eq!(gaps.track(&uplink(1, &[])[0]), Some(0));
+    }
+
+    #[test]
+    fn timeline_per_second() {
+        let mut gaps = GapTracker::default();
+        let mut timeline = Timeline::default();
+        for record in uplink(8, &[5]) {
+            timeline.record(&record, gaps.track(&record));
+        }
+
+        assert_eq!(
+            timeline.seconds.get(&0),
+            Some(&Second {
+                sent: 4,
+                acked: 2,
+                retries: 6,
+                max_retries: 3,
+                ..Default::default()
+            })
+        );
+        assert_eq!(
+            timeline.seconds.get(&1),
+            Some(&Second {
+                sent: 3,
+                acked: 2,
+                retries: 17,
+                max_retries: 7,
+                missing: 1,
+                ..Default::default()
+            })
+        );
+    }
+
+    #[test]
+    fn rows() {
+        let record = &uplink(1, &[])[0];
+        assert!(
+            text_row(record, Some(0))
+                .starts_with("     0.000 uplink   sent     ch  20  0 retries acked #")
+        );
+        assert!(csv_row(record, Some(0)).starts_with("0,sent,uplink,20,0,true,"));
+    }
+}